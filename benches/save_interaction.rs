@@ -0,0 +1,124 @@
+//! Compares the ways `Db::save_interaction` has written a turn's items to SQLite (in memory):
+//! picking each item's sequence index with `COUNT(*)` against `MAX(sequence_index) + 1`
+//! inside the INSERT, and one INSERT per item against multi-row INSERTs in one transaction.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use sqlx::{sqlite::SqlitePoolOptions, QueryBuilder, SqlitePool};
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+const PAYLOAD: &str = r#"{"type":"message","role":"user","content":[{"type":"input_text","text":"Hello there"}]}"#;
+
+async fn pool() -> SqlitePool {
+    // One connection: each connection to `sqlite::memory:` gets a database of its own
+    let pool = SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await.unwrap();
+    sqlx::query(
+        "CREATE TABLE items (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            conversation_id TEXT NOT NULL,
+            sequence_index INTEGER NOT NULL,
+            item_type TEXT NOT NULL,
+            payload JSON NOT NULL
+        )",
+    )
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query("CREATE INDEX idx_items_seq ON items(conversation_id, sequence_index)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    pool
+}
+
+async fn insert_at_count(pool: &SqlitePool, conversation_id: &str) {
+    sqlx::query(
+        "INSERT INTO items (conversation_id, sequence_index, item_type, payload) \
+         VALUES (?, (SELECT COUNT(*) FROM items WHERE conversation_id = ?), 'input', ?)",
+    )
+    .bind(conversation_id)
+    .bind(conversation_id)
+    .bind(PAYLOAD)
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+async fn insert_after_max(pool: &SqlitePool, conversation_id: &str) {
+    sqlx::query(
+        "INSERT INTO items (conversation_id, sequence_index, item_type, payload) \
+         VALUES (?, (SELECT COALESCE(MAX(sequence_index) + 1, 0) FROM items WHERE conversation_id = ?), 'input', ?)",
+    )
+    .bind(conversation_id)
+    .bind(conversation_id)
+    .bind(PAYLOAD)
+    .execute(pool)
+    .await
+    .unwrap();
+}
+
+async fn insert_batch(pool: &SqlitePool, conversation_id: &str, items: usize) {
+    let mut tx = pool.begin().await.unwrap();
+    let (mut sequence_index,): (i64,) =
+        sqlx::query_as("SELECT COALESCE(MAX(sequence_index) + 1, 0) FROM items WHERE conversation_id = ?")
+            .bind(conversation_id)
+            .fetch_one(&mut *tx)
+            .await
+            .unwrap();
+    let mut query = QueryBuilder::new("INSERT INTO items (conversation_id, sequence_index, item_type, payload) ");
+    query.push_values(0..items, |mut row, _| {
+        row.push_bind(conversation_id).push_bind(sequence_index).push_bind("input").push_bind(PAYLOAD);
+        sequence_index += 1;
+    });
+    query.build().execute(&mut *tx).await.unwrap();
+    tx.commit().await.unwrap();
+}
+
+/// Times `iters` saves into a fresh conversation of a fresh database.
+fn timed<F, Fut>(rt: &Runtime, iters: u64, save: F) -> Duration
+where
+    F: Fn(SqlitePool) -> Fut,
+    Fut: std::future::Future<Output = ()>,
+{
+    rt.block_on(async {
+        let pool = pool().await;
+        let started = Instant::now();
+        for _ in 0..iters {
+            save(pool.clone()).await;
+        }
+        started.elapsed()
+    })
+}
+
+fn single_item_saves(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("save_1_item");
+    group.bench_function("index_from_count", |b| {
+        b.iter_custom(|iters| timed(&rt, iters, |pool| async move { insert_at_count(&pool, "conv").await }))
+    });
+    group.bench_function("index_from_max", |b| {
+        b.iter_custom(|iters| timed(&rt, iters, |pool| async move { insert_after_max(&pool, "conv").await }))
+    });
+    group.finish();
+}
+
+fn ten_item_saves(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("save_10_items");
+    group.bench_function("insert_per_item", |b| {
+        b.iter_custom(|iters| {
+            timed(&rt, iters, |pool| async move {
+                for _ in 0..10 {
+                    insert_after_max(&pool, "conv").await;
+                }
+            })
+        })
+    });
+    group.bench_function("multi_row_transaction", |b| {
+        b.iter_custom(|iters| timed(&rt, iters, |pool| async move { insert_batch(&pool, "conv", 10).await }))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, single_item_saves, ten_item_saves);
+criterion_main!(benches);
@@ -0,0 +1,63 @@
+//! Compares reading the upstream URL through `ArcSwap` (as `create_response` does) against
+//! an `RwLock`, with 16 threads reading concurrently.
+
+use arc_swap::ArcSwap;
+use criterion::{criterion_group, criterion_main, Criterion};
+use std::{
+    hint::black_box,
+    sync::{Arc, Barrier, RwLock},
+    thread,
+    time::{Duration, Instant},
+};
+
+const READERS: usize = 16;
+const URL: &str = "http://localhost:11434/v1/chat/completions";
+
+/// Runs `read` `iters` times on each of `READERS` threads and returns the slowest thread's time.
+fn contended(iters: u64, read: impl Fn() + Send + Sync + 'static) -> Duration {
+    let read = Arc::new(read);
+    let barrier = Arc::new(Barrier::new(READERS));
+    let handles: Vec<_> = (0..READERS)
+        .map(|_| {
+            let (read, barrier) = (read.clone(), barrier.clone());
+            thread::spawn(move || {
+                barrier.wait();
+                let started = Instant::now();
+                for _ in 0..iters {
+                    read();
+                }
+                started.elapsed()
+            })
+        })
+        .collect();
+    handles.into_iter().map(|h| h.join().unwrap()).max().unwrap_or_default()
+}
+
+fn upstream_url_reads(c: &mut Criterion) {
+    let mut group = c.benchmark_group("upstream_url_16_readers");
+
+    let swap = Arc::new(ArcSwap::from_pointee(URL.to_string()));
+    group.bench_function("arc_swap", |b| {
+        b.iter_custom(|iters| {
+            let swap = swap.clone();
+            contended(iters, move || {
+                black_box(swap.load_full().len());
+            })
+        })
+    });
+
+    let lock = Arc::new(RwLock::new(URL.to_string()));
+    group.bench_function("rw_lock", |b| {
+        b.iter_custom(|iters| {
+            let lock = lock.clone();
+            contended(iters, move || {
+                black_box(lock.read().unwrap().clone().len());
+            })
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, upstream_url_reads);
+criterion_main!(benches);
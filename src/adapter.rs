@@ -0,0 +1,281 @@
+use crate::{
+    transcoder::Transcoder,
+    types::{LegacyChatRequest, LegacyChunk, OrsEvent, OrsInputItem, OrsRequest},
+    upstream,
+};
+use reqwest::{Client, RequestBuilder};
+use serde_json::Value;
+use std::{fmt, sync::Arc, time::Duration};
+
+#[derive(Debug)]
+pub enum AdapterError {
+    /// The request uses something this backend can't express.
+    Unsupported(String),
+    /// An upstream chunk couldn't be understood.
+    InvalidChunk(String),
+}
+
+impl fmt::Display for AdapterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AdapterError::Unsupported(what) => write!(f, "{} is not supported by this upstream", what),
+            AdapterError::InvalidChunk(chunk) => write!(f, "failed to parse upstream chunk: {}", chunk),
+        }
+    }
+}
+
+impl std::error::Error for AdapterError {}
+
+/// Everything besides the (already merged and trimmed) input needed to call the upstream.
+pub struct RequestConfig<'a> {
+    pub client: &'a Client,
+    pub url: &'a str,
+    pub api_key: Option<&'a str>,
+    /// Forwarded so upstream logs can be matched with ours.
+    pub request_id: &'a str,
+    pub request: &'a OrsRequest,
+}
+
+/// Settings for transcoding one response stream.
+#[derive(Default)]
+pub struct StreamOptions {
+    /// The request set `parallel_tool_calls: false`.
+    pub sequential_tool_calls: bool,
+    /// Estimated prompt tokens, reported if the upstream never sends usage.
+    pub prompt_estimate: u32,
+    /// The request listed `output_text` in `include`, so done parts carry their full text.
+    pub full_text_parts: bool,
+}
+
+impl StreamOptions {
+    /// A chat completions transcoder with these settings, for adapters that translate into chunks.
+    pub fn transcoder(&self) -> Transcoder {
+        Transcoder::new()
+            .with_sequential_tool_calls(self.sequential_tool_calls)
+            .with_prompt_estimate(self.prompt_estimate)
+            .with_full_text_parts(self.full_text_parts)
+    }
+}
+
+/// Translates between ORS and one upstream API format.
+pub trait UpstreamAdapter: Send + Sync {
+    fn build_request(&self, input: Vec<OrsInputItem>, config: &RequestConfig) -> Result<RequestBuilder, AdapterError>;
+
+    /// Adds the upstream's credentials, and any headers all its requests need, to `builder`.
+    /// Used for the files API as well as completions.
+    fn authorize(&self, builder: RequestBuilder, api_key: Option<&str>) -> RequestBuilder {
+        match api_key {
+            Some(key) => builder.bearer_auth(key),
+            None => builder,
+        }
+    }
+
+    /// Content type of a successful streaming response.
+    fn stream_content_type(&self) -> &'static str {
+        "text/event-stream"
+    }
+
+    /// Whether the upstream accepts files uploaded through its files API as input.
+    fn supports_files(&self) -> bool {
+        false
+    }
+
+    /// Starts transcoding a new response stream.
+    fn stream(&self, options: StreamOptions) -> Box<dyn StreamTranscoder>;
+}
+
+/// Turns one response's upstream stream into ORS events.
+pub trait StreamTranscoder: Send {
+    /// Extracts the chunk payload from one line of the upstream body, or `None` for lines that
+    /// carry none (SSE `event:` lines, comments, blank lines).
+    fn payload<'a>(&self, line: &'a str) -> Option<&'a str> {
+        line.strip_prefix("data: ")
+    }
+
+    /// Transcodes the payload of one upstream SSE `data:` line.
+    fn transcode_chunk(&mut self, raw: &str) -> Result<Vec<OrsEvent>, AdapterError>;
+
+    /// Events closing the response once the upstream stream has ended.
+    fn finish(&mut self) -> Vec<OrsEvent>;
+
+    /// Events closing the items still open, as `incomplete`, when the response is cut short.
+    fn interrupt(&mut self) -> Vec<OrsEvent>;
+
+    /// An event telling the client the upstream is rate limiting and will be retried after `wait`.
+    fn queued(&mut self, wait: Duration) -> OrsEvent;
+
+    /// An event reporting that the response was aborted.
+    fn error(&mut self, code: &str, message: String) -> OrsEvent;
+
+    /// The `response.done` event ending a completed response, with its `model`, final `status`,
+    /// collected output and usage.
+    fn done(&mut self, model: &str, status: &str, output: Vec<Value>, usage: Option<Value>) -> OrsEvent;
+}
+
+/// How the API key is sent.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Auth {
+    Bearer,
+    /// Azure OpenAI's `api-key` header.
+    ApiKeyHeader,
+}
+
+/// Chat Completions, as spoken by OpenAI and compatible servers (vLLM, Ollama's `/v1`, Azure).
+pub struct OpenAiAdapter {
+    auth: Auth,
+}
+
+impl OpenAiAdapter {
+    pub fn new() -> Self {
+        Self { auth: Auth::Bearer }
+    }
+
+    pub fn azure() -> Self {
+        Self { auth: Auth::ApiKeyHeader }
+    }
+}
+
+impl UpstreamAdapter for OpenAiAdapter {
+    fn build_request(&self, input: Vec<OrsInputItem>, config: &RequestConfig) -> Result<RequestBuilder, AdapterError> {
+        let request = config.request;
+        let legacy_req = LegacyChatRequest {
+            model: request.model.clone(),
+            messages: upstream::transform_ors_to_legacy(request.instructions.as_deref(), input),
+            stream: true,
+            n: request.n,
+            tools: request.tools.as_deref().map(upstream::legacy_tools),
+            parallel_tool_calls: request.parallel_tool_calls,
+            modalities: request.modalities.clone(),
+            audio: request.audio.clone(),
+            user: request.user.clone(),
+            logit_bias: request.logit_bias.clone(),
+            logprobs: request.logprobs,
+            top_logprobs: request.top_logprobs,
+        };
+
+        let builder = config
+            .client
+            .post(config.url)
+            .header("x-request-id", config.request_id)
+            .json(&legacy_req);
+        Ok(self.authorize(builder, config.api_key))
+    }
+
+    fn authorize(&self, builder: RequestBuilder, api_key: Option<&str>) -> RequestBuilder {
+        match (api_key, self.auth) {
+            (Some(key), Auth::Bearer) => builder.bearer_auth(key),
+            (Some(key), Auth::ApiKeyHeader) => builder.header("api-key", key),
+            (None, _) => builder,
+        }
+    }
+
+    /// Azure's files API is separate from its deployments', so only OpenAI proper.
+    fn supports_files(&self) -> bool {
+        self.auth == Auth::Bearer
+    }
+
+    fn stream(&self, options: StreamOptions) -> Box<dyn StreamTranscoder> {
+        Box::new(options.transcoder())
+    }
+}
+
+impl StreamTranscoder for Transcoder {
+    fn transcode_chunk(&mut self, raw: &str) -> Result<Vec<OrsEvent>, AdapterError> {
+        if raw == "[DONE]" {
+            return Ok(Vec::new());
+        }
+        let chunk: LegacyChunk = serde_json::from_str(raw).map_err(|_| AdapterError::InvalidChunk(raw.to_string()))?;
+        Ok(self.process(chunk))
+    }
+
+    fn finish(&mut self) -> Vec<OrsEvent> {
+        Transcoder::finish(self)
+    }
+
+    fn interrupt(&mut self) -> Vec<OrsEvent> {
+        Transcoder::interrupt(self)
+    }
+
+    fn queued(&mut self, wait: Duration) -> OrsEvent {
+        Transcoder::queued(self, wait)
+    }
+
+    fn error(&mut self, code: &str, message: String) -> OrsEvent {
+        Transcoder::error(self, code, message)
+    }
+
+    fn done(&mut self, model: &str, status: &str, output: Vec<Value>, usage: Option<Value>) -> OrsEvent {
+        Transcoder::done(self, model, status, output, usage)
+    }
+}
+
+/// Picks the adapter for `UPSTREAM_TYPE`.
+pub fn from_type(upstream_type: &str) -> Result<Arc<dyn UpstreamAdapter>, String> {
+    match upstream_type {
+        "openai" => Ok(Arc::new(OpenAiAdapter::new())),
+        "azure" => Ok(Arc::new(OpenAiAdapter::azure())),
+        "ollama" => Ok(Arc::new(upstream::ollama::OllamaAdapter)),
+        "anthropic" => Ok(Arc::new(upstream::anthropic::AnthropicAdapter)),
+        other => Err(format!("unknown UPSTREAM_TYPE: {} (expected openai, azure, ollama or anthropic)", other)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request() -> OrsRequest {
+        serde_json::from_value(serde_json::json!({ "model": "gpt-4o", "input": [], "instructions": "Be terse", "user": "user-42" })).unwrap()
+    }
+
+    fn build(adapter: &OpenAiAdapter, api_key: Option<&str>) -> reqwest::Request {
+        let client = Client::new();
+        let request = request();
+        let config = RequestConfig {
+            client: &client,
+            url: "http://upstream.test/v1/chat/completions",
+            api_key,
+            request_id: "req-1",
+            request: &request,
+        };
+        adapter.build_request(Vec::new(), &config).unwrap().build().unwrap()
+    }
+
+    #[test]
+    fn test_openai_request() {
+        let req = build(&OpenAiAdapter::new(), Some("sk-test"));
+        assert_eq!(req.headers()["authorization"], "Bearer sk-test");
+        assert_eq!(req.headers()["x-request-id"], "req-1");
+
+        let body: serde_json::Value = serde_json::from_slice(req.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(body["model"], "gpt-4o");
+        assert_eq!(body["stream"], true);
+        assert_eq!(body["messages"][0]["content"], "Be terse");
+        assert_eq!(body["user"], "user-42");
+    }
+
+    #[test]
+    fn test_azure_uses_api_key_header() {
+        let req = build(&OpenAiAdapter::azure(), Some("az-key"));
+        assert_eq!(req.headers()["api-key"], "az-key");
+        assert!(req.headers().get("authorization").is_none());
+    }
+
+    #[test]
+    fn test_transcode_chunks() {
+        let mut stream = OpenAiAdapter::new().stream(StreamOptions::default());
+        let events = stream.transcode_chunk(r#"{"choices":[{"index":0,"delta":{"content":"Hi"}}]}"#).unwrap();
+        assert!(matches!(events.last(), Some(OrsEvent::TextDelta { delta, .. }) if delta == "Hi"));
+        assert!(stream.transcode_chunk("[DONE]").unwrap().is_empty());
+        assert!(matches!(stream.transcode_chunk("{oops"), Err(AdapterError::InvalidChunk(_))));
+    }
+
+    #[test]
+    fn test_from_type() {
+        assert!(from_type("openai").is_ok());
+        assert!(from_type("azure").is_ok());
+        assert!(from_type("ollama").is_ok());
+        assert!(from_type("anthropic").is_ok());
+        assert!(from_type("bogus").is_err());
+    }
+}
@@ -0,0 +1,300 @@
+use crate::{
+    db::{is_valid_metadata_key, Tenant, TenantUpsert, DEFAULT_TENANT_ID},
+    error_response,
+    tenants::is_valid_tenant_id,
+    AppState,
+};
+use axum::{
+    extract::{Query, State},
+    http::{header::AUTHORIZATION, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use subtle::ConstantTimeEq;
+
+#[derive(Deserialize, Debug)]
+pub struct PurgeRequest {
+    /// Only conversations created before this RFC 3339 timestamp are purged.
+    pub before: Option<String>,
+    /// Only conversations whose metadata matches every pair are purged.
+    #[serde(default)]
+    pub metadata: HashMap<String, String>,
+}
+
+/// Admin routes require `Authorization: Bearer $ADMIN_API_KEY`; without a configured key they're disabled.
+/// The token is compared in constant time, by digest so its length doesn't show either.
+pub fn is_authorized(state: &AppState, headers: &HeaderMap) -> bool {
+    let Some(expected) = state.admin_api_key.as_deref() else {
+        return false;
+    };
+    headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .is_some_and(|token| Sha256::digest(token).ct_eq(&Sha256::digest(expected)).into())
+}
+
+const DEFAULT_AUDIT_LIMIT: i64 = 100;
+const MAX_AUDIT_LIMIT: i64 = 1000;
+
+/// Reads the optional `from` and `to` query parameters, given as Unix seconds or RFC 3339.
+pub async fn parse_time_range(
+    state: &AppState,
+    params: &HashMap<String, String>,
+) -> Result<(Option<i64>, Option<i64>), Response> {
+    let mut range = [None, None];
+    for (bound, name) in range.iter_mut().zip(["from", "to"]) {
+        let Some(value) = params.get(name) else { continue };
+        if let Ok(secs) = value.parse::<i64>() {
+            *bound = Some(secs);
+            continue;
+        }
+        *bound = match state.db.parse_timestamp(value).await {
+            Ok(Some(secs)) => Some(secs),
+            Ok(None) => {
+                return Err(error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    format!("Invalid timestamp for {}: {}", name, value),
+                ))
+            }
+            Err(e) => {
+                tracing::error!("Failed to parse timestamp: {}", e);
+                return Err(error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to parse timestamp"));
+            }
+        };
+    }
+    let [from, to] = range;
+    Ok((from, to))
+}
+
+/// `GET /admin/audit`: audit records created in `[from, to)`, oldest first, at most `limit`
+/// (default 100, max 1000) of them.
+pub async fn audit_log(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    if !is_authorized(&state, &headers) {
+        return error_response(StatusCode::FORBIDDEN, "forbidden", "Invalid or missing admin API key");
+    }
+
+    let (from, to) = match parse_time_range(&state, &params).await {
+        Ok(range) => range,
+        Err(res) => return res,
+    };
+    let limit = match params.get("limit").map(|l| l.parse::<i64>()) {
+        None => DEFAULT_AUDIT_LIMIT,
+        Some(Ok(limit)) => limit.clamp(1, MAX_AUDIT_LIMIT),
+        Some(Err(_)) => {
+            return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", "limit must be an integer")
+        }
+    };
+
+    match state.db.get_audit_log(from.unwrap_or(0), to.unwrap_or(i64::MAX), limit).await {
+        Ok(records) => Json(serde_json::json!({ "object": "list", "data": records })).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to read audit log: {}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to read audit log")
+        }
+    }
+}
+
+pub async fn stats(State(state): State<AppState>, headers: HeaderMap) -> Response {
+    if !is_authorized(&state, &headers) {
+        return error_response(StatusCode::FORBIDDEN, "forbidden", "Invalid or missing admin API key");
+    }
+
+    let db_stats = match state.db.stats().await {
+        Ok(stats) => stats,
+        Err(e) => {
+            tracing::error!("Failed to collect DB stats: {}", e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to collect stats");
+        }
+    };
+
+    let mut body = state.stats.snapshot();
+    if let (Some(body), serde_json::Value::Object(db)) = (body.as_object_mut(), serde_json::json!(db_stats)) {
+        body.extend(db);
+        body.insert("db_write_queue_depth".to_string(), state.db_writer.queue_depth().into());
+        if let serde_json::Value::Object(jobs) = state.background_jobs.snapshot() {
+            body.extend(jobs);
+        }
+        body.insert("version".to_string(), env!("CARGO_PKG_VERSION").into());
+    }
+    Json(body).into_response()
+}
+
+/// Registers a tenant, or updates the one using the same API key. The response leaves out
+/// the keys.
+pub async fn register_tenant(State(state): State<AppState>, headers: HeaderMap, Json(tenant): Json<Tenant>) -> Response {
+    if !is_authorized(&state, &headers) {
+        return error_response(StatusCode::FORBIDDEN, "forbidden", "Invalid or missing admin API key");
+    }
+
+    if !is_valid_tenant_id(&tenant.id) {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "Tenant id must be 1-64 letters, digits, '_' or '-'",
+        );
+    }
+    if tenant.id == DEFAULT_TENANT_ID {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("Tenant id '{}' is reserved for requests without a tenant", DEFAULT_TENANT_ID),
+        );
+    }
+    if tenant.api_key.is_empty() {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", "api_key must not be empty");
+    }
+    if let Some(url) = tenant.upstream_url.as_deref().filter(|url| reqwest::Url::parse(url).is_err()) {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", format!("Invalid upstream_url: {}", url));
+    }
+
+    match state.db.upsert_tenant(&tenant).await {
+        Ok(TenantUpsert::Saved { replaced_key }) => {
+            state.tenants.invalidate(&tenant.api_key);
+            if let Some(key) = replaced_key {
+                // The old key stops working right away rather than once its cache entry expires
+                state.tenants.invalidate(&key);
+                tracing::info!("Rotated the API key of tenant {}", tenant.id);
+            }
+            tracing::info!("Registered tenant {}", tenant.id);
+            let body = serde_json::json!({
+                "id": tenant.id,
+                "upstream_url": tenant.upstream_url,
+                "has_upstream_key": tenant.upstream_key.is_some(),
+                "rate_limit_rps": tenant.rate_limit_rps,
+            });
+            (StatusCode::CREATED, Json(body)).into_response()
+        }
+        Ok(TenantUpsert::KeyInUse) => {
+            error_response(StatusCode::CONFLICT, "conflict", "api_key already belongs to another tenant")
+        }
+        Err(e) => {
+            tracing::error!("Failed to register tenant: {}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to register tenant")
+        }
+    }
+}
+
+pub async fn purge_conversations(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    Json(req): Json<PurgeRequest>,
+) -> Response {
+    if !is_authorized(&state, &headers) {
+        return error_response(StatusCode::FORBIDDEN, "forbidden", "Invalid or missing admin API key");
+    }
+
+    // An empty filter would wipe everything; make that an explicit choice elsewhere
+    if req.before.is_none() && req.metadata.is_empty() {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            "Purge requires at least one of: before, metadata",
+        );
+    }
+    if let Some(key) = req.metadata.keys().find(|k| !is_valid_metadata_key(k)) {
+        return error_response(
+            StatusCode::BAD_REQUEST,
+            "invalid_request_error",
+            format!("Invalid metadata filter key: {}", key),
+        );
+    }
+
+    let before = match &req.before {
+        None => None,
+        Some(ts) => match state.db.parse_timestamp(ts).await {
+            Ok(Some(secs)) => Some(secs),
+            Ok(None) => {
+                return error_response(
+                    StatusCode::BAD_REQUEST,
+                    "invalid_request_error",
+                    format!("Invalid timestamp for before: {}", ts),
+                )
+            }
+            Err(e) => {
+                tracing::error!("Failed to parse purge timestamp: {}", e);
+                return error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to purge conversations");
+            }
+        },
+    };
+
+    let filters: Vec<(String, String)> = req.metadata.into_iter().collect();
+    match state.db.purge_conversations(before, &filters).await {
+        Ok(purged_count) => {
+            tracing::info!(
+                "Purged {} conversations (before: {:?}, metadata: {:?})",
+                purged_count, req.before, filters
+            );
+            Json(serde_json::json!({ "purged_count": purged_count })).into_response()
+        }
+        Err(e) => {
+            tracing::error!("Failed to purge conversations: {}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to purge conversations")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::test_state;
+
+    #[tokio::test]
+    async fn test_is_authorized() {
+        let bearer = |token: &str| HeaderMap::from_iter([(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap())]);
+        let mut state = test_state().await;
+        assert!(!is_authorized(&state, &bearer("")));
+
+        state.admin_api_key = Some("s3cret".to_string());
+        assert!(is_authorized(&state, &bearer("s3cret")));
+        assert!(!is_authorized(&state, &bearer("s3cre")));
+        assert!(!is_authorized(&state, &bearer("s3cret2")));
+        assert!(!is_authorized(&state, &HeaderMap::new()));
+    }
+
+    async fn register(state: &AppState, id: &str, api_key: &str) -> StatusCode {
+        let headers = HeaderMap::from_iter([(AUTHORIZATION, "Bearer s3cret".parse().unwrap())]);
+        let tenant = Tenant {
+            id: id.to_string(),
+            api_key: api_key.to_string(),
+            upstream_key: None,
+            upstream_url: None,
+            rate_limit_rps: None,
+        };
+        register_tenant(State(state.clone()), headers, Json(tenant)).await.status()
+    }
+
+    #[tokio::test]
+    async fn test_registering_an_id_again_rotates_its_key() {
+        let mut state = test_state().await;
+        state.admin_api_key = Some("s3cret".to_string());
+        assert_eq!(register(&state, "acme", "sk-old").await, StatusCode::CREATED);
+        assert_eq!(register(&state, "acme", "sk-new").await, StatusCode::CREATED);
+
+        assert!(state.db.get_tenant("sk-old").await.unwrap().is_none());
+        assert_eq!(state.db.get_tenant("sk-new").await.unwrap().unwrap().id, "acme");
+    }
+
+    #[tokio::test]
+    async fn test_another_tenants_key_is_rejected() {
+        let mut state = test_state().await;
+        state.admin_api_key = Some("s3cret".to_string());
+        assert_eq!(register(&state, "acme", "sk-acme").await, StatusCode::CREATED);
+        assert_eq!(register(&state, "globex", "sk-globex").await, StatusCode::CREATED);
+
+        // Neither under a new id nor by rotating an existing tenant's key onto it
+        assert_eq!(register(&state, "initech", "sk-acme").await, StatusCode::CONFLICT);
+        assert_eq!(register(&state, "globex", "sk-acme").await, StatusCode::CONFLICT);
+
+        assert_eq!(state.db.get_tenant("sk-acme").await.unwrap().unwrap().id, "acme");
+        assert_eq!(state.db.get_tenant("sk-globex").await.unwrap().unwrap().id, "globex");
+    }
+}
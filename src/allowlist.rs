@@ -0,0 +1,154 @@
+use crate::error_response;
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, StatusCode},
+    middleware::Next,
+    response::Response,
+};
+use ipnet::IpNet;
+use std::{net::IpAddr, net::SocketAddr, sync::Arc};
+
+/// Client networks allowed to reach the proxy (`ALLOWED_CIDRS`).
+#[derive(Debug, Clone)]
+pub struct CidrAllowlist {
+    networks: Vec<IpNet>,
+    /// Take the client from `X-Forwarded-For` rather than the socket (`TRUST_PROXY_HEADERS`).
+    trust_proxy_headers: bool,
+}
+
+impl CidrAllowlist {
+    /// Parses a comma-separated CIDR list; bare addresses are taken as single hosts. Returns
+    /// `None` when the list is empty, i.e. everyone is allowed.
+    pub fn parse(cidrs: &str, trust_proxy_headers: bool) -> Result<Option<Self>, String> {
+        let networks = cidrs
+            .split(',')
+            .map(str::trim)
+            .filter(|cidr| !cidr.is_empty())
+            .map(|cidr| {
+                cidr.parse::<IpNet>()
+                    .or_else(|_| cidr.parse::<IpAddr>().map(IpNet::from))
+                    .map_err(|_| format!("invalid CIDR in ALLOWED_CIDRS: {}", cidr))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((!networks.is_empty()).then_some(Self { networks, trust_proxy_headers }))
+    }
+
+    pub fn allows(&self, ip: IpAddr) -> bool {
+        // An IPv4 client on a dual-stack socket shows up as ::ffff:a.b.c.d
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            v4 => v4,
+        };
+        self.networks.iter().any(|net| net.contains(&ip))
+    }
+
+    fn client_ip(&self, req: &Request) -> Option<IpAddr> {
+        let peer = req.extensions().get::<ConnectInfo<SocketAddr>>().map(|ConnectInfo(addr)| *addr);
+        client_ip(req.headers(), peer, self.trust_proxy_headers)
+    }
+}
+
+/// The client's address: the socket peer, or, when `trust_proxy_headers` is set and the header
+/// is present, the address the proxy in front of us appended to `X-Forwarded-For`.
+///
+/// Only the rightmost entry is taken: everything left of it came from the client (or from
+/// hops we can't vouch for), so a client could put any address there.
+pub fn client_ip(headers: &HeaderMap, peer: Option<SocketAddr>, trust_proxy_headers: bool) -> Option<IpAddr> {
+    if trust_proxy_headers {
+        let forwarded = headers
+            .get_all("x-forwarded-for")
+            .iter()
+            .next_back()
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.rsplit(',').next())
+            .and_then(|ip| ip.trim().parse().ok());
+        if forwarded.is_some() {
+            return forwarded;
+        }
+    }
+    peer.map(|addr| addr.ip())
+}
+
+pub async fn enforce(State(allowlist): State<Arc<CidrAllowlist>>, req: Request, next: Next) -> Response {
+    match allowlist.client_ip(&req) {
+        Some(ip) if allowlist.allows(ip) => next.run(req).await,
+        ip => {
+            tracing::warn!("Rejected request from {:?}: not in ALLOWED_CIDRS", ip);
+            error_response(StatusCode::FORBIDDEN, "forbidden", "IP not in allowlist")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app(cidrs: &str, trust_proxy_headers: bool) -> Router {
+        let allowlist = CidrAllowlist::parse(cidrs, trust_proxy_headers).unwrap().unwrap();
+        Router::new()
+            .route("/health", get(|| async { "OK" }))
+            .layer(axum::middleware::from_fn_with_state(Arc::new(allowlist), enforce))
+    }
+
+    fn request_from(peer: &str, forwarded_for: Option<&str>) -> Request {
+        let mut builder = Request::builder().uri("/health");
+        if let Some(forwarded_for) = forwarded_for {
+            builder = builder.header("x-forwarded-for", forwarded_for);
+        }
+        let mut req = builder.body(Body::empty()).unwrap();
+        req.extensions_mut().insert(ConnectInfo(peer.parse::<SocketAddr>().unwrap()));
+        req
+    }
+
+    #[tokio::test]
+    async fn test_peer_address_checked_against_allowlist() {
+        let app = app("10.0.0.0/8, 192.168.1.0/24", false);
+
+        let res = app.clone().oneshot(request_from("10.1.2.3:5000", None)).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // Without TRUST_PROXY_HEADERS the header can't be used to sneak in
+        let res = app.oneshot(request_from("172.16.0.1:5000", Some("10.1.2.3"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_for_used_when_trusted() {
+        let app = app("192.168.1.0/24", true);
+
+        let res = app.clone().oneshot(request_from("10.0.0.1:5000", Some("8.8.8.8, 192.168.1.7"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+
+        let res = app.clone().oneshot(request_from("192.168.1.7:5000", Some("8.8.8.8"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        // A client can prepend whatever it likes; only the entry our proxy appended counts
+        let res = app.oneshot(request_from("10.0.0.1:5000", Some("192.168.1.7, 8.8.8.8"))).await.unwrap();
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn test_client_ip_takes_the_last_hop() {
+        let peer = Some("10.0.0.1:5000".parse().unwrap());
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", "1.1.1.1, 2.2.2.2".parse().unwrap());
+        headers.append("x-forwarded-for", "3.3.3.3".parse().unwrap());
+        assert_eq!(client_ip(&headers, peer, true), Some("3.3.3.3".parse().unwrap()));
+        assert_eq!(client_ip(&headers, peer, false), Some("10.0.0.1".parse().unwrap()));
+        assert_eq!(client_ip(&HeaderMap::new(), peer, true), Some("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn test_parse_allowlist() {
+        assert!(CidrAllowlist::parse("", false).unwrap().is_none());
+        assert!(CidrAllowlist::parse("10.0.0.0/33", false).is_err());
+
+        let allowlist = CidrAllowlist::parse("127.0.0.1,fd00::/8", false).unwrap().unwrap();
+        assert!(allowlist.allows("127.0.0.1".parse().unwrap()));
+        assert!(allowlist.allows("::ffff:127.0.0.1".parse().unwrap()));
+        assert!(allowlist.allows("fd12::1".parse().unwrap()));
+        assert!(!allowlist.allows("127.0.0.2".parse().unwrap()));
+    }
+}
@@ -0,0 +1,133 @@
+use crate::{
+    allowlist,
+    db::{self, AuditEntry, Db},
+    request_id::RequestId,
+    AppState,
+};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::Response,
+};
+use std::{
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+/// A request's entry in `audit_log`, shared by everything working on the request and written
+/// once the last handle is dropped. Failed and abandoned requests are logged that way too, with
+/// whatever was learned before they ended.
+#[derive(Clone)]
+pub struct AuditHandle(Arc<Pending>);
+
+struct Pending {
+    db: Arc<Db>,
+    entry: Mutex<AuditEntry>,
+}
+
+impl AuditHandle {
+    pub fn new(db: Arc<Db>, entry: AuditEntry) -> Self {
+        Self(Arc::new(Pending { db, entry: Mutex::new(entry) }))
+    }
+
+    pub fn update(&self, f: impl FnOnce(&mut AuditEntry)) {
+        f(&mut self.0.entry.lock().unwrap());
+    }
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        let entry = std::mem::take(self.entry.get_mut().unwrap());
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            tracing::error!("Dropped audit entry for {}: no runtime to write it", entry.request_id);
+            return;
+        };
+        let db = self.db.clone();
+        runtime.spawn(async move {
+            if let Err(e) = db.record_audit(&entry).await {
+                tracing::error!("Failed to write audit entry for {}: {}", entry.request_id, e);
+            }
+        });
+    }
+}
+
+/// Opens the request's audit entry before anything reads the body, so requests rejected for it
+/// (malformed, oversized, the wrong content type) are logged too. The handler finds the handle in
+/// the request extensions.
+pub async fn open(
+    State(state): State<AppState>,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    mut req: Request,
+    next: Next,
+) -> Response {
+    let peer = connect_info.map(|ConnectInfo(addr)| addr);
+    let client_ip = allowlist::client_ip(req.headers(), peer, state.trust_proxy_headers);
+    let entry = AuditEntry {
+        request_id: req.extensions().get::<RequestId>().map(|RequestId(id)| id.clone()).unwrap_or_default(),
+        remote_ip: client_ip.map(|ip| ip.to_string()),
+        created_at: db::now_secs(),
+        ..Default::default()
+    };
+    req.extensions_mut().insert(AuditHandle::new(state.db.clone(), entry));
+    next.run(req).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ndjson::OrsRequestBody, tests::test_state, validation::ValidationLayer};
+    use axum::{body::Body, http::StatusCode, routing::post, Router};
+    use tower::ServiceExt;
+
+    #[tokio::test]
+    async fn test_written_when_last_handle_drops() {
+        let db = Arc::new(Db::new("sqlite::memory:").await.unwrap());
+        let handle = AuditHandle::new(db.clone(), AuditEntry { request_id: "req_1".to_string(), ..Default::default() });
+        let stream_handle = handle.clone();
+
+        drop(handle);
+        stream_handle.update(|entry| entry.output_token_count = Some(7));
+        tokio::task::yield_now().await;
+        assert!(db.get_audit_log(0, i64::MAX, 10).await.unwrap().is_empty());
+
+        drop(stream_handle);
+        let log = loop {
+            let log = db.get_audit_log(0, i64::MAX, 10).await.unwrap();
+            if !log.is_empty() {
+                break log;
+            }
+            tokio::task::yield_now().await;
+        };
+        assert_eq!(log[0].entry.request_id, "req_1");
+        assert_eq!(log[0].entry.output_token_count, Some(7));
+    }
+
+    #[tokio::test]
+    async fn test_requests_rejected_before_the_handler_are_logged() {
+        let state = test_state().await;
+        let app = Router::new()
+            .route("/", post(|OrsRequestBody(req): OrsRequestBody| async move { req.model }))
+            .layer(ValidationLayer::<OrsRequestBody>::new(state.clone()))
+            .layer(axum::middleware::from_fn_with_state(state.clone(), open));
+
+        let mut req = Request::post("/").header("content-type", "text/plain").body(Body::from("{}")).unwrap();
+        req.extensions_mut().insert(RequestId("req_wrong_type".to_string()));
+        let res = app.clone().oneshot(req).await.unwrap();
+        assert!(res.status().is_client_error());
+        let mut req = Request::post("/").header("content-type", "application/json").body(Body::from("{")).unwrap();
+        req.extensions_mut().insert(RequestId("req_malformed".to_string()));
+        let res = app.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+
+        let log = loop {
+            let log = state.db.get_audit_log(0, i64::MAX, 10).await.unwrap();
+            if log.len() >= 2 {
+                break log;
+            }
+            tokio::task::yield_now().await;
+        };
+        let mut ids: Vec<_> = log.iter().map(|record| record.entry.request_id.as_str()).collect();
+        ids.sort();
+        assert_eq!(ids, ["req_malformed", "req_wrong_type"]);
+    }
+}
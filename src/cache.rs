@@ -0,0 +1,193 @@
+use crate::types::OrsInputItem;
+use lru::LruCache;
+use std::{
+    num::NonZeroUsize,
+    time::{Duration, Instant},
+};
+
+/// When an entry was last used, the tenant owning the conversation, and its items.
+type Entry = (Instant, String, LoadedContext);
+
+/// A conversation's items as loaded from the DB.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LoadedContext {
+    pub items: Vec<OrsInputItem>,
+    /// Whether `items` starts with the `instructions` stored from an earlier turn.
+    pub has_instructions: bool,
+}
+
+/// Recently used conversation contexts, so follow-up turns skip the DB. Entries expire after
+/// `ttl` without access, and are only served to the conversation's own tenant; writers must
+/// `invalidate` a conversation when its items change.
+///
+/// Each invalidation bumps the conversation's generation. A load takes the generation before
+/// reading the DB and hands it to `insert`, which drops the items if the conversation was
+/// invalidated meanwhile, so a slow load can't bring back what a write just replaced.
+pub struct ContextCache {
+    entries: LruCache<String, Entry>,
+    ttl: Duration,
+    hits: u64,
+    misses: u64,
+    /// Generation of recently invalidated conversations, bounded like `entries`.
+    generations: LruCache<String, u64>,
+    /// Last generation handed out.
+    clock: u64,
+    /// Generation of every conversation not in `generations`: at least that of any evicted.
+    floor: u64,
+}
+
+impl ContextCache {
+    pub fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: LruCache::new(NonZeroUsize::new(capacity.max(1)).unwrap()),
+            ttl,
+            hits: 0,
+            misses: 0,
+            generations: LruCache::new(NonZeroUsize::new(capacity.max(1) * 4).unwrap()),
+            clock: 0,
+            floor: 0,
+        }
+    }
+
+    /// The conversation's current generation, to pass to `insert` after loading it.
+    pub fn generation(&self, conversation_id: &str) -> u64 {
+        self.generations.peek(conversation_id).copied().unwrap_or(self.floor)
+    }
+
+    pub fn get(&mut self, conversation_id: &str, tenant_id: &str) -> Option<LoadedContext> {
+        let fresh = match self.entries.get_mut(conversation_id) {
+            Some((_, owner, _)) if owner != tenant_id => {
+                self.misses += 1;
+                return None;
+            }
+            Some((last_used, _, items)) if last_used.elapsed() < self.ttl => {
+                *last_used = Instant::now();
+                Some(items.clone())
+            }
+            _ => None,
+        };
+        match fresh {
+            Some(items) => {
+                self.hits += 1;
+                Some(items)
+            }
+            None => {
+                self.entries.pop(conversation_id);
+                self.misses += 1;
+                None
+            }
+        }
+    }
+
+    /// Caches the items loaded at `generation`, unless the conversation has been invalidated since.
+    pub fn insert(&mut self, conversation_id: &str, tenant_id: &str, items: LoadedContext, generation: u64) {
+        if generation != self.generation(conversation_id) {
+            return;
+        }
+        self.entries.put(conversation_id.to_string(), (Instant::now(), tenant_id.to_string(), items));
+    }
+
+    pub fn invalidate(&mut self, conversation_id: &str) {
+        self.entries.pop(conversation_id);
+        self.clock += 1;
+        if let Some((evicted, generation)) = self.generations.push(conversation_id.to_string(), self.clock) {
+            if evicted != conversation_id {
+                self.floor = self.floor.max(generation);
+            }
+        }
+    }
+
+    /// Invalidates every conversation.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.generations.clear();
+        self.clock += 1;
+        self.floor = self.clock;
+    }
+
+    pub fn hit_rate_percent(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 { 0.0 } else { self.hits as f64 * 100.0 / lookups as f64 }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{OrsContentPart, OrsRole};
+
+    fn items(text: &str) -> LoadedContext {
+        LoadedContext {
+            items: vec![OrsInputItem::Message {
+                role: OrsRole::User,
+                content: vec![OrsContentPart::InputText { text: text.to_string() }],
+            }],
+            has_instructions: false,
+        }
+    }
+
+    /// Inserts as a load that started just now would.
+    fn insert(cache: &mut ContextCache, conversation_id: &str, tenant_id: &str, items: LoadedContext) {
+        let generation = cache.generation(conversation_id);
+        cache.insert(conversation_id, tenant_id, items, generation);
+    }
+
+    #[test]
+    fn test_hits_evictions_and_invalidation() {
+        let mut cache = ContextCache::new(2, Duration::from_secs(300));
+        assert!(cache.get("a", "t").is_none());
+
+        insert(&mut cache, "a", "t", items("one"));
+        insert(&mut cache, "b", "t", items("two"));
+        assert_eq!(cache.get("a", "t"), Some(items("one")));
+
+        // "b" is least recently used, so it makes way for "c"
+        insert(&mut cache, "c", "t", items("three"));
+        assert!(cache.get("b", "t").is_none());
+
+        cache.invalidate("a");
+        assert!(cache.get("a", "t").is_none());
+        assert_eq!(cache.hit_rate_percent(), 25.0);
+    }
+
+    #[test]
+    fn test_entries_only_served_to_their_tenant() {
+        let mut cache = ContextCache::new(10, Duration::from_secs(300));
+        insert(&mut cache, "a", "team-a", items("one"));
+        assert!(cache.get("a", "team-b").is_none());
+        assert_eq!(cache.get("a", "team-a"), Some(items("one")));
+    }
+
+    #[test]
+    fn test_entries_expire_after_ttl() {
+        let mut cache = ContextCache::new(10, Duration::ZERO);
+        insert(&mut cache, "a", "t", items("one"));
+        assert!(cache.get("a", "t").is_none());
+    }
+
+    #[test]
+    fn test_load_started_before_invalidation_is_not_cached() {
+        let mut cache = ContextCache::new(1, Duration::from_secs(300));
+        let generation = cache.generation("a");
+        cache.invalidate("a");
+        cache.insert("a", "t", items("stale"), generation);
+        assert!(cache.get("a", "t").is_none());
+
+        // Still refused once "a" has been pushed out of the generations by others
+        let generation = cache.generation("a");
+        cache.invalidate("a");
+        for other in ["b", "c", "d", "e"] {
+            cache.invalidate(other);
+        }
+        cache.insert("a", "t", items("stale"), generation);
+        assert!(cache.get("a", "t").is_none());
+
+        let generation = cache.generation("a");
+        cache.clear();
+        cache.insert("a", "t", items("stale"), generation);
+        assert!(cache.get("a", "t").is_none());
+
+        insert(&mut cache, "a", "t", items("fresh"));
+        assert_eq!(cache.get("a", "t"), Some(items("fresh")));
+    }
+}
@@ -0,0 +1,111 @@
+use axum::{
+    extract::Request,
+    http::{header::CONTENT_TYPE, Extensions, HeaderMap, StatusCode, Version},
+    middleware::Next,
+    response::Response,
+};
+use tower_http::compression::{
+    predicate::{DefaultPredicate, Predicate},
+    CompressionLayer,
+};
+
+/// Response extension that keeps a response out of `CompressionLayer`.
+#[derive(Debug, Clone, Copy)]
+pub struct SkipCompression;
+
+/// Gzips responses for clients that send `Accept-Encoding: gzip`, except those marked with
+/// `SkipCompression`: a compressed SSE stream would be buffered instead of delivered per event.
+pub fn layer() -> CompressionLayer<impl Predicate> {
+    CompressionLayer::new()
+        .gzip(true)
+        .compress_when(DefaultPredicate::new().and(
+            |_: StatusCode, _: Version, _: &HeaderMap, extensions: &Extensions| extensions.get::<SkipCompression>().is_none(),
+        ))
+}
+
+/// Marks SSE responses with `SkipCompression`. Must sit inside `layer()` so it sees the
+/// response first.
+pub async fn skip_sse(req: Request, next: Next) -> Response {
+    let mut response = next.run(req).await;
+    let is_sse = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+    if is_sse {
+        response.extensions_mut().insert(SkipCompression);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        body::Body,
+        http::header::{ACCEPT_ENCODING, CONTENT_ENCODING},
+        response::{sse::Event, IntoResponse, Sse},
+        routing::get,
+        Json, Router,
+    };
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route(
+                "/v1/conversations",
+                get(|| async {
+                    let data: Vec<_> = (0..1000)
+                        .map(|i| {
+                            serde_json::json!({
+                                "id": format!("4f1c2a9e-0000-4000-8000-{:012}", i),
+                                "created_at": 1_700_000_000 + i,
+                                "updated_at": 1_700_000_000 + i,
+                                "title": format!("Conversation number {}", i),
+                                "metadata": { "user_id": "u_123", "team": "research" },
+                                "item_count": i % 40,
+                                "total_tokens": i * 17,
+                            })
+                        })
+                        .collect();
+                    Json(serde_json::json!({ "object": "list", "data": data }))
+                }),
+            )
+            .route(
+                "/stream",
+                get(|| async {
+                    let events = futures::stream::iter((0..100).map(|i| {
+                        Ok::<_, std::convert::Infallible>(Event::default().data(format!("event {}", i)))
+                    }));
+                    Sse::new(events).into_response()
+                }),
+            )
+            .layer(axum::middleware::from_fn(skip_sse))
+            .layer(layer())
+    }
+
+    async fn get_gzip(path: &str) -> Response {
+        let req = Request::builder().uri(path).header(ACCEPT_ENCODING, "gzip").body(Body::empty()).unwrap();
+        app().oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_conversation_list_is_gzipped() {
+        let plain = app().oneshot(Request::builder().uri("/v1/conversations").body(Body::empty()).unwrap()).await.unwrap();
+        assert!(plain.headers().get(CONTENT_ENCODING).is_none());
+        let plain_len = axum::body::to_bytes(plain.into_body(), usize::MAX).await.unwrap().len();
+
+        let gzipped = get_gzip("/v1/conversations").await;
+        assert_eq!(gzipped.headers()[CONTENT_ENCODING], "gzip");
+        let gzipped_len = axum::body::to_bytes(gzipped.into_body(), usize::MAX).await.unwrap().len();
+
+        assert!(plain_len > 200_000, "uncompressed list was {} bytes", plain_len);
+        assert!(gzipped_len * 10 < plain_len, "compressed {} -> {} bytes", plain_len, gzipped_len);
+    }
+
+    #[tokio::test]
+    async fn test_sse_is_not_compressed() {
+        let res = get_gzip("/stream").await;
+        assert!(res.headers().get(CONTENT_ENCODING).is_none());
+    }
+}
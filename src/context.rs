@@ -0,0 +1,392 @@
+use crate::types::{OrsContentPart, OrsInputItem, OrsRole, TruncationStrategy};
+use std::str::FromStr;
+
+/// Longest excerpt of a single trimmed item kept in a summary.
+const SUMMARY_SNIPPET_CHARS: usize = 200;
+
+/// Left where `slide_window` removed items, so the model knows the conversation has a gap.
+pub const TRIMMED_PLACEHOLDER: &str = "[...context trimmed...]";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrimStrategy {
+    /// Drop the oldest non-system items outright.
+    OldestFirst,
+    /// Drop the same items, but leave a condensed note of them in their place.
+    Summarize,
+    /// Keep system messages and the last `preserve_turns` turns, cutting from the middle.
+    SlidingWindow,
+}
+
+impl FromStr for TrimStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "oldest_first" => Ok(Self::OldestFirst),
+            "summarize" => Ok(Self::Summarize),
+            "sliding_window" => Ok(Self::SlidingWindow),
+            other => Err(format!("unknown trim strategy: {}", other)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ContextLimits {
+    pub max_items: usize,
+    pub max_chars: usize,
+    pub strategy: TrimStrategy,
+    pub preserve_turns: usize,
+}
+
+fn is_system(item: &OrsInputItem) -> bool {
+    matches!(item, OrsInputItem::Message { role: OrsRole::Developer, .. })
+}
+
+fn item_chars(item: &OrsInputItem) -> usize {
+    serde_json::to_string(item).map(|s| s.len()).unwrap_or(0)
+}
+
+/// Trims `items` from the front until they fit `limits`, never touching system (developer)
+/// messages or the most recent item. Returns the kept items and how many were removed.
+pub fn apply_limits(mut items: Vec<OrsInputItem>, limits: &ContextLimits) -> (Vec<OrsInputItem>, usize) {
+    let mut slid = 0;
+    if limits.strategy == TrimStrategy::SlidingWindow {
+        let real_items = |items: &[OrsInputItem]| items.iter().filter(|item| **item != trimmed_placeholder()).count();
+        let before = real_items(&items);
+        items = slide_window(items, limits.max_chars, limits.preserve_turns);
+        slid = before - real_items(&items);
+    }
+
+    let mut total_chars: usize = items.iter().map(item_chars).sum();
+    let mut trimmed = Vec::new();
+
+    while items.len() > limits.max_items || total_chars > limits.max_chars {
+        let last = items.len().saturating_sub(1);
+        let Some(pos) = items[..last].iter().position(|item| !is_system(item)) else {
+            break;
+        };
+
+        let removed = items.remove(pos);
+        total_chars -= item_chars(&removed);
+
+        // An output whose call was trimmed would be rejected upstream, so it goes too
+        if let OrsInputItem::FunctionCall { call_id, .. } = &removed {
+            let (orphans, kept): (Vec<_>, Vec<_>) = items.into_iter().partition(|item| {
+                item.output_call_id() == Some(call_id.as_str())
+            });
+            items = kept;
+            total_chars -= orphans.iter().map(item_chars).sum::<usize>();
+            trimmed.push(removed);
+            trimmed.extend(orphans);
+        } else {
+            trimmed.push(removed);
+        }
+    }
+
+    let removed_count = trimmed.len();
+    if limits.strategy == TrimStrategy::Summarize && removed_count > 0 {
+        let insert_at = items.iter().take_while(|item| is_system(item)).count();
+        items.insert(insert_at, summarize(&trimmed, limits.max_chars / 10));
+    }
+
+    (items, slid + removed_count)
+}
+
+/// Fits `items` into `max_chars` by dropping the oldest non-system items between the system
+/// messages and the last `preserve_turns` turns (a turn starts at a user message), leaving a
+/// `TRIMMED_PLACEHOLDER` note where they were.
+pub fn slide_window(items: Vec<OrsInputItem>, max_chars: usize, preserve_turns: usize) -> Vec<OrsInputItem> {
+    let mut total_chars: usize = items.iter().map(item_chars).sum();
+    if total_chars <= max_chars {
+        return items;
+    }
+    // Any trim brings the placeholder along, so budget for it up front
+    total_chars += item_chars(&trimmed_placeholder());
+
+    let user_turns: Vec<usize> = items
+        .iter()
+        .enumerate()
+        .filter(|(_, item)| matches!(item, OrsInputItem::Message { role: OrsRole::User, .. }))
+        .map(|(i, _)| i)
+        .collect();
+    let preserved_from = match preserve_turns {
+        0 => items.len(),
+        n if n > user_turns.len() => return items,
+        n => user_turns[user_turns.len() - n],
+    };
+
+    let mut removed = vec![false; items.len()];
+    let mut dropped_calls = Vec::new();
+    for (i, item) in items[..preserved_from].iter().enumerate() {
+        if total_chars <= max_chars {
+            break;
+        }
+        if is_system(item) {
+            continue;
+        }
+        removed[i] = true;
+        total_chars -= item_chars(item);
+        if let OrsInputItem::FunctionCall { call_id, .. } = item {
+            dropped_calls.push(call_id.clone());
+        }
+    }
+
+    // An output whose call was trimmed would be rejected upstream, so it goes too
+    for (i, item) in items.iter().enumerate() {
+        if item.output_call_id().is_some_and(|call_id| dropped_calls.iter().any(|c| c == call_id)) {
+            removed[i] = true;
+        }
+    }
+
+    let Some(trim_point) = removed.iter().position(|r| *r) else {
+        return items;
+    };
+
+    let mut kept = Vec::with_capacity(items.len());
+    for (i, item) in items.into_iter().enumerate() {
+        if i == trim_point {
+            kept.push(trimmed_placeholder());
+        }
+        if !removed[i] {
+            kept.push(item);
+        }
+    }
+    kept
+}
+
+fn trimmed_placeholder() -> OrsInputItem {
+    OrsInputItem::Message {
+        role: OrsRole::Developer,
+        content: vec![OrsContentPart::InputText { text: TRIMMED_PLACEHOLDER.to_string() }],
+    }
+}
+
+/// Applies the request's `truncation_strategy` on top of the configured `limits`: `"disabled"`
+/// leaves the context untouched, `"auto"` (or no strategy) trims, keeping at most
+/// `last_messages` non-system items when given.
+pub fn truncate(
+    items: Vec<OrsInputItem>,
+    strategy: Option<&TruncationStrategy>,
+    limits: &ContextLimits,
+) -> (Vec<OrsInputItem>, usize) {
+    match strategy {
+        Some(s) if s.type_ == "disabled" => (items, 0),
+        Some(TruncationStrategy { last_messages: Some(n), .. }) => {
+            let system_items = items.iter().filter(|item| is_system(item)).count();
+            let limits = ContextLimits {
+                max_items: limits.max_items.min(system_items + *n as usize),
+                ..*limits
+            };
+            apply_limits(items, &limits)
+        }
+        _ => apply_limits(items, limits),
+    }
+}
+
+/// Condenses trimmed items into a single developer note of at most `max_chars` characters.
+fn summarize(trimmed: &[OrsInputItem], max_chars: usize) -> OrsInputItem {
+    let mut text = format!("Summary of {} earlier conversation items:", trimmed.len());
+
+    for item in trimmed {
+        let line = match item {
+            OrsInputItem::Message { role, content } => {
+                let role = match role {
+                    OrsRole::User => "user",
+                    OrsRole::Assistant => "assistant",
+                    OrsRole::Developer => "developer",
+                };
+                let body: String = content
+                    .iter()
+                    .filter_map(|part| match part {
+                        OrsContentPart::InputText { text } => Some(text.as_str()),
+                        _ => None,
+                    })
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                format!("{}: {}", role, body)
+            }
+            OrsInputItem::FunctionCall { name, .. } => format!("assistant called {}", name),
+            OrsInputItem::FunctionCallOutput { output, .. } => format!("tool returned: {}", output.to_text()),
+            OrsInputItem::ComputerToolResult { output, .. } => format!("computer returned {} outputs", output.len()),
+        };
+        text.push_str("\n- ");
+        text.extend(line.chars().take(SUMMARY_SNIPPET_CHARS));
+    }
+
+    OrsInputItem::Message {
+        role: OrsRole::Developer,
+        content: vec![OrsContentPart::InputText { text: text.chars().take(max_chars).collect() }],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::FunctionCallOutputContent;
+
+    fn message(role: OrsRole, text: &str) -> OrsInputItem {
+        OrsInputItem::Message {
+            role,
+            content: vec![OrsContentPart::InputText { text: text.to_string() }],
+        }
+    }
+
+    fn limits(max_items: usize, max_chars: usize, strategy: TrimStrategy) -> ContextLimits {
+        ContextLimits { max_items, max_chars, strategy, preserve_turns: 5 }
+    }
+
+    #[test]
+    fn test_trims_oldest_but_keeps_system() {
+        let items = vec![
+            message(OrsRole::Developer, "Be terse"),
+            message(OrsRole::User, "One"),
+            message(OrsRole::Assistant, "Two"),
+            message(OrsRole::User, "Three"),
+        ];
+
+        let (kept, removed) = apply_limits(items, &limits(2, usize::MAX, TrimStrategy::OldestFirst));
+        assert_eq!(removed, 2);
+        assert_eq!(kept, vec![message(OrsRole::Developer, "Be terse"), message(OrsRole::User, "Three")]);
+    }
+
+    #[test]
+    fn test_trims_by_chars_and_drops_orphaned_outputs() {
+        let items = vec![
+            OrsInputItem::FunctionCall {
+                id: "fc_1".to_string(),
+                call_id: "call_1".to_string(),
+                name: "get_weather".to_string(),
+                arguments: serde_json::json!({}),
+            },
+            OrsInputItem::FunctionCallOutput {
+                id: "out_1".to_string(),
+                call_id: "call_1".to_string(),
+                output: FunctionCallOutputContent::Text("Sunny".to_string()),
+                name: None,
+            },
+            message(OrsRole::User, "Thanks"),
+        ];
+        let last_chars = item_chars(&items[2]);
+
+        let (kept, removed) = apply_limits(items, &limits(100, last_chars, TrimStrategy::OldestFirst));
+        assert_eq!(removed, 2);
+        assert_eq!(kept, vec![message(OrsRole::User, "Thanks")]);
+    }
+
+    #[test]
+    fn test_summarize_leaves_note_after_system() {
+        let items = vec![
+            message(OrsRole::Developer, "Be terse"),
+            message(OrsRole::User, "What is Rust?"),
+            message(OrsRole::User, "Latest"),
+        ];
+
+        let (kept, removed) = apply_limits(items, &limits(2, 10_000, TrimStrategy::Summarize));
+        assert_eq!(removed, 1);
+        assert_eq!(kept.len(), 3);
+        match &kept[1] {
+            OrsInputItem::Message { role: OrsRole::Developer, content } => match &content[0] {
+                OrsContentPart::InputText { text } => assert!(text.contains("user: What is Rust?")),
+                _ => panic!("Expected text summary"),
+            },
+            _ => panic!("Expected summary note after the system prompt"),
+        }
+    }
+
+    #[test]
+    fn test_within_limits_is_untouched() {
+        let items = vec![message(OrsRole::User, "Hi")];
+        let (kept, removed) = apply_limits(items.clone(), &limits(10, 10_000, TrimStrategy::OldestFirst));
+        assert_eq!(removed, 0);
+        assert_eq!(kept, items);
+    }
+
+    fn strategy(type_: &str, last_messages: Option<u32>) -> TruncationStrategy {
+        TruncationStrategy { type_: type_.to_string(), last_messages }
+    }
+
+    #[test]
+    fn test_truncate_disabled_keeps_everything() {
+        let items: Vec<_> = (0..10).map(|i| message(OrsRole::User, &i.to_string())).collect();
+        let limits = ContextLimits { max_items: 3, max_chars: 1_000_000, strategy: TrimStrategy::OldestFirst, preserve_turns: 5 };
+
+        let (kept, trimmed) = truncate(items, Some(&strategy("disabled", None)), &limits);
+        assert_eq!(kept.len(), 10);
+        assert_eq!(trimmed, 0);
+    }
+
+    #[test]
+    fn test_truncate_auto_last_messages_preserves_system() {
+        let mut items = vec![message(OrsRole::Developer, "rules")];
+        items.extend((0..10).map(|i| message(OrsRole::User, &i.to_string())));
+        let limits = ContextLimits { max_items: 100, max_chars: 1_000_000, strategy: TrimStrategy::OldestFirst, preserve_turns: 5 };
+
+        let (kept, trimmed) = truncate(items, Some(&strategy("auto", Some(3))), &limits);
+        assert_eq!(trimmed, 7);
+        assert_eq!(kept.len(), 4);
+        assert!(matches!(&kept[0], OrsInputItem::Message { role: OrsRole::Developer, .. }));
+        assert_eq!(kept[1], message(OrsRole::User, "7"));
+    }
+
+    #[test]
+    fn test_truncate_auto_without_count_uses_limits() {
+        let items: Vec<_> = (0..10).map(|i| message(OrsRole::User, &i.to_string())).collect();
+        let limits = ContextLimits { max_items: 4, max_chars: 1_000_000, strategy: TrimStrategy::OldestFirst, preserve_turns: 5 };
+
+        let (kept, trimmed) = truncate(items.clone(), Some(&strategy("auto", None)), &limits);
+        assert_eq!((kept.len(), trimmed), (4, 6));
+        assert_eq!(truncate(items, None, &limits).0, kept);
+    }
+
+    fn conversation(turns: usize) -> Vec<OrsInputItem> {
+        let mut items = vec![message(OrsRole::Developer, "rules")];
+        for i in 0..turns {
+            items.push(message(OrsRole::User, &format!("question {}", i)));
+            items.push(message(OrsRole::Assistant, &format!("answer {}", i)));
+        }
+        items
+    }
+
+    #[test]
+    fn test_slide_window_trims_middle_and_marks_gap() {
+        let items = conversation(10);
+        let recent: Vec<_> = items[items.len() - 4..].to_vec();
+        let max_chars = item_chars(&items[0]) + recent.iter().map(item_chars).sum::<usize>() + 200;
+
+        let kept = slide_window(items, max_chars, 2);
+        assert_eq!(kept[0], message(OrsRole::Developer, "rules"));
+        assert_eq!(kept[1], message(OrsRole::Developer, TRIMMED_PLACEHOLDER));
+        assert_eq!(kept[kept.len() - 4..], recent[..]);
+        assert!(kept.iter().map(item_chars).sum::<usize>() <= max_chars);
+    }
+
+    #[test]
+    fn test_slide_window_keeps_preserved_turns_even_over_budget() {
+        let items = conversation(3);
+        let kept = slide_window(items.clone(), 1, 5);
+        assert_eq!(kept, items);
+
+        let kept = slide_window(items, 1, 1);
+        assert_eq!(kept.len(), 4);
+        assert_eq!(kept[2], message(OrsRole::User, "question 2"));
+    }
+
+    #[test]
+    fn test_sliding_window_strategy_counts_removed_items() {
+        let limits = ContextLimits {
+            max_items: 100,
+            max_chars: item_chars(&message(OrsRole::User, "question 0")) * 12,
+            strategy: TrimStrategy::SlidingWindow,
+            preserve_turns: 2,
+        };
+        let (kept, trimmed) = apply_limits(conversation(10), &limits);
+        assert_eq!(kept.len() - 1 + trimmed, 21);
+        assert!(kept.contains(&message(OrsRole::Developer, TRIMMED_PLACEHOLDER)));
+    }
+
+    #[test]
+    fn test_slide_window_within_budget_is_untouched() {
+        let items = conversation(3);
+        assert_eq!(slide_window(items.clone(), usize::MAX, 1), items);
+    }
+}
@@ -0,0 +1,306 @@
+use crate::{
+    db::{is_valid_metadata_key, ConversationPatch, ForkOutcome},
+    error_response,
+    request_id::RequestId,
+    tenants::TenantId,
+    AppState,
+};
+use axum::{
+    extract::{Path, Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension, Json,
+};
+use serde::Deserialize;
+use std::collections::HashMap;
+
+const DEFAULT_LIST_LIMIT: i64 = 20;
+const MAX_LIST_LIMIT: i64 = 100;
+const MAX_METADATA_FILTERS: usize = 3;
+
+/// Query parameters: `limit`, plus `metadata.<key>=<value>` filters (`user_id` is shorthand for
+/// `metadata.user_id`).
+pub async fn list_conversations(
+    State(state): State<AppState>,
+    TenantId(tenant_id): TenantId,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let limit = match params.get("limit").map(|l| l.parse::<i64>()) {
+        None => DEFAULT_LIST_LIMIT,
+        Some(Ok(limit)) => limit.clamp(1, MAX_LIST_LIMIT),
+        Some(Err(_)) => return bad_request("limit must be an integer"),
+    };
+
+    let mut filters: Vec<(String, String)> = params
+        .iter()
+        .filter_map(|(k, v)| k.strip_prefix("metadata.").map(|key| (key.to_string(), v.clone())))
+        .collect();
+    if let Some(user_id) = params.get("user_id") {
+        if !filters.iter().any(|(k, _)| k == "user_id") {
+            filters.push(("user_id".to_string(), user_id.clone()));
+        }
+    }
+
+    if filters.len() > MAX_METADATA_FILTERS {
+        return bad_request(format!("At most {} metadata filters are supported", MAX_METADATA_FILTERS));
+    }
+    if let Some((key, _)) = filters.iter().find(|(k, _)| !is_valid_metadata_key(k)) {
+        return bad_request(format!("Invalid metadata filter key: {}", key));
+    }
+
+    match state.db.list_conversations(&tenant_id, &filters, limit).await {
+        Ok(conversations) => Json(serde_json::json!({
+            "object": "list",
+            "data": conversations,
+        }))
+        .into_response(),
+        Err(e) => {
+            tracing::error!("Failed to list conversations: {}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to list conversations")
+        }
+    }
+}
+
+pub async fn get_conversation(
+    State(state): State<AppState>,
+    TenantId(tenant_id): TenantId,
+    Path(id): Path<String>,
+) -> Response {
+    match state.db.get_conversation(&id, &tenant_id).await {
+        Ok(Some(conversation)) => Json(conversation).into_response(),
+        Ok(None) => not_found(&id),
+        Err(e) => {
+            tracing::error!("Failed to load conversation {}: {}", id, e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to load conversation")
+        }
+    }
+}
+
+/// Query parameters: `limit`, and `before`, the `first_sequence_index` of the previous page.
+pub async fn list_input_items(
+    State(state): State<AppState>,
+    TenantId(tenant_id): TenantId,
+    Path(id): Path<String>,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    let limit = match params.get("limit").map(|l| l.parse::<i64>()) {
+        None => DEFAULT_LIST_LIMIT,
+        Some(Ok(limit)) => limit.clamp(1, MAX_LIST_LIMIT),
+        Some(Err(_)) => return bad_request("limit must be an integer"),
+    };
+    let before = match params.get("before").map(|b| b.parse::<i64>()) {
+        None => None,
+        Some(Ok(before)) => Some(before),
+        Some(Err(_)) => return bad_request("before must be an integer"),
+    };
+
+    match state.db.get_input_items(&id, &tenant_id, limit, before).await {
+        Ok(Some((items, has_more))) => Json(serde_json::json!({
+            "object": "list",
+            "first_sequence_index": items.first().map(|(seq, _)| seq),
+            "has_more": has_more,
+            "data": items.into_iter().map(|(_, item)| item).collect::<Vec<_>>(),
+        }))
+        .into_response(),
+        Ok(None) => error_response(StatusCode::NOT_FOUND, "not_found", format!("Response {} not found", id)),
+        Err(e) => {
+            tracing::error!("Failed to load input items for {}: {}", id, e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to load input items")
+        }
+    }
+}
+
+pub async fn get_conversation_metadata(
+    State(state): State<AppState>,
+    TenantId(tenant_id): TenantId,
+    Path(id): Path<String>,
+) -> Response {
+    match state.db.get_conversation_metadata(&id, &tenant_id).await {
+        Ok(Some(metadata)) => Json(metadata).into_response(),
+        Ok(None) => not_found(&id),
+        Err(e) => {
+            tracing::error!("Failed to load conversation {}: {}", id, e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to load conversation")
+        }
+    }
+}
+
+pub async fn delete_conversation(
+    State(state): State<AppState>,
+    TenantId(tenant_id): TenantId,
+    Path(id): Path<String>,
+) -> Response {
+    match state.db.delete_conversation(&id, &tenant_id).await {
+        Ok(true) => {
+            tracing::info!("Deleted conversation {}", id);
+            StatusCode::NO_CONTENT.into_response()
+        }
+        Ok(false) => not_found(&id),
+        Err(e) => {
+            tracing::error!("Failed to delete conversation {}: {}", id, e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to delete conversation")
+        }
+    }
+}
+
+const FORGET_CONFIRM_HEADER: &str = "x-forget-confirm";
+const FORGET_CONFIRMATION: &str = "I understand this is irreversible";
+
+/// Right-to-erasure: unlike a delete, also scrubs the conversation from usage records and the
+/// audit log. Requires `X-Forget-Confirm: I understand this is irreversible`.
+pub async fn forget_conversation(
+    State(state): State<AppState>,
+    TenantId(tenant_id): TenantId,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> Response {
+    if headers.get(FORGET_CONFIRM_HEADER).and_then(|v| v.to_str().ok()) != Some(FORGET_CONFIRMATION) {
+        return bad_request(format!("Set the X-Forget-Confirm header to \"{}\"", FORGET_CONFIRMATION));
+    }
+
+    match state.db.forget_conversation(&id, &tenant_id).await {
+        Ok(Some(tables_affected)) => {
+            // Outside the request span, whose path would name the conversation
+            tracing::warn!(parent: None, request_id = %request_id, ?tables_affected, "Forgot a conversation on request");
+            Json(serde_json::json!({ "forgotten": true, "tables_affected": tables_affected })).into_response()
+        }
+        Ok(None) => not_found(&id),
+        Err(e) => {
+            tracing::error!(parent: None, request_id = %request_id, "Failed to forget a conversation: {}", e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to forget conversation")
+        }
+    }
+}
+
+pub async fn patch_conversation(
+    State(state): State<AppState>,
+    TenantId(tenant_id): TenantId,
+    Path(id): Path<String>,
+    Json(patch): Json<ConversationPatch>,
+) -> Response {
+    if patch.is_empty() {
+        return bad_request("Patch must contain at least one of: title, metadata");
+    }
+
+    match state.db.update_conversation_metadata(&id, &tenant_id, &patch).await {
+        Ok(Some(conversation)) => Json(conversation).into_response(),
+        Ok(None) => not_found(&id),
+        Err(e) => {
+            tracing::error!("Failed to update conversation {}: {}", id, e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to update conversation")
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SystemPromptRequest {
+    pub text: String,
+}
+
+/// Replaces the conversation's system prompt, which leads its context on every later turn.
+pub async fn set_system_prompt(
+    State(state): State<AppState>,
+    TenantId(tenant_id): TenantId,
+    Path(id): Path<String>,
+    Json(req): Json<SystemPromptRequest>,
+) -> Response {
+    if req.text.trim().is_empty() {
+        return bad_request("text must not be empty");
+    }
+    match state.db.conversation_tenant(&id).await {
+        Ok(Some(owner)) if owner == tenant_id => {}
+        Ok(_) => return not_found(&id),
+        Err(e) => {
+            tracing::error!("Failed to look up owner of {}: {}", id, e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to set system prompt");
+        }
+    }
+
+    match state.db.set_system_prompt(&id, &req.text).await {
+        Ok(()) => Json(serde_json::json!({ "conversation_id": id, "system_prompt": req.text })).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to set system prompt of {}: {}", id, e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to set system prompt")
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ForkRequest {
+    /// sequence_index of the last item to carry over into the fork.
+    pub branch_at_sequence: i64,
+}
+
+pub async fn fork_conversation(
+    State(state): State<AppState>,
+    TenantId(tenant_id): TenantId,
+    Path(id): Path<String>,
+    Json(req): Json<ForkRequest>,
+) -> Response {
+    match state.db.fork_conversation(&id, &tenant_id, req.branch_at_sequence).await {
+        Ok(ForkOutcome::Forked { conversation_id, copied_items }) => {
+            tracing::info!("Forked conversation {} into {} ({} items)", id, conversation_id, copied_items);
+            Json(serde_json::json!({
+                "forked_conversation_id": conversation_id,
+                "copied_items": copied_items,
+            }))
+            .into_response()
+        }
+        Ok(ForkOutcome::NotFound) => not_found(&id),
+        Ok(ForkOutcome::OutOfRange { end }) => bad_request(format!(
+            "branch_at_sequence {} is out of range: the conversation's sequence indexes are below {}",
+            req.branch_at_sequence, end
+        )),
+        Err(e) => {
+            tracing::error!("Failed to fork conversation {}: {}", id, e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to fork conversation")
+        }
+    }
+}
+
+fn not_found(id: &str) -> Response {
+    error_response(
+        StatusCode::NOT_FOUND,
+        "not_found",
+        format!("Conversation not found: {}", id),
+    )
+}
+
+fn bad_request(message: impl std::fmt::Display) -> Response {
+    error_response(StatusCode::BAD_REQUEST, "invalid_request_error", message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{db::DEFAULT_TENANT_ID, tests::test_state, types::{OrsContentPart, OrsInputItem, OrsRole}};
+
+    #[tokio::test]
+    async fn test_set_system_prompt() {
+        let state = test_state().await;
+        let user = OrsInputItem::Message {
+            role: OrsRole::User,
+            content: vec![OrsContentPart::InputText { text: "Hi".to_string() }],
+        };
+        state.db.save_interaction("conv_sp", DEFAULT_TENANT_ID, None, vec![user], vec![]).await.unwrap();
+        let set = |tenant_id: &str, id: &str, text: &str| {
+            set_system_prompt(
+                State(state.clone()),
+                TenantId(tenant_id.to_string()),
+                Path(id.to_string()),
+                Json(SystemPromptRequest { text: text.to_string() }),
+            )
+        };
+
+        assert_eq!(set(DEFAULT_TENANT_ID, "conv_sp", "Be terse").await.status(), StatusCode::OK);
+        let context = state.db.load_context("conv_sp", DEFAULT_TENANT_ID).await.unwrap();
+        assert!(matches!(&context[0], OrsInputItem::Message { role: OrsRole::Developer, content }
+            if matches!(content.as_slice(), [OrsContentPart::InputText { text }] if text == "Be terse")));
+
+        assert_eq!(set("team-b", "conv_sp", "Be loud").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(set(DEFAULT_TENANT_ID, "conv_missing", "Be terse").await.status(), StatusCode::NOT_FOUND);
+        assert_eq!(set(DEFAULT_TENANT_ID, "conv_sp", " ").await.status(), StatusCode::BAD_REQUEST);
+        assert_eq!(state.db.load_context("conv_sp", DEFAULT_TENANT_ID).await.unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,114 @@
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use std::fmt;
+
+/// Marks a stored payload as `base64(nonce || ciphertext)` rather than plain JSON.
+const ENCRYPTED_PREFIX: &str = "enc:";
+const NONCE_LEN: usize = 12;
+
+/// Whether a stored payload was written by [`PayloadCipher::seal`].
+pub fn is_sealed(stored: &str) -> bool {
+    stored.starts_with(ENCRYPTED_PREFIX)
+}
+
+#[derive(Debug)]
+pub struct DecryptError(&'static str);
+
+impl fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "failed to decrypt payload: {}", self.0)
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+/// AES-256-GCM for payloads at rest. New payloads are sealed with the current key; the old key,
+/// if any, only opens payloads written before a rotation.
+pub struct PayloadCipher {
+    current: Aes256Gcm,
+    old: Option<Aes256Gcm>,
+}
+
+impl PayloadCipher {
+    /// From `DB_ENCRYPTION_KEY` and `DB_OLD_ENCRYPTION_KEY`, each 32 base64-encoded bytes.
+    /// `None` without a current key: payloads are then stored in plaintext.
+    pub fn from_env() -> Result<Option<Self>, String> {
+        let key = |name: &str| std::env::var(name).ok().filter(|k| !k.is_empty()).map(|k| parse_key(name, &k));
+        match key("DB_ENCRYPTION_KEY") {
+            None => Ok(None),
+            Some(current) => Ok(Some(Self { current: current?, old: key("DB_OLD_ENCRYPTION_KEY").transpose()? })),
+        }
+    }
+
+    pub fn seal(&self, plaintext: &str) -> String {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self.current.encrypt(&nonce, plaintext.as_bytes()).expect("AES-GCM encryption can't fail");
+        let mut sealed = nonce.to_vec();
+        sealed.extend(ciphertext);
+        format!("{}{}", ENCRYPTED_PREFIX, BASE64.encode(sealed))
+    }
+
+    /// Decrypts a sealed payload; anything without the marker was stored in plaintext and is
+    /// returned as is.
+    pub fn open(&self, stored: String) -> Result<String, DecryptError> {
+        let Some(encoded) = stored.strip_prefix(ENCRYPTED_PREFIX) else {
+            return Ok(stored);
+        };
+        let sealed = BASE64.decode(encoded).map_err(|_| DecryptError("invalid base64"))?;
+        if sealed.len() < NONCE_LEN {
+            return Err(DecryptError("too short"));
+        }
+        let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+        let nonce = Nonce::from_slice(nonce);
+        let plaintext = std::iter::once(&self.current)
+            .chain(&self.old)
+            .find_map(|key| key.decrypt(nonce, ciphertext).ok())
+            .ok_or(DecryptError("no key matches"))?;
+        String::from_utf8(plaintext).map_err(|_| DecryptError("not UTF-8"))
+    }
+}
+
+fn parse_key(name: &str, key: &str) -> Result<Aes256Gcm, String> {
+    let bytes = BASE64.decode(key.trim()).map_err(|_| format!("{} is not valid base64", name))?;
+    Aes256Gcm::new_from_slice(&bytes).map_err(|_| format!("{} must decode to 32 bytes, got {}", name, bytes.len()))
+}
+
+#[cfg(test)]
+pub fn test_cipher(current: u8, old: Option<u8>) -> PayloadCipher {
+    let key = |byte| Aes256Gcm::new_from_slice(&[byte; 32]).unwrap();
+    PayloadCipher { current: key(current), old: old.map(key) }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip_with_random_nonces() {
+        let cipher = test_cipher(1, None);
+        let first = cipher.seal(r#"{"type":"message"}"#);
+        let second = cipher.seal(r#"{"type":"message"}"#);
+        assert!(first.starts_with(ENCRYPTED_PREFIX));
+        assert_ne!(first, second);
+        assert_eq!(cipher.open(first).unwrap(), r#"{"type":"message"}"#);
+        assert_eq!(cipher.open(r#"{"plain":true}"#.to_string()).unwrap(), r#"{"plain":true}"#);
+    }
+
+    #[test]
+    fn test_old_key_opens_rows_from_before_rotation() {
+        let sealed = test_cipher(1, None).seal("secret");
+        assert_eq!(test_cipher(2, Some(1)).open(sealed.clone()).unwrap(), "secret");
+        assert!(test_cipher(2, None).open(sealed).is_err());
+        assert!(test_cipher(2, None).open("enc:AAAA".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_parse_key() {
+        assert!(parse_key("K", &BASE64.encode([7u8; 32])).is_ok());
+        assert!(parse_key("K", &BASE64.encode([7u8; 16])).is_err_and(|e| e.contains("32 bytes")));
+        assert!(parse_key("K", "not base64!").is_err());
+    }
+}
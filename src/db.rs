@@ -1,17 +1,219 @@
 use crate::types::{OrsEvent, OrsInputItem, OrsRole, OrsContentPart};
-use sqlx::{sqlite::SqlitePool, Row};
-use std::time::{SystemTime, UNIX_EPOCH};
-use tracing::{info, warn};
+use crate::transcoder::output_items;
+use serde::{Deserialize, Serialize};
+use sqlx::{sqlite::SqlitePool, FromRow, QueryBuilder, Row};
+use std::collections::HashMap;
+use crate::cache::{ContextCache, LoadedContext};
+use crate::crypto::{self, PayloadCipher};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use futures::TryStreamExt;
+use tracing::{error, info, warn};
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Conversation {
+    pub id: String,
+    pub object: &'static str,
+    pub created_at: i64,
+    pub updated_at: Option<i64>,
+    pub title: Option<String>,
+    pub metadata: HashMap<String, String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct ConversationPatch {
+    pub title: Option<String>,
+    /// Merge-patch: `null` values delete the key.
+    pub metadata: Option<HashMap<String, Option<String>>>,
+}
+
+impl ConversationPatch {
+    pub fn is_empty(&self) -> bool {
+        self.title.is_none() && self.metadata.is_none()
+    }
+}
+
+impl Conversation {
+    fn from_row(row: &sqlx::sqlite::SqliteRow) -> Self {
+        let metadata: Option<String> = row.get("metadata");
+        Self {
+            id: row.get("id"),
+            object: "conversation",
+            created_at: row.get("created_at"),
+            updated_at: row.get("updated_at"),
+            title: row.get("title"),
+            metadata: metadata
+                .and_then(|m| serde_json::from_str(&m).ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Metadata keys usable as list filters: ASCII letters, digits and underscores, so they can be
+/// spliced into a JSON path.
+pub fn is_valid_metadata_key(key: &str) -> bool {
+    !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// `AND json_extract(...) = ?N` clauses, one per key, numbering parameters from `first_param`.
+/// Paths are inlined rather than bound so expression indexes like idx_conversations_user_id apply.
+fn metadata_filter_sql<'a>(keys: impl Iterator<Item = &'a str>, first_param: usize) -> String {
+    keys.enumerate()
+        .map(|(i, key)| format!(" AND json_extract(metadata, '$.{}') = ?{}", key, first_param + i))
+        .collect()
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct DbStats {
+    pub conversations_total: i64,
+    pub items_total: i64,
+    pub conversations_created_last_hour: i64,
+    pub db_pool_idle_connections: usize,
+    pub db_pool_active_connections: usize,
+    pub context_cache_hit_rate_percent: f64,
+}
+
+/// Tenant that owns conversations created without a tenant API key, including every
+/// conversation stored before tenants existed.
+pub const DEFAULT_TENANT_ID: &str = "default";
+
+/// Rows per multi-row `INSERT` into `items`. At four parameters a row that's 2000 parameters,
+/// well under SQLite's limit of 32766.
+const ITEM_INSERT_BATCH: usize = 500;
+
+/// One response's token usage and upstream timing, as recorded in `usage_events`.
+pub struct UsageEvent<'a> {
+    pub tenant_id: &'a str,
+    pub model: &'a str,
+    /// `(input_tokens, output_tokens, total_tokens)`
+    pub usage: (u32, u32, u32),
+    pub upstream_latency_ms: Option<u64>,
+}
+
+/// A tenant's token usage over a time range, as billed from `usage_events`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct TenantUsage {
+    pub tenant_id: String,
+    /// Unix seconds, inclusive.
+    pub from: i64,
+    /// Unix seconds, exclusive.
+    pub to: i64,
+    #[serde(flatten)]
+    pub totals: UsageTotals,
+    pub by_model: Vec<ModelUsage>,
+}
+
+#[derive(Serialize, Debug, Clone, Default, PartialEq, sqlx::FromRow)]
+pub struct UsageTotals {
+    pub input_tokens: i64,
+    pub output_tokens: i64,
+    pub requests: i64,
+    pub conversations: i64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ModelUsage {
+    pub model: String,
+    #[serde(flatten)]
+    pub totals: UsageTotals,
+}
+
+/// One `POST /v1/responses` call as recorded in `audit_log`, filled in as far as the request got.
+#[derive(Serialize, Debug, Clone, Default, PartialEq, sqlx::FromRow)]
+pub struct AuditEntry {
+    pub request_id: String,
+    /// `None` when the request failed tenant authentication.
+    pub tenant_id: Option<String>,
+    pub remote_ip: Option<String>,
+    pub model: Option<String>,
+    pub conversation_id: Option<String>,
+    pub input_item_count: Option<i64>,
+    pub output_token_count: Option<i64>,
+    /// The response's final status (`completed`, `incomplete` or `failed`); `None` when no
+    /// response was generated.
+    pub finish_reason: Option<String>,
+    pub upstream_status: Option<u16>,
+    pub created_at: i64,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct AuditRecord {
+    pub id: i64,
+    #[serde(flatten)]
+    pub entry: AuditEntry,
+}
+
+/// A client team sharing the proxy, identified by the key its clients send as
+/// `Authorization: Bearer`. Its upstream settings replace the global ones for its requests.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Tenant {
+    /// Public identifier, used where the API key itself mustn't appear.
+    pub id: String,
+    pub api_key: String,
+    pub upstream_key: Option<String>,
+    pub upstream_url: Option<String>,
+    pub rate_limit_rps: Option<u32>,
+}
+
+/// The outcome of POSTing a finished response to its `webhook_url`.
+pub struct WebhookDelivery<'a> {
+    pub url: &'a str,
+    pub delivered: bool,
+    pub attempts: u32,
+    /// Status of the last attempt; `None` if it never got a response.
+    pub status_code: Option<u16>,
+    pub error: Option<&'a str>,
+}
+
+/// An emitted `OrsEvent`, kept so an interrupted stream can be resumed.
+#[derive(Debug, Clone)]
+pub struct StoredEvent {
+    pub sequence_number: u32,
+    pub event_type: String,
+    pub payload: String,
+}
+
+/// A stored item that no longer loads, found by [`Db::validate_all_items`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct ItemFailure {
+    pub id: i64,
+    pub conversation_id: String,
+    pub error: String,
+}
+
+pub enum ForkOutcome {
+    Forked { conversation_id: String, copied_items: u64 },
+    NotFound,
+    /// `branch_at_sequence` is past the conversation's last item; its sequence indexes are all
+    /// below `end`. They can have gaps (replaced instructions leave one), so `end` isn't a count.
+    OutOfRange { end: i64 },
+}
+
+/// What [`Db::upsert_tenant`] did.
+pub enum TenantUpsert {
+    /// The tenant is registered with the given settings; `replaced_key` is the API key it had
+    /// until now, if that changed.
+    Saved { replaced_key: Option<String> },
+    /// The API key belongs to another tenant; nothing changed.
+    KeyInUse,
+}
 
 #[derive(Clone)]
 pub struct Db {
     pool: SqlitePool,
+    context_cache: Arc<Mutex<ContextCache>>,
+    /// Encrypts item and event payloads at rest when set.
+    cipher: Option<Arc<PayloadCipher>>,
+    /// Keep the `logprobs` of stored text deltas (`STORE_LOGPROBS`); they're dropped by default,
+    /// as they can be several times the size of the text.
+    store_logprobs: bool,
 }
 
 impl Db {
     pub async fn new(database_url: &str) -> Result<Self, sqlx::Error> {
         let pool = SqlitePool::connect(database_url).await?;
-        let db = Self { pool };
+        let context_cache = Arc::new(Mutex::new(ContextCache::new(100, Duration::from_secs(300))));
+        let db = Self { pool, context_cache, cipher: None, store_logprobs: false };
         db.init().await?;
         Ok(db)
     }
@@ -33,156 +235,1154 @@ impl Db {
             );
             
             CREATE INDEX IF NOT EXISTS idx_items_seq ON items(conversation_id, sequence_index);
+
+            CREATE TABLE IF NOT EXISTS events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                conversation_id TEXT NOT NULL,
+                response_id TEXT NOT NULL,
+                sequence_number INTEGER NOT NULL,
+                payload JSON NOT NULL,
+                FOREIGN KEY(conversation_id) REFERENCES conversations(id)
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_events_seq ON events(conversation_id, sequence_number);
+
+            CREATE TABLE IF NOT EXISTS usage_events (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                conversation_id TEXT NOT NULL,
+                tenant_id TEXT,
+                model TEXT NOT NULL,
+                input_tokens INTEGER NOT NULL,
+                output_tokens INTEGER NOT NULL,
+                total_tokens INTEGER NOT NULL,
+                upstream_latency_ms INTEGER,
+                created_at INTEGER NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS tenants (
+                api_key TEXT PRIMARY KEY,
+                id TEXT NOT NULL UNIQUE,
+                upstream_key TEXT,
+                upstream_url TEXT,
+                rate_limit_rps INTEGER
+            );
+
+            -- Append-only: only purge_conversations ever deletes from it
+            CREATE TABLE IF NOT EXISTS audit_log (
+                id INTEGER PRIMARY KEY,
+                request_id TEXT NOT NULL,
+                tenant_id TEXT,
+                remote_ip TEXT,
+                model TEXT,
+                conversation_id TEXT,
+                input_item_count INTEGER,
+                output_token_count INTEGER,
+                finish_reason TEXT,
+                upstream_status INTEGER,
+                created_at INTEGER NOT NULL
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_audit_log_created_at ON audit_log(created_at);
+            CREATE INDEX IF NOT EXISTS idx_audit_log_tenant ON audit_log(tenant_id, created_at);
+
+            CREATE TABLE IF NOT EXISTS webhook_deliveries (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                conversation_id TEXT NOT NULL,
+                url TEXT NOT NULL,
+                delivered INTEGER NOT NULL,
+                attempts INTEGER NOT NULL,
+                status_code INTEGER,
+                error TEXT,
+                created_at INTEGER NOT NULL
+            );
+
+            CREATE TABLE IF NOT EXISTS conversation_files (
+                conversation_id TEXT NOT NULL,
+                file_id TEXT NOT NULL,
+                created_at INTEGER NOT NULL,
+                PRIMARY KEY (conversation_id, file_id)
+            );
+
+            CREATE INDEX IF NOT EXISTS idx_conversation_files_file ON conversation_files(file_id);
+
+            CREATE TABLE IF NOT EXISTS files (
+                file_id TEXT PRIMARY KEY,
+                tenant_id TEXT NOT NULL,
+                created_at INTEGER NOT NULL
+            );
         "#;
 
         sqlx::query(schema).execute(&self.pool).await?;
+        self.migrate().await?;
         info!("Database initialized");
         Ok(())
     }
 
-    pub async fn load_context(&self, conversation_id: &str) -> Result<Vec<OrsInputItem>, sqlx::Error> {
+    /// Brings databases created by older versions up to the current schema.
+    async fn migrate(&self) -> Result<(), sqlx::Error> {
+        self.ensure_column("conversations", "metadata", "JSON").await?;
+        self.ensure_column("conversations", "title", "TEXT").await?;
+        self.ensure_column("conversations", "updated_at", "INTEGER").await?;
+        self.ensure_column("conversations", "prompt_tokens", "INTEGER NOT NULL DEFAULT 0").await?;
+        self.ensure_column("conversations", "completion_tokens", "INTEGER NOT NULL DEFAULT 0").await?;
+        self.ensure_column("conversations", "total_tokens", "INTEGER NOT NULL DEFAULT 0").await?;
+        self.ensure_column("conversations", "status", "TEXT NOT NULL DEFAULT 'completed'").await?;
+        self.ensure_column("conversations", "tenant_id", "TEXT NOT NULL DEFAULT 'default'").await?;
+        self.ensure_column("usage_events", "tenant_id", "TEXT").await?;
+
+        // Usage recorded before events carried a tenant is billed to the conversation's owner
+        sqlx::query(
+            "UPDATE usage_events SET tenant_id = \
+             (SELECT tenant_id FROM conversations WHERE conversations.id = usage_events.conversation_id) \
+             WHERE tenant_id IS NULL",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS idx_usage_events_tenant ON usage_events(tenant_id, created_at)")
+            .execute(&self.pool)
+            .await?;
+
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS idx_conversations_user_id ON conversations(json_extract(metadata, '$.user_id'))",
+        )
+        .execute(&self.pool)
+        .await?;
+
+        // Backfill the function name onto tool outputs stored before they carried one. Encrypted
+        // payloads aren't JSON to SQLite and are checked for first; they're all newer than this.
+        sqlx::query(
+            r#"
+            UPDATE items SET payload = json_set(payload, '$.name', (
+                SELECT json_extract(fc.payload, '$.name') FROM items fc
+                WHERE fc.conversation_id = items.conversation_id
+                  AND json_valid(fc.payload)
+                  AND json_extract(fc.payload, '$.type') = 'function_call'
+                  AND json_extract(fc.payload, '$.call_id') = json_extract(items.payload, '$.call_id')
+                LIMIT 1
+            ))
+            WHERE json_valid(payload)
+              AND json_extract(payload, '$.type') = 'function_call_output'
+              AND json_extract(payload, '$.name') IS NULL
+              AND EXISTS (
+                SELECT 1 FROM items fc
+                WHERE fc.conversation_id = items.conversation_id
+                  AND json_valid(fc.payload)
+                  AND json_extract(fc.payload, '$.type') = 'function_call'
+                  AND json_extract(fc.payload, '$.call_id') = json_extract(items.payload, '$.call_id')
+              )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Resizes the context cache; a capacity of 0 keeps a single entry.
+    pub fn with_context_cache(mut self, capacity: usize, ttl: Duration) -> Self {
+        self.context_cache = Arc::new(Mutex::new(ContextCache::new(capacity, ttl)));
+        self
+    }
+
+    /// Stores item and event payloads encrypted with `cipher` from now on.
+    pub fn with_encryption(mut self, cipher: PayloadCipher) -> Self {
+        self.cipher = Some(Arc::new(cipher));
+        self
+    }
+
+    pub fn with_logprobs(mut self, store: bool) -> Self {
+        self.store_logprobs = store;
+        self
+    }
+
+    fn seal(&self, payload: String) -> String {
+        match &self.cipher {
+            Some(cipher) => cipher.seal(&payload),
+            None => payload,
+        }
+    }
+
+    /// A stored payload as JSON, decrypted if it was sealed.
+    fn open(&self, stored: String) -> Result<String, sqlx::Error> {
+        match &self.cipher {
+            Some(cipher) => cipher.open(stored).map_err(|e| sqlx::Error::Decode(Box::new(e))),
+            None if crypto::is_sealed(&stored) => {
+                Err(sqlx::Error::Decode("payload is encrypted but DB_ENCRYPTION_KEY isn't set".into()))
+            }
+            None => Ok(stored),
+        }
+    }
+
+    fn invalidate_context(&self, conversation_id: &str) {
+        self.context_cache.lock().unwrap().invalidate(conversation_id);
+    }
+
+    async fn ensure_column(&self, table: &str, column: &str, decl: &str) -> Result<(), sqlx::Error> {
+        let exists: (i64,) = sqlx::query_as(
+            "SELECT COUNT(*) FROM pragma_table_info(?) WHERE name = ?",
+        )
+        .bind(table)
+        .bind(column)
+        .fetch_one(&self.pool)
+        .await?;
+
+        if exists.0 == 0 {
+            info!("Migrating: adding {}.{}", table, column);
+            sqlx::query(&format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, decl))
+                .execute(&self.pool)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// The conversation's items, or none if it doesn't exist or belongs to another tenant.
+    pub async fn load_context(&self, conversation_id: &str, tenant_id: &str) -> Result<Vec<OrsInputItem>, sqlx::Error> {
+        Ok(self.load_tagged_context(conversation_id, tenant_id).await?.items)
+    }
+
+    /// Like [`Db::load_context`], leaving out the `instructions` stored from an earlier turn; for
+    /// requests bringing instructions of their own, which supersede them.
+    pub async fn load_context_without_instructions(
+        &self,
+        conversation_id: &str,
+        tenant_id: &str,
+    ) -> Result<Vec<OrsInputItem>, sqlx::Error> {
+        let LoadedContext { mut items, has_instructions } = self.load_tagged_context(conversation_id, tenant_id).await?;
+        if has_instructions {
+            items.remove(0);
+        }
+        Ok(items)
+    }
+
+    async fn load_tagged_context(&self, conversation_id: &str, tenant_id: &str) -> Result<LoadedContext, sqlx::Error> {
+        let generation = {
+            let mut cache = self.context_cache.lock().unwrap();
+            if let Some(items) = cache.get(conversation_id, tenant_id) {
+                return Ok(items);
+            }
+            cache.generation(conversation_id)
+        };
+
+        // Instructions, then system prompts, always lead the context, wherever they were stored in the sequence
+        let rows = sqlx::query(
+            "SELECT payload, item_type FROM items WHERE conversation_id = ?1 \
+               AND EXISTS (SELECT 1 FROM conversations WHERE id = ?1 AND tenant_id = ?2) \
+             ORDER BY item_type = 'instructions' DESC, item_type = 'system_prompt' DESC, sequence_index ASC",
+        )
+        .bind(conversation_id)
+        .bind(tenant_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let has_instructions = rows.first().is_some_and(|row| row.get::<String, _>("item_type") == "instructions");
+        let items: Vec<OrsInputItem> = rows
+            .into_iter()
+            .map(|row| {
+                let json_str = self.open(row.get("payload"))?;
+                serde_json::from_str(&json_str).map_err(|e| {
+                    warn!("Corrupt item in conversation {}: {}", conversation_id, e);
+                    sqlx::Error::Decode(Box::new(e))
+                })
+            })
+            .collect::<Result<_, sqlx::Error>>()?;
+
+        let context = LoadedContext { items, has_instructions };
+        self.context_cache.lock().unwrap().insert(conversation_id, tenant_id, context.clone(), generation);
+        Ok(context)
+    }
+
+    /// Replaces the conversation's system prompt with a single developer message.
+    pub async fn set_system_prompt(&self, conversation_id: &str, text: &str) -> Result<(), sqlx::Error> {
+        self.replace_tagged_item(conversation_id, "system_prompt", text).await
+    }
+
+    /// Records the request's top-level `instructions`, replacing any from earlier turns.
+    pub async fn save_instructions(&self, conversation_id: &str, text: &str) -> Result<(), sqlx::Error> {
+        self.replace_tagged_item(conversation_id, "instructions", text).await
+    }
+
+    /// Stores `text` as the conversation's only developer message of the given `item_type`.
+    async fn replace_tagged_item(&self, conversation_id: &str, item_type: &str, text: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query("INSERT OR IGNORE INTO conversations (id, created_at) VALUES (?, ?)")
+            .bind(conversation_id)
+            .bind(now_secs())
+            .execute(&mut *tx)
+            .await?;
+
+        sqlx::query("DELETE FROM items WHERE conversation_id = ? AND item_type = ?")
+            .bind(conversation_id)
+            .bind(item_type)
+            .execute(&mut *tx)
+            .await?;
+
+        let item = OrsInputItem::Message {
+            role: OrsRole::Developer,
+            content: vec![OrsContentPart::InputText { text: text.to_string() }],
+        };
+        let payload = serde_json::to_string(&item).unwrap();
+
+        sqlx::query(
+            "INSERT INTO items (conversation_id, sequence_index, item_type, payload) \
+             VALUES (?, (SELECT COALESCE(MAX(sequence_index) + 1, 0) FROM items WHERE conversation_id = ?), ?, ?)",
+        )
+        .bind(conversation_id)
+        .bind(conversation_id)
+        .bind(item_type)
+        .bind(self.seal(payload))
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        self.invalidate_context(conversation_id);
+        Ok(())
+    }
+
+    /// Lists the tenant's newest conversations whose metadata matches every `(key, value)` filter.
+    /// Keys must satisfy [`is_valid_metadata_key`]; other keys are ignored.
+    pub async fn list_conversations(
+        &self,
+        tenant_id: &str,
+        filters: &[(String, String)],
+        limit: i64,
+    ) -> Result<Vec<Conversation>, sqlx::Error> {
+        let filters: Vec<_> = filters.iter().filter(|(key, _)| is_valid_metadata_key(key)).collect();
+
+        let sql = format!(
+            "SELECT id, created_at, updated_at, title, metadata FROM conversations WHERE tenant_id = ?1{} \
+             ORDER BY created_at DESC LIMIT ?{}",
+            metadata_filter_sql(filters.iter().map(|(key, _)| key.as_str()), 2),
+            filters.len() + 2,
+        );
+
+        let mut query = sqlx::query(&sql).bind(tenant_id);
+        for (_, value) in &filters {
+            query = query.bind(value);
+        }
+        let rows = query.bind(limit).fetch_all(&self.pool).await?;
+
+        Ok(rows.iter().map(Conversation::from_row).collect())
+    }
+
+    pub async fn stats(&self) -> Result<DbStats, sqlx::Error> {
+        let (conversations_total, conversations_created_last_hour): (i64, i64) = sqlx::query_as(
+            "SELECT COUNT(*), COUNT(*) FILTER (WHERE created_at >= ?) FROM conversations",
+        )
+        .bind(now_secs() - 3600)
+        .fetch_one(&self.pool)
+        .await?;
+        let (items_total,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM items")
+            .fetch_one(&self.pool)
+            .await?;
+
+        let idle = self.pool.num_idle();
+        Ok(DbStats {
+            conversations_total,
+            items_total,
+            conversations_created_last_hour,
+            db_pool_idle_connections: idle,
+            db_pool_active_connections: (self.pool.size() as usize).saturating_sub(idle),
+            context_cache_hit_rate_percent: self.context_cache.lock().unwrap().hit_rate_percent(),
+        })
+    }
+
+    /// Adds a response's token usage to the conversation's running totals.
+    pub async fn add_usage(
+        &self,
+        conversation_id: &str,
+        input_tokens: u32,
+        output_tokens: u32,
+        total_tokens: u32,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "UPDATE conversations SET \
+                prompt_tokens = prompt_tokens + ?, \
+                completion_tokens = completion_tokens + ?, \
+                total_tokens = total_tokens + ? \
+             WHERE id = ?",
+        )
+        .bind(input_tokens)
+        .bind(output_tokens)
+        .bind(total_tokens)
+        .bind(conversation_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn record_usage_event(&self, conversation_id: &str, event: &UsageEvent<'_>) -> Result<(), sqlx::Error> {
+        let (input_tokens, output_tokens, total_tokens) = event.usage;
+        sqlx::query(
+            "INSERT INTO usage_events \
+             (conversation_id, tenant_id, model, input_tokens, output_tokens, total_tokens, upstream_latency_ms, created_at) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(conversation_id)
+        .bind(event.tenant_id)
+        .bind(event.model)
+        .bind(input_tokens)
+        .bind(output_tokens)
+        .bind(total_tokens)
+        .bind(event.upstream_latency_ms.map(|ms| ms as i64))
+        .bind(now_secs())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Totals of `tenant_id`'s usage events created in `[from, to)`, overall and per model.
+    pub async fn get_tenant_usage(&self, tenant_id: &str, from: i64, to: i64) -> Result<TenantUsage, sqlx::Error> {
+        const TOTALS: &str = "COALESCE(SUM(input_tokens), 0) AS input_tokens, \
+             COALESCE(SUM(output_tokens), 0) AS output_tokens, \
+             COUNT(*) AS requests, \
+             COUNT(DISTINCT conversation_id) AS conversations";
+        const RANGE: &str = "FROM usage_events WHERE tenant_id = ? AND created_at >= ? AND created_at < ?";
+
+        let totals: UsageTotals = sqlx::query_as(&format!("SELECT {} {}", TOTALS, RANGE))
+            .bind(tenant_id)
+            .bind(from)
+            .bind(to)
+            .fetch_one(&self.pool)
+            .await?;
+        let rows = sqlx::query(&format!("SELECT model, {} {} GROUP BY model ORDER BY model", TOTALS, RANGE))
+            .bind(tenant_id)
+            .bind(from)
+            .bind(to)
+            .fetch_all(&self.pool)
+            .await?;
+        let by_model = rows
+            .iter()
+            .map(|row| {
+                Ok(ModelUsage { model: row.try_get("model")?, totals: UsageTotals::from_row(row)? })
+            })
+            .collect::<Result<_, sqlx::Error>>()?;
+
+        Ok(TenantUsage { tenant_id: tenant_id.to_string(), from, to, totals, by_model })
+    }
+
+    pub async fn record_audit(&self, entry: &AuditEntry) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO audit_log \
+             (request_id, tenant_id, remote_ip, model, conversation_id, input_item_count, output_token_count, \
+              finish_reason, upstream_status, created_at) \
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&entry.request_id)
+        .bind(&entry.tenant_id)
+        .bind(&entry.remote_ip)
+        .bind(&entry.model)
+        .bind(&entry.conversation_id)
+        .bind(entry.input_item_count)
+        .bind(entry.output_token_count)
+        .bind(&entry.finish_reason)
+        .bind(entry.upstream_status)
+        .bind(entry.created_at)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Up to `limit` audit records created in `[from, to)`, oldest first.
+    pub async fn get_audit_log(&self, from: i64, to: i64, limit: i64) -> Result<Vec<AuditRecord>, sqlx::Error> {
+        let rows = sqlx::query(
+            "SELECT * FROM audit_log WHERE created_at >= ? AND created_at < ? ORDER BY created_at, id LIMIT ?",
+        )
+        .bind(from)
+        .bind(to)
+        .bind(limit)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter()
+            .map(|row| Ok(AuditRecord { id: row.try_get("id")?, entry: AuditEntry::from_row(row)? }))
+            .collect()
+    }
+
+    pub async fn record_webhook_delivery(
+        &self,
+        conversation_id: &str,
+        delivery: &WebhookDelivery<'_>,
+    ) -> Result<(), sqlx::Error> {
+        sqlx::query(
+            "INSERT INTO webhook_deliveries \
+             (conversation_id, url, delivered, attempts, status_code, error, created_at) \
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(conversation_id)
+        .bind(delivery.url)
+        .bind(delivery.delivered)
+        .bind(delivery.attempts)
+        .bind(delivery.status_code)
+        .bind(delivery.error)
+        .bind(now_secs())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Registers the tenant, or replaces the settings (API key included) of the one with its id.
+    /// Conversations are tagged with the id, so it's never renamed.
+    pub async fn upsert_tenant(&self, tenant: &Tenant) -> Result<TenantUpsert, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let owner: Option<String> = sqlx::query_scalar("SELECT id FROM tenants WHERE api_key = ?")
+            .bind(&tenant.api_key)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if owner.is_some_and(|owner| owner != tenant.id) {
+            return Ok(TenantUpsert::KeyInUse);
+        }
+        let previous_key: Option<String> = sqlx::query_scalar("SELECT api_key FROM tenants WHERE id = ?")
+            .bind(&tenant.id)
+            .fetch_optional(&mut *tx)
+            .await?;
+
+        let saved = sqlx::query(
+            "INSERT INTO tenants (api_key, id, upstream_key, upstream_url, rate_limit_rps) VALUES (?, ?, ?, ?, ?) \
+             ON CONFLICT(id) DO UPDATE SET api_key = excluded.api_key, upstream_key = excluded.upstream_key, \
+                upstream_url = excluded.upstream_url, rate_limit_rps = excluded.rate_limit_rps",
+        )
+        .bind(&tenant.api_key)
+        .bind(&tenant.id)
+        .bind(&tenant.upstream_key)
+        .bind(&tenant.upstream_url)
+        .bind(tenant.rate_limit_rps)
+        .execute(&mut *tx)
+        .await;
+        match saved {
+            Ok(_) => {}
+            // Another registration took the key since it was checked
+            Err(e) if e.as_database_error().is_some_and(|e| e.is_unique_violation()) => return Ok(TenantUpsert::KeyInUse),
+            Err(e) => return Err(e),
+        }
+        tx.commit().await?;
+
+        Ok(TenantUpsert::Saved { replaced_key: previous_key.filter(|key| *key != tenant.api_key) })
+    }
+
+    pub async fn get_tenant(&self, api_key: &str) -> Result<Option<Tenant>, sqlx::Error> {
+        let row = sqlx::query("SELECT api_key, id, upstream_key, upstream_url, rate_limit_rps FROM tenants WHERE api_key = ?")
+            .bind(api_key)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|row| Tenant {
+            id: row.get("id"),
+            api_key: row.get("api_key"),
+            upstream_key: row.get("upstream_key"),
+            upstream_url: row.get("upstream_url"),
+            rate_limit_rps: row.get("rate_limit_rps"),
+        }))
+    }
+
+    /// Parses an ISO 8601 / RFC 3339 timestamp into Unix seconds, or `None` if it's malformed.
+    pub async fn parse_timestamp(&self, timestamp: &str) -> Result<Option<i64>, sqlx::Error> {
+        let row: (Option<i64>,) = sqlx::query_as("SELECT CAST(strftime('%s', ?) AS INTEGER)")
+            .bind(timestamp)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(row.0)
+    }
+
+    /// Deletes conversations (with their items, events, usage, webhook deliveries, file links and
+    /// audit records) created before `before`, if given, and matching every metadata filter.
+    /// Returns how many conversations were removed.
+    pub async fn purge_conversations(
+        &self,
+        before: Option<i64>,
+        filters: &[(String, String)],
+    ) -> Result<u64, sqlx::Error> {
+        let filters: Vec<_> = filters.iter().filter(|(key, _)| is_valid_metadata_key(key)).collect();
+        let condition = format!(
+            "(?1 IS NULL OR created_at < ?1){}",
+            metadata_filter_sql(filters.iter().map(|(key, _)| key.as_str()), 2),
+        );
+
+        let mut tx = self.pool.begin().await?;
+        let mut purged = 0;
+        let tables =
+            ["items", "events", "usage_events", "webhook_deliveries", "audit_log", "conversation_files", "conversations"];
+        for table in tables {
+            let sql = if table == "conversations" {
+                format!("DELETE FROM conversations WHERE {}", condition)
+            } else {
+                format!(
+                    "DELETE FROM {} WHERE conversation_id IN (SELECT id FROM conversations WHERE {})",
+                    table, condition
+                )
+            };
+            let mut query = sqlx::query(&sql).bind(before);
+            for (_, value) in &filters {
+                query = query.bind(value);
+            }
+            purged = query.execute(&mut *tx).await?.rows_affected();
+        }
+        tx.commit().await?;
+        // Which conversations went isn't known here, and purges are rare
+        self.context_cache.lock().unwrap().clear();
+
+        Ok(purged)
+    }
+
+    /// Deletes the conversation with its items, events, usage, webhook delivery records and file
+    /// links. Returns false if it didn't exist or belongs to another tenant.
+    pub async fn delete_conversation(&self, conversation_id: &str, tenant_id: &str) -> Result<bool, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let owned = sqlx::query("SELECT 1 FROM conversations WHERE id = ? AND tenant_id = ?")
+            .bind(conversation_id)
+            .bind(tenant_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if owned.is_none() {
+            return Ok(false);
+        }
+        for table in ["items", "events", "usage_events", "webhook_deliveries", "conversation_files"] {
+            sqlx::query(&format!("DELETE FROM {} WHERE conversation_id = ?", table))
+                .bind(conversation_id)
+                .execute(&mut *tx)
+                .await?;
+        }
+        let deleted = sqlx::query("DELETE FROM conversations WHERE id = ?")
+            .bind(conversation_id)
+            .execute(&mut *tx)
+            .await?
+            .rows_affected();
+        tx.commit().await?;
+        self.invalidate_context(conversation_id);
+
+        Ok(deleted > 0)
+    }
+
+    /// Erases every trace of the conversation: its rows are deleted everywhere, and its audit
+    /// records keep only what isn't personal (timestamps, counts, statuses). Returns the tables
+    /// that had rows for it, or `None` if it didn't exist or belongs to another tenant.
+    pub async fn forget_conversation(
+        &self,
+        conversation_id: &str,
+        tenant_id: &str,
+    ) -> Result<Option<Vec<&'static str>>, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        let owned = sqlx::query("SELECT 1 FROM conversations WHERE id = ? AND tenant_id = ?")
+            .bind(conversation_id)
+            .bind(tenant_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if owned.is_none() {
+            return Ok(None);
+        }
+        let mut affected = Vec::new();
+        for table in ["items", "events", "usage_events", "webhook_deliveries", "conversation_files", "conversations"] {
+            let column = if table == "conversations" { "id" } else { "conversation_id" };
+            let deleted = sqlx::query(&format!("DELETE FROM {} WHERE {} = ?", table, column))
+                .bind(conversation_id)
+                .execute(&mut *tx)
+                .await?
+                .rows_affected();
+            if deleted > 0 {
+                affected.push(table);
+            }
+        }
+        let redacted = sqlx::query(
+            "UPDATE audit_log SET conversation_id = '[REDACTED]', model = '[REDACTED]' WHERE conversation_id = ?",
+        )
+        .bind(conversation_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+        if redacted > 0 {
+            affected.push("audit_log");
+        }
+        tx.commit().await?;
+        self.invalidate_context(conversation_id);
+
+        Ok(Some(affected))
+    }
+
+    /// Links an uploaded file to the conversation.
+    pub async fn attach_file(&self, conversation_id: &str, file_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT OR IGNORE INTO conversation_files (conversation_id, file_id, created_at) VALUES (?, ?, ?)")
+            .bind(conversation_id)
+            .bind(file_id)
+            .bind(now_secs())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Files linked to the conversation, oldest first.
+    pub async fn conversation_files(&self, conversation_id: &str) -> Result<Vec<String>, sqlx::Error> {
+        sqlx::query_scalar("SELECT file_id FROM conversation_files WHERE conversation_id = ? ORDER BY created_at, file_id")
+            .bind(conversation_id)
+            .fetch_all(&self.pool)
+            .await
+    }
+
+    /// Records that the tenant uploaded the file. All tenants share the upstream's files, so
+    /// this is what keeps them apart.
+    pub async fn record_file(&self, file_id: &str, tenant_id: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("INSERT OR IGNORE INTO files (file_id, tenant_id, created_at) VALUES (?, ?, ?)")
+            .bind(file_id)
+            .bind(tenant_id)
+            .bind(now_secs())
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Whether the tenant uploaded the file through the proxy.
+    pub async fn owns_file(&self, file_id: &str, tenant_id: &str) -> Result<bool, sqlx::Error> {
+        let row = sqlx::query("SELECT 1 FROM files WHERE file_id = ? AND tenant_id = ?")
+            .bind(file_id)
+            .bind(tenant_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.is_some())
+    }
+
+    /// Forgets a file the tenant deleted upstream, with its links to the tenant's conversations.
+    pub async fn detach_file(&self, file_id: &str, tenant_id: &str) -> Result<(), sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+        sqlx::query(
+            "DELETE FROM conversation_files WHERE file_id = ? \
+             AND conversation_id IN (SELECT id FROM conversations WHERE tenant_id = ?)",
+        )
+        .bind(file_id)
+        .bind(tenant_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query("DELETE FROM files WHERE file_id = ? AND tenant_id = ?")
+            .bind(file_id)
+            .bind(tenant_id)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await
+    }
+
+    /// The tenant owning the conversation, or `None` if it doesn't exist.
+    pub async fn conversation_tenant(&self, conversation_id: &str) -> Result<Option<String>, sqlx::Error> {
+        let row: Option<(String,)> = sqlx::query_as("SELECT tenant_id FROM conversations WHERE id = ?")
+            .bind(conversation_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|(tenant_id,)| tenant_id))
+    }
+
+    pub async fn get_conversation(&self, conversation_id: &str, tenant_id: &str) -> Result<Option<Conversation>, sqlx::Error> {
+        let row = sqlx::query(
+            "SELECT id, created_at, updated_at, title, metadata FROM conversations WHERE id = ? AND tenant_id = ?",
+        )
+        .bind(conversation_id)
+        .bind(tenant_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.as_ref().map(Conversation::from_row))
+    }
+
+    /// Marks a background response as queued, creating its conversation if this is the first turn.
+    pub async fn queue_response(
+        &self,
+        conversation_id: &str,
+        tenant_id: &str,
+        metadata: Option<&HashMap<String, String>>,
+    ) -> Result<(), sqlx::Error> {
+        // Callers have checked the conversation is the tenant's; the WHERE keeps it that way
+        sqlx::query(
+            "INSERT INTO conversations (id, created_at, metadata, status, tenant_id) VALUES (?1, ?2, ?3, 'queued', ?4) \
+             ON CONFLICT(id) DO UPDATE SET status = 'queued' WHERE tenant_id = ?4",
+        )
+        .bind(conversation_id)
+        .bind(now_secs())
+        .bind(metadata.map(|m| serde_json::to_string(m).unwrap()))
+        .bind(tenant_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn set_response_status(&self, conversation_id: &str, status: &str) -> Result<(), sqlx::Error> {
+        sqlx::query("UPDATE conversations SET status = ? WHERE id = ?")
+            .bind(status)
+            .bind(conversation_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Status of the conversation's latest response: `queued`, `in_progress`, `completed`,
+    /// `incomplete` or `failed`. `None` if the conversation doesn't exist or is another tenant's.
+    pub async fn get_response_status(&self, conversation_id: &str, tenant_id: &str) -> Result<Option<String>, sqlx::Error> {
+        let row: Option<(String,)> = sqlx::query_as("SELECT status FROM conversations WHERE id = ? AND tenant_id = ?")
+            .bind(conversation_id)
+            .bind(tenant_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.map(|(status,)| status))
+    }
+
+    /// Returns up to `limit` of the items clients sent in the conversation (not model output or
+    /// system prompts) with their sequence indexes, oldest first. With `before`, only items before
+    /// that sequence index are considered, and the page is the newest `limit` of them.
+    /// The flag is whether older items remain; `None` if the conversation doesn't exist.
+    pub async fn get_input_items(
+        &self,
+        conversation_id: &str,
+        tenant_id: &str,
+        limit: i64,
+        before: Option<i64>,
+    ) -> Result<Option<(Vec<(i64, OrsInputItem)>, bool)>, sqlx::Error> {
+        if self.get_conversation(conversation_id, tenant_id).await?.is_none() {
+            return Ok(None);
+        }
+
+        let rows = sqlx::query(
+            "SELECT sequence_index, payload FROM items \
+             WHERE conversation_id = ?1 AND item_type IN ('input', 'computer_tool_result') AND (?2 IS NULL OR sequence_index < ?2) \
+             ORDER BY sequence_index DESC LIMIT ?3",
+        )
+        .bind(conversation_id)
+        .bind(before)
+        .bind(limit + 1)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let has_more = rows.len() as i64 > limit;
+        let mut items: Vec<(i64, OrsInputItem)> = rows
+            .iter()
+            .take(limit as usize)
+            .filter_map(|row| {
+                let payload = match self.open(row.get("payload")) {
+                    Ok(payload) => payload,
+                    Err(e) => {
+                        warn!("Failed to read item payload: {}", e);
+                        return None;
+                    }
+                };
+                match serde_json::from_str(&payload) {
+                    Ok(item) => Some((row.get("sequence_index"), item)),
+                    Err(e) => {
+                        warn!("Failed to deserialize item payload: {}", e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        items.reverse();
+        Ok(Some((items, has_more)))
+    }
+
+    /// Returns the conversation's metadata, or `None` if the conversation doesn't exist.
+    pub async fn get_conversation_metadata(
+        &self,
+        conversation_id: &str,
+        tenant_id: &str,
+    ) -> Result<Option<HashMap<String, String>>, sqlx::Error> {
+        Ok(self.get_conversation(conversation_id, tenant_id).await?.map(|c| c.metadata))
+    }
+
+    /// Applies `patch` to the conversation: the title is replaced, while metadata is merged
+    /// (new keys added, existing keys overwritten, `None` values removed).
+    /// Returns `None` if the conversation doesn't exist or is another tenant's.
+    pub async fn update_conversation_metadata(
+        &self,
+        conversation_id: &str,
+        tenant_id: &str,
+        patch: &ConversationPatch,
+    ) -> Result<Option<Conversation>, sqlx::Error> {
+        // SQLite's json_patch implements RFC 7396 merge-patch, where null deletes a key
+        let result = sqlx::query(
+            "UPDATE conversations SET \
+                title = COALESCE(?1, title), \
+                metadata = CASE WHEN ?2 IS NULL THEN metadata ELSE json_patch(COALESCE(metadata, '{}'), ?2) END, \
+                updated_at = ?3 \
+             WHERE id = ?4 AND tenant_id = ?5",
+        )
+        .bind(&patch.title)
+        .bind(patch.metadata.as_ref().map(|m| serde_json::to_string(m).unwrap()))
+        .bind(now_secs())
+        .bind(conversation_id)
+        .bind(tenant_id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Ok(None);
+        }
+        self.get_conversation(conversation_id, tenant_id).await
+    }
+
+    /// Copies the conversation's items up to and including `branch_at_sequence`
+    /// into a new conversation, atomically. The fork belongs to the same tenant.
+    pub async fn fork_conversation(
+        &self,
+        conversation_id: &str,
+        tenant_id: &str,
+        branch_at_sequence: i64,
+    ) -> Result<ForkOutcome, sqlx::Error> {
+        let mut tx = self.pool.begin().await?;
+
+        let exists = sqlx::query("SELECT 1 FROM conversations WHERE id = ? AND tenant_id = ?")
+            .bind(conversation_id)
+            .bind(tenant_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        if exists.is_none() {
+            return Ok(ForkOutcome::NotFound);
+        }
+
+        let (end,): (i64,) =
+            sqlx::query_as("SELECT COALESCE(MAX(sequence_index) + 1, 0) FROM items WHERE conversation_id = ?")
+                .bind(conversation_id)
+                .fetch_one(&mut *tx)
+                .await?;
+        if branch_at_sequence < 0 || branch_at_sequence >= end {
+            return Ok(ForkOutcome::OutOfRange { end });
+        }
+
+        let fork_id = uuid::Uuid::new_v4().to_string();
+        sqlx::query(
+            "INSERT INTO conversations (id, created_at, title, metadata, tenant_id) \
+             SELECT ?, ?, title, metadata, tenant_id FROM conversations WHERE id = ?",
+        )
+        .bind(&fork_id)
+        .bind(now_secs())
+        .bind(conversation_id)
+        .execute(&mut *tx)
+        .await?;
+
+        let copied = sqlx::query(
+            "INSERT INTO items (conversation_id, sequence_index, item_type, payload) \
+             SELECT ?, sequence_index, item_type, payload FROM items \
+             WHERE conversation_id = ? AND sequence_index <= ? ORDER BY sequence_index",
+        )
+        .bind(&fork_id)
+        .bind(conversation_id)
+        .bind(branch_at_sequence)
+        .execute(&mut *tx)
+        .await?;
+
+        tx.commit().await?;
+        Ok(ForkOutcome::Forked {
+            conversation_id: fork_id,
+            copied_items: copied.rows_affected(),
+        })
+    }
+
+    /// Stores the events of one response, in emission order.
+    pub async fn save_events(&self, conversation_id: &str, events: &[OrsEvent]) -> Result<(), sqlx::Error> {
+        let response_id = events
+            .iter()
+            .find_map(|event| match event {
+                OrsEvent::Created { id, .. } => Some(id.as_str()),
+                _ => None,
+            })
+            .unwrap_or("unknown");
+
+        let mut tx = self.pool.begin().await?;
+        for event in events {
+            let Some(seq) = event.sequence_number() else { continue };
+            sqlx::query(
+                "INSERT INTO events (conversation_id, response_id, sequence_number, payload) VALUES (?, ?, ?, ?)",
+            )
+            .bind(conversation_id)
+            .bind(response_id)
+            .bind(seq)
+            .bind(self.seal(self.event_payload(event)))
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await
+    }
+
+    fn event_payload(&self, event: &OrsEvent) -> String {
+        match event {
+            OrsEvent::TextDelta { logprobs: Some(_), .. } if !self.store_logprobs => {
+                let mut event = event.clone();
+                if let OrsEvent::TextDelta { logprobs, .. } = &mut event {
+                    *logprobs = None;
+                }
+                serde_json::to_string(&event).unwrap()
+            }
+            _ => serde_json::to_string(event).unwrap(),
+        }
+    }
+
+    /// Returns the events of the conversation's latest response with a sequence number above `seq`.
+    pub async fn get_events_after(
+        &self,
+        conversation_id: &str,
+        tenant_id: &str,
+        seq: u32,
+    ) -> Result<Vec<StoredEvent>, sqlx::Error> {
         let rows = sqlx::query(
-            "SELECT payload FROM items WHERE conversation_id = ? ORDER BY sequence_index ASC",
+            "SELECT sequence_number, payload FROM events \
+             WHERE conversation_id = ?1 AND sequence_number > ?2 AND response_id = ( \
+                 SELECT response_id FROM events WHERE conversation_id = ?1 ORDER BY id DESC LIMIT 1 \
+             ) AND EXISTS (SELECT 1 FROM conversations WHERE id = ?1 AND tenant_id = ?3) \
+             ORDER BY sequence_number ASC",
         )
         .bind(conversation_id)
+        .bind(seq)
+        .bind(tenant_id)
         .fetch_all(&self.pool)
         .await?;
 
-        let items = rows
-            .into_iter()
+        rows.into_iter()
             .map(|row| {
-                let json_str: String = row.get("payload");
-                serde_json::from_str(&json_str).unwrap_or_else(|e| {
-                    warn!("Failed to deserialize item payload: {}", e);
-                    // Fallback or skip? For now, we panic in unwrap or allow corruption?
-                    // Safe fallback: Return a dummy or valid "error" item if we had one.
-                    // But here we must match the return type.
-                    // Let's assume DB integrity for now.
-                    panic!("Corrupt DB item: {}", e);
+                let payload = self.open(row.get("payload"))?;
+                let event_type = serde_json::from_str::<serde_json::Value>(&payload)
+                    .ok()
+                    .and_then(|v| v.get("type").and_then(|t| t.as_str()).map(str::to_string))
+                    .unwrap_or_default();
+                Ok(StoredEvent {
+                    sequence_number: row.get::<i64, _>("sequence_number") as u32,
+                    event_type,
+                    payload,
                 })
             })
-            .collect();
+            .collect()
+    }
 
-        Ok(items)
+    /// Reads every stored item the way [`Db::load_context`] would, reporting those that fail,
+    /// e.g. after a change to `OrsInputItem`. Returns how many items were checked, and the
+    /// failures.
+    pub async fn validate_all_items(&self) -> Result<(u64, Vec<ItemFailure>), sqlx::Error> {
+        let mut rows = sqlx::query("SELECT id, conversation_id, payload FROM items ORDER BY id").fetch(&self.pool);
+        let (mut checked, mut failures) = (0, Vec::new());
+        while let Some(row) = rows.try_next().await? {
+            checked += 1;
+            let error = match self.open(row.get("payload")) {
+                Ok(payload) => serde_json::from_str::<OrsInputItem>(&payload).err().map(|e| e.to_string()),
+                Err(e) => Some(e.to_string()),
+            };
+            if let Some(error) = error {
+                failures.push(ItemFailure { id: row.get("id"), conversation_id: row.get("conversation_id"), error });
+            }
+        }
+        Ok((checked, failures))
     }
 
+    /// Appends a turn to the conversation, creating it for `tenant_id` if it's new. Fails with
+    /// `RowNotFound` if the conversation belongs to another tenant.
     pub async fn save_interaction(
         &self,
         conversation_id: &str,
+        tenant_id: &str,
+        metadata: Option<&HashMap<String, String>>,
         input: Vec<OrsInputItem>,
         output_events: Vec<OrsEvent>,
     ) -> Result<(), sqlx::Error> {
-        // 1. Ensure conversation exists
-        let now = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap()
-            .as_secs() as i64;
+        // One transaction, so the turn's items get consecutive indexes even if another save to
+        // the conversation is racing this one
+        let mut tx = self.pool.begin().await?;
+
+        // 1. Ensure conversation exists (metadata is only recorded when it's first created)
+        let now = now_secs();
 
         sqlx::query(
-            "INSERT OR IGNORE INTO conversations (id, created_at) VALUES (?, ?)",
+            "INSERT OR IGNORE INTO conversations (id, created_at, metadata, tenant_id) VALUES (?, ?, ?, ?)",
         )
         .bind(conversation_id)
         .bind(now)
-        .execute(&self.pool)
-        .await?;
-
-        // 2. Determine next sequence index
-        let count_row: (i64,) = sqlx::query_as(
-            "SELECT COUNT(*) FROM items WHERE conversation_id = ?",
-        )
-        .bind(conversation_id)
-        .fetch_one(&self.pool)
+        .bind(metadata.map(|m| serde_json::to_string(m).unwrap()))
+        .bind(tenant_id)
+        .execute(&mut *tx)
         .await?;
-        
-        let mut sequence_index = count_row.0;
-
-        // 3. Save Input Items
-        for item in input {
-            let payload = serde_json::to_string(&item).unwrap();
-            sqlx::query(
-                "INSERT INTO items (conversation_id, sequence_index, item_type, payload) VALUES (?, ?, ?, ?)",
-            )
+        let owner: Option<(String,)> = sqlx::query_as("SELECT tenant_id FROM conversations WHERE id = ?")
             .bind(conversation_id)
-            .bind(sequence_index)
-            .bind("input") // Just a label, payload has real type
-            .bind(payload)
-            .execute(&self.pool)
+            .fetch_optional(&mut *tx)
             .await?;
-            sequence_index += 1;
+        if owner.map(|(owner,)| owner).as_deref() != Some(tenant_id) {
+            return Err(sqlx::Error::RowNotFound);
         }
 
-        // 4. Reconstruct Output Items from Events
-        // The events are a stream of Created, Added, Delta, Done.
-        // We need to aggregate them into OrsInputItem format (Message or FunctionCall) to store them.
-        // For simplicity in this proxy, we only store the FINAL state as a "assistant message".
-        
-        // This aggregation logic is tricky because we only have the raw events here.
-        // Ideally, the caller should pass the aggregated output as OrsInputItem.
-        // But the caller is streaming.
-        
-        // Strategy: We can aggregate locally here.
-        // Group by item_id.
-        use std::collections::HashMap;
-        
-        struct ItemState {
-            item_type: String, // "message"
-            content: String,
-        }
-        let mut items_map: HashMap<String, ItemState> = HashMap::new();
-        let mut item_order: Vec<String> = Vec::new();
-
-        for event in output_events {
-            match event {
-                OrsEvent::ItemAdded { item, .. } => {
-                    let item_id = item.get("id").and_then(|v| v.as_str()).unwrap_or("unknown").to_string();
-                    let item_type = item.get("type").and_then(|v| v.as_str()).unwrap_or("unknown").to_string();
-                    items_map.insert(item_id.clone(), ItemState { item_type, content: String::new() });
-                    item_order.push(item_id);
-                }
-                OrsEvent::TextDelta { item_id, delta, .. } => {
-                     if let Some(state) = items_map.get_mut(&item_id) {
-                         state.content.push_str(&delta);
-                     }
-                }
-                OrsEvent::FunctionCallArgumentsDelta { item_id, delta, .. } => {
-                     if let Some(state) = items_map.get_mut(&item_id) {
-                         state.content.push_str(&delta);
-                     }
+        // 2. Input items, then the output items reconstructed from the events
+        let input_items = input.iter().map(|item| match item {
+            // Developer messages are tagged so load_context can hoist them to the front
+            OrsInputItem::Message { role: OrsRole::Developer, .. } => ("system_prompt", item),
+            OrsInputItem::ComputerToolResult { .. } => ("computer_tool_result", item),
+            _ => ("input", item), // Just a label, payload has real type
+        });
+        let output = output_items(&output_events);
+        let output_items = output.iter().map(|item| match item {
+            OrsInputItem::FunctionCall { .. } => ("function_call", item),
+            _ => ("message", item),
+        });
+        let items: Vec<(&str, String)> = input_items
+            .chain(output_items)
+            .filter_map(|(item_type, item)| match item_payload(item) {
+                Some(payload) => Some((item_type, self.seal(payload))),
+                None => {
+                    warn!("Skipping a {} item of conversation {}: it wouldn't load back", item_type, conversation_id);
+                    None
                 }
-                _ => {}
-            }
-        }
+            })
+            .collect();
 
-        for item_id in item_order {
-            if let Some(state) = items_map.get(&item_id) {
-                // Convert to OrsInputItem
-                let item = OrsInputItem::Message {
-                    role: OrsRole::Assistant,
-                    content: vec![OrsContentPart::InputText { text: state.content.clone() }]
-                };
-                
-                let payload = serde_json::to_string(&item).unwrap();
-                sqlx::query(
-                    "INSERT INTO items (conversation_id, sequence_index, item_type, payload) VALUES (?, ?, ?, ?)",
-                )
+        // 3. Insert them after the conversation's last item, many rows per statement
+        let (mut sequence_index,): (i64,) =
+            sqlx::query_as("SELECT COALESCE(MAX(sequence_index) + 1, 0) FROM items WHERE conversation_id = ?")
                 .bind(conversation_id)
-                .bind(sequence_index)
-                .bind(&state.item_type)
-                .bind(payload)
-                .execute(&self.pool)
+                .fetch_one(&mut *tx)
                 .await?;
+        for batch in items.chunks(ITEM_INSERT_BATCH) {
+            let mut query = QueryBuilder::new("INSERT INTO items (conversation_id, sequence_index, item_type, payload) ");
+            query.push_values(batch, |mut row, (item_type, payload)| {
+                row.push_bind(conversation_id)
+                    .push_bind(sequence_index)
+                    .push_bind(*item_type)
+                    .push_bind(payload);
                 sequence_index += 1;
-            }
+            });
+            query.build().execute(&mut *tx).await?;
         }
+        tx.commit().await?;
 
+        // Last, so a load that raced the writes above can't leave a partial context cached
+        self.invalidate_context(conversation_id);
         Ok(())
     }
 }
 
+#[cfg(test)]
+impl Db {
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+}
+
+pub fn now_secs() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+/// Serializes an item for storage, or `None` (logged) if it wouldn't load back as the same
+/// item. Storing such an item would break every later load of its conversation.
+fn item_payload(item: &OrsInputItem) -> Option<String> {
+    let payload = match serde_json::to_string(item) {
+        Ok(payload) => payload,
+        Err(e) => {
+            error!("Not storing an item that doesn't serialize: {}", e);
+            return None;
+        }
+    };
+    match serde_json::from_str::<OrsInputItem>(&payload) {
+        Ok(parsed) if parsed == *item => Some(payload),
+        Ok(_) => {
+            error!("Not storing an item that changes when reloaded: {}", payload);
+            None
+        }
+        Err(e) => {
+            error!("Not storing an item that doesn't deserialize ({}): {}", e, payload);
+            None
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::{OrsContentPart, OrsInputItem, OrsRole};
+    use crate::types::{FunctionCallOutputContent, FunctionCallOutputPart, LogprobEntry, ResponseItem};
 
     #[tokio::test]
     async fn test_db_init_and_save() {
@@ -190,7 +1390,7 @@ mod tests {
         let db = Db::new("sqlite::memory:").await.unwrap();
         
         // 1. Initial Load (empty)
-        let history = db.load_context("conv_1").await.unwrap();
+        let history = db.load_context("conv_1", DEFAULT_TENANT_ID).await.unwrap();
         assert!(history.is_empty());
 
         // 2. Save Interaction
@@ -199,29 +1399,31 @@ mod tests {
             content: vec![OrsContentPart::InputText { text: "Hello".to_string() }],
         }];
         let output_events = vec![
-            OrsEvent::Created { id: "res_1".to_string(), sequence_number: Some(0) },
+            OrsEvent::Created { id: "res_1".to_string(), created_at: 0, sequence_number: Some(0) },
             OrsEvent::ItemAdded { 
                 sequence_number: Some(1),
-                item: serde_json::json!({"id": "msg_1", "type": "message", "role": "assistant"})
+                output_index: Some(0),
+                item: ResponseItem::message("msg_1"),
             },
             OrsEvent::TextDelta { 
                 sequence_number: Some(2), 
                 item_id: "msg_1".to_string(), 
                 output_index: Some(0),
                 content_index: Some(0),
-                delta: "Hi".to_string() 
+                delta: "Hi".to_string(),
+                logprobs: None,
             },
             OrsEvent::ItemDone { 
                 sequence_number: Some(3),
                 output_index: Some(0),
-                item: serde_json::json!({"id": "msg_1", "type": "message", "status": "completed"})
+                item: ResponseItem::message("msg_1"),
             },
         ];
 
-        db.save_interaction("conv_1", input, output_events).await.unwrap();
+        db.save_interaction("conv_1", DEFAULT_TENANT_ID, None, input, output_events).await.unwrap();
 
         // 3. Load Context Again
-        let history2 = db.load_context("conv_1").await.unwrap();
+        let history2 = db.load_context("conv_1", DEFAULT_TENANT_ID).await.unwrap();
         assert_eq!(history2.len(), 2);
         
         if let OrsInputItem::Message { role, content } = &history2[0] {
@@ -238,4 +1440,755 @@ mod tests {
              }
         }
     }
+
+    fn user_message(text: &str) -> OrsInputItem {
+        OrsInputItem::Message {
+            role: OrsRole::User,
+            content: vec![OrsContentPart::InputText { text: text.to_string() }],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_get_input_items() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        assert!(db.get_input_items("conv_1", DEFAULT_TENANT_ID, 10, None).await.unwrap().is_none());
+
+        db.set_system_prompt("conv_1", "Be brief").await.unwrap();
+        let reply = |text: &str| {
+            vec![
+                OrsEvent::ItemAdded { sequence_number: Some(0), output_index: Some(0), item: ResponseItem::message("msg_1") },
+                OrsEvent::TextDelta {
+                    sequence_number: Some(1),
+                    item_id: "msg_1".to_string(),
+                    output_index: Some(0),
+                    content_index: Some(0),
+                    delta: text.to_string(),
+                    logprobs: None,
+                },
+                OrsEvent::ItemDone { sequence_number: Some(2), output_index: Some(0), item: ResponseItem::message("msg_1") },
+            ]
+        };
+        for (question, answer) in [("one", "1"), ("two", "2"), ("three", "3")] {
+            db.save_interaction("conv_1", DEFAULT_TENANT_ID, None, vec![user_message(question)], reply(answer)).await.unwrap();
+        }
+
+        let (items, has_more) = db.get_input_items("conv_1", DEFAULT_TENANT_ID, 10, None).await.unwrap().unwrap();
+        assert!(!has_more);
+        let inputs: Vec<_> = items.iter().map(|(_, item)| item.clone()).collect();
+        assert_eq!(inputs, vec![user_message("one"), user_message("two"), user_message("three")]);
+
+        // Paging backwards from the newest
+        let (page, has_more) = db.get_input_items("conv_1", DEFAULT_TENANT_ID, 2, None).await.unwrap().unwrap();
+        assert!(has_more);
+        assert_eq!(page.iter().map(|(_, item)| item.clone()).collect::<Vec<_>>(), inputs[1..]);
+        let (page, has_more) = db.get_input_items("conv_1", DEFAULT_TENANT_ID, 2, Some(page[0].0)).await.unwrap().unwrap();
+        assert!(!has_more);
+        assert_eq!(page.iter().map(|(_, item)| item.clone()).collect::<Vec<_>>(), inputs[..1]);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_saves_get_distinct_indices() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let save = |text: &'static str| {
+            db.save_interaction("conv_1", DEFAULT_TENANT_ID, None, vec![user_message(text), user_message(text)], Vec::new())
+        };
+        let results = futures::future::join_all(["a", "b", "c", "d", "e"].map(save)).await;
+        assert!(results.iter().all(Result::is_ok));
+
+        let indices: Vec<i64> =
+            sqlx::query_scalar("SELECT sequence_index FROM items WHERE conversation_id = 'conv_1' ORDER BY sequence_index")
+                .fetch_all(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(indices, (0..10).collect::<Vec<_>>());
+    }
+
+    #[tokio::test]
+    async fn test_system_prompt_loads_first() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+
+        db.save_interaction("conv_sp", DEFAULT_TENANT_ID, None, vec![user_message("First")], vec![]).await.unwrap();
+        db.set_system_prompt("conv_sp", "Be terse").await.unwrap();
+        db.save_interaction("conv_sp", DEFAULT_TENANT_ID, None, vec![user_message("Second")], vec![]).await.unwrap();
+
+        let history = db.load_context("conv_sp", DEFAULT_TENANT_ID).await.unwrap();
+        assert_eq!(history.len(), 3);
+        assert!(matches!(&history[0], OrsInputItem::Message { role: OrsRole::Developer, .. }));
+
+        // Setting it again replaces rather than appends
+        db.set_system_prompt("conv_sp", "Be verbose").await.unwrap();
+        let history = db.load_context("conv_sp", DEFAULT_TENANT_ID).await.unwrap();
+        assert_eq!(history.len(), 3);
+        if let OrsInputItem::Message { content, .. } = &history[0] {
+            assert_eq!(content[0], OrsContentPart::InputText { text: "Be verbose".to_string() });
+        }
+    }
+
+    #[tokio::test]
+    async fn test_instructions_load_before_system_prompt() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+
+        let input = vec![
+            OrsInputItem::Message {
+                role: OrsRole::Developer,
+                content: vec![OrsContentPart::InputText { text: "Be terse".to_string() }],
+            },
+            user_message("Hi"),
+        ];
+        db.save_interaction("conv_ins", DEFAULT_TENANT_ID, None, input, vec![]).await.unwrap();
+        db.save_instructions("conv_ins", "You are a pirate").await.unwrap();
+
+        let history = db.load_context("conv_ins", DEFAULT_TENANT_ID).await.unwrap();
+        assert_eq!(history.len(), 3);
+        let first_text = |item: &OrsInputItem| match item {
+            OrsInputItem::Message { content, .. } => content[0].clone(),
+            other => panic!("unexpected item {:?}", other),
+        };
+        assert_eq!(first_text(&history[0]), OrsContentPart::InputText { text: "You are a pirate".to_string() });
+        assert_eq!(first_text(&history[1]), OrsContentPart::InputText { text: "Be terse".to_string() });
+    }
+
+    #[tokio::test]
+    async fn test_load_context_without_instructions() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let developer = OrsInputItem::Message {
+            role: OrsRole::Developer,
+            content: vec![OrsContentPart::InputText { text: "Be terse".to_string() }],
+        };
+        db.save_interaction("conv_wi", DEFAULT_TENANT_ID, None, vec![developer.clone(), user_message("Hi")], vec![])
+            .await
+            .unwrap();
+
+        // A leading developer message of the client's own isn't mistaken for instructions
+        assert_eq!(db.load_context_without_instructions("conv_wi", DEFAULT_TENANT_ID).await.unwrap().len(), 2);
+
+        db.save_instructions("conv_wi", "You are a pirate").await.unwrap();
+        assert_eq!(db.load_context("conv_wi", DEFAULT_TENANT_ID).await.unwrap().len(), 3);
+        // Served from the cache this time, still without them
+        for _ in 0..2 {
+            let history = db.load_context_without_instructions("conv_wi", DEFAULT_TENANT_ID).await.unwrap();
+            assert_eq!(history, vec![developer.clone(), user_message("Hi")]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_conversation_metadata_merge_and_filter() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+
+        let meta: HashMap<String, String> = [
+            ("user_id".to_string(), "u_1".to_string()),
+            ("title".to_string(), "Draft".to_string()),
+        ]
+        .into_iter()
+        .collect();
+        db.save_interaction("conv_a", DEFAULT_TENANT_ID, Some(&meta), vec![user_message("Hi")], vec![]).await.unwrap();
+        db.save_interaction("conv_b", DEFAULT_TENANT_ID, None, vec![user_message("Hi")], vec![]).await.unwrap();
+        assert_eq!(db.get_conversation_metadata("conv_a", DEFAULT_TENANT_ID).await.unwrap(), Some(meta.clone()));
+        assert_eq!(db.get_conversation_metadata("missing", DEFAULT_TENANT_ID).await.unwrap(), None);
+
+        let patch = ConversationPatch {
+            title: Some("Trip planning".to_string()),
+            metadata: Some(
+                [
+                    ("title".to_string(), None),
+                    ("tag".to_string(), Some("work".to_string())),
+                ]
+                .into_iter()
+                .collect(),
+            ),
+        };
+        let conv = db.update_conversation_metadata("conv_a", DEFAULT_TENANT_ID, &patch).await.unwrap().unwrap();
+        assert_eq!(conv.title.as_deref(), Some("Trip planning"));
+        assert!(conv.updated_at.is_some());
+        assert_eq!(conv.metadata.get("user_id").map(String::as_str), Some("u_1"));
+        assert_eq!(conv.metadata.get("tag").map(String::as_str), Some("work"));
+        assert!(!conv.metadata.contains_key("title"));
+
+        // Title-only patch leaves metadata untouched
+        let rename = ConversationPatch { title: Some("Renamed".to_string()), metadata: None };
+        let conv = db.update_conversation_metadata("conv_a", DEFAULT_TENANT_ID, &rename).await.unwrap().unwrap();
+        assert_eq!(conv.title.as_deref(), Some("Renamed"));
+        assert_eq!(conv.metadata.len(), 2);
+
+        assert!(db.update_conversation_metadata("missing", DEFAULT_TENANT_ID, &patch).await.unwrap().is_none());
+
+        let all = db.list_conversations(DEFAULT_TENANT_ID, &[], 20).await.unwrap();
+        assert_eq!(all.len(), 2);
+        let filtered = db.list_conversations(DEFAULT_TENANT_ID, &[filter("user_id", "u_1")], 20).await.unwrap();
+        assert_eq!(filtered.len(), 1);
+        assert_eq!(filtered[0].id, "conv_a");
+    }
+
+    fn filter(key: &str, value: &str) -> (String, String) {
+        (key.to_string(), value.to_string())
+    }
+
+    #[tokio::test]
+    async fn test_list_conversations_by_multiple_metadata_fields() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let meta = |user: &str, env: &str| -> HashMap<String, String> {
+            [("user_id".to_string(), user.to_string()), ("env".to_string(), env.to_string())]
+                .into_iter()
+                .collect()
+        };
+        db.save_interaction("c1", DEFAULT_TENANT_ID, Some(&meta("u_1", "prod")), vec![user_message("Hi")], vec![]).await.unwrap();
+        db.save_interaction("c2", DEFAULT_TENANT_ID, Some(&meta("u_1", "test")), vec![user_message("Hi")], vec![]).await.unwrap();
+        db.save_interaction("c3", DEFAULT_TENANT_ID, Some(&meta("u_2", "test")), vec![user_message("Hi")], vec![]).await.unwrap();
+
+        let by_env = db.list_conversations(DEFAULT_TENANT_ID, &[filter("env", "test")], 20).await.unwrap();
+        assert_eq!(by_env.len(), 2);
+
+        let both = db
+            .list_conversations(DEFAULT_TENANT_ID, &[filter("user_id", "u_1"), filter("env", "test")], 20)
+            .await
+            .unwrap();
+        assert_eq!(both.len(), 1);
+        assert_eq!(both[0].id, "c2");
+    }
+
+    #[tokio::test]
+    async fn test_fork_conversation() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let input = vec![user_message("One"), user_message("Two"), user_message("Three")];
+        db.save_interaction("conv_src", DEFAULT_TENANT_ID, None, input, vec![]).await.unwrap();
+
+        let fork_id = match db.fork_conversation("conv_src", DEFAULT_TENANT_ID, 1).await.unwrap() {
+            ForkOutcome::Forked { conversation_id, copied_items } => {
+                assert_eq!(copied_items, 2);
+                conversation_id
+            }
+            _ => panic!("Expected fork to succeed"),
+        };
+
+        let forked = db.load_context(&fork_id, DEFAULT_TENANT_ID).await.unwrap();
+        assert_eq!(forked, vec![user_message("One"), user_message("Two")]);
+        // Source is untouched
+        assert_eq!(db.load_context("conv_src", DEFAULT_TENANT_ID).await.unwrap().len(), 3);
+
+        assert!(matches!(db.fork_conversation("missing", DEFAULT_TENANT_ID, 0).await.unwrap(), ForkOutcome::NotFound));
+        assert!(matches!(
+            db.fork_conversation("conv_src", DEFAULT_TENANT_ID, 3).await.unwrap(),
+            ForkOutcome::OutOfRange { end: 3 }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fork_range_follows_sequence_indexes_not_count() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.save_interaction("conv_gap", DEFAULT_TENANT_ID, None, vec![user_message("One")], vec![]).await.unwrap();
+        db.save_instructions("conv_gap", "Be brief").await.unwrap();
+        db.save_interaction("conv_gap", DEFAULT_TENANT_ID, None, vec![user_message("Two")], vec![]).await.unwrap();
+        // Replacing the instructions deletes the old item, leaving a gap: 0, 2, 3
+        db.save_instructions("conv_gap", "Be briefer").await.unwrap();
+
+        match db.fork_conversation("conv_gap", DEFAULT_TENANT_ID, 3).await.unwrap() {
+            ForkOutcome::Forked { copied_items, .. } => assert_eq!(copied_items, 3),
+            _ => panic!("Expected fork at the last item to succeed"),
+        }
+        assert!(matches!(
+            db.fork_conversation("conv_gap", DEFAULT_TENANT_ID, 4).await.unwrap(),
+            ForkOutcome::OutOfRange { end: 4 }
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_function_call_output_persisted_as_function_call() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let output_events = vec![
+            OrsEvent::ItemAdded {
+                sequence_number: Some(0),
+                output_index: Some(0),
+                item: ResponseItem::function_call("fc_1", "call_1", "get_weather"),
+            },
+            OrsEvent::FunctionCallArgumentsDelta {
+                sequence_number: Some(1),
+                item_id: "fc_1".to_string(),
+                output_index: Some(0),
+                delta: "{\"city\":\"SF\"}".to_string(),
+            },
+        ];
+        db.save_interaction("conv_fc", DEFAULT_TENANT_ID, None, vec![user_message("Weather?")], output_events).await.unwrap();
+
+        let history = db.load_context("conv_fc", DEFAULT_TENANT_ID).await.unwrap();
+        assert_eq!(
+            history[1],
+            OrsInputItem::FunctionCall {
+                id: "fc_1".to_string(),
+                call_id: "call_1".to_string(),
+                name: "get_weather".to_string(),
+                arguments: serde_json::json!({"city": "SF"}),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_migration_backfills_tool_output_name() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let input = vec![
+            OrsInputItem::FunctionCall {
+                id: "fc_1".to_string(),
+                call_id: "call_1".to_string(),
+                name: "get_weather".to_string(),
+                arguments: serde_json::json!({}),
+            },
+            OrsInputItem::FunctionCallOutput {
+                id: "out_1".to_string(),
+                call_id: "call_1".to_string(),
+                output: FunctionCallOutputContent::Text("Sunny".to_string()),
+                name: None,
+            },
+        ];
+        db.save_interaction("conv_legacy", DEFAULT_TENANT_ID, None, input, vec![]).await.unwrap();
+
+        db.migrate().await.unwrap();
+
+        let history = db.load_context("conv_legacy", DEFAULT_TENANT_ID).await.unwrap();
+        match &history[1] {
+            OrsInputItem::FunctionCallOutput { name, .. } => assert_eq!(name.as_deref(), Some("get_weather")),
+            _ => panic!("Expected FunctionCallOutput"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_image_tool_output_round_trip() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let input = vec![
+            OrsInputItem::FunctionCall {
+                id: "fc_1".to_string(),
+                call_id: "call_1".to_string(),
+                name: "render_chart".to_string(),
+                arguments: serde_json::json!({}),
+            },
+            OrsInputItem::FunctionCallOutput {
+                id: "out_1".to_string(),
+                call_id: "call_1".to_string(),
+                output: FunctionCallOutputContent::Parts(vec![FunctionCallOutputPart::ImageUrl {
+                    image_url: "data:image/png;base64,AAAA".to_string(),
+                    detail: None,
+                }]),
+                name: Some("render_chart".to_string()),
+            },
+        ];
+        db.save_interaction("conv_img", DEFAULT_TENANT_ID, None, input.clone(), vec![]).await.unwrap();
+
+        assert_eq!(db.load_context("conv_img", DEFAULT_TENANT_ID).await.unwrap(), input);
+    }
+
+    #[tokio::test]
+    async fn test_get_events_after_uses_latest_response() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let response = |id: &str| {
+            vec![
+                OrsEvent::Created { id: id.to_string(), created_at: 0, sequence_number: Some(0) },
+                OrsEvent::TextDelta {
+                    sequence_number: Some(1),
+                    item_id: "msg_1".to_string(),
+                    output_index: Some(0),
+                    content_index: Some(0),
+                    delta: format!("from {}", id),
+                    logprobs: None,
+                },
+            ]
+        };
+        db.save_interaction("conv_ev", DEFAULT_TENANT_ID, None, vec![user_message("hi")], vec![]).await.unwrap();
+        db.save_events("conv_ev", &response("resp_1")).await.unwrap();
+        db.save_events("conv_ev", &response("resp_2")).await.unwrap();
+
+        let events = db.get_events_after("conv_ev", DEFAULT_TENANT_ID, 0).await.unwrap();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].sequence_number, 1);
+        assert_eq!(events[0].event_type, "response.output_text.delta");
+        assert!(events[0].payload.contains("from resp_2"));
+
+        assert!(db.get_events_after("conv_ev", DEFAULT_TENANT_ID, 1).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_logprobs_are_stored_only_when_enabled() {
+        let delta = OrsEvent::TextDelta {
+            sequence_number: Some(1),
+            item_id: "msg_1".to_string(),
+            output_index: Some(0),
+            content_index: Some(0),
+            delta: "Yes".to_string(),
+            logprobs: Some(vec![LogprobEntry { token: "Yes".to_string(), logprob: -0.01, bytes: None, top_logprobs: vec![] }]),
+        };
+        for store in [false, true] {
+            let db = Db::new("sqlite::memory:").await.unwrap().with_logprobs(store);
+            db.save_interaction("conv_lp", DEFAULT_TENANT_ID, None, vec![user_message("hi")], vec![]).await.unwrap();
+            db.save_events("conv_lp", std::slice::from_ref(&delta)).await.unwrap();
+
+            let events = db.get_events_after("conv_lp", DEFAULT_TENANT_ID, 0).await.unwrap();
+            assert_eq!(events[0].payload.contains("logprob"), store);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_purge_conversations() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let env = |e: &str| -> HashMap<String, String> { [("env".to_string(), e.to_string())].into_iter().collect() };
+        db.save_interaction("old_test", DEFAULT_TENANT_ID, Some(&env("test")), vec![user_message("Hi")], vec![]).await.unwrap();
+        db.save_interaction("old_prod", DEFAULT_TENANT_ID, Some(&env("prod")), vec![user_message("Hi")], vec![]).await.unwrap();
+        sqlx::query("UPDATE conversations SET created_at = 1000").execute(&db.pool).await.unwrap();
+        db.save_interaction("new_test", DEFAULT_TENANT_ID, Some(&env("test")), vec![user_message("Hi")], vec![]).await.unwrap();
+        let event = UsageEvent { tenant_id: DEFAULT_TENANT_ID, model: "llama3", usage: (1, 2, 3), upstream_latency_ms: None };
+        db.record_usage_event("old_test", &event).await.unwrap();
+
+        let cutoff = db.parse_timestamp("2024-01-01T00:00:00Z").await.unwrap();
+        assert_eq!(cutoff, Some(1_704_067_200));
+        assert_eq!(db.parse_timestamp("yesterday").await.unwrap(), None);
+
+        let purged = db.purge_conversations(cutoff, &[filter("env", "test")]).await.unwrap();
+        assert_eq!(purged, 1);
+        assert!(db.get_conversation("old_test", DEFAULT_TENANT_ID).await.unwrap().is_none());
+        assert!(db.load_context("old_test", DEFAULT_TENANT_ID).await.unwrap().is_empty());
+        let (usage,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM usage_events WHERE conversation_id = 'old_test'")
+            .fetch_one(&db.pool)
+            .await
+            .unwrap();
+        assert_eq!(usage, 0);
+        assert!(db.get_conversation("old_prod", DEFAULT_TENANT_ID).await.unwrap().is_some());
+        assert!(db.get_conversation("new_test", DEFAULT_TENANT_ID).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_delete_conversation() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.save_interaction("conv_d", DEFAULT_TENANT_ID, None, vec![user_message("Hi")], vec![]).await.unwrap();
+        db.save_interaction("conv_keep", DEFAULT_TENANT_ID, None, vec![user_message("Hi")], vec![]).await.unwrap();
+        let event = UsageEvent { tenant_id: DEFAULT_TENANT_ID, model: "llama3", usage: (1, 2, 3), upstream_latency_ms: None };
+        db.record_usage_event("conv_d", &event).await.unwrap();
+        assert_eq!(db.load_context("conv_d", DEFAULT_TENANT_ID).await.unwrap().len(), 1);
+
+        assert!(db.delete_conversation("conv_d", DEFAULT_TENANT_ID).await.unwrap());
+        for table in ["conversations", "items", "usage_events"] {
+            let column = if table == "conversations" { "id" } else { "conversation_id" };
+            let (count,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) FROM {} WHERE {} = 'conv_d'", table, column))
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+            assert_eq!(count, 0, "{} still has rows", table);
+        }
+        // The cached context went with it
+        assert!(db.load_context("conv_d", DEFAULT_TENANT_ID).await.unwrap().is_empty());
+        assert_eq!(db.load_context("conv_keep", DEFAULT_TENANT_ID).await.unwrap().len(), 1);
+
+        assert!(!db.delete_conversation("conv_d", DEFAULT_TENANT_ID).await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn test_conversations_isolated_by_tenant() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.save_interaction("conv_a", "team-a", None, vec![user_message("Hi")], vec![]).await.unwrap();
+        db.save_interaction("conv_b", "team-b", None, vec![user_message("Hi")], vec![]).await.unwrap();
+        assert_eq!(db.conversation_tenant("conv_a").await.unwrap().as_deref(), Some("team-a"));
+
+        // Warm the cache for the owner first, so a hit can't leak across tenants either
+        assert_eq!(db.load_context("conv_a", "team-a").await.unwrap().len(), 1);
+        assert!(db.load_context("conv_a", "team-b").await.unwrap().is_empty());
+        assert!(db.get_conversation("conv_a", "team-b").await.unwrap().is_none());
+        assert!(db.get_input_items("conv_a", "team-b", 10, None).await.unwrap().is_none());
+        assert!(matches!(
+            db.save_interaction("conv_a", "team-b", None, vec![user_message("Hijack")], vec![]).await,
+            Err(sqlx::Error::RowNotFound)
+        ));
+        assert!(!db.delete_conversation("conv_a", "team-b").await.unwrap());
+
+        let listed = db.list_conversations("team-a", &[], 10).await.unwrap();
+        assert_eq!(listed.iter().map(|c| c.id.as_str()).collect::<Vec<_>>(), vec!["conv_a"]);
+        assert_eq!(db.load_context("conv_a", "team-a").await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_conversation_files() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let files = || {
+            sqlx::query_as::<_, (String, String)>("SELECT conversation_id, file_id FROM conversation_files ORDER BY file_id")
+                .fetch_all(&db.pool)
+        };
+        for conversation_id in ["conv_a", "conv_b"] {
+            db.save_interaction(conversation_id, DEFAULT_TENANT_ID, None, vec![user_message("Hi")], vec![]).await.unwrap();
+        }
+        db.attach_file("conv_a", "file-1").await.unwrap();
+        db.attach_file("conv_a", "file-1").await.unwrap();
+        db.attach_file("conv_a", "file-2").await.unwrap();
+        db.attach_file("conv_b", "file-2").await.unwrap();
+        assert_eq!(db.conversation_files("conv_a").await.unwrap(), vec!["file-1", "file-2"]);
+
+        // Another tenant can't unlink the caller's files
+        db.detach_file("file-2", "team-b").await.unwrap();
+        assert_eq!(db.conversation_files("conv_b").await.unwrap(), vec!["file-2"]);
+
+        db.record_file("file-2", DEFAULT_TENANT_ID).await.unwrap();
+        assert!(db.owns_file("file-2", DEFAULT_TENANT_ID).await.unwrap());
+        assert!(!db.owns_file("file-2", "team-b").await.unwrap());
+        db.detach_file("file-2", DEFAULT_TENANT_ID).await.unwrap();
+        assert_eq!(files().await.unwrap(), vec![("conv_a".to_string(), "file-1".to_string())]);
+        assert!(!db.owns_file("file-2", DEFAULT_TENANT_ID).await.unwrap());
+
+        assert!(db.delete_conversation("conv_a", DEFAULT_TENANT_ID).await.unwrap());
+        assert!(files().await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_forget_conversation() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.save_interaction("conv_f", DEFAULT_TENANT_ID, None, vec![user_message("Hi")], vec![]).await.unwrap();
+        let event = UsageEvent { tenant_id: DEFAULT_TENANT_ID, model: "llama3", usage: (1, 2, 3), upstream_latency_ms: None };
+        db.record_usage_event("conv_f", &event).await.unwrap();
+        let entry = AuditEntry {
+            request_id: "req_f".to_string(),
+            model: Some("llama3".to_string()),
+            conversation_id: Some("conv_f".to_string()),
+            created_at: 42,
+            ..Default::default()
+        };
+        db.record_audit(&entry).await.unwrap();
+
+        assert_eq!(db.forget_conversation("conv_f", "team-b").await.unwrap(), None);
+        let affected = db.forget_conversation("conv_f", DEFAULT_TENANT_ID).await.unwrap().unwrap();
+        assert_eq!(affected, vec!["items", "usage_events", "conversations", "audit_log"]);
+
+        assert!(db.load_context("conv_f", DEFAULT_TENANT_ID).await.unwrap().is_empty());
+        let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM usage_events").fetch_one(&db.pool).await.unwrap();
+        assert_eq!(count, 0);
+        let audit = &db.get_audit_log(0, i64::MAX, 10).await.unwrap()[0].entry;
+        assert_eq!(audit.conversation_id.as_deref(), Some("[REDACTED]"));
+        assert_eq!(audit.model.as_deref(), Some("[REDACTED]"));
+        assert_eq!((audit.request_id.as_str(), audit.created_at), ("req_f", 42));
+
+        assert_eq!(db.forget_conversation("conv_f", DEFAULT_TENANT_ID).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn test_response_status_lifecycle() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        assert_eq!(db.get_response_status("conv_bg", DEFAULT_TENANT_ID).await.unwrap(), None);
+
+        let metadata = HashMap::from([("user_id".to_string(), "u1".to_string())]);
+        db.queue_response("conv_bg", DEFAULT_TENANT_ID, Some(&metadata)).await.unwrap();
+        assert_eq!(db.get_response_status("conv_bg", DEFAULT_TENANT_ID).await.unwrap().as_deref(), Some("queued"));
+        assert_eq!(db.get_conversation_metadata("conv_bg", DEFAULT_TENANT_ID).await.unwrap(), Some(metadata));
+
+        db.set_response_status("conv_bg", "in_progress").await.unwrap();
+        db.save_interaction("conv_bg", DEFAULT_TENANT_ID, None, vec![user_message("Hi")], vec![]).await.unwrap();
+        db.set_response_status("conv_bg", "completed").await.unwrap();
+        assert_eq!(db.get_response_status("conv_bg", DEFAULT_TENANT_ID).await.unwrap().as_deref(), Some("completed"));
+
+        // A follow-up turn re-queues the existing conversation
+        db.queue_response("conv_bg", DEFAULT_TENANT_ID, None).await.unwrap();
+        assert_eq!(db.get_response_status("conv_bg", DEFAULT_TENANT_ID).await.unwrap().as_deref(), Some("queued"));
+        assert_eq!(db.load_context("conv_bg", DEFAULT_TENANT_ID).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_upsert_tenant() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        assert_eq!(db.get_tenant("sk-team-a").await.unwrap(), None);
+
+        let mut tenant = Tenant {
+            id: "team-a".to_string(),
+            api_key: "sk-team-a".to_string(),
+            upstream_key: Some("sk-upstream".to_string()),
+            upstream_url: None,
+            rate_limit_rps: Some(5),
+        };
+        db.upsert_tenant(&tenant).await.unwrap();
+        assert_eq!(db.get_tenant("sk-team-a").await.unwrap().as_ref(), Some(&tenant));
+
+        tenant.upstream_url = Some("http://localhost:8080/v1/chat/completions".to_string());
+        tenant.rate_limit_rps = None;
+        db.upsert_tenant(&tenant).await.unwrap();
+        assert_eq!(db.get_tenant("sk-team-a").await.unwrap(), Some(tenant));
+
+        // A new key for the same id replaces the old one
+        let rotated = Tenant { api_key: "sk-rotated".to_string(), ..db.get_tenant("sk-team-a").await.unwrap().unwrap() };
+        assert!(matches!(
+            db.upsert_tenant(&rotated).await.unwrap(),
+            TenantUpsert::Saved { replaced_key: Some(key) } if key == "sk-team-a"
+        ));
+        assert_eq!(db.get_tenant("sk-team-a").await.unwrap(), None);
+
+        // Another id can't take the key over
+        let other = Tenant { id: "team-b".to_string(), ..rotated.clone() };
+        assert!(matches!(db.upsert_tenant(&other).await.unwrap(), TenantUpsert::KeyInUse));
+        assert_eq!(db.get_tenant("sk-rotated").await.unwrap(), Some(rotated));
+    }
+
+    #[tokio::test]
+    async fn test_stats_counts() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.save_interaction("c1", DEFAULT_TENANT_ID, None, vec![user_message("Hi"), user_message("There")], vec![]).await.unwrap();
+        db.save_interaction("c2", DEFAULT_TENANT_ID, None, vec![user_message("Hi")], vec![]).await.unwrap();
+        sqlx::query("UPDATE conversations SET created_at = 1000 WHERE id = 'c2'").execute(&db.pool).await.unwrap();
+
+        let stats = db.stats().await.unwrap();
+        assert_eq!(stats.conversations_total, 2);
+        assert_eq!(stats.items_total, 3);
+        assert_eq!(stats.conversations_created_last_hour, 1);
+    }
+
+    #[tokio::test]
+    async fn test_add_usage_accumulates() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.save_interaction("conv_u", DEFAULT_TENANT_ID, None, vec![user_message("Hi")], vec![]).await.unwrap();
+
+        db.add_usage("conv_u", 10, 25, 35).await.unwrap();
+        db.add_usage("conv_u", 10, 25, 35).await.unwrap();
+
+        let totals: (i64, i64, i64) =
+            sqlx::query_as("SELECT prompt_tokens, completion_tokens, total_tokens FROM conversations WHERE id = 'conv_u'")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(totals, (20, 50, 70));
+    }
+
+    #[tokio::test]
+    async fn test_record_usage_event() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let event = UsageEvent { tenant_id: DEFAULT_TENANT_ID, model: "llama3", usage: (10, 25, 35), upstream_latency_ms: Some(120) };
+        db.record_usage_event("conv_u", &event).await.unwrap();
+
+        let row: (String, i64, Option<i64>) =
+            sqlx::query_as("SELECT model, total_tokens, upstream_latency_ms FROM usage_events WHERE conversation_id = 'conv_u'")
+                .fetch_one(&db.pool)
+                .await
+                .unwrap();
+        assert_eq!(row, ("llama3".to_string(), 35, Some(120)));
+    }
+
+    #[tokio::test]
+    async fn test_get_tenant_usage() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let events = [
+            ("conv_1", "team-a", "llama3", (10, 20, 30)),
+            ("conv_1", "team-a", "llama3", (5, 5, 10)),
+            ("conv_2", "team-a", "mistral", (1, 2, 3)),
+            ("conv_3", "team-b", "llama3", (100, 100, 200)),
+        ];
+        for (conversation_id, tenant_id, model, usage) in events {
+            let event = UsageEvent { tenant_id, model, usage, upstream_latency_ms: None };
+            db.record_usage_event(conversation_id, &event).await.unwrap();
+        }
+
+        let now = now_secs();
+        let usage = db.get_tenant_usage("team-a", now - 60, now + 60).await.unwrap();
+        assert_eq!(usage.totals, UsageTotals { input_tokens: 16, output_tokens: 27, requests: 3, conversations: 2 });
+        assert_eq!(
+            usage.by_model,
+            vec![
+                ModelUsage {
+                    model: "llama3".to_string(),
+                    totals: UsageTotals { input_tokens: 15, output_tokens: 25, requests: 2, conversations: 1 },
+                },
+                ModelUsage {
+                    model: "mistral".to_string(),
+                    totals: UsageTotals { input_tokens: 1, output_tokens: 2, requests: 1, conversations: 1 },
+                },
+            ]
+        );
+
+        let empty = db.get_tenant_usage("team-a", now + 60, now + 120).await.unwrap();
+        assert_eq!(empty.totals, UsageTotals::default());
+        assert!(empty.by_model.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_audit_log() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.save_interaction("conv_a", DEFAULT_TENANT_ID, None, vec![user_message("Hi")], vec![]).await.unwrap();
+        let now = now_secs();
+        let rejected = AuditEntry { request_id: "req_1".to_string(), upstream_status: None, created_at: now - 10, ..Default::default() };
+        let answered = AuditEntry {
+            request_id: "req_2".to_string(),
+            tenant_id: Some(DEFAULT_TENANT_ID.to_string()),
+            remote_ip: Some("10.0.0.1".to_string()),
+            model: Some("llama3".to_string()),
+            conversation_id: Some("conv_a".to_string()),
+            input_item_count: Some(1),
+            output_token_count: Some(12),
+            finish_reason: Some("completed".to_string()),
+            upstream_status: Some(200),
+            created_at: now,
+        };
+        db.record_audit(&answered).await.unwrap();
+        db.record_audit(&rejected).await.unwrap();
+
+        let log = db.get_audit_log(0, now + 1, 10).await.unwrap();
+        assert_eq!(log.iter().map(|r| &r.entry).collect::<Vec<_>>(), vec![&rejected, &answered]);
+        assert_eq!(db.get_audit_log(now - 5, now + 1, 10).await.unwrap().len(), 1);
+        assert_eq!(db.get_audit_log(0, now + 1, 1).await.unwrap()[0].entry, rejected);
+
+        // Deleting a conversation leaves its audit trail; purging it doesn't
+        assert!(db.delete_conversation("conv_a", DEFAULT_TENANT_ID).await.unwrap());
+        assert_eq!(db.get_audit_log(0, now + 1, 10).await.unwrap().len(), 2);
+        db.save_interaction("conv_a", DEFAULT_TENANT_ID, None, vec![user_message("Hi")], vec![]).await.unwrap();
+        db.purge_conversations(Some(now + 1), &[]).await.unwrap();
+        assert_eq!(db.get_audit_log(0, now + 1, 10).await.unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_payloads() {
+        let db = Db::new("sqlite::memory:").await.unwrap().with_encryption(crypto::test_cipher(1, None));
+        db.save_interaction("conv_e", DEFAULT_TENANT_ID, None, vec![user_message("secret")], vec![]).await.unwrap();
+        db.save_instructions("conv_e", "be brief").await.unwrap();
+
+        let stored: Vec<(String,)> = sqlx::query_as("SELECT payload FROM items").fetch_all(&db.pool).await.unwrap();
+        assert!(stored.iter().all(|(payload,)| payload.starts_with("enc:") && !payload.contains("secret")));
+        assert_eq!(db.load_context("conv_e", DEFAULT_TENANT_ID).await.unwrap().len(), 2);
+        let (items, _) = db.get_input_items("conv_e", DEFAULT_TENANT_ID, 10, None).await.unwrap().unwrap();
+        assert_eq!(items[0].1, user_message("secret"));
+        // The migration leaves encrypted rows alone
+        db.migrate().await.unwrap();
+
+        // After a rotation, rows under the old key still load next to new ones
+        let rotated = Db { cipher: Some(Arc::new(crypto::test_cipher(2, Some(1)))), ..db.clone() };
+        rotated.save_interaction("conv_e", DEFAULT_TENANT_ID, None, vec![user_message("again")], vec![]).await.unwrap();
+        assert_eq!(rotated.load_context("conv_e", DEFAULT_TENANT_ID).await.unwrap().len(), 3);
+
+        let without_key = Db { cipher: None, context_cache: Arc::new(Mutex::new(ContextCache::new(1, Duration::ZERO))), ..db };
+        assert!(without_key.load_context("conv_e", DEFAULT_TENANT_ID).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_validate_all_items() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.save_interaction("conv_v", DEFAULT_TENANT_ID, None, vec![user_message("Hi"), user_message("Ok")], vec![])
+            .await
+            .unwrap();
+        assert_eq!(db.validate_all_items().await.unwrap(), (2, vec![]));
+
+        sqlx::query("INSERT INTO items (conversation_id, sequence_index, item_type, payload) VALUES ('conv_v', 2, 'input', ?)")
+            .bind(r#"{"type": "hologram"}"#)
+            .execute(&db.pool)
+            .await
+            .unwrap();
+        let (checked, failures) = db.validate_all_items().await.unwrap();
+        assert_eq!(checked, 3);
+        assert_eq!(failures.len(), 1);
+        assert_eq!((failures[0].id, failures[0].conversation_id.as_str()), (3, "conv_v"));
+        assert!(failures[0].error.contains("hologram"));
+        // Loading the conversation fails rather than taking the process down
+        assert!(matches!(db.load_context("conv_v", DEFAULT_TENANT_ID).await, Err(sqlx::Error::Decode(_))));
+    }
+
+    #[test]
+    fn test_item_payload_round_trips() {
+        let item = user_message("Hi");
+        let payload = item_payload(&item).unwrap();
+        assert_eq!(serde_json::from_str::<OrsInputItem>(&payload).unwrap(), item);
+    }
+
+    #[tokio::test]
+    async fn test_context_cache_invalidated_on_write() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        db.save_interaction("conv_c", DEFAULT_TENANT_ID, None, vec![user_message("One")], vec![]).await.unwrap();
+        assert_eq!(db.load_context("conv_c", DEFAULT_TENANT_ID).await.unwrap().len(), 1);
+
+        db.save_interaction("conv_c", DEFAULT_TENANT_ID, None, vec![user_message("Two")], vec![]).await.unwrap();
+        assert_eq!(db.load_context("conv_c", DEFAULT_TENANT_ID).await.unwrap().len(), 2);
+        assert_eq!(db.load_context("conv_c", DEFAULT_TENANT_ID).await.unwrap().len(), 2);
+        assert!(db.stats().await.unwrap().context_cache_hit_rate_percent > 0.0);
+    }
 }
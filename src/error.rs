@@ -0,0 +1,176 @@
+use crate::types::ValidationError;
+use axum::{
+    http::{header::RETRY_AFTER, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use std::fmt;
+
+/// Why a request to `POST /v1/responses` failed. Answered with its status and
+/// `{"error": {"type", "code", "message"}}`, the shape OpenAI uses.
+#[derive(Debug)]
+pub enum AppError {
+    /// The upstream answered with an error status; `body` is what it said.
+    Upstream { status: u16, body: String },
+    /// The upstream couldn't be reached, or answered with something that isn't a response stream.
+    Gateway(String),
+    /// Details stay in the logs; the client only learns that storage failed.
+    Database(sqlx::Error),
+    /// The request is invalid; `field` names the offending parameter, if there is one.
+    Validation { field: String, message: String, code: Option<&'static str> },
+    NotFound(String),
+    /// Too many requests or open streams; `retry_after` in seconds, when it's known.
+    RateLimit { message: String, retry_after: Option<u64> },
+    /// The proxy is at capacity.
+    Unavailable(String),
+    Internal(String),
+}
+
+impl AppError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            AppError::Upstream { .. } | AppError::Gateway(_) => StatusCode::BAD_GATEWAY,
+            AppError::Database(_) | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            AppError::Validation { .. } => StatusCode::BAD_REQUEST,
+            AppError::NotFound(_) => StatusCode::NOT_FOUND,
+            AppError::RateLimit { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Unavailable(_) => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    /// The `type` and `code` of the JSON body.
+    fn kind(&self) -> (&'static str, &'static str) {
+        match self {
+            AppError::Upstream { .. } => ("upstream_error", "upstream_failed"),
+            AppError::Gateway(_) => ("upstream_error", "upstream_unavailable"),
+            AppError::Database(_) => ("server_error", "database_error"),
+            AppError::Validation { code, .. } => ("invalid_request_error", code.unwrap_or("invalid_value")),
+            AppError::NotFound(_) => ("not_found", "not_found"),
+            AppError::RateLimit { .. } => ("rate_limit_exceeded", "rate_limit_exceeded"),
+            AppError::Unavailable(_) => ("service_unavailable", "service_unavailable"),
+            AppError::Internal(_) => ("server_error", "internal_error"),
+        }
+    }
+}
+
+impl fmt::Display for AppError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AppError::Upstream { status, body } => write!(f, "Upstream provider error (HTTP {}): {}", status, body),
+            AppError::Database(_) => f.write_str("Database error"),
+            AppError::Validation { message, .. } => f.write_str(message),
+            AppError::RateLimit { message, .. } => f.write_str(message),
+            AppError::Gateway(message)
+            | AppError::NotFound(message)
+            | AppError::Unavailable(message)
+            | AppError::Internal(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<ValidationError> for AppError {
+    fn from(e: ValidationError) -> Self {
+        AppError::Validation { field: e.param, message: e.message, code: e.code }
+    }
+}
+
+/// Lets `?` on a [`Db`](crate::db::Db) call answer with a fitting status: a missing row is a 404,
+/// a duplicate key the client's mistake, and anything else (connection, pool, I/O) a 500.
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            sqlx::Error::RowNotFound => AppError::NotFound("Record not found".to_string()),
+            sqlx::Error::Database(ref dbe) if dbe.is_unique_violation() => AppError::Validation {
+                field: "id".to_string(),
+                message: "Duplicate ID".to_string(),
+                code: Some("duplicate_id"),
+            },
+            e => AppError::Database(e),
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        if let AppError::Database(e) = &self {
+            tracing::error!("Database error: {}", e);
+        }
+        let (error_type, code) = self.kind();
+        let mut body = serde_json::json!({
+            "error": {
+                "type": error_type,
+                "code": code,
+                "message": self.to_string(),
+            }
+        });
+        if let AppError::Validation { field, .. } = &self {
+            if !field.is_empty() {
+                body["error"]["param"] = field.as_str().into();
+            }
+        }
+        let mut res = (self.status(), Json(body)).into_response();
+        if let AppError::RateLimit { retry_after: Some(secs), .. } = self {
+            res.headers_mut().insert(RETRY_AFTER, secs.into());
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn body(res: Response) -> serde_json::Value {
+        let bytes = axum::body::to_bytes(res.into_body(), 64 * 1024).await.unwrap();
+        serde_json::from_slice(&bytes).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_error_bodies() {
+        let res = AppError::Upstream { status: 500, body: "overloaded".to_string() }.into_response();
+        assert_eq!(res.status(), StatusCode::BAD_GATEWAY);
+        assert_eq!(
+            body(res).await,
+            serde_json::json!({ "error": {
+                "type": "upstream_error",
+                "code": "upstream_failed",
+                "message": "Upstream provider error (HTTP 500): overloaded"
+            } })
+        );
+
+        let res = AppError::from(ValidationError::new("model", "Unknown model").with_code("model_not_found")).into_response();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let error = &body(res).await["error"];
+        assert_eq!(error["code"], "model_not_found");
+        assert_eq!(error["param"], "model");
+
+        let res = AppError::Database(sqlx::Error::RowNotFound).into_response();
+        assert_eq!(body(res).await["error"]["message"], "Database error");
+    }
+
+    #[tokio::test]
+    async fn test_sqlx_errors() {
+        assert!(matches!(AppError::from(sqlx::Error::RowNotFound), AppError::NotFound(_)));
+        assert_eq!(AppError::from(sqlx::Error::PoolTimedOut).status(), StatusCode::INTERNAL_SERVER_ERROR);
+
+        let pool = sqlx::SqlitePool::connect("sqlite::memory:").await.unwrap();
+        sqlx::query("CREATE TABLE t (id TEXT PRIMARY KEY)").execute(&pool).await.unwrap();
+        sqlx::query("INSERT INTO t VALUES ('a')").execute(&pool).await.unwrap();
+        let duplicate = sqlx::query("INSERT INTO t VALUES ('a')").execute(&pool).await.unwrap_err();
+        let e = AppError::from(duplicate);
+        assert!(matches!(&e, AppError::Validation { field, .. } if field == "id"));
+        assert_eq!(e.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn test_rate_limit_sets_retry_after() {
+        let res = AppError::RateLimit { message: "slow down".to_string(), retry_after: Some(3) }.into_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[RETRY_AFTER], "3");
+
+        let res = AppError::RateLimit { message: "slow down".to_string(), retry_after: None }.into_response();
+        assert!(!res.headers().contains_key(RETRY_AFTER));
+    }
+}
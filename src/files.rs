@@ -0,0 +1,335 @@
+use crate::{authenticate_tenant, error_response, types::is_valid_conversation_id, AppState};
+use axum::{
+    body::Body,
+    extract::{Multipart, Path, State},
+    http::{header::CONTENT_TYPE, HeaderMap, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use reqwest::multipart::{Form, Part};
+
+/// Largest upload accepted by `POST /v1/files` unless `MAX_UPLOAD_BYTES` says otherwise. Uploads
+/// are buffered in memory before being forwarded.
+pub const DEFAULT_MAX_UPLOAD_BYTES: usize = 100 * 1024 * 1024;
+
+/// Form field linking the upload to a conversation; kept from the upstream.
+const CONVERSATION_FIELD: &str = "conversation_id";
+
+/// The upstream's files endpoint, alongside its completions one: `.../v1/chat/completions`
+/// (or Anthropic's `.../v1/messages`) becomes `.../v1/files`.
+fn files_url(upstream_url: &str) -> String {
+    let base = upstream_url.trim_end_matches('/');
+    let base = base
+        .strip_suffix("/chat/completions")
+        .or_else(|| base.strip_suffix("/messages"))
+        .unwrap_or(base);
+    format!("{}/files", base)
+}
+
+/// Upstream file IDs are ASCII letters, digits, `_` or `-` (e.g. `file-abc123`), so they can't
+/// reach any other upstream path.
+fn is_valid_file_id(id: &str) -> bool {
+    (1..=128).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Authenticated the way the upstream's adapter authenticates completions requests.
+fn upstream_request(state: &AppState, method: Method, url: String) -> reqwest::RequestBuilder {
+    state.upstream_adapter.authorize(state.client.request(method, url), state.openai_api_key.as_deref())
+}
+
+/// Sends the client the upstream's status, content type and body as they are.
+fn forward(res: reqwest::Response) -> Response {
+    let status = StatusCode::from_u16(res.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+    let content_type = res.headers().get(reqwest::header::CONTENT_TYPE).cloned();
+    let mut response = Body::from_stream(res.bytes_stream()).into_response();
+    *response.status_mut() = status;
+    if let Some(content_type) = content_type {
+        response.headers_mut().insert(CONTENT_TYPE, content_type);
+    }
+    response
+}
+
+fn upstream_failed(e: reqwest::Error) -> Response {
+    tracing::error!("Files request to upstream failed: {}", e);
+    error_response(StatusCode::BAD_GATEWAY, "upstream_error", format!("Upstream error: {}", e))
+}
+
+/// `POST /v1/files`: forwards a `multipart/form-data` upload to the upstream's files API and
+/// returns its answer. The file is recorded as the caller's, so other tenants can't reach it.
+/// A `conversation_id` field, which isn't forwarded, links it to one of the caller's
+/// conversations.
+pub async fn upload_file(State(state): State<AppState>, headers: HeaderMap, mut multipart: Multipart) -> Response {
+    let state = match authenticate_tenant(state, &headers, true).await {
+        Ok(state) => state,
+        Err(res) => return res,
+    };
+
+    let mut form = Form::new();
+    let mut conversation_id = None;
+    let mut uploaded = None;
+    loop {
+        let field = match multipart.next_field().await {
+            Ok(Some(field)) => field,
+            Ok(None) => break,
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", e.body_text()),
+        };
+        let name = field.name().unwrap_or_default().to_string();
+        let file_name = field.file_name().map(str::to_string);
+        let content_type = field.content_type().map(str::to_string);
+        let data = match field.bytes().await {
+            Ok(data) => data,
+            Err(e) => return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", e.body_text()),
+        };
+
+        if name == CONVERSATION_FIELD {
+            conversation_id = Some(String::from_utf8_lossy(&data).into_owned());
+            continue;
+        }
+        let size = data.len();
+        let mut part = Part::bytes(Vec::from(data));
+        if let Some(file_name) = file_name {
+            uploaded = Some((file_name.clone(), size, content_type.clone()));
+            part = part.file_name(file_name);
+        }
+        if let Some(content_type) = &content_type {
+            part = match part.mime_str(content_type) {
+                Ok(part) => part,
+                Err(_) => {
+                    let message = format!("Invalid content type for field {}: {}", name, content_type);
+                    return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", message);
+                }
+            };
+        }
+        form = form.part(name, part);
+    }
+
+    let Some((file_name, size, content_type)) = uploaded else {
+        return error_response(StatusCode::BAD_REQUEST, "invalid_request_error", "No file in the upload");
+    };
+    // Checked before anything is sent, so a bad ID doesn't leave an orphaned upload behind
+    if let Some(id) = &conversation_id {
+        let owned = is_valid_conversation_id(id)
+            && match state.db.conversation_tenant(id).await {
+                Ok(owner) => owner.as_deref() == Some(state.tenant_id()),
+                Err(e) => {
+                    tracing::error!("Failed to look up conversation {}: {}", id, e);
+                    return error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to upload file");
+                }
+            };
+        if !owned {
+            return error_response(StatusCode::NOT_FOUND, "not_found", format!("Conversation {} not found", id));
+        }
+    }
+
+    tracing::info!(
+        "Uploading {} ({} bytes, {})",
+        file_name,
+        size,
+        content_type.as_deref().unwrap_or("no content type")
+    );
+    let url = files_url(&state.upstream_url.load());
+    let res = match upstream_request(&state, Method::POST, url).multipart(form).send().await {
+        Ok(res) => res,
+        Err(e) => return upstream_failed(e),
+    };
+    if !res.status().is_success() {
+        tracing::warn!("Upstream rejected upload of {} with {}", file_name, res.status());
+        return forward(res);
+    }
+
+    let status = StatusCode::from_u16(res.status().as_u16()).unwrap_or(StatusCode::OK);
+    let body = match res.bytes().await {
+        Ok(body) => body,
+        Err(e) => return upstream_failed(e),
+    };
+    let file_id = serde_json::from_slice::<serde_json::Value>(&body)
+        .ok()
+        .and_then(|file| file.get("id").and_then(|id| id.as_str()).map(str::to_string));
+    if let Some(file_id) = &file_id {
+        if let Err(e) = state.db.record_file(file_id, state.tenant_id()).await {
+            tracing::error!("Failed to record the owner of file {}: {}", file_id, e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to upload file");
+        }
+        if let Some(conversation_id) = &conversation_id {
+            if let Err(e) = state.db.attach_file(conversation_id, file_id).await {
+                tracing::error!("Failed to link file {} to conversation {}: {}", file_id, conversation_id, e);
+            }
+        }
+    }
+    tracing::info!("Uploaded {} as {}", file_name, file_id.as_deref().unwrap_or("unknown id"));
+    (status, [(CONTENT_TYPE, "application/json")], body).into_response()
+}
+
+/// `GET /v1/files/:file_id`: the upstream's description of the file.
+pub async fn get_file(State(state): State<AppState>, headers: HeaderMap, Path(file_id): Path<String>) -> Response {
+    match owned_file(state, &headers, &file_id, false).await {
+        Ok(state) => file_request(&state, Method::GET, &file_id).await,
+        Err(res) => res,
+    }
+}
+
+/// `DELETE /v1/files/:file_id`: deletes the file upstream and unlinks it from conversations.
+pub async fn delete_file(State(state): State<AppState>, headers: HeaderMap, Path(file_id): Path<String>) -> Response {
+    let state = match owned_file(state, &headers, &file_id, true).await {
+        Ok(state) => state,
+        Err(res) => return res,
+    };
+    let res = file_request(&state, Method::DELETE, &file_id).await;
+    if res.status().is_success() {
+        if let Err(e) = state.db.detach_file(&file_id, state.tenant_id()).await {
+            tracing::error!("Failed to unlink deleted file {}: {}", file_id, e);
+        }
+    }
+    res
+}
+
+/// The caller's state if it uploaded the file; anyone else's files answer 404, as if they
+/// didn't exist.
+async fn owned_file(state: AppState, headers: &HeaderMap, file_id: &str, rate_limited: bool) -> Result<AppState, Response> {
+    let state = authenticate_tenant(state, headers, rate_limited).await?;
+    let not_found = || error_response(StatusCode::NOT_FOUND, "not_found", format!("File {} not found", file_id));
+    if !is_valid_file_id(file_id) {
+        return Err(not_found());
+    }
+    match state.db.owns_file(file_id, state.tenant_id()).await {
+        Ok(true) => Ok(state),
+        Ok(false) => Err(not_found()),
+        Err(e) => {
+            tracing::error!("Failed to look up owner of file {}: {}", file_id, e);
+            Err(error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to look up file"))
+        }
+    }
+}
+
+async fn file_request(state: &AppState, method: Method, file_id: &str) -> Response {
+    let url = format!("{}/{}", files_url(&state.upstream_url.load()), file_id);
+    match upstream_request(state, method, url).send().await {
+        Ok(res) => forward(res),
+        Err(e) => upstream_failed(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{adapter, db::Tenant, tests::test_state};
+    use axum::{
+        http::{header::AUTHORIZATION, Request},
+        routing::{get, post},
+        Router,
+    };
+    use std::sync::{Arc, Mutex};
+    use tower::ServiceExt;
+
+    const BOUNDARY: &str = "test-boundary";
+
+    /// A files API that records the credentials of each request it gets.
+    async fn mock_upstream() -> (String, Arc<Mutex<Vec<(Option<String>, Option<String>)>>>) {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let record = |seen: Arc<Mutex<Vec<_>>>| {
+            move |headers: HeaderMap| async move {
+                let header = |name: &str| headers.get(name).and_then(|v| v.to_str().ok()).map(str::to_string);
+                seen.lock().unwrap().push((header("authorization"), header("x-api-key")));
+                axum::Json(serde_json::json!({ "id": "file-abc", "object": "file" }))
+            }
+        };
+        let app = Router::new()
+            .route("/v1/files", post(record(seen.clone())))
+            .route("/v1/files/:file_id", get(record(seen.clone())).delete(record(seen.clone())));
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/v1/chat/completions", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, seen)
+    }
+
+    /// The files routes over a state with tenants `team-a` and `team-b` talking to `upstream_url`.
+    async fn app(upstream_url: &str, upstream_type: &str) -> Router {
+        let mut state = test_state().await;
+        state.upstream_url.store(Arc::new(upstream_url.to_string()));
+        state.upstream_adapter = adapter::from_type(upstream_type).unwrap();
+        state.openai_api_key = Some("sk-upstream".to_string());
+        for id in ["team-a", "team-b"] {
+            let tenant = Tenant {
+                id: id.to_string(),
+                api_key: format!("sk-{}", id),
+                upstream_key: None,
+                upstream_url: None,
+                rate_limit_rps: None,
+            };
+            state.db.upsert_tenant(&tenant).await.unwrap();
+        }
+        Router::new()
+            .route("/v1/files", post(upload_file))
+            .route("/v1/files/:file_id", get(get_file).delete(delete_file))
+            .with_state(state)
+    }
+
+    fn upload(api_key: &str) -> Request<Body> {
+        let body = format!(
+            "--{b}\r\nContent-Disposition: form-data; name=\"purpose\"\r\n\r\nassistants\r\n\
+             --{b}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"notes.txt\"\r\n\
+             Content-Type: text/plain\r\n\r\nhello\r\n--{b}--\r\n",
+            b = BOUNDARY
+        );
+        Request::post("/v1/files")
+            .header(AUTHORIZATION, format!("Bearer {}", api_key))
+            .header(CONTENT_TYPE, format!("multipart/form-data; boundary={}", BOUNDARY))
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    fn file_request(method: Method, api_key: &str) -> Request<Body> {
+        Request::builder()
+            .method(method)
+            .uri("/v1/files/file-abc")
+            .header(AUTHORIZATION, format!("Bearer {}", api_key))
+            .body(Body::empty())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_files_are_only_reachable_by_their_uploader() {
+        let (url, seen) = mock_upstream().await;
+        let app = app(&url, "openai").await;
+
+        assert_eq!(app.clone().oneshot(upload("sk-team-a")).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(app.clone().oneshot(file_request(Method::GET, "sk-team-a")).await.unwrap().status(), StatusCode::OK);
+
+        for method in [Method::GET, Method::DELETE] {
+            let res = app.clone().oneshot(file_request(method, "sk-team-b")).await.unwrap();
+            assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        }
+        // Neither of team-b's requests got as far as the upstream
+        assert_eq!(seen.lock().unwrap().len(), 2);
+
+        let res = app.clone().oneshot(file_request(Method::DELETE, "sk-team-a")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let res = app.oneshot(file_request(Method::GET, "sk-team-a")).await.unwrap();
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+        assert!(seen.lock().unwrap().iter().all(|(bearer, _)| bearer.as_deref() == Some("Bearer sk-upstream")));
+    }
+
+    #[tokio::test]
+    async fn test_files_use_the_adapters_auth() {
+        let (url, seen) = mock_upstream().await;
+        let app = app(&url, "anthropic").await;
+
+        assert_eq!(app.oneshot(upload("sk-team-a")).await.unwrap().status(), StatusCode::OK);
+        let seen = seen.lock().unwrap();
+        assert_eq!(*seen, vec![(None, Some("sk-upstream".to_string()))]);
+    }
+
+    #[test]
+    fn test_files_url() {
+        assert_eq!(files_url("https://api.openai.com/v1/chat/completions"), "https://api.openai.com/v1/files");
+        assert_eq!(files_url("https://api.anthropic.com/v1/messages"), "https://api.anthropic.com/v1/files");
+        assert_eq!(files_url("http://localhost:8000/v1/"), "http://localhost:8000/v1/files");
+    }
+
+    #[test]
+    fn test_is_valid_file_id() {
+        assert!(is_valid_file_id("file-abc123"));
+        assert!(!is_valid_file_id(""));
+        assert!(!is_valid_file_id("../chat/completions"));
+        assert!(!is_valid_file_id("file?x=1"));
+    }
+}
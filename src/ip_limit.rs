@@ -0,0 +1,137 @@
+use axum::{body::Body, response::Response};
+use dashmap::DashMap;
+use futures::StreamExt;
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+/// How often addresses without open streams are dropped from the map.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Open SSE streams per client address, capped at `MAX_SSE_PER_IP` so one client can't hold
+/// every file descriptor and pool connection.
+///
+/// The address is the one [`crate::allowlist::client_ip`] trusts: the socket's peer, or with
+/// `TRUST_PROXY_HEADERS` the last `X-Forwarded-For` hop. Behind a load balancer without it,
+/// every client shares the balancer's address, and so a single budget.
+pub struct IpConnectionLimiter {
+    open: DashMap<IpAddr, AtomicU32>,
+    max: u32,
+}
+
+impl IpConnectionLimiter {
+    pub fn new(max: u32) -> Self {
+        Self { open: DashMap::new(), max }
+    }
+
+    /// Counts a stream from `ip` until the returned guard is dropped, or returns `None` if `ip`
+    /// already has the maximum open.
+    pub fn try_acquire(self: &Arc<Self>, ip: IpAddr) -> Option<IpConnectionGuard> {
+        // The entry holds its shard's lock, so a purge can't remove it between here and the increment
+        self.open
+            .entry(ip)
+            .or_default()
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |open| (open < self.max).then_some(open + 1))
+            .ok()?;
+        Some(IpConnectionGuard { limiter: self.clone(), ip })
+    }
+
+    /// Streams currently open from `ip`.
+    pub fn open(&self, ip: IpAddr) -> u32 {
+        self.open.get(&ip).map_or(0, |open| open.load(Ordering::Relaxed))
+    }
+
+    /// Drops addresses with no open streams.
+    fn purge_idle(&self) {
+        self.open.retain(|_, open| open.load(Ordering::Relaxed) > 0);
+    }
+
+    /// Purges idle addresses every minute, keeping the map bounded by current clients.
+    pub async fn purge_idle_loop(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(PURGE_INTERVAL);
+        loop {
+            interval.tick().await;
+            self.purge_idle();
+        }
+    }
+}
+
+pub struct IpConnectionGuard {
+    limiter: Arc<IpConnectionLimiter>,
+    ip: IpAddr,
+}
+
+impl IpConnectionGuard {
+    /// Keeps the stream counted until `response`'s body is dropped: once it has all been sent,
+    /// or the client disconnected.
+    pub fn hold_for(self, response: Response) -> Response {
+        response.map(|body| {
+            Body::from_stream(body.into_data_stream().map(move |chunk| {
+                let _ = &self;
+                chunk
+            }))
+        })
+    }
+}
+
+impl Drop for IpConnectionGuard {
+    fn drop(&mut self) {
+        if let Some(open) = self.limiter.open.get(&self.ip) {
+            open.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limit_per_ip() {
+        let limiter = Arc::new(IpConnectionLimiter::new(2));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+
+        let first = limiter.try_acquire(a).unwrap();
+        let _second = limiter.try_acquire(a).unwrap();
+        assert!(limiter.try_acquire(a).is_none());
+        assert!(limiter.try_acquire(b).is_some());
+
+        drop(first);
+        assert_eq!(limiter.open(a), 1);
+        assert!(limiter.try_acquire(a).is_some());
+    }
+
+    #[tokio::test]
+    async fn test_guard_held_by_the_response_body() {
+        let limiter = Arc::new(IpConnectionLimiter::new(1));
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        let guard = limiter.try_acquire(ip).unwrap();
+
+        let response = guard.hold_for(Response::new(Body::from("data: hi\n\n")));
+        assert_eq!(limiter.open(ip), 1);
+        assert!(limiter.try_acquire(ip).is_none());
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"data: hi\n\n");
+        assert_eq!(limiter.open(ip), 0);
+    }
+
+    #[test]
+    fn test_purge_keeps_open_addresses() {
+        let limiter = Arc::new(IpConnectionLimiter::new(2));
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let _open = limiter.try_acquire(a).unwrap();
+        drop(limiter.try_acquire(b).unwrap());
+
+        limiter.purge_idle();
+        assert!(limiter.open.contains_key(&a));
+        assert!(!limiter.open.contains_key(&b));
+    }
+}
@@ -0,0 +1,197 @@
+use crate::{
+    types::{OrsInputItem, OrsRequest},
+    AppState,
+};
+use std::future::Future;
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+use tokio::sync::{mpsc, Mutex};
+
+/// A `background: true` request waiting for a worker.
+pub struct Job {
+    /// Upstream client and config the request runs against.
+    pub state: AppState,
+    pub request_id: String,
+    pub payload: OrsRequest,
+    pub conversation_id: String,
+    pub full_input: Vec<OrsInputItem>,
+    /// The submitting request's span, so the job's logs stay attributed to it.
+    pub span: tracing::Span,
+}
+
+#[derive(Default)]
+struct Counters {
+    active_workers: AtomicUsize,
+    completed_total: AtomicU64,
+}
+
+/// Counts a worker as active for as long as it's held, however the job ends.
+struct ActiveWorker(Arc<Counters>);
+
+impl ActiveWorker {
+    fn new(counters: Arc<Counters>) -> Self {
+        counters.active_workers.fetch_add(1, Ordering::Relaxed);
+        Self(counters)
+    }
+}
+
+impl Drop for ActiveWorker {
+    fn drop(&mut self) {
+        self.0.active_workers.fetch_sub(1, Ordering::Relaxed);
+        self.0.completed_total.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Bounded queue drained by a fixed pool of workers, so background requests arriving faster than
+/// they can be answered are turned away instead of piling up in memory.
+pub struct JobQueue<J = Job> {
+    tx: mpsc::Sender<J>,
+    capacity: usize,
+    workers: usize,
+    counters: Arc<Counters>,
+}
+
+impl<J> Clone for JobQueue<J> {
+    fn clone(&self) -> Self {
+        Self { tx: self.tx.clone(), capacity: self.capacity, workers: self.workers, counters: self.counters.clone() }
+    }
+}
+
+impl<J: Send + 'static> JobQueue<J> {
+    /// Starts `workers` tasks that each run one job at a time with `run`. Each job runs in a task
+    /// of its own, so one that panics fails alone rather than taking its worker down.
+    pub fn spawn<F, Fut>(capacity: usize, workers: usize, run: F) -> Self
+    where
+        F: Fn(J) -> Fut + Clone + Send + 'static,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (capacity, workers) = (capacity.max(1), workers.max(1));
+        let (tx, rx) = mpsc::channel::<J>(capacity);
+        let rx = Arc::new(Mutex::new(rx));
+        let counters = Arc::new(Counters::default());
+
+        for _ in 0..workers {
+            let (rx, counters, run) = (rx.clone(), counters.clone(), run.clone());
+            tokio::spawn(async move {
+                loop {
+                    // Only the idle worker holding the lock waits on the channel
+                    let Some(job) = rx.lock().await.recv().await else { break };
+                    let _active = ActiveWorker::new(counters.clone());
+                    if let Err(e) = tokio::spawn(run(job)).await {
+                        tracing::error!("Background job failed: {}", e);
+                    }
+                }
+            });
+        }
+
+        Self { tx, capacity, workers, counters }
+    }
+
+    /// Claims a queue slot, or `None` if the queue is full. Claiming before doing any work for
+    /// the job means a rejected request leaves nothing behind.
+    pub fn reserve(&self) -> Option<mpsc::Permit<'_, J>> {
+        self.tx.try_reserve().ok()
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.capacity - self.tx.capacity()
+    }
+
+    pub fn active_workers(&self) -> usize {
+        self.counters.active_workers.load(Ordering::Relaxed)
+    }
+
+    pub fn completed_total(&self) -> u64 {
+        self.counters.completed_total.load(Ordering::Relaxed)
+    }
+
+    pub fn snapshot(&self) -> serde_json::Value {
+        serde_json::json!({
+            "background_queue_depth": self.queue_depth(),
+            "background_workers": self.workers,
+            "background_active_workers": self.active_workers(),
+            "background_jobs_completed_total": self.completed_total(),
+        })
+    }
+
+    pub fn prometheus(&self) -> String {
+        format!(
+            "# HELP ors_background_queue_depth Background requests waiting for a worker.\n\
+             # TYPE ors_background_queue_depth gauge\n\
+             ors_background_queue_depth {}\n\
+             # HELP ors_background_active_workers Workers currently running a background request.\n\
+             # TYPE ors_background_active_workers gauge\n\
+             ors_background_active_workers {}\n\
+             # HELP ors_background_jobs_completed_total Background requests run to the end, successfully or not.\n\
+             # TYPE ors_background_jobs_completed_total counter\n\
+             ors_background_jobs_completed_total {}\n",
+            self.queue_depth(),
+            self.active_workers(),
+            self.completed_total(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::oneshot;
+
+    #[tokio::test]
+    async fn test_full_queue_rejects_until_drained() {
+        let (release_tx, release_rx) = oneshot::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(Some(release_rx)));
+        let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+        // The first job blocks the only worker until released
+        let queue = JobQueue::spawn(1, 1, move |n: u32| {
+            let (release_rx, done_tx) = (release_rx.clone(), done_tx.clone());
+            async move {
+                if let Some(rx) = release_rx.lock().await.take() {
+                    rx.await.unwrap();
+                }
+                done_tx.send(n).unwrap();
+            }
+        });
+
+        queue.reserve().unwrap().send(1);
+        while queue.active_workers() == 0 {
+            tokio::task::yield_now().await;
+        }
+        queue.reserve().unwrap().send(2);
+        assert_eq!(queue.queue_depth(), 1);
+        assert!(queue.reserve().is_none());
+
+        release_tx.send(()).unwrap();
+        assert_eq!(done_rx.recv().await, Some(1));
+        assert_eq!(done_rx.recv().await, Some(2));
+        while queue.completed_total() < 2 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(queue.queue_depth(), 0);
+        assert!(queue.reserve().is_some());
+    }
+
+    #[tokio::test]
+    async fn test_panicking_job_leaves_the_worker_running() {
+        let (done_tx, mut done_rx) = mpsc::unbounded_channel();
+        let queue = JobQueue::spawn(2, 1, move |n: u32| {
+            let done_tx = done_tx.clone();
+            async move {
+                if n == 1 {
+                    panic!("job {} failed", n);
+                }
+                done_tx.send(n).unwrap();
+            }
+        });
+
+        queue.reserve().unwrap().send(1);
+        queue.reserve().unwrap().send(2);
+        assert_eq!(done_rx.recv().await, Some(2));
+        while queue.completed_total() < 2 {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(queue.active_workers(), 0);
+    }
+}
@@ -0,0 +1,159 @@
+use crate::db::StoredEvent;
+use dashmap::DashMap;
+use futures::Stream;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::watch;
+
+/// How long a finished response stays buffered, covering the write-behind queue's lag before
+/// its events can be read back from the DB.
+const RETENTION: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct Buffer {
+    events: Vec<StoredEvent>,
+    finished: bool,
+}
+
+struct LiveResponse {
+    tenant_id: String,
+    buffer: watch::Sender<Buffer>,
+}
+
+/// Responses being streamed right now, by conversation. Their events are only saved once the
+/// response ends, so a client reconnecting with `Last-Event-ID` mid-stream is resumed from here
+/// and follows the rest as it's produced.
+#[derive(Default)]
+pub struct LiveStreams {
+    responses: DashMap<String, Arc<LiveResponse>>,
+}
+
+impl LiveStreams {
+    /// Starts buffering a response of the conversation, replacing any earlier one. The response
+    /// is finished when the returned writer is dropped.
+    pub fn start(self: &Arc<Self>, conversation_id: &str, tenant_id: &str) -> LiveWriter {
+        let response = Arc::new(LiveResponse {
+            tenant_id: tenant_id.to_string(),
+            buffer: watch::Sender::new(Buffer::default()),
+        });
+        self.responses.insert(conversation_id.to_string(), response.clone());
+        LiveWriter { streams: self.clone(), conversation_id: conversation_id.to_string(), response }
+    }
+
+    /// The events after `seq` of the tenant's live response in the conversation, then the rest
+    /// as they're produced until it finishes; `None` if there's no such response.
+    pub fn follow(&self, conversation_id: &str, tenant_id: &str, seq: u32) -> Option<impl Stream<Item = StoredEvent>> {
+        let response = self.responses.get(conversation_id)?.clone();
+        if response.tenant_id != tenant_id {
+            return None;
+        }
+        let mut rx = response.buffer.subscribe();
+        Some(async_stream::stream! {
+            let mut next = None;
+            loop {
+                let (events, finished) = {
+                    let buffer = rx.borrow_and_update();
+                    let start = *next.get_or_insert_with(|| {
+                        buffer.events.iter().position(|event| event.sequence_number > seq).unwrap_or(buffer.events.len())
+                    });
+                    next = Some(buffer.events.len());
+                    (buffer.events[start..].to_vec(), buffer.finished)
+                };
+                for event in events {
+                    yield event;
+                }
+                if finished || rx.changed().await.is_err() {
+                    break;
+                }
+            }
+        })
+    }
+}
+
+/// Appends a response's events to its [`LiveStreams`] buffer.
+pub struct LiveWriter {
+    streams: Arc<LiveStreams>,
+    conversation_id: String,
+    response: Arc<LiveResponse>,
+}
+
+impl LiveWriter {
+    pub fn push(&self, event: StoredEvent) {
+        self.response.buffer.send_modify(|buffer| buffer.events.push(event));
+    }
+}
+
+impl Drop for LiveWriter {
+    /// Ends the followers' streams, and forgets the response once it has had time to be saved.
+    fn drop(&mut self) {
+        self.response.buffer.send_modify(|buffer| buffer.finished = true);
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else { return };
+        let (streams, conversation_id, response) =
+            (self.streams.clone(), std::mem::take(&mut self.conversation_id), self.response.clone());
+        runtime.spawn(async move {
+            tokio::time::sleep(RETENTION).await;
+            // Unless a newer response of the conversation has replaced it meanwhile
+            streams.responses.remove_if(&conversation_id, |_, live| Arc::ptr_eq(live, &response));
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn event(seq: u32) -> StoredEvent {
+        StoredEvent { sequence_number: seq, event_type: "response.output_text.delta".to_string(), payload: seq.to_string() }
+    }
+
+    #[tokio::test]
+    async fn test_follow_replays_then_streams_live_events() {
+        let streams = Arc::new(LiveStreams::default());
+        let writer = streams.start("conv_l", "team-a");
+        for seq in 0..3 {
+            writer.push(event(seq));
+        }
+
+        assert!(streams.follow("conv_l", "team-b", 0).is_none());
+        assert!(streams.follow("conv_other", "team-a", 0).is_none());
+        let follower = streams.follow("conv_l", "team-a", 0).unwrap();
+        let collected = tokio::spawn(async move {
+            follower.map(|event| event.sequence_number).collect::<Vec<_>>().await
+        });
+
+        tokio::task::yield_now().await;
+        writer.push(event(3));
+        writer.push(event(4));
+        drop(writer);
+        assert_eq!(collected.await.unwrap(), vec![1, 2, 3, 4]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_finished_response_forgotten_after_retention() {
+        let streams = Arc::new(LiveStreams::default());
+        let writer = streams.start("conv_l", "team-a");
+        writer.push(event(0));
+        drop(writer);
+
+        // Still served, and ends right away, while the response is being saved
+        let follower = streams.follow("conv_l", "team-a", 0).unwrap();
+        assert_eq!(follower.collect::<Vec<_>>().await.len(), 0);
+
+        tokio::time::sleep(RETENTION + Duration::from_secs(1)).await;
+        assert!(streams.follow("conv_l", "team-a", 0).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_newer_response_survives_older_ones_removal() {
+        let streams = Arc::new(LiveStreams::default());
+        drop(streams.start("conv_l", "team-a"));
+        let newer = streams.start("conv_l", "team-a");
+        newer.push(event(7));
+
+        tokio::time::sleep(RETENTION + Duration::from_secs(1)).await;
+        let follower = streams.follow("conv_l", "team-a", 0).unwrap();
+        drop(newer);
+        let events: Vec<_> = follower.collect().await;
+        assert_eq!(events.len(), 1);
+    }
+}
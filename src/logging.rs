@@ -0,0 +1,245 @@
+use tracing::{level_filters::LevelFilter, span, Level, Metadata, Subscriber};
+use tracing_subscriber::{
+    layer::{Context, Filter},
+    registry::LookupSpan,
+};
+use axum::http::header::CONTENT_TYPE;
+use futures::StreamExt;
+use std::time::Instant;
+use uuid::Uuid;
+
+/// Span field holding a request's sampling draw, a uniform value in `[0, 1)`.
+const SAMPLE_FIELD: &str = "log_sample";
+
+/// Drops INFO/TRACE logs for all but `LOG_SAMPLE_RATE` of requests, and WARN logs for all but
+/// `LOG_WARN_SAMPLE_RATE`. ERROR and DEBUG are always kept, as is anything logged outside a request.
+pub struct SamplingFilter {
+    rate: f64,
+    warn_rate: f64,
+}
+
+/// The request's draw, cached in its span's extensions.
+struct LogSample(f64);
+
+impl SamplingFilter {
+    pub fn from_env() -> Self {
+        Self {
+            rate: crate::env_parse("LOG_SAMPLE_RATE", 1.0_f64).clamp(0.0, 1.0),
+            warn_rate: crate::env_parse("LOG_WARN_SAMPLE_RATE", 1.0_f64).clamp(0.0, 1.0),
+        }
+    }
+
+    fn allows(&self, level: &Level, sample: f64) -> bool {
+        match *level {
+            Level::ERROR | Level::DEBUG => true,
+            Level::WARN => sample < self.warn_rate,
+            _ => sample < self.rate,
+        }
+    }
+}
+
+/// Draws the per-request sampling value, made once when the request span is created.
+pub fn draw_sample() -> f64 {
+    // 53 random bits are exactly what an f64 mantissa holds
+    (Uuid::new_v4().as_u64_pair().0 >> 11) as f64 / (1u64 << 53) as f64
+}
+
+impl<S> Filter<S> for SamplingFilter
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn enabled(&self, meta: &Metadata<'_>, cx: &Context<'_, S>) -> bool {
+        if meta.is_span() {
+            return true;
+        }
+        let sample = cx.lookup_current().and_then(|span| {
+            span.scope().find_map(|s| s.extensions().get::<LogSample>().map(|sample| sample.0))
+        });
+        match sample {
+            Some(sample) => self.allows(meta.level(), sample),
+            None => true,
+        }
+    }
+
+    // Decisions depend on the current request, so they can't be cached per callsite
+    fn callsite_enabled(&self, _meta: &'static Metadata<'static>) -> tracing::subscriber::Interest {
+        tracing::subscriber::Interest::sometimes()
+    }
+
+    fn max_level_hint(&self) -> Option<LevelFilter> {
+        None
+    }
+
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, cx: Context<'_, S>) {
+        let mut visitor = SampleVisitor(None);
+        attrs.record(&mut visitor);
+        if let (Some(sample), Some(span)) = (visitor.0, cx.span(id)) {
+            span.extensions_mut().insert(LogSample(sample));
+        }
+    }
+}
+
+struct SampleVisitor(Option<f64>);
+
+impl tracing::field::Visit for SampleVisitor {
+    fn record_f64(&mut self, field: &tracing::field::Field, value: f64) {
+        if field.name() == SAMPLE_FIELD {
+            self.0 = Some(value);
+        }
+    }
+
+    fn record_debug(&mut self, _field: &tracing::field::Field, _value: &dyn std::fmt::Debug) {}
+}
+
+/// Logs each request's outcome and duration. SSE responses are logged once when the stream
+/// starts and again (`stream_ended`) when its body is dropped, whether it finished or the
+/// client went away.
+pub async fn access_log(req: axum::extract::Request, next: axum::middleware::Next) -> axum::response::Response {
+    let started = Instant::now();
+    let response = next.run(req).await;
+    let status = response.status().as_u16();
+    let duration_ms = started.elapsed().as_millis() as u64;
+
+    let is_stream = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("text/event-stream"));
+    if !is_stream {
+        tracing::info!(status, duration_ms, "request completed");
+        return response;
+    }
+
+    tracing::info!(status, duration_ms, "stream started");
+    let ended = StreamEndLog { span: tracing::Span::current(), started };
+    response.map(|body| {
+        axum::body::Body::from_stream(body.into_data_stream().map(move |chunk| {
+            let _ = &ended;
+            chunk
+        }))
+    })
+}
+
+struct StreamEndLog {
+    span: tracing::Span,
+    started: Instant,
+}
+
+impl Drop for StreamEndLog {
+    fn drop(&mut self) {
+        let _entered = self.span.enter();
+        tracing::info!(duration_ms = self.started.elapsed().as_millis() as u64, "stream_ended");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode, routing::get, Router};
+    use std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+    };
+    use tower::ServiceExt;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    type Fields = HashMap<String, String>;
+
+    /// Keeps the fields of every event logged, `message` included.
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<Fields>>>);
+
+    impl Captured {
+        fn messages(&self) -> Vec<String> {
+            self.0.lock().unwrap().iter().map(|fields| fields["message"].clone()).collect()
+        }
+
+        fn event(&self, message: &str) -> Fields {
+            self.0.lock().unwrap().iter().find(|fields| fields["message"] == message).cloned().unwrap()
+        }
+    }
+
+    impl<S: Subscriber> tracing_subscriber::Layer<S> for Captured {
+        fn on_event(&self, event: &tracing::Event<'_>, _cx: tracing_subscriber::layer::Context<'_, S>) {
+            struct Visitor<'a>(&'a mut Fields);
+            impl tracing::field::Visit for Visitor<'_> {
+                fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn std::fmt::Debug) {
+                    self.0.insert(field.name().to_string(), format!("{:?}", value));
+                }
+            }
+            let mut fields = Fields::new();
+            event.record(&mut Visitor(&mut fields));
+            self.0.lock().unwrap().push(fields);
+        }
+    }
+
+    /// Logs of this thread go to the returned capture until the guard drops.
+    fn capture() -> (Captured, tracing::subscriber::DefaultGuard) {
+        let captured = Captured::default();
+        let guard = tracing::subscriber::set_default(tracing_subscriber::registry().with(captured.clone()));
+        (captured, guard)
+    }
+
+    fn app() -> Router {
+        Router::new()
+            .route("/plain", get(|| async { (StatusCode::CREATED, "done") }))
+            .route("/stream", get(|| async { ([(CONTENT_TYPE, "text/event-stream")], "data: 1\n\n") }))
+            .layer(axum::middleware::from_fn(access_log))
+    }
+
+    #[tokio::test]
+    async fn test_request_logged_with_status_and_duration() {
+        let (captured, _guard) = capture();
+        let res = app().oneshot(axum::extract::Request::get("/plain").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.status(), StatusCode::CREATED);
+
+        assert_eq!(captured.messages(), ["request completed"]);
+        let event = captured.event("request completed");
+        assert_eq!(event["status"], "201");
+        assert!(event["duration_ms"].parse::<u64>().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_stream_logged_when_started_and_when_ended() {
+        let (captured, _guard) = capture();
+        let res = app().oneshot(axum::extract::Request::get("/stream").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(captured.messages(), ["stream started"]);
+        assert_eq!(captured.event("stream started")["status"], "200");
+
+        // Read to the end, as a client would; the end is logged once the body goes
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"data: 1\n\n");
+        assert_eq!(captured.messages(), ["stream started", "stream_ended"]);
+        assert!(captured.event("stream_ended")["duration_ms"].parse::<u64>().is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_stream_end_logged_when_client_goes_away() {
+        let (captured, _guard) = capture();
+        let res = app().oneshot(axum::extract::Request::get("/stream").body(Body::empty()).unwrap()).await.unwrap();
+        drop(res);
+        assert_eq!(captured.messages(), ["stream started", "stream_ended"]);
+    }
+
+    #[test]
+    fn test_sampling_levels() {
+        let filter = SamplingFilter { rate: 0.1, warn_rate: 0.5 };
+
+        // Unselected request: only ERROR and DEBUG survive
+        assert!(filter.allows(&Level::ERROR, 0.9));
+        assert!(filter.allows(&Level::DEBUG, 0.9));
+        assert!(!filter.allows(&Level::WARN, 0.9));
+        assert!(!filter.allows(&Level::INFO, 0.9));
+
+        // WARN has its own rate
+        assert!(filter.allows(&Level::WARN, 0.3));
+        assert!(!filter.allows(&Level::INFO, 0.3));
+
+        // Selected request logs everything
+        assert!(filter.allows(&Level::INFO, 0.05));
+        assert!(filter.allows(&Level::TRACE, 0.05));
+
+        let sample = draw_sample();
+        assert!((0.0..1.0).contains(&sample));
+    }
+}
@@ -1,14 +1,17 @@
+use arc_swap::ArcSwap;
 use axum::{
-    extract::State,
-    response::{sse::{Event, KeepAlive}, Sse, IntoResponse},
-    routing::{get, post},
-    Json, Router,
+    extract::{ConnectInfo, State},
+    http::{header::{ACCEPT, AUTHORIZATION, CONTENT_TYPE}, HeaderMap, StatusCode},
+    response::{sse::{Event, KeepAlive}, Sse, IntoResponse, Response},
+    routing::{get, post, put},
+    Extension, Json, Router,
 };
 use futures::stream::Stream;
 use reqwest::Client;
-use std::{net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, net::SocketAddr, sync::Arc, time::{Duration, Instant}};
 use tokio_stream::StreamExt;
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use tracing::Instrument;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, Layer};
 use uuid::Uuid;
 
 mod types;
@@ -16,6 +19,35 @@ mod transcoder;
 mod upstream;
 mod db;
 mod sse_codec;
+mod conversations;
+mod context;
+mod replay;
+mod admin;
+mod stats;
+mod logging;
+mod writer;
+mod metrics;
+mod cache;
+mod ndjson;
+mod request_id;
+mod compression;
+mod security;
+mod allowlist;
+mod adapter;
+mod jobs;
+mod webhook;
+mod ws;
+mod tenants;
+mod audit;
+mod crypto;
+mod ors_stream;
+mod ip_limit;
+mod files;
+mod error;
+mod validation;
+mod live;
+
+use error::AppError;
 
 // use types::{LegacyChatRequest, LegacyChunk}; // Removed unused imports
 // Wait, I named it LegacyChatRequest in types.rs. 
@@ -26,9 +58,68 @@ mod sse_codec;
 #[derive(Clone)]
 struct AppState {
     client: Client,
-    upstream_url: String,
+    /// Swapped in place on SIGHUP, so readers never wait on a lock.
+    upstream_url: Arc<ArcSwap<String>>,
     openai_api_key: Option<String>,
+    /// Bearer token for `/admin` routes; they're disabled when unset.
+    admin_api_key: Option<String>,
+    model_auth_keys: HashMap<String, String>,
+    /// Speaks the upstream's API format (`UPSTREAM_TYPE`).
+    upstream_adapter: Arc<dyn adapter::UpstreamAdapter>,
+    /// Model names (or `*` patterns) clients may request; any model is allowed when empty.
+    known_models: Vec<String>,
+    retry_on_reset: bool,
+    /// Stream upstream bodies even when their Content-Type says they aren't a stream.
+    ignore_content_type_check: bool,
+    /// Longest wait honoured from an upstream 429's `Retry-After`.
+    max_retry_after: Duration,
+    /// Fail the stream on upstream lines that aren't valid UTF-8 rather than patching them.
+    strict_utf8: bool,
+    /// Longest upstream line buffered while waiting for its newline.
+    max_sse_line_bytes: usize,
+    /// Initial capacity of the buffer upstream bodies are read into.
+    stream_buffer_size: usize,
+    /// Skip the upstream entirely and answer with a canned completion (`NO_UPSTREAM=1`).
+    no_upstream: bool,
+    context_limits: context::ContextLimits,
+    /// Upstream responses slower than this are logged as warnings.
+    slow_upstream_threshold: Option<Duration>,
+    /// How long a response may stream from the upstream before it's cut off; `None` when unlimited.
+    sse_max_duration: Option<Duration>,
+    /// Open SSE streams allowed at once; further streaming requests get a 503.
+    max_connections: Option<u64>,
+    /// Caps open SSE streams per client address (`MAX_SSE_PER_IP`); `None` when unlimited.
+    ip_connections: Option<Arc<ip_limit::IpConnectionLimiter>>,
+    /// Tenant API key lookups, cached for a minute.
+    tenants: Arc<tenants::TenantCache>,
+    /// Enforces each tenant's `rate_limit_rps`.
+    tenant_limiter: Arc<tenants::TenantRateLimiter>,
+    /// Responses being streamed, so a reconnecting client can be resumed before they're saved.
+    live_streams: Arc<live::LiveStreams>,
+    /// Usage reports, cached for a minute.
+    tenant_usage: Arc<tenants::UsageCache>,
+    /// Reject requests whose bearer key isn't a registered tenant (`REQUIRE_TENANT_AUTH=true`)
+    /// instead of serving them with the global upstream settings.
+    require_tenant_auth: bool,
+    /// The tenant the current request authenticated as; set per request by [`AppState::for_tenant`].
+    tenant: Option<Arc<db::Tenant>>,
+    /// Take client addresses from `X-Forwarded-For` (`TRUST_PROXY_HEADERS`).
+    trust_proxy_headers: bool,
+    /// The current `POST /v1/responses` call's audit entry; set per request.
+    audit: Option<audit::AuditHandle>,
+    /// Accept plain `http` webhook URLs (`ALLOW_HTTP_WEBHOOKS=true`), e.g. for local receivers.
+    allow_http_webhooks: bool,
+    /// Accept webhook URLs resolving to loopback, private or link-local addresses
+    /// (`ALLOW_PRIVATE_WEBHOOKS=true`).
+    allow_private_webhooks: bool,
+    /// Pause between events when replaying a stored response.
+    replay_delay: Duration,
     db: Arc<db::Db>,
+    db_writer: writer::DbWriter,
+    /// Workers answering `background: true` requests.
+    background_jobs: jobs::JobQueue,
+    stats: Arc<stats::Stats>,
+    model_metrics: metrics::PerModelMetrics,
 }
 
 #[tokio::main]
@@ -37,176 +128,959 @@ async fn main() {
         .with(tracing_subscriber::EnvFilter::new(
             std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into()),
         ))
-        .with(tracing_subscriber::fmt::layer())
+        .with(log_layer().with_filter(logging::SamplingFilter::from_env()))
         .init();
 
     // Load env vars
     let upstream_url = std::env::var("UPSTREAM_URL")
         .unwrap_or_else(|_| "http://localhost:11434/v1/chat/completions".to_string());
+    let upstream_url = Arc::new(ArcSwap::from_pointee(upstream_url));
+    tokio::spawn(reload_on_sighup(upstream_url.clone()));
     let openai_api_key = std::env::var("OPENAI_API_KEY").ok();
     let database_url = std::env::var("DATABASE_URL") // Default to explicit file or in-memory?
         .unwrap_or_else(|_| "sqlite://ors_proxy.db?mode=rwc".to_string());
 
-    let db = db::Db::new(&database_url).await.expect("Failed to init DB");
+    let db = db::Db::new(&database_url)
+        .await
+        .expect("Failed to init DB")
+        .with_context_cache(
+            env_parse("CONTEXT_CACHE_SIZE", 100),
+            Duration::from_secs(env_parse("CONTEXT_CACHE_TTL_SECS", 300)),
+        )
+        .with_logprobs(env_flag("STORE_LOGPROBS"));
+    let db = match crypto::PayloadCipher::from_env().expect("Invalid DB_ENCRYPTION_KEY") {
+        Some(cipher) => db.with_encryption(cipher),
+        None => db,
+    };
+    if std::env::args().nth(1).as_deref() == Some("validate-db") {
+        std::process::exit(validate_db(&db).await);
+    }
+    let db = Arc::new(db);
+    let (db_writer, db_worker) = writer::DbWriter::spawn(db.clone(), env_parse("DB_WRITE_QUEUE_SIZE", 100));
+    // Resolved up front so a generated key is logged at startup rather than on the first delivery
+    webhook::signing_key();
+    let trust_proxy_headers = env_flag("TRUST_PROXY_HEADERS");
 
     let state = AppState {
-        client: Client::new(),
-        upstream_url,
+        client: build_http_client(),
+        upstream_url: upstream_url.clone(),
         openai_api_key,
-        db: Arc::new(db),
+        admin_api_key: std::env::var("ADMIN_API_KEY").ok().filter(|k| !k.is_empty()),
+        model_auth_keys: env_map("MODEL_AUTH_KEYS"),
+        upstream_adapter: adapter::from_type(&std::env::var("UPSTREAM_TYPE").unwrap_or_else(|_| "openai".into()))
+            .expect("Invalid UPSTREAM_TYPE"),
+        known_models: env_list("KNOWN_MODELS"),
+        retry_on_reset: env_flag("UPSTREAM_RETRY_ON_RESET"),
+        ignore_content_type_check: env_flag("IGNORE_CONTENT_TYPE_CHECK"),
+        max_retry_after: Duration::from_secs(env_parse("MAX_RETRY_AFTER_SECS", 30)),
+        strict_utf8: env_flag("STRICT_UTF8"),
+        max_sse_line_bytes: env_parse("MAX_SSE_LINE_BYTES", sse_codec::DEFAULT_MAX_LINE_BYTES),
+        stream_buffer_size: env_parse("STREAM_BUFFER_SIZE", 8192),
+        no_upstream: env_flag("NO_UPSTREAM"),
+        context_limits: context::ContextLimits {
+            max_items: env_parse("MAX_CONTEXT_ITEMS", 100),
+            max_chars: env_parse("MAX_CONTEXT_CHARS", 200_000),
+            strategy: env_parse("CONTEXT_TRIM_STRATEGY", context::TrimStrategy::OldestFirst),
+            preserve_turns: env_parse("CONTEXT_PRESERVE_TURNS", 5),
+        },
+        sse_max_duration: Some(env_parse("SSE_MAX_DURATION_SECS", 300))
+            .filter(|&secs| secs > 0)
+            .map(Duration::from_secs),
+        max_connections: std::env::var("MAX_CONNECTIONS").ok().and_then(|v| v.parse().ok()),
+        ip_connections: Some(env_parse("MAX_SSE_PER_IP", 10))
+            .filter(|&max| max > 0)
+            .map(|max| Arc::new(ip_limit::IpConnectionLimiter::new(max))),
+        slow_upstream_threshold: std::env::var("UPSTREAM_SLOW_LOG_THRESHOLD_MS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .map(Duration::from_millis),
+        replay_delay: Duration::from_millis(env_parse("REPLAY_DELAY_MS", 0)),
+        allow_http_webhooks: env_flag("ALLOW_HTTP_WEBHOOKS"),
+        allow_private_webhooks: env_flag("ALLOW_PRIVATE_WEBHOOKS"),
+        tenants: Arc::new(tenants::TenantCache::new(tenants::TENANT_CACHE_TTL)),
+        tenant_limiter: Arc::new(tenants::TenantRateLimiter::default()),
+        live_streams: Arc::new(live::LiveStreams::default()),
+        tenant_usage: Arc::new(tenants::UsageCache::new(tenants::USAGE_CACHE_TTL)),
+        require_tenant_auth: env_flag("REQUIRE_TENANT_AUTH"),
+        tenant: None,
+        trust_proxy_headers,
+        audit: None,
+        db,
+        db_writer,
+        background_jobs: jobs::JobQueue::spawn(
+            env_parse("BACKGROUND_QUEUE_SIZE", 100),
+            env_parse("BACKGROUND_WORKERS", 4),
+            run_background_job,
+        ),
+        stats: Arc::new(stats::Stats::new()),
+        model_metrics: metrics::PerModelMetrics::default(),
     };
 
+    tokio::spawn(state.tenant_limiter.clone().purge_idle_loop());
+    if let Some(ip_connections) = &state.ip_connections {
+        tokio::spawn(ip_connections.clone().purge_idle_loop());
+    }
+
     let app = Router::new()
         .route("/health", get(health_check))
-        .route("/v1/responses", post(create_response))
+        .route(
+            "/v1/responses",
+            post(create_response)
+                .layer(validation::ValidationLayer::<ndjson::OrsRequestBody>::new(state.clone()))
+                .layer(axum::middleware::from_fn_with_state(state.clone(), audit::open)),
+        )
+        .route("/v1/responses/stream", get(ws::stream_responses))
+        .route("/v1/responses/:id", get(replay::get_response))
+        .route("/v1/responses/:id/replay", get(replay::replay_response))
+        .route("/v1/responses/:id/input_items", get(conversations::list_input_items))
+        .route("/v1/conversations", get(conversations::list_conversations))
+        .route(
+            "/v1/conversations/:id",
+            get(conversations::get_conversation)
+                .patch(conversations::patch_conversation)
+                .delete(conversations::delete_conversation),
+        )
+        .route("/v1/conversations/:id/metadata", get(conversations::get_conversation_metadata))
+        .route("/v1/conversations/:id/system_prompt", put(conversations::set_system_prompt))
+        .route("/v1/conversations/:id/fork", post(conversations::fork_conversation))
+        .route("/v1/conversations/:id/forget", post(conversations::forget_conversation))
+        .route(
+            "/v1/files",
+            post(files::upload_file).layer(axum::extract::DefaultBodyLimit::max(env_parse(
+                "MAX_UPLOAD_BYTES",
+                files::DEFAULT_MAX_UPLOAD_BYTES,
+            ))),
+        )
+        .route("/v1/files/:file_id", get(files::get_file).delete(files::delete_file))
+        .route("/v1/tenants/:tenant_id/usage", get(tenants::tenant_usage))
+        .route("/admin/conversations/purge", post(admin::purge_conversations))
+        .route("/admin/stats", get(admin::stats))
+        .route("/admin/audit", get(admin::audit_log))
+        .route("/admin/tenants", post(admin::register_tenant))
+        .route("/metrics", get(metrics::prometheus))
+        .route("/metrics/models", get(metrics::model_metrics))
+        .layer(axum::middleware::from_fn(compression::skip_sse))
+        .layer(compression::layer())
+        .layer(axum::middleware::from_fn(logging::access_log))
+        .layer(request_id::RequestIdLayer)
         .with_state(state);
+    let allowlist = allowlist::CidrAllowlist::parse(
+        &std::env::var("ALLOWED_CIDRS").unwrap_or_default(),
+        trust_proxy_headers,
+    )
+    .expect("Invalid ALLOWED_CIDRS");
+    let app = match allowlist {
+        Some(allowlist) => app.layer(axum::middleware::from_fn_with_state(Arc::new(allowlist), allowlist::enforce)),
+        None => app,
+    };
+    // Deployments behind a reverse proxy that already sets these can turn them off
+    let app = if env_flag_or("SECURITY_HEADERS_ENABLED", true) {
+        app.layer(axum::middleware::from_fn(security::security_headers))
+    } else {
+        app
+    };
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
+    let addr = env_parse("LISTEN_ADDR", SocketAddr::from(([0, 0, 0, 0], 3000)));
     tracing::info!("listening on {}", addr);
     let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-    axum::serve(listener, app).await.unwrap();
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+        .with_graceful_shutdown(shutdown_signal())
+        .await
+        .unwrap();
+
+    // Streams still generating hold writer handles; give them a bounded window to finish and flush
+    tracing::info!("Shutting down, draining DB write queue");
+    if tokio::time::timeout(Duration::from_secs(30), db_worker).await.is_err() {
+        tracing::warn!("Timed out draining DB write queue");
+    }
+}
+
+/// `validate-db`: checks that every stored item still loads, e.g. before deploying a schema
+/// change. Exits non-zero if any doesn't.
+async fn validate_db(db: &db::Db) -> i32 {
+    match db.validate_all_items().await {
+        Ok((checked, failures)) if failures.is_empty() => {
+            println!("All {} items load", checked);
+            0
+        }
+        Ok((checked, failures)) => {
+            for failure in &failures {
+                println!("item {} in conversation {}: {}", failure.id, failure.conversation_id, failure.error);
+            }
+            println!("{} of {} items fail to load", failures.len(), checked);
+            1
+        }
+        Err(e) => {
+            eprintln!("Failed to read items: {}", e);
+            2
+        }
+    }
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Re-reads `UPSTREAM_URL` (from the environment and `.env`) on every SIGHUP.
+async fn reload_on_sighup(upstream_url: Arc<ArcSwap<String>>) {
+    #[cfg(unix)]
+    {
+        let mut hangup = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup())
+            .expect("Failed to install SIGHUP handler");
+        while hangup.recv().await.is_some() {
+            // `.env` is read without loading it: setting env vars isn't safe once threads run
+            let from_file = dotenvy::dotenv_iter().ok().and_then(|vars| {
+                vars.filter_map(Result::ok).filter(|(key, _)| key == "UPSTREAM_URL").last().map(|(_, url)| url)
+            });
+            match from_file.or_else(|| std::env::var("UPSTREAM_URL").ok()) {
+                Some(url) if url != **upstream_url.load() => {
+                    tracing::info!("SIGHUP: switching upstream to {}", url);
+                    upstream_url.store(Arc::new(url));
+                }
+                _ => tracing::info!("SIGHUP: upstream unchanged"),
+            }
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = upstream_url;
+}
+
+/// Builds the log output layer from `RUST_LOG_FORMAT` (`text` or `json`) and
+/// `RUST_LOG_TIMESTAMP` (`utc`, `local` or `none`).
+fn log_layer<S>() -> Box<dyn tracing_subscriber::Layer<S> + Send + Sync>
+where
+    S: tracing::Subscriber + for<'a> tracing_subscriber::registry::LookupSpan<'a>,
+{
+    use tracing_subscriber::fmt::time::ChronoLocal;
+
+    let json = std::env::var("RUST_LOG_FORMAT").is_ok_and(|f| f.eq_ignore_ascii_case("json"));
+    let timestamp = std::env::var("RUST_LOG_TIMESTAMP").unwrap_or_else(|_| "utc".to_string());
+
+    // Each format/timer combination is its own type, hence the boxing
+    let layer = tracing_subscriber::fmt::layer();
+    match (json, timestamp.as_str()) {
+        (true, "none") => layer.json().without_time().boxed(),
+        (true, "local") => layer.json().with_timer(ChronoLocal::rfc_3339()).boxed(),
+        (true, _) => layer.json().boxed(),
+        (false, "none") => layer.without_time().boxed(),
+        (false, "local") => layer.with_timer(ChronoLocal::rfc_3339()).boxed(),
+        (false, _) => layer.boxed(),
+    }
+}
+
+/// Reads a boolean env var, accepting `true` or `1`.
+fn env_flag(name: &str) -> bool {
+    env_flag_or(name, false)
+}
+
+/// Reads a boolean env var that's on unless turned off (or the other way around): `true`/`1`
+/// and `false`/`0` set it, anything else leaves `default`.
+fn env_flag_or(name: &str, default: bool) -> bool {
+    std::env::var(name).ok().and_then(|v| parse_flag(&v)).unwrap_or(default)
+}
+
+fn parse_flag(value: &str) -> Option<bool> {
+    match value.trim() {
+        "1" => Some(true),
+        "0" => Some(false),
+        v if v.eq_ignore_ascii_case("true") => Some(true),
+        v if v.eq_ignore_ascii_case("false") => Some(false),
+        _ => None,
+    }
+}
+
+/// Reads a comma-separated list of `key=value` pairs, e.g. `gpt-4=sk-...,llama=r8_...`.
+fn env_map(name: &str) -> HashMap<String, String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .filter_map(|pair| pair.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .filter(|(k, v)| !k.is_empty() && !v.is_empty())
+        .collect()
+}
+
+/// Reads a comma-separated list, e.g. `gpt-4*,llama-3-70b`.
+fn env_list(name: &str) -> Vec<String> {
+    std::env::var(name)
+        .unwrap_or_default()
+        .split(',')
+        .map(|item| item.trim().to_string())
+        .filter(|item| !item.is_empty())
+        .collect()
+}
+
+/// Reads and parses an env var, falling back to `default` when unset or malformed.
+fn env_parse<T: std::str::FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+fn build_http_client() -> Client {
+    let mut builder = Client::builder()
+        // Upstreams silently drop idle connections after a few minutes; retire ours first
+        .pool_idle_timeout(Duration::from_secs(env_parse("HTTP_IDLE_TIMEOUT_SECS", 120)))
+        .pool_max_idle_per_host(env_parse("HTTP_POOL_IDLE_MAX", 10));
+
+    if let Some(secs) = std::env::var("HTTP_KEEPALIVE_INTERVAL_SECS").ok().and_then(|v| v.parse().ok()) {
+        builder = builder.tcp_keepalive(Duration::from_secs(secs));
+    }
+
+    with_http_version(builder, env_flag("UPSTREAM_HTTP2_PRIOR_KNOWLEDGE"), env_flag("UPSTREAM_HTTP2"))
+        .build()
+        .expect("Failed to build HTTP client")
+}
+
+/// Applies `UPSTREAM_HTTP2_PRIOR_KNOWLEDGE` (`prior_knowledge`) and `UPSTREAM_HTTP2` (`http2`).
+fn with_http_version(builder: reqwest::ClientBuilder, prior_knowledge: bool, http2: bool) -> reqwest::ClientBuilder {
+    if prior_knowledge {
+        // h2c: cleartext upstreams (e.g. Ollama) can't negotiate via ALPN, so speak HTTP/2 from the first byte
+        builder.http2_prior_knowledge()
+    } else if !http2 {
+        builder.http1_only()
+    } else {
+        // TLS upstreams negotiate HTTP/2 via ALPN and fall back to HTTP/1.1
+        builder
+    }
+}
+
+/// Builds a JSON error body in the same `{"error": {...}}` shape the upstream path uses.
+fn error_response(status: StatusCode, error_type: &str, message: impl std::fmt::Display) -> Response {
+    let body = serde_json::json!({
+        "error": {
+            "message": message.to_string(),
+            "type": error_type,
+        }
+    });
+    (status, Json(body)).into_response()
+}
+
+impl IntoResponse for types::ValidationError {
+    fn into_response(self) -> Response {
+        AppError::from(self).into_response()
+    }
 }
 
 async fn health_check() -> &'static str {
     "OK"
 }
 
+/// SSE event ids carry the conversation so a reconnecting client can be resumed: `{conversation_id}:{seq}`.
+fn sse_event_id(conversation_id: &str, sequence_number: u32) -> String {
+    format!("{}:{}", conversation_id, sequence_number)
+}
+
+fn parse_event_id(id: &str) -> Option<(&str, u32)> {
+    let (conversation_id, seq) = id.rsplit_once(':')?;
+    Some((conversation_id, seq.parse().ok()?))
+}
+
+/// Replays the events a client missed after `Last-Event-ID`: from the live response if it's
+/// still being generated, following it to the end, or else from the stored ones.
+async fn resume_stream(state: &AppState, last_event_id: &str) -> Result<Response, AppError> {
+    let Some((conversation_id, seq)) = parse_event_id(last_event_id) else {
+        return Err(types::ValidationError::new("Last-Event-ID", "invalid format").into());
+    };
+
+    if let Some(events) = state.live_streams.follow(conversation_id, state.tenant_id(), seq) {
+        tracing::info!("Resuming live response of conversation {} after sequence {}", conversation_id, seq);
+        let conversation_id = conversation_id.to_string();
+        let stream = events.map(move |event| {
+            Ok::<_, std::convert::Infallible>(
+                Event::default()
+                    .event(event.event_type)
+                    .id(sse_event_id(&conversation_id, event.sequence_number))
+                    .data(event.payload),
+            )
+        });
+        return Ok(Sse::new(stream).keep_alive(KeepAlive::default()).into_response());
+    }
+
+    let events = state.db.get_events_after(conversation_id, state.tenant_id(), seq).await.map_err(|e| {
+        tracing::error!("Failed to load events for {}: {}", conversation_id, e);
+        AppError::Internal("Failed to load events".to_string())
+    })?;
+    if events.is_empty() {
+        return Err(AppError::NotFound(format!("No stored events after {}", last_event_id)));
+    }
+
+    tracing::info!("Resuming conversation {} after sequence {} ({} events)", conversation_id, seq, events.len());
+    let conversation_id = conversation_id.to_string();
+    let stream = futures::stream::iter(events.into_iter().map(move |stored| {
+        Ok::<_, std::convert::Infallible>(
+            Event::default()
+                .event(stored.event_type)
+                .id(sse_event_id(&conversation_id, stored.sequence_number))
+                .data(stored.payload),
+        )
+    }));
+
+    Ok(Sse::new(stream).into_response())
+}
+
 async fn create_response(
-    State(state): State<AppState>,
-    Json(payload): Json<types::OrsRequest>,
-) -> impl IntoResponse {
-    tracing::info!("Received request for model: {}", payload.model);
+    State(mut state): State<AppState>,
+    headers: HeaderMap,
+    connect_info: Option<ConnectInfo<SocketAddr>>,
+    Extension(request_id::RequestId(request_id)): Extension<request_id::RequestId>,
+    Extension(audit): Extension<audit::AuditHandle>,
+    ndjson::OrsRequestBody(payload): ndjson::OrsRequestBody,
+) -> Result<Response, AppError> {
+    // Opened by `audit::open`, so logged whatever the outcome
+    audit.update(|entry| {
+        entry.model = Some(payload.model.clone());
+        entry.input_item_count = Some(payload.input.len() as i64);
+    });
+    state.audit = Some(audit);
+    let peer = connect_info.map(|ConnectInfo(addr)| addr);
+    let client_ip = allowlist::client_ip(&headers, peer, state.trust_proxy_headers);
+    let state = match authenticate_tenant(state, &headers, true).await {
+        Ok(state) => state,
+        // Shared with the other routes, which answer with it as is (429s with rate limit headers)
+        Err(res) => return Ok(res),
+    };
+    state.update_audit(|entry| entry.tenant_id = Some(state.tenant_id().to_string()));
+    if let Some(last_event_id) = headers.get("last-event-id").and_then(|v| v.to_str().ok()) {
+        return resume_stream(&state, last_event_id).await;
+    }
+
+    state.stats.record_request(db::now_secs());
+    let request_started = Instant::now();
+
+    let (conversation_id, full_input) = prepare(&state, &payload).await?;
+
+    if payload.background {
+        return start_background(state, request_id, payload, conversation_id, full_input).await;
+    }
+
+    let streaming = payload.wants_stream(headers.get(ACCEPT).and_then(|v| v.to_str().ok()));
+    // Counted against the trusted hop's address, like the allowlist
+    let ip_connection = match (&state.ip_connections, client_ip) {
+        (Some(limiter), Some(ip)) if streaming => match limiter.try_acquire(ip) {
+            Some(guard) => Some(guard),
+            None => {
+                tracing::warn!("Rejecting stream: {} already has {} open", ip, limiter.open(ip));
+                state.stats.record_failure();
+                return Err(AppError::RateLimit {
+                    message: "Too many concurrent connections from this IP".to_string(),
+                    retry_after: None,
+                });
+            }
+        },
+        _ => None,
+    };
+
+    let response = respond(state, request_id, payload, conversation_id, full_input, streaming, request_started).await?;
+    Ok(match ip_connection {
+        Some(guard) => guard.hold_for(response),
+        None => response,
+    })
+}
+
+impl AppState {
+    /// The state for a request from `tenant`: its upstream URL and key replace the global ones
+    /// (per-model keys included). A tenant with its own URL never gets the global keys, even
+    /// without a key of its own: they'd be sent to a server the tenant controls.
+    fn for_tenant(mut self, tenant: Arc<db::Tenant>) -> Self {
+        if let Some(url) = &tenant.upstream_url {
+            self.upstream_url = Arc::new(ArcSwap::from_pointee(url.clone()));
+        }
+        if tenant.upstream_url.is_some() || tenant.upstream_key.is_some() {
+            self.openai_api_key = tenant.upstream_key.clone();
+            self.model_auth_keys = HashMap::new();
+        }
+        self.tenant = Some(tenant);
+        self
+    }
+
+    /// The tenant whose conversations this request may see; requests without one share
+    /// [`db::DEFAULT_TENANT_ID`].
+    fn tenant_id(&self) -> &str {
+        self.tenant.as_ref().map_or(db::DEFAULT_TENANT_ID, |t| t.id.as_str())
+    }
 
+    /// Adds to the request's audit entry, if it has one.
+    fn update_audit(&self, f: impl FnOnce(&mut db::AuditEntry)) {
+        if let Some(audit) = &self.audit {
+            audit.update(f);
+        }
+    }
+}
+
+/// Matches the request's `Authorization: Bearer` key against the registered tenants. Unknown or
+/// missing keys keep the global settings, or get a 401 when `REQUIRE_TENANT_AUTH` is set.
+/// A `rate_limited` request counts against the tenant's `rate_limit_rps`, and gets a 429 over
+/// it; read-only ones don't, so polling stored data can't starve generation.
+async fn authenticate_tenant(state: AppState, headers: &HeaderMap, rate_limited: bool) -> Result<AppState, Response> {
+    match resolve_tenant(&state, headers, rate_limited).await? {
+        Some(tenant) => Ok(state.for_tenant(tenant)),
+        None => Ok(state),
+    }
+}
+
+/// The tenant owning the request's bearer key, if any; see [`authenticate_tenant`].
+async fn resolve_tenant(
+    state: &AppState,
+    headers: &HeaderMap,
+    rate_limited: bool,
+) -> Result<Option<Arc<db::Tenant>>, Response> {
+    let api_key = headers
+        .get(AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+    let tenant = match api_key {
+        Some(api_key) => match state.tenants.lookup(&state.db, api_key).await {
+            Ok(tenant) => tenant,
+            Err(e) => {
+                tracing::error!("Failed to look up tenant: {}", e);
+                return Err(error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to authenticate"));
+            }
+        },
+        None => None,
+    };
+    match tenant {
+        Some(tenant) => {
+            tracing::Span::current().record("tenant", tenant.id.as_str());
+            if let Some(limit) = tenant.rate_limit_rps.filter(|_| rate_limited) {
+                if let Err(retry_after) = state.tenant_limiter.check(&tenant.id, limit) {
+                    tracing::warn!("Tenant {} is over its limit of {} requests per second", tenant.id, limit);
+                    return Err(tenants::rate_limited(limit, retry_after));
+                }
+            }
+            Ok(Some(tenant))
+        }
+        None if state.require_tenant_auth => {
+            Err(error_response(StatusCode::UNAUTHORIZED, "invalid_api_key", "Missing or unknown API key"))
+        }
+        None => Ok(None),
+    }
+}
+
+/// Picks the request's conversation, loads its history and validates the request against it;
+/// checks on the request alone already ran in [`validation::ValidationLayer`] (or, over
+/// WebSocket, when the frame was parsed). Returns the conversation ID and history, or the error.
+async fn prepare(state: &AppState, payload: &types::OrsRequest) -> Result<(String, Vec<types::OrsInputItem>), AppError> {
     // 1. Context Management
+    // Checked before the ID reaches logs or the database
+    if payload.previous_response_id.as_deref().is_some_and(|id| !types::is_valid_conversation_id(id)) {
+        state.stats.record_failure();
+        return Err(types::ValidationError::new("previous_response_id", "invalid format").into());
+    }
     let conversation_id = payload.previous_response_id
         .clone()
         .unwrap_or_else(|| Uuid::new_v4().to_string());
 
-    let mut full_input = if payload.previous_response_id.is_some() {
-        match state.db.load_context(&conversation_id).await {
+    let span = tracing::Span::current();
+    span.record("conversation_id", conversation_id.as_str());
+    state.update_audit(|entry| entry.conversation_id = Some(conversation_id.clone()));
+    span.record("model", payload.model.as_str());
+    if let Some(user) = &payload.user {
+        span.record("user", user.as_str());
+    }
+
+    let full_input = if payload.previous_response_id.is_some() {
+        // The previous turn may still be in the write-behind queue
+        state.db_writer.flush(&conversation_id).await;
+        // Another tenant's conversation is reported as missing rather than forbidden, so its
+        // existence doesn't leak
+        match state.db.conversation_tenant(&conversation_id).await {
+            Ok(Some(owner)) if owner != state.tenant_id() => {
+                state.stats.record_failure();
+                return Err(AppError::NotFound(format!("Response {} not found", conversation_id)));
+            }
+            Ok(_) => {}
+            Err(e) => tracing::error!("Failed to look up owner of {}: {}", conversation_id, e),
+        }
+        // Instructions restored from the conversation are superseded by the request's, whether or
+        // not the text changed: the same text would be sent twice, and different text would
+        // contradict them
+        let loaded = if payload.instructions.is_some() {
+            state.db.load_context_without_instructions(&conversation_id, state.tenant_id()).await
+        } else {
+            state.db.load_context(&conversation_id, state.tenant_id()).await
+        };
+        match loaded {
             Ok(history) => history,
             Err(e) => {
                 tracing::error!("Failed to load context: {}", e);
-                return axum::response::Response::builder()
-                    .status(500)
-                    .body(axum::body::Body::from("Failed to load context"))
-                    .unwrap();
+                state.stats.record_failure();
+                return Err(AppError::Database(e));
             }
         }
     } else {
         Vec::new()
     };
     
-    // Append current input
-    full_input.extend(payload.input.clone());
-
-    // 2. Transform request with FULL history
-    let legacy_messages = upstream::transform_ors_to_legacy(full_input); // Use full_input here!
+    if let Err(e) = payload.validate_context(&full_input, &state.known_models) {
+        state.stats.record_failure();
+        return Err(e.into());
+    }
+    if let Some(url) = &payload.webhook_url {
+        if let Err(e) = webhook::validate_url(url, state.allow_http_webhooks, state.allow_private_webhooks).await {
+            state.stats.record_failure();
+            return Err(e.into());
+        }
+    }
 
-    let legacy_req = types::LegacyChatRequest {
-        model: payload.model,
-        messages: legacy_messages,
-        stream: true,
-    };
+    Ok((conversation_id, full_input))
+}
 
-    // 3. Prepare upstream request
-    let mut req_builder = state.client.post(&state.upstream_url)
-        .json(&legacy_req);
-    
-    if let Some(key) = &state.openai_api_key {
-        req_builder = req_builder.bearer_auth(key);
+/// Answers `202 Accepted` right away and generates the response on a background task; clients
+/// poll `GET /v1/responses/:id` for its status and output.
+async fn start_background(
+    state: AppState,
+    request_id: String,
+    payload: types::OrsRequest,
+    conversation_id: String,
+    full_input: Vec<types::OrsInputItem>,
+) -> Result<Response, AppError> {
+    if payload.stream == Some(true) {
+        state.stats.record_failure();
+        return Err(types::ValidationError::new("stream", "background responses can't be streamed").into());
     }
-
-    // 4. Execute request
-    let res = match req_builder.send().await {
-        Ok(res) => res,
-        Err(e) => {
-            tracing::error!("Upstream error: {}", e);
-            return axum::response::Response::builder()
-                .status(502)
-                .body(axum::body::Body::from(format!("Upstream error: {}", e)))
-                .unwrap(); 
-        }
+    let Some(permit) = state.background_jobs.reserve() else {
+        state.stats.record_failure();
+        return Err(AppError::RateLimit { message: "background queue is full".to_string(), retry_after: None });
     };
-
-    if !res.status().is_success() {
-         let error_text = res.text().await.unwrap_or_default();
-         tracing::error!("Upstream failed: {}", error_text);
-         
-         let error_body = serde_json::json!({
-             "error": {
-                 "message": format!("Upstream provider error: {}", error_text),
-                 "type": "upstream_error",
-                 "code": "upstream_failed"
-             }
-         });
-         
-         return axum::response::Response::builder()
-                .status(502) // Bad Gateway
-                .header("Content-Type", "application/json")
-                .body(axum::body::Body::from(error_body.to_string()))
-                .unwrap();
+    if let Err(e) = state.db.queue_response(&conversation_id, state.tenant_id(), payload.conversation_metadata().as_ref()).await {
+        tracing::error!("Failed to queue background response for {}: {}", conversation_id, e);
+        state.stats.record_failure();
+        return Err(AppError::Internal("Failed to queue response".to_string()));
     }
 
-    // 5. Stream and Transcode (and Save)
-    let stream = make_stream(res, state, conversation_id, payload.input);
+    permit.send(jobs::Job {
+        state: state.clone(),
+        request_id,
+        payload,
+        conversation_id: conversation_id.clone(),
+        full_input,
+        span: tracing::Span::current(),
+    });
 
-    Sse::new(stream)
-        .keep_alive(KeepAlive::default())
-        .into_response()
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(serde_json::json!({ "id": conversation_id, "object": "response", "status": "queued" })),
+    )
+        .into_response())
+}
+
+async fn run_background_job(job: jobs::Job) {
+    let jobs::Job { state, request_id, payload, conversation_id: id, full_input, span } = job;
+    async move {
+        if let Err(e) = state.db.set_response_status(&id, "in_progress").await {
+            tracing::error!("Failed to update status of {}: {}", id, e);
+        }
+        // Completion is recorded when the interaction is persisted; only failures before
+        // there's anything to persist are handled here
+        let res = respond(state.clone(), request_id, payload, id.clone(), full_input, false, Instant::now()).await;
+        if let Err(e) = res {
+            tracing::warn!("Background response {} failed with {}: {}", id, e.status(), e);
+            if let Err(e) = state.db.set_response_status(&id, "failed").await {
+                tracing::error!("Failed to update status of {}: {}", id, e);
+            }
+        }
+    }
+    .instrument(span)
+    .await
 }
 
-fn make_stream(
-    res: reqwest::Response,
+/// Calls the upstream with the validated request and its full context, then streams or
+/// collects the transcoded response.
+async fn respond(
     state: AppState,
+    request_id: String,
+    payload: types::OrsRequest,
     conversation_id: String,
-    input_items: Vec<types::OrsInputItem>
-) -> impl Stream<Item = Result<Event, std::io::Error>> {
-    async_stream::try_stream! {
-        let mut upstream_stream = res.bytes_stream();
-        let mut transcoder = transcoder::Transcoder::new();
-        let mut accumulated_events: Vec<types::OrsEvent> = Vec::new();
-        let mut codec = sse_codec::SseCodec::new();
-        
-        while let Some(chunk_result) = upstream_stream.next().await {
-            let chunk_bytes = chunk_result.map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-            
-            // Use codec to extract complete lines
-            let lines = codec.decode(chunk_bytes);
-            
-            for line in lines {
-                let line = line.trim();
-                if line.starts_with("data: ") {
-                    let json_str = &line["data: ".len()..];
-                    if json_str == "[DONE]" {
-                        continue;
-                    }
-                    
-                    if let Ok(legacy_chunk) = serde_json::from_str::<types::LegacyChunk>(json_str) {
-                        let events = transcoder.process(legacy_chunk);
-                        for event in events {
-                            // Accumulate for storage
-                            accumulated_events.push(event.clone());
-
-                            let sse_event = Event::default()
-                                .event(event_name(&event))
-                                .json_data(&event)
-                                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
-                            
-                            yield sse_event;
+    full_input: Vec<types::OrsInputItem>,
+    streaming: bool,
+    request_started: Instant,
+) -> Result<Response, AppError> {
+    tracing::debug!("Responding with {}", if streaming { "SSE stream" } else { "single JSON response" });
+
+    // Claimed before the upstream call so a full proxy turns clients away without spending tokens
+    let connection = if streaming {
+        let Some(guard) = state.stats.try_track_sse_connection(state.max_connections) else {
+            tracing::warn!("Rejecting stream: {} SSE connections already open", state.max_connections.unwrap_or_default());
+            state.stats.record_failure();
+            return Err(AppError::Unavailable("Too many active connections".to_string()));
+        };
+        Some(guard)
+    } else {
+        None
+    };
+
+    let model = payload.model.clone();
+    let (events, upstream_latency) = start_upstream(&state, &request_id, payload, conversation_id.clone(), full_input).await?;
+
+    if !streaming {
+        let mut collected = Vec::new();
+        let mut events = std::pin::pin!(events);
+        while let Some(event) = events.next().await {
+            match event {
+                Ok(event) => collected.push(event),
+                Err(e) => {
+                    tracing::error!("Upstream stream failed: {}", e);
+                    state.stats.record_failure();
+                    return Err(AppError::Gateway(format!("Upstream error: {}", e)));
+                }
+            }
+        }
+        let mut response = Json(transcoder::collect_response(&model, &collected)).into_response();
+        let headers = response.headers_mut();
+        headers.insert("x-upstream-latency-ms", (upstream_latency.as_millis() as u64).into());
+        headers.insert("x-total-latency-ms", (request_started.elapsed().as_millis() as u64).into());
+        return Ok(response);
+    }
+
+    // Drive generation on its own task so it completes (and is persisted) even if the client
+    // disconnects; a reconnecting client then picks up the rest via Last-Event-ID.
+    let (tx, rx) = tokio::sync::mpsc::channel(64);
+    let stats = state.stats.clone();
+    let live = state.live_streams.start(&conversation_id, state.tenant_id());
+    tokio::spawn(
+        async move {
+            let mut events = std::pin::pin!(events);
+            while let Some(event) = events.next().await {
+                match &event {
+                    Ok(event) => {
+                        if let (Some(sequence_number), Ok(payload)) = (event.sequence_number(), serde_json::to_string(event)) {
+                            let event_type = event_name(event).to_string();
+                            live.push(db::StoredEvent { sequence_number, event_type, payload });
                         }
-                    } else {
-                        tracing::warn!("Failed to parse legacy chunk: {}", json_str);
+                    }
+                    Err(e) => {
+                        tracing::error!("Upstream stream failed: {}", e);
+                        stats.record_failure();
                     }
                 }
+                let event = event.and_then(|event| to_sse_event(&conversation_id, &event));
+                // A send error only means the client went away; keep draining regardless
+                let _ = tx.send(event).await;
             }
+            // Headers are long gone by now, so the end-to-end time goes out as a final SSE comment
+            let latency = format!("x-total-latency-ms: {}", request_started.elapsed().as_millis());
+            let _ = tx.send(Ok(Event::default().comment(latency))).await;
+        }
+        .instrument(tracing::Span::current()),
+    );
+
+    // The guard lives as long as the client's stream, so the count drops when it disconnects
+    let client_stream = tokio_stream::wrappers::ReceiverStream::new(rx).map(move |event| {
+        let _ = &connection;
+        event
+    });
+
+    let mut response = Sse::new(client_stream)
+        .keep_alive(KeepAlive::default())
+        .into_response();
+    response
+        .headers_mut()
+        .insert("x-upstream-latency-ms", (upstream_latency.as_millis() as u64).into());
+    Ok(response)
+}
+
+/// Every file linked to the conversation, so files attached on an earlier turn are sent again,
+/// followed by the request's `file_ids`. Those must have been uploaded by the caller; they're
+/// linked to the conversation once the interaction is saved.
+async fn conversation_files(
+    state: &AppState,
+    conversation_id: &str,
+    payload: &types::OrsRequest,
+) -> Result<Vec<String>, AppError> {
+    let requested = payload.file_ids.as_deref().unwrap_or_default();
+    for file_id in requested {
+        if !state.db.owns_file(file_id, state.tenant_id()).await? {
+            return Err(AppError::NotFound(format!("File {} not found", file_id)));
+        }
+    }
+    let mut files = match state.db.conversation_files(conversation_id).await {
+        Ok(files) => files,
+        Err(e) => {
+            tracing::error!("Failed to load files of conversation {}: {}", conversation_id, e);
+            Vec::new()
+        }
+    };
+    for file_id in requested {
+        if !files.contains(file_id) {
+            files.push(file_id.clone());
+        }
+    }
+    Ok(files)
+}
+
+/// Sends the request with its full context upstream. Returns the transcoded event stream, which
+/// saves the interaction once it has been driven to the end, and the time to the upstream's
+/// response headers; or the error response to send if the upstream call failed.
+async fn start_upstream(
+    state: &AppState,
+    request_id: &str,
+    payload: types::OrsRequest,
+    conversation_id: String,
+    mut full_input: Vec<types::OrsInputItem>,
+) -> Result<(EventStream, Duration), AppError> {
+    let upstream_url = state.upstream_url.load_full();
+    if payload.wants_audio() && !upstream_url.contains("openai.com") {
+        tracing::warn!("Audio output requested but upstream {} may not support it", upstream_url);
+    }
+
+    // Append current input
+    full_input.extend(payload.input.clone());
+
+    let (mut full_input, trimmed) =
+        context::truncate(full_input, payload.truncation_strategy.as_ref(), &state.context_limits);
+    if trimmed > 0 {
+        tracing::warn!("Trimmed {} items from conversation {} to fit context limits", trimmed, conversation_id);
+    }
+    let files = conversation_files(state, &conversation_id, &payload).await?;
+    if state.upstream_adapter.supports_files() {
+        upstream::attach_files(&mut full_input, &files);
+    } else if !files.is_empty() {
+        tracing::warn!("Not sending {} file(s): the upstream doesn't take uploaded files", files.len());
+    }
+
+    // Per-model keys are keyed on the requested model name, independent of where it's routed
+    let api_key = upstream::resolve_auth_key(&state.model_auth_keys, &payload.model)
+        .or(state.openai_api_key.as_deref())
+        .map(str::to_string);
+
+    let prompt_estimate = upstream::estimate_tokens(
+        serde_json::to_string(&full_input).map(|s| s.chars().count()).unwrap_or(0)
+            + payload.instructions.as_deref().map_or(0, |s| s.chars().count()),
+    );
+
+    // 2. Build the upstream request with the FULL history
+    let config = adapter::RequestConfig {
+        client: &state.client,
+        url: upstream_url.as_str(),
+        api_key: api_key.as_deref(),
+        request_id,
+        request: &payload,
+    };
+    let req_builder = match state.upstream_adapter.build_request(full_input, &config) {
+        Ok(builder) => builder,
+        Err(e) => {
+            state.stats.record_failure();
+            return Err(AppError::Validation { field: String::new(), message: e.to_string(), code: Some("unsupported") });
         }
-        
-        // Post-stream persistence
-        if let Err(e) = state.db.save_interaction(&conversation_id, input_items, accumulated_events).await {
-             tracing::error!("Failed to save interaction: {}", e);
+    };
+    let model = payload.model.clone();
+
+    // Kept around so a connection reset before any output can be retried once
+    let retry_builder = if state.retry_on_reset { req_builder.try_clone() } else { None };
+
+    // 4. Execute request
+    let upstream_started = Instant::now();
+    let res = if state.no_upstream {
+        tracing::debug!("NO_UPSTREAM set, returning dry-run response");
+        upstream::dry_run_response(&model)
+    } else {
+        state.stats.record_upstream_request();
+        let rate_limit_builder = req_builder.try_clone();
+        let mut sent = upstream::send_with_retry(req_builder, state.retry_on_reset).await;
+        for _ in 0..upstream::MAX_RATE_LIMIT_RETRIES {
+            let Ok(res) = &sent else { break };
+            let Some(wait) = upstream::rate_limit_delay(res, state.max_retry_after) else { break };
+            let Some(req) = rate_limit_builder.as_ref().and_then(reqwest::RequestBuilder::try_clone) else { break };
+            tracing::warn!("Upstream rate limited {}, retrying in {}ms", model, wait.as_millis());
+            state.stats.record_rate_limit_retry();
+            tokio::time::sleep(wait).await;
+            sent = upstream::send_with_retry(req, state.retry_on_reset).await;
         }
+        match sent {
+            Ok(res) => res,
+            Err(e) => {
+                state.stats.record_upstream_error();
+                state.stats.record_failure();
+                state.model_metrics.record(&model, "error", upstream_started.elapsed());
+                tracing::error!("Upstream error: {}", e);
+                return Err(AppError::Gateway(format!("Upstream error: {}", e)));
+            }
+        }
+    };
+
+    let upstream_latency = upstream_started.elapsed();
+    if !state.no_upstream {
+        state.model_metrics.record(&model, res.status().as_str(), upstream_latency);
+        state.update_audit(|entry| entry.upstream_status = Some(res.status().as_u16()));
     }
+    tracing::debug!("Upstream responded over {:?} in {:?}", res.version(), upstream_latency);
+    if state.slow_upstream_threshold.is_some_and(|threshold| upstream_latency > threshold) {
+        tracing::warn!("Slow upstream: {} took {}ms to respond", model, upstream_latency.as_millis());
+    }
+
+    if !res.status().is_success() {
+        state.stats.record_upstream_error();
+        state.stats.record_failure();
+        let status = res.status().as_u16();
+        let body = res.text().await.unwrap_or_default();
+        tracing::error!("Upstream failed with {}: {}", status, body);
+        return Err(AppError::Upstream { status, body });
+    }
+
+    // A 200 with some other body (typically a JSON error rewritten by a proxy in between)
+    // would otherwise only show up as a stream of unparseable chunks
+    let expected_type = state.upstream_adapter.stream_content_type();
+    let content_type = res.headers().get(CONTENT_TYPE).and_then(|v| v.to_str().ok()).unwrap_or_default();
+    if !state.ignore_content_type_check && !content_type.contains(expected_type) {
+        state.stats.record_upstream_error();
+        state.stats.record_failure();
+        let content_type = content_type.to_string();
+        let body = res.text().await.unwrap_or_default();
+        tracing::error!("Upstream answered with {:?} instead of {}: {}", content_type, expected_type, body);
+        return Err(AppError::Gateway(format!(
+            "Upstream returned Content-Type {:?}, expected {}",
+            content_type, expected_type
+        )));
+    }
+
+    // 5. Stream and Transcode (and Save)
+    let transcoder = state.upstream_adapter.stream(adapter::StreamOptions {
+        sequential_tool_calls: payload.parallel_tool_calls == Some(false),
+        prompt_estimate,
+        full_text_parts: payload.includes("output_text"),
+    });
+    let interaction = writer::Interaction {
+        conversation_id: conversation_id.clone(),
+        tenant_id: state.tenant_id().to_string(),
+        model: model.clone(),
+        upstream_latency_ms: (!state.no_upstream).then_some(upstream_latency.as_millis() as u64),
+        metadata: payload.conversation_metadata(),
+        input: payload.input,
+        instructions: payload.instructions,
+        webhook_url: payload.webhook_url,
+        allow_private_webhook: state.allow_private_webhooks,
+        file_ids: payload.file_ids.unwrap_or_default(),
+    };
+    let events = ors_stream::OrsStream::new(res, retry_builder, transcoder, state.clone(), interaction);
+    Ok((Box::pin(events), upstream_latency))
+}
+
+/// Transcoded upstream events, ending with the interaction saved.
+type EventStream = std::pin::Pin<Box<dyn Stream<Item = Result<types::OrsEvent, std::io::Error>> + Send>>;
+
+fn to_sse_event(conversation_id: &str, event: &types::OrsEvent) -> Result<Event, std::io::Error> {
+    let mut sse_event = Event::default().event(event_name(event));
+    // Ids let clients reconnect with Last-Event-ID and resume from here
+    if let Some(seq) = event.sequence_number() {
+        sse_event = sse_event.id(sse_event_id(conversation_id, seq));
+    }
+    sse_event.json_data(event).map_err(std::io::Error::other)
 }
 
 fn event_name(event: &types::OrsEvent) -> &'static str {
@@ -215,9 +1089,224 @@ fn event_name(event: &types::OrsEvent) -> &'static str {
         types::OrsEvent::ItemAdded { .. } => "response.output_item.added",
         types::OrsEvent::ContentPartAdded { .. } => "response.content_part.added",
         types::OrsEvent::TextDelta { .. } => "response.output_text.delta",
+        types::OrsEvent::ReasoningDelta { .. } => "response.reasoning_text.delta",
         types::OrsEvent::FunctionCallArgumentsDelta { .. } => "response.function_call_arguments.delta",
+        types::OrsEvent::ComputerUseAction { .. } => "response.computer_use.action",
         types::OrsEvent::ContentPartDone { .. } => "response.content_part.done",
         types::OrsEvent::ItemDone { .. } => "response.output_item.done",
+        types::OrsEvent::CompletionUsage { .. } => "response.completed",
+        types::OrsEvent::Queued { .. } => "response.queued",
+        types::OrsEvent::Error { .. } => "response.error",
+        types::OrsEvent::ResponseDone { .. } => "response.done",
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An `AppState` as `main` builds it with no env vars set, over an in-memory DB.
+    pub(crate) async fn test_state() -> AppState {
+        let db = Arc::new(db::Db::new("sqlite::memory:").await.unwrap());
+        let (db_writer, _) = writer::DbWriter::spawn(db.clone(), 100);
+        AppState {
+            client: Client::new(),
+            upstream_url: Arc::new(ArcSwap::from_pointee("http://127.0.0.1:9/v1/chat/completions".to_string())),
+            openai_api_key: None,
+            admin_api_key: None,
+            model_auth_keys: HashMap::new(),
+            upstream_adapter: adapter::from_type("openai").unwrap(),
+            known_models: Vec::new(),
+            retry_on_reset: false,
+            ignore_content_type_check: false,
+            max_retry_after: Duration::from_secs(30),
+            strict_utf8: false,
+            max_sse_line_bytes: sse_codec::DEFAULT_MAX_LINE_BYTES,
+            stream_buffer_size: 8192,
+            no_upstream: false,
+            context_limits: context::ContextLimits {
+                max_items: 100,
+                max_chars: 200_000,
+                strategy: context::TrimStrategy::OldestFirst,
+                preserve_turns: 5,
+            },
+            slow_upstream_threshold: None,
+            sse_max_duration: None,
+            max_connections: None,
+            ip_connections: None,
+            tenants: Arc::new(tenants::TenantCache::new(tenants::TENANT_CACHE_TTL)),
+            tenant_limiter: Arc::new(tenants::TenantRateLimiter::default()),
+            live_streams: Arc::new(live::LiveStreams::default()),
+            tenant_usage: Arc::new(tenants::UsageCache::new(tenants::USAGE_CACHE_TTL)),
+            require_tenant_auth: false,
+            tenant: None,
+            trust_proxy_headers: false,
+            audit: None,
+            allow_http_webhooks: false,
+            allow_private_webhooks: false,
+            replay_delay: Duration::ZERO,
+            db,
+            db_writer,
+            background_jobs: jobs::JobQueue::spawn(10, 1, run_background_job),
+            stats: Arc::new(stats::Stats::new()),
+            model_metrics: metrics::PerModelMetrics::default(),
+        }
+    }
+
+    fn tenant(upstream_url: Option<&str>, upstream_key: Option<&str>) -> Arc<db::Tenant> {
+        Arc::new(db::Tenant {
+            id: "acme".to_string(),
+            api_key: "sk-acme".to_string(),
+            upstream_key: upstream_key.map(str::to_string),
+            upstream_url: upstream_url.map(str::to_string),
+            rate_limit_rps: None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_tenant_url_never_gets_the_global_keys() {
+        let mut state = test_state().await;
+        state.openai_api_key = Some("sk-proxy".to_string());
+        state.model_auth_keys = HashMap::from([("gpt-4o".to_string(), "sk-model".to_string())]);
+
+        let own_url = state.clone().for_tenant(tenant(Some("https://tenant.example/v1/chat/completions"), None));
+        assert_eq!(**own_url.upstream_url.load(), "https://tenant.example/v1/chat/completions");
+        assert_eq!(own_url.openai_api_key, None);
+        assert!(own_url.model_auth_keys.is_empty());
+
+        let own_key = state.clone().for_tenant(tenant(None, Some("sk-tenant")));
+        assert_eq!(own_key.openai_api_key.as_deref(), Some("sk-tenant"));
+        assert!(own_key.model_auth_keys.is_empty());
+
+        // Neither: the global settings are served as they are
+        let shared = state.for_tenant(tenant(None, None));
+        assert_eq!(shared.openai_api_key.as_deref(), Some("sk-proxy"));
+        assert_eq!(shared.model_auth_keys.len(), 1);
+    }
+
+    #[test]
+    fn test_parse_flag() {
+        for (value, flag) in [("1", Some(true)), ("TRUE", Some(true)), ("0", Some(false)), (" false", Some(false))] {
+            assert_eq!(parse_flag(value), flag, "{}", value);
+        }
+        assert_eq!(parse_flag("off"), None);
+        assert_eq!(parse_flag(""), None);
+    }
+
+    #[tokio::test]
+    async fn test_reads_dont_count_against_the_tenant_rate_limit() {
+        let state = test_state().await;
+        let tenant = db::Tenant { rate_limit_rps: Some(1), ..(*tenant(None, None)).clone() };
+        state.db.upsert_tenant(&tenant).await.unwrap();
+        let headers = HeaderMap::from_iter([(AUTHORIZATION, "Bearer sk-acme".parse().unwrap())]);
+
+        for _ in 0..3 {
+            assert!(resolve_tenant(&state, &headers, false).await.is_ok());
+        }
+        assert!(resolve_tenant(&state, &headers, true).await.is_ok());
+        let res = resolve_tenant(&state, &headers, true).await.unwrap_err();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        // Reads still go through while the tenant is limited
+        assert!(resolve_tenant(&state, &headers, false).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_streams_from_an_h2c_upstream() {
+        let versions = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let seen = versions.clone();
+        let upstream = Router::new().route(
+            "/v1/chat/completions",
+            post(move |version: axum::http::Version| async move {
+                seen.lock().unwrap().push(version);
+                let body: String = ["Hel", "lo"]
+                    .iter()
+                    .map(|text| serde_json::json!({ "choices": [{ "index": 0, "delta": { "content": text }, "finish_reason": null }] }))
+                    .chain([serde_json::json!({ "choices": [{ "index": 0, "delta": {}, "finish_reason": "stop" }] })])
+                    .map(|chunk| format!("data: {}\n\n", chunk))
+                    .collect();
+                ([("content-type", "text/event-stream")], body + "data: [DONE]\n\n")
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, upstream).await.unwrap() });
+
+        // As with UPSTREAM_HTTP2_PRIOR_KNOWLEDGE=true
+        let mut state = test_state().await;
+        state.client = with_http_version(Client::builder(), true, false).build().unwrap();
+        state.upstream_url.store(Arc::new(format!("http://{}/v1/chat/completions", addr)));
+        let payload: types::OrsRequest =
+            serde_json::from_value(serde_json::json!({ "model": "m", "input": "Hi", "stream": true })).unwrap();
+
+        let (events, _) = start_upstream(&state, "req_h2", payload, "conv_h2c".to_string(), Vec::new()).await.unwrap();
+        let events: Vec<_> = events.map(Result::unwrap).collect().await;
+        let names: Vec<_> = events.iter().map(event_name).collect();
+        assert_eq!(
+            names,
+            [
+                "response.created",
+                "response.output_item.added",
+                "response.content_part.added",
+                "response.output_text.delta",
+                "response.output_text.delta",
+                "response.content_part.done",
+                "response.output_item.done",
+                "response.completed",
+                "response.done",
+            ]
+        );
+        let deltas: Vec<_> = events
+            .iter()
+            .filter_map(|event| match event {
+                types::OrsEvent::TextDelta { delta, .. } => Some(delta.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(deltas, ["Hel", "lo"]);
+        assert_eq!(*versions.lock().unwrap(), [axum::http::Version::HTTP_2]);
+    }
+
+    #[tokio::test]
+    async fn test_request_instructions_replace_stored_ones() {
+        let state = test_state().await;
+        let user = |text: &str| types::OrsInputItem::Message {
+            role: types::OrsRole::User,
+            content: vec![types::OrsContentPart::InputText { text: text.to_string() }],
+        };
+        state.db.save_interaction("conv_instr", db::DEFAULT_TENANT_ID, None, vec![user("Hi")], vec![]).await.unwrap();
+        state.db.save_instructions("conv_instr", "Be terse").await.unwrap();
+        let request = |instructions: Option<&str>| -> types::OrsRequest {
+            serde_json::from_value(serde_json::json!({
+                "model": "m",
+                "input": "Again",
+                "previous_response_id": "conv_instr",
+                "instructions": instructions,
+            }))
+            .unwrap()
+        };
+
+        let (_, context) = prepare(&state, &request(None)).await.unwrap();
+        assert_eq!(context.len(), 2);
+        let (_, context) = prepare(&state, &request(Some("Be verbose"))).await.unwrap();
+        assert!(matches!(context.as_slice(), [types::OrsInputItem::Message { role: types::OrsRole::User, .. }]));
+    }
+
+    #[tokio::test]
+    async fn test_file_ids_must_belong_to_the_caller() {
+        let state = test_state().await;
+        state.db.record_file("file-mine", db::DEFAULT_TENANT_ID).await.unwrap();
+        state.db.record_file("file-theirs", "team-b").await.unwrap();
+        let request = |file_ids: &[&str]| -> types::OrsRequest {
+            serde_json::from_value(serde_json::json!({ "model": "m", "input": [], "file_ids": file_ids })).unwrap()
+        };
+
+        let err = conversation_files(&state, "conv_f", &request(&["file-mine", "file-theirs"])).await.unwrap_err();
+        assert!(matches!(err, AppError::NotFound(message) if message.contains("file-theirs")));
+
+        // Nothing is linked until the interaction is saved
+        let files = conversation_files(&state, "conv_f", &request(&["file-mine"])).await.unwrap();
+        assert_eq!(files, vec!["file-mine"]);
+        assert!(state.db.conversation_files("conv_f").await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,113 @@
+use axum::{extract::State, http::header::CONTENT_TYPE, response::IntoResponse, Json};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use crate::AppState;
+
+/// Upper bounds, in seconds, of the upstream latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 8] = [0.1, 0.5, 1.0, 2.0, 5.0, 15.0, 30.0, 60.0];
+
+/// Cumulative histogram in the Prometheus style: `counts[i]` counts observations `<= LATENCY_BUCKETS[i]`.
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct Histogram {
+    counts: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.counts.iter_mut().zip(LATENCY_BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += seconds;
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct ModelMetrics {
+    upstream_latency: Histogram,
+    /// Requests by upstream outcome: the HTTP status code, or `error` if no response arrived.
+    requests_total: BTreeMap<String, u64>,
+}
+
+/// Per-model upstream latency and request counters.
+#[derive(Clone, Default)]
+pub struct PerModelMetrics {
+    models: Arc<Mutex<HashMap<String, ModelMetrics>>>,
+}
+
+impl PerModelMetrics {
+    pub fn record(&self, model: &str, status: &str, latency: Duration) {
+        let mut models = self.models.lock().unwrap();
+        let metrics = models.entry(model.to_string()).or_default();
+        metrics.upstream_latency.observe(latency.as_secs_f64());
+        *metrics.requests_total.entry(status.to_string()).or_default() += 1;
+    }
+
+    /// Human-readable summary for operators who don't run Prometheus.
+    pub fn summary(&self) -> serde_json::Value {
+        let models = self.models.lock().unwrap();
+        let summary: BTreeMap<&str, serde_json::Value> = models
+            .iter()
+            .map(|(model, m)| {
+                let h = &m.upstream_latency;
+                let buckets: BTreeMap<String, u64> = LATENCY_BUCKETS
+                    .iter()
+                    .zip(h.counts)
+                    .map(|(bound, count)| (format!("le_{}s", bound), count))
+                    .collect();
+                let mean = if h.count == 0 { 0.0 } else { h.sum / h.count as f64 };
+                let value = serde_json::json!({
+                    "requests_total": m.requests_total,
+                    "upstream_latency_seconds": {
+                        "count": h.count,
+                        "mean": mean,
+                        "buckets": buckets,
+                    },
+                });
+                (model.as_str(), value)
+            })
+            .collect();
+        serde_json::json!({ "models": summary })
+    }
+}
+
+pub async fn model_metrics(State(state): State<AppState>) -> impl IntoResponse {
+    Json(state.model_metrics.summary())
+}
+
+pub async fn prometheus(State(state): State<AppState>) -> impl IntoResponse {
+    ([(CONTENT_TYPE, "text/plain; version=0.0.4")], format!("{}{}", state.stats.prometheus(), state.background_jobs.prometheus()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_record_per_model() {
+        let metrics = PerModelMetrics::default();
+        metrics.record("llama3", "200", Duration::from_millis(300));
+        metrics.record("llama3", "200", Duration::from_secs(3));
+        metrics.record("llama3", "error", Duration::from_secs(90));
+        metrics.record("gpt-4o", "200", Duration::from_millis(50));
+
+        let summary = metrics.summary();
+        let llama = &summary["models"]["llama3"];
+        assert_eq!(llama["requests_total"]["200"], 2);
+        assert_eq!(llama["requests_total"]["error"], 1);
+        assert_eq!(llama["upstream_latency_seconds"]["count"], 3);
+        assert_eq!(llama["upstream_latency_seconds"]["buckets"]["le_0.5s"], 1);
+        assert_eq!(llama["upstream_latency_seconds"]["buckets"]["le_5s"], 2);
+        assert_eq!(llama["upstream_latency_seconds"]["buckets"]["le_60s"], 2);
+        assert_eq!(summary["models"]["gpt-4o"]["upstream_latency_seconds"]["buckets"]["le_0.1s"], 1);
+    }
+}
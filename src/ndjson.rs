@@ -0,0 +1,153 @@
+use crate::{
+    types::{OrsInputItem, OrsRequest, ValidationError},
+    validation::Validated,
+};
+use axum::{
+    async_trait,
+    body::Bytes,
+    extract::{FromRequest, Request},
+    http::header::CONTENT_TYPE,
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::de::DeserializeOwned;
+
+const NDJSON_CONTENT_TYPE: &str = "application/x-ndjson";
+
+/// A line-delimited JSON body, one `T` per non-blank line, each with its line number (counting
+/// blank lines too, so it points into the body as sent). Buffered within the route's
+/// `DefaultBodyLimit`.
+pub struct NdJsonBody<T>(pub Vec<(usize, T)>);
+
+#[async_trait]
+impl<S: Send + Sync, T: DeserializeOwned + Send> FromRequest<S> for NdJsonBody<T> {
+    type Rejection = Response;
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let bytes = Bytes::from_request(req, state).await.map_err(IntoResponse::into_response)?;
+        let body = std::str::from_utf8(&bytes)
+            .map_err(|e| ValidationError::new("body", format!("invalid UTF-8: {}", e)).into_response())?;
+
+        let mut values = Vec::new();
+        for (i, line) in body.split('\n').enumerate() {
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            if line.trim().is_empty() {
+                continue;
+            }
+            values.push((i + 1, parse_line(line, i + 1).map_err(IntoResponse::into_response)?));
+        }
+
+        Ok(Self(values))
+    }
+}
+
+fn parse_line<T: DeserializeOwned>(line: &str, line_number: usize) -> Result<T, ValidationError> {
+    serde_json::from_str(line).map_err(|e| bad_line(line_number, e))
+}
+
+fn bad_line(line_number: usize, error: impl std::fmt::Display) -> ValidationError {
+    ValidationError::new("body", format!("line {}: {}", line_number, error))
+}
+
+/// The body of `POST /v1/responses`: either a JSON `OrsRequest`, or with
+/// `Content-Type: application/x-ndjson`, the request (minus or including `input`) on the first
+/// line followed by one input item per line, appended to `input` in order.
+pub struct OrsRequestBody(pub OrsRequest);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequest<S> for OrsRequestBody {
+    type Rejection = Response;
+
+    async fn from_request(mut req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(body) = req.extensions_mut().remove::<Validated<Self>>().and_then(|validated| validated.take()) {
+            return Ok(body);
+        }
+        let is_ndjson = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|v| v.starts_with(NDJSON_CONTENT_TYPE));
+        if !is_ndjson {
+            let Json(payload) = Json::<OrsRequest>::from_request(req, state)
+                .await
+                .map_err(IntoResponse::into_response)?;
+            return Ok(Self(payload));
+        }
+
+        let NdJsonBody(lines) = NdJsonBody::<serde_json::Value>::from_request(req, state).await?;
+        let mut lines = lines.into_iter();
+        let Some((header_line, mut header)) = lines.next() else {
+            return Err(ValidationError::new("body", "empty request body").into_response());
+        };
+        if let Some(fields) = header.as_object_mut() {
+            fields.entry("input").or_insert_with(|| serde_json::json!([]));
+        }
+        let mut payload: OrsRequest = serde_json::from_value(header).map_err(|e| bad_line(header_line, e).into_response())?;
+
+        for (line_number, line) in lines {
+            let item: OrsInputItem =
+                serde_json::from_value(line).map_err(|e| bad_line(line_number, e).into_response())?;
+            payload.input.push(item);
+        }
+
+        Ok(Self(payload))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, http::StatusCode};
+
+    fn ndjson_request(body: &'static str) -> Request {
+        Request::builder()
+            .header(CONTENT_TYPE, NDJSON_CONTENT_TYPE)
+            .body(Body::from(body))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_request_appends_input_lines() {
+        let body = "{\"model\":\"gpt-4o\"}\n\
+            {\"type\":\"message\",\"role\":\"user\",\"content\":[{\"type\":\"input_text\",\"text\":\"Hi\"}]}\r\n\n\
+            {\"type\":\"message\",\"role\":\"user\",\"content\":[{\"type\":\"input_text\",\"text\":\"There\"}]}";
+        let OrsRequestBody(payload) = OrsRequestBody::from_request(ndjson_request(body), &()).await.ok().unwrap();
+
+        assert_eq!(payload.model, "gpt-4o");
+        assert_eq!(payload.input.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_reports_failing_line() {
+        let body = "{\"model\":\"gpt-4o\"}\n{\"type\":\"message\",\"role\":\"user\",\"content\":[]}\n{\"type\":\"bogus\"}\n";
+        let response = OrsRequestBody::from_request(ndjson_request(body), &()).await.err().unwrap();
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["error"]["message"].as_str().unwrap().starts_with("line 3:"));
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_line_numbers_count_blank_lines() {
+        let body = "{\"model\":\"gpt-4o\"}\n\n\r\n{\"type\":\"bogus\"}\n";
+        let response = OrsRequestBody::from_request(ndjson_request(body), &()).await.err().unwrap();
+
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["error"]["message"].as_str().unwrap().starts_with("line 4:"));
+    }
+
+    #[tokio::test]
+    async fn test_ndjson_body_respects_the_body_limit() {
+        let app = axum::Router::new()
+            .route("/", axum::routing::post(|OrsRequestBody(req): OrsRequestBody| async move { req.model }))
+            .layer(axum::extract::DefaultBodyLimit::max(16));
+        let req = Request::post("/")
+            .header(CONTENT_TYPE, NDJSON_CONTENT_TYPE)
+            .body(Body::from("{\"model\":\"gpt-4o\"}\n"))
+            .unwrap();
+        let res = tower::ServiceExt::oneshot(app, req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
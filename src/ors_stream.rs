@@ -0,0 +1,568 @@
+use crate::{
+    adapter::StreamTranscoder,
+    sse_codec::{SseCodec, SseError},
+    transcoder,
+    types::OrsEvent,
+    upstream, writer, AppState,
+};
+use futures::{future::BoxFuture, Stream, StreamExt};
+use std::{
+    collections::VecDeque,
+    future::Future,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+use tokio_util::{codec::FramedRead, io::StreamReader};
+
+type UpstreamBody = StreamReader<futures::stream::BoxStream<'static, io::Result<bytes::Bytes>>, bytes::Bytes>;
+
+/// Where the stream is in its life.
+enum Phase {
+    /// Reading lines from the upstream body.
+    Reading(FramedRead<UpstreamBody, SseCodec>),
+    /// Re-sending the request after the upstream dropped the connection, waiting out a 429
+    /// first if it's a rate limit retry.
+    Resending {
+        send: BoxFuture<'static, io::Result<reqwest::Response>>,
+        /// To send again if this attempt is rate limited.
+        builder: Option<reqwest::RequestBuilder>,
+        rate_limit_retries: u32,
+    },
+    /// Handing the interaction to the background writer.
+    Saving(BoxFuture<'static, ()>),
+    Done,
+}
+
+/// Transcoded upstream events, ending with the interaction saved.
+///
+/// Lines of the upstream body go through [`SseCodec`] and the transcoder; the events they
+/// produce are kept for storage and yielded to the caller. A connection reset before anything
+/// was yielded re-sends the request once; other read failures end the response with a
+/// `response.error` event rather than an error item. When the upstream ends, the transcoder's
+/// closing events and `response.done` follow, the request's stats and audit entry are settled,
+/// and the interaction is submitted to the DB writer before the stream ends.
+pub struct OrsStream {
+    phase: Phase,
+    /// Events waiting to be yielded, in order.
+    pending: VecDeque<OrsEvent>,
+    retry_builder: Option<reqwest::RequestBuilder>,
+    transcoder: Box<dyn StreamTranscoder>,
+    state: AppState,
+    /// Taken when the interaction is saved.
+    interaction: Option<writer::Interaction>,
+    accumulated_events: Vec<OrsEvent>,
+    failed: bool,
+    /// When the upstream is cut off (`SSE_MAX_DURATION_SECS`).
+    deadline: Option<Pin<Box<tokio::time::Sleep>>>,
+    timed_out: bool,
+}
+
+impl OrsStream {
+    pub fn new(
+        res: reqwest::Response,
+        retry_builder: Option<reqwest::RequestBuilder>,
+        transcoder: Box<dyn StreamTranscoder>,
+        state: AppState,
+        interaction: writer::Interaction,
+    ) -> Self {
+        // Stays that way if the stream ends early with an error
+        state.update_audit(|entry| entry.finish_reason = Some("failed".to_string()));
+        Self {
+            phase: Phase::Reading(read_lines(&state, res)),
+            pending: VecDeque::new(),
+            retry_builder,
+            transcoder,
+            interaction: Some(interaction),
+            accumulated_events: Vec::new(),
+            failed: false,
+            deadline: state.sse_max_duration.map(|max| Box::pin(tokio::time::sleep(max))),
+            timed_out: false,
+            state,
+        }
+    }
+
+    /// Queues events for the caller, keeping them for storage.
+    fn push(&mut self, events: impl IntoIterator<Item = OrsEvent>) {
+        for event in events {
+            self.accumulated_events.push(event.clone());
+            self.pending.push_back(event);
+        }
+    }
+
+    fn handle_line(&mut self, line: &str) {
+        let Some(json_str) = self.transcoder.payload(line.trim()) else { return };
+        match self.transcoder.transcode_chunk(json_str) {
+            Ok(events) => self.push(events),
+            Err(e) => tracing::warn!("{}", e),
+        }
+    }
+
+    /// The phase after a failed read. Unless the request can be re-sent, the stream ends with
+    /// a `response.error` event so clients can tell a broken stream from a finished one.
+    fn handle_read_error(&mut self, e: SseError) -> Phase {
+        match e {
+            SseError::Io(e) => {
+                if is_connection_reset(&e) {
+                    tracing::warn!("Upstream connection reset mid-stream: {}", e);
+                    // Only safe to retry while the client hasn't seen any output yet
+                    if let Some(req) = self.retry_builder.take().filter(|_| self.accumulated_events.is_empty()) {
+                        tracing::warn!("Retrying upstream request once");
+                        let builder = req.try_clone();
+                        return Phase::Resending { send: send(req), builder, rate_limit_retries: 0 };
+                    }
+                }
+                self.abort("connection_error", "upstream connection lost", &e)
+            }
+            e @ SseError::LineTooLong { .. } => self.abort("line_too_long", e.to_string(), &e),
+            e => self.abort("upstream_error", e.to_string(), &e),
+        }
+    }
+
+    /// Ends the stream with a `response.error` event, saving what was generated so far.
+    fn abort(&mut self, code: &str, message: impl Into<String>, cause: &dyn std::fmt::Display) -> Phase {
+        tracing::error!("Aborting upstream stream: {}", cause);
+        let event = self.transcoder.error(code, message.into());
+        self.push([event]);
+        self.failed = true;
+        self.finish()
+    }
+
+    /// Ends a response that ran past its deadline: its open items closed as incomplete,
+    /// `response.error`, estimated usage, then `response.done` with the output so far, which is
+    /// saved like any other.
+    fn time_out(&mut self) -> Phase {
+        let chars: usize = self
+            .accumulated_events
+            .iter()
+            .map(|event| match event {
+                OrsEvent::TextDelta { delta, .. }
+                | OrsEvent::ReasoningDelta { delta, .. }
+                | OrsEvent::FunctionCallArgumentsDelta { delta, .. } => delta.len(),
+                _ => 0,
+            })
+            .sum();
+        let conversation_id = self.interaction.as_ref().map_or("", |interaction| interaction.conversation_id.as_str());
+        tracing::warn!(
+            "Stream for {} exceeded its maximum duration, closing it after ~{} output tokens",
+            conversation_id,
+            upstream::estimate_tokens(chars)
+        );
+        let events = self.transcoder.interrupt();
+        self.push(events);
+        let event = self.transcoder.error("timeout", "stream exceeded maximum duration".to_string());
+        self.push([event]);
+        self.failed = true;
+        self.timed_out = true;
+        self.finish()
+    }
+
+    /// The phase once the re-sent request has answered: another attempt after a rate limit,
+    /// reading its body, or if it still failed, the end of the response.
+    fn handle_resent(&mut self, res: reqwest::Response, builder: Option<reqwest::RequestBuilder>, retries: u32) -> Phase {
+        if retries < upstream::MAX_RATE_LIMIT_RETRIES {
+            if let Some(wait) = upstream::rate_limit_delay(&res, self.state.max_retry_after) {
+                if let Some(req) = builder.as_ref().and_then(reqwest::RequestBuilder::try_clone) {
+                    let model = self.interaction.as_ref().map_or("", |interaction| interaction.model.as_str());
+                    tracing::warn!("Upstream rate limited {}, retrying in {}ms", model, wait.as_millis());
+                    self.state.stats.record_rate_limit_retry();
+                    // The client is already waiting on the stream, so tell it why nothing's happening
+                    let event = self.transcoder.queued(wait);
+                    self.pending.push_back(event);
+                    let send = Box::pin(async move {
+                        tokio::time::sleep(wait).await;
+                        req.send().await.map_err(io::Error::other)
+                    });
+                    return Phase::Resending { send, builder, rate_limit_retries: retries + 1 };
+                }
+            }
+        }
+        let status = res.status();
+        if !status.is_success() {
+            self.state.update_audit(|entry| entry.upstream_status = Some(status.as_u16()));
+            let message = format!("upstream answered the retried request with {}", status);
+            return self.abort("upstream_error", message.clone(), &message);
+        }
+        Phase::Reading(read_lines(&self.state, res))
+    }
+
+    /// Closes the response once the upstream has ended and starts saving the interaction.
+    fn finish(&mut self) -> Phase {
+        if !self.failed || self.timed_out {
+            // Best-effort usage when the upstream didn't report any
+            let events = self.transcoder.finish();
+            self.push(events);
+        }
+        if self.failed {
+            self.state.stats.record_failure();
+        } else {
+            self.state.stats.record_success();
+        }
+        // Unless an error ended the response early; a timeout still reports the partial output
+        if self.timed_out || (!self.failed && transcoder::response_status(&self.accumulated_events) != "failed") {
+            let model = self.interaction.as_ref().map_or("", |interaction| interaction.model.as_str());
+            let mut response = transcoder::collect_response(model, &self.accumulated_events);
+            let output = match response["output"].take() {
+                serde_json::Value::Array(output) => output,
+                _ => Vec::new(),
+            };
+            let usage = Some(response["usage"].take()).filter(|usage| !usage.is_null());
+            let status = response["status"].as_str().unwrap_or("completed");
+            let done = self.transcoder.done(model, status, output, usage);
+            self.push([done]);
+        }
+
+        let output_tokens = self.accumulated_events.iter().find_map(|event| match event {
+            OrsEvent::CompletionUsage { output_tokens, .. } => Some(i64::from(*output_tokens)),
+            _ => None,
+        });
+        let status = transcoder::response_status(&self.accumulated_events);
+        self.state.update_audit(|entry| {
+            entry.output_token_count = output_tokens;
+            entry.finish_reason = Some(status.to_string());
+        });
+
+        let Some(interaction) = self.interaction.take() else {
+            return Phase::Done;
+        };
+        // Post-stream persistence, handed to the background writer
+        let save = writer::SaveRequest { interaction, events: std::mem::take(&mut self.accumulated_events) };
+        let db_writer = self.state.db_writer.clone();
+        Phase::Saving(Box::pin(async move { db_writer.submit(save).await }))
+    }
+}
+
+impl Stream for OrsStream {
+    type Item = io::Result<OrsEvent>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let this = &mut *self;
+        loop {
+            if let Some(event) = this.pending.pop_front() {
+                return Poll::Ready(Some(Ok(event)));
+            }
+            if let (Phase::Reading(_) | Phase::Resending { .. }, Some(deadline)) = (&this.phase, &mut this.deadline) {
+                if deadline.as_mut().poll(cx).is_ready() {
+                    this.deadline = None;
+                    this.phase = this.time_out();
+                    continue;
+                }
+            }
+            let next = match &mut this.phase {
+                Phase::Reading(lines) => match ready!(lines.poll_next_unpin(cx)) {
+                    Some(Ok(line)) => {
+                        this.handle_line(&line);
+                        continue;
+                    }
+                    Some(Err(e)) => this.handle_read_error(e),
+                    None => this.finish(),
+                },
+                Phase::Resending { send, builder, rate_limit_retries } => match ready!(send.as_mut().poll(cx)) {
+                    Ok(res) => {
+                        let (builder, retries) = (builder.take(), *rate_limit_retries);
+                        this.handle_resent(res, builder, retries)
+                    }
+                    Err(e) => this.abort("connection_error", "upstream connection lost", &e),
+                },
+                Phase::Saving(save) => {
+                    ready!(save.as_mut().poll(cx));
+                    Phase::Done
+                }
+                Phase::Done => return Poll::Ready(None),
+            };
+            this.phase = next;
+        }
+    }
+}
+
+fn read_lines(state: &AppState, res: reqwest::Response) -> FramedRead<UpstreamBody, SseCodec> {
+    let body = StreamReader::new(res.bytes_stream().map(|chunk| chunk.map_err(io::Error::other)).boxed());
+    let codec = SseCodec::new()
+        .with_strict_utf8(state.strict_utf8)
+        .with_max_line_length(state.max_sse_line_bytes);
+    FramedRead::with_capacity(body, codec, state.stream_buffer_size)
+}
+
+fn send(req: reqwest::RequestBuilder) -> BoxFuture<'static, io::Result<reqwest::Response>> {
+    Box::pin(async move { req.send().await.map_err(io::Error::other) })
+}
+
+/// Whether a read error from the upstream body is a dropped connection; see
+/// [`upstream::is_connection_reset`].
+fn is_connection_reset(e: &io::Error) -> bool {
+    e.get_ref()
+        .and_then(|inner| inner.downcast_ref::<reqwest::Error>())
+        .is_some_and(upstream::is_connection_reset)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        adapter::StreamOptions,
+        db::DEFAULT_TENANT_ID,
+        tests::test_state,
+        types::{OrsContentPart, OrsInputItem, OrsRole},
+    };
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+        time::Duration,
+    };
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    fn interaction(conversation_id: &str) -> writer::Interaction {
+        writer::Interaction {
+            conversation_id: conversation_id.to_string(),
+            tenant_id: DEFAULT_TENANT_ID.to_string(),
+            model: "m".to_string(),
+            upstream_latency_ms: Some(1),
+            input: vec![OrsInputItem::Message {
+                role: OrsRole::User,
+                content: vec![OrsContentPart::InputText { text: "Hi".to_string() }],
+            }],
+            instructions: None,
+            metadata: None,
+            webhook_url: None,
+            allow_private_webhook: false,
+            file_ids: vec![],
+        }
+    }
+
+    /// An SSE line carrying a chat completion chunk with `content`.
+    fn data(content: &str) -> String {
+        let chunk = serde_json::json!({ "choices": [{ "index": 0, "delta": { "content": content }, "finish_reason": null }] });
+        format!("data: {}\n\n", chunk)
+    }
+
+    fn chunk(content: &str) -> io::Result<bytes::Bytes> {
+        Ok(bytes::Bytes::from(data(content)))
+    }
+
+    /// The rest of a streamed "Hello": its finish reason and the end of the stream.
+    const HELLO_END: &str = "data: {\"choices\":[{\"index\":0,\"delta\":{},\"finish_reason\":\"stop\"}]}\n\ndata: [DONE]\n\n";
+
+    /// A complete streaming answer of `body`.
+    fn streamed(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\nconnection: close\r\ncontent-length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    }
+
+    /// A streaming answer that promises more than `body`, so hanging up after it resets the stream.
+    fn cut_off(body: &str) -> String {
+        format!(
+            "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\ncontent-length: {}\r\n\r\n{}",
+            body.len() + 1000,
+            body
+        )
+    }
+
+    const RATE_LIMITED: &str =
+        "HTTP/1.1 429 Too Many Requests\r\nretry-after: 0\r\nconnection: close\r\ncontent-length: 0\r\n\r\n";
+
+    /// An upstream answering its nth connection with the nth of `responses`, then hanging up.
+    /// Returns its URL and how many connections it has accepted.
+    async fn mock_upstream(responses: Vec<String>) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let accepted = Arc::new(AtomicUsize::new(0));
+        let counter = accepted.clone();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                counter.fetch_add(1, Ordering::SeqCst);
+                let _ = sock.read(&mut [0u8; 4096]).await;
+                let _ = sock.write_all(response.as_bytes()).await;
+            }
+        });
+        (url, accepted)
+    }
+
+    /// Streams a request to the mock upstream at `url` through an [`OrsStream`] that may re-send it.
+    async fn stream_from(state: &AppState, url: &str, conversation_id: &str) -> Vec<OrsEvent> {
+        let client = reqwest::Client::new();
+        let res = client.post(url).body("{}").send().await.unwrap();
+        let transcoder = state.upstream_adapter.stream(StreamOptions::default());
+        let retry = Some(client.post(url).body("{}"));
+        let stream = OrsStream::new(res, retry, transcoder, state.clone(), interaction(conversation_id));
+        stream.map(Result::unwrap).collect().await
+    }
+
+    /// The events of a response streaming "Hello", through to its end.
+    const COMPLETED: [&str; 8] = [
+        "response.created",
+        "response.output_item.added",
+        "response.content_part.added",
+        "response.output_text.delta",
+        "response.content_part.done",
+        "response.output_item.done",
+        "response.completed",
+        "response.done",
+    ];
+
+    fn assert_saved_hello(items: &[OrsInputItem]) {
+        assert_eq!(items.len(), 2);
+        assert!(matches!(&items[1], OrsInputItem::Message { role: OrsRole::Assistant, content }
+            if matches!(content.as_slice(), [OrsContentPart::InputText { text }] if text == "Hello")));
+    }
+
+    /// A streaming upstream response with `body` as its body.
+    fn upstream(body: impl Stream<Item = io::Result<bytes::Bytes>> + Send + 'static) -> reqwest::Response {
+        let res = axum::http::Response::builder()
+            .header("content-type", "text/event-stream")
+            .body(reqwest::Body::wrap_stream(body))
+            .unwrap();
+        reqwest::Response::from(res)
+    }
+
+    fn event_types(events: &[OrsEvent]) -> Vec<String> {
+        events
+            .iter()
+            .map(|event| serde_json::to_value(event).unwrap()["type"].as_str().unwrap_or_default().to_string())
+            .collect()
+    }
+
+    /// The stored context of the conversation, once the DB writer has got to it.
+    async fn saved(state: &AppState, conversation_id: &str) -> Vec<OrsInputItem> {
+        for _ in 0..100 {
+            let items = state.db.load_context(conversation_id, DEFAULT_TENANT_ID).await.unwrap();
+            if !items.is_empty() {
+                return items;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        Vec::new()
+    }
+
+    #[tokio::test]
+    async fn test_time_out_closes_the_response() {
+        let mut state = test_state().await;
+        state.sse_max_duration = Some(Duration::from_secs(10));
+        // The upstream sends a little text, then nothing more
+        let body = futures::stream::iter([chunk("Hello")]).chain(futures::stream::pending());
+        let transcoder = state.upstream_adapter.stream(StreamOptions::default());
+
+        tokio::time::pause();
+        let stream = OrsStream::new(upstream(body), None, transcoder, state.clone(), interaction("conv_t"));
+        let events: Vec<OrsEvent> = stream.map(Result::unwrap).collect().await;
+        tokio::time::resume();
+
+        assert_eq!(
+            event_types(&events),
+            vec![
+                "response.created",
+                "response.output_item.added",
+                "response.content_part.added",
+                "response.output_text.delta",
+                "response.content_part.done",
+                "response.output_item.done",
+                "response.error",
+                "response.completed",
+                "response.done",
+            ]
+        );
+        assert!(matches!(&events[5], OrsEvent::ItemDone { item, .. } if item.status() == "incomplete"));
+        assert!(matches!(&events[7], OrsEvent::CompletionUsage { output_tokens: 2, is_approximate: true, .. }));
+
+        let items = saved(&state, "conv_t").await;
+        assert_eq!(items.len(), 2);
+        assert!(matches!(&items[1], OrsInputItem::Message { role: OrsRole::Assistant, content }
+            if matches!(content.as_slice(), [OrsContentPart::InputText { text }] if text == "Hello")));
+    }
+
+    #[tokio::test]
+    async fn test_normal_end() {
+        let state = test_state().await;
+        let (url, accepted) = mock_upstream(vec![streamed(&format!("{}{}", data("Hello"), HELLO_END))]).await;
+
+        let events = stream_from(&state, &url, "conv_n").await;
+        assert_eq!(event_types(&events), COMPLETED);
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        assert_saved_hello(&saved(&state, "conv_n").await);
+        assert_eq!(state.stats.snapshot()["failed_requests"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_reset_before_output_resends() {
+        let state = test_state().await;
+        let (url, accepted) =
+            mock_upstream(vec![cut_off(""), streamed(&format!("{}{}", data("Hello"), HELLO_END))]).await;
+
+        let events = stream_from(&state, &url, "conv_rb").await;
+        assert_eq!(event_types(&events), COMPLETED);
+        assert_eq!(accepted.load(Ordering::SeqCst), 2);
+        assert_saved_hello(&saved(&state, "conv_rb").await);
+    }
+
+    #[tokio::test]
+    async fn test_reset_after_output_ends_with_error() {
+        let state = test_state().await;
+        let (url, accepted) = mock_upstream(vec![cut_off(&data("Hello")), streamed(HELLO_END)]).await;
+
+        let events = stream_from(&state, &url, "conv_ra").await;
+        // The client has seen output, so the request isn't sent again
+        assert_eq!(
+            event_types(&events),
+            vec![
+                "response.created",
+                "response.output_item.added",
+                "response.content_part.added",
+                "response.output_text.delta",
+                "response.error",
+            ]
+        );
+        assert_eq!(accepted.load(Ordering::SeqCst), 1);
+        // What was generated is kept, though the response failed
+        assert_saved_hello(&saved(&state, "conv_ra").await);
+        assert_eq!(state.stats.snapshot()["failed_requests"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_resend_waits_and_retries() {
+        let state = test_state().await;
+        let (url, accepted) = mock_upstream(vec![
+            cut_off(""),
+            RATE_LIMITED.to_string(),
+            streamed(&format!("{}{}", data("Hello"), HELLO_END)),
+        ])
+        .await;
+
+        let events = stream_from(&state, &url, "conv_rl").await;
+        // The client is told it's waiting, then gets the whole response
+        assert_eq!(event_types(&events[..1]), ["response.queued"]);
+        assert_eq!(event_types(&events[1..]), COMPLETED);
+        assert_eq!(accepted.load(Ordering::SeqCst), 3);
+        assert_saved_hello(&saved(&state, "conv_rl").await);
+        assert_eq!(state.stats.snapshot()["upstream_rate_limit_retries_total"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_failed_resend_ends_with_error() {
+        let state = test_state().await;
+        let server_error =
+            "HTTP/1.1 500 Internal Server Error\r\nconnection: close\r\ncontent-length: 0\r\n\r\n".to_string();
+        let (url, _) = mock_upstream(vec![cut_off(""), server_error]).await;
+
+        let events = stream_from(&state, &url, "conv_fr").await;
+        assert_eq!(event_types(&events), ["response.error"]);
+        assert_eq!(state.stats.snapshot()["failed_requests"], 1);
+
+        // Likewise once the rate limit retries are used up
+        let mut responses = vec![cut_off("")];
+        responses.extend((0..=upstream::MAX_RATE_LIMIT_RETRIES).map(|_| RATE_LIMITED.to_string()));
+        let (url, accepted) = mock_upstream(responses).await;
+        let events = stream_from(&state, &url, "conv_fr").await;
+        let types = event_types(&events);
+        assert_eq!(types.last().map(String::as_str), Some("response.error"));
+        assert_eq!(types.len(), upstream::MAX_RATE_LIMIT_RETRIES as usize + 1);
+        assert_eq!(accepted.load(Ordering::SeqCst), upstream::MAX_RATE_LIMIT_RETRIES as usize + 2);
+    }
+}
@@ -0,0 +1,223 @@
+use crate::{
+    error_response, event_name, sse_event_id,
+    tenants::TenantId,
+    transcoder::collect_response,
+    types::{OrsContentPart, OrsEvent, OrsInputItem, OrsRole, ResponseItem},
+    AppState,
+};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{sse::Event, IntoResponse, Response, Sse},
+    Json,
+};
+
+/// Characters per replayed `response.output_text.delta`, roughly one upstream token's worth.
+const REPLAY_CHUNK_CHARS: usize = 8;
+
+/// Re-streams the last stored response of a conversation without calling the upstream.
+pub async fn replay_response(
+    State(state): State<AppState>,
+    TenantId(tenant_id): TenantId,
+    Path(id): Path<String>,
+) -> Response {
+    let items = match state.db.load_context(&id, &tenant_id).await {
+        Ok(items) => items,
+        Err(e) => {
+            tracing::error!("Failed to load context for {}: {}", id, e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to load response");
+        }
+    };
+
+    let events = replay_events(&id, &items);
+    if events.is_empty() {
+        return error_response(StatusCode::NOT_FOUND, "not_found", format!("Response {} not found", id));
+    }
+
+    let delay = state.replay_delay;
+    let stream = async_stream::stream! {
+        for event in events {
+            if !delay.is_zero() {
+                tokio::time::sleep(delay).await;
+            }
+            let mut sse_event = Event::default().event(event_name(&event));
+            if let Some(seq) = event.sequence_number() {
+                sse_event = sse_event.id(sse_event_id(&id, seq));
+            }
+            yield sse_event.json_data(&event);
+        }
+    };
+
+    Sse::new(stream).into_response()
+}
+
+/// Polls a response, typically one started with `background: true`. Its output is included
+/// once it has finished.
+pub async fn get_response(
+    State(state): State<AppState>,
+    TenantId(tenant_id): TenantId,
+    Path(id): Path<String>,
+) -> Response {
+    let status = match state.db.get_response_status(&id, &tenant_id).await {
+        Ok(Some(status)) => status,
+        Ok(None) => return error_response(StatusCode::NOT_FOUND, "not_found", format!("Response {} not found", id)),
+        Err(e) => {
+            tracing::error!("Failed to load status for {}: {}", id, e);
+            return error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to load response");
+        }
+    };
+
+    let mut body = serde_json::json!({ "id": id, "object": "response", "status": status });
+    if !matches!(status.as_str(), "queued" | "in_progress") {
+        match state.db.load_context(&id, &tenant_id).await {
+            Ok(items) => body["output"] = collect_response("", &replay_events(&id, &items))["output"].take(),
+            Err(e) => {
+                tracing::error!("Failed to load context for {}: {}", id, e);
+                return error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to load response");
+            }
+        }
+    }
+
+    Json(body).into_response()
+}
+
+/// Rebuilds the event sequence of the last response from stored items: everything after the
+/// final user message or tool result is treated as output.
+pub fn replay_events(response_id: &str, items: &[OrsInputItem]) -> Vec<OrsEvent> {
+    let output_start = items
+        .iter()
+        .rposition(|item| match item {
+            OrsInputItem::Message { role, .. } => *role != OrsRole::Assistant,
+            OrsInputItem::FunctionCallOutput { .. } | OrsInputItem::ComputerToolResult { .. } => true,
+            OrsInputItem::FunctionCall { .. } => false,
+        })
+        .map_or(0, |i| i + 1);
+    let outputs = &items[output_start..];
+    if outputs.is_empty() {
+        return Vec::new();
+    }
+
+    let mut seq = 0u32;
+    let mut next_seq = || {
+        let current = seq;
+        seq += 1;
+        Some(current)
+    };
+
+    let mut events = vec![OrsEvent::Created {
+        id: response_id.to_string(),
+        created_at: crate::db::now_secs() as u64,
+        sequence_number: next_seq(),
+    }];
+
+    for (index, item) in outputs.iter().enumerate() {
+        let output_index = Some(index as u32);
+        match item {
+            OrsInputItem::Message { content, .. } => {
+                let item_id = format!("msg_{}", index);
+                let text: String = content
+                    .iter()
+                    .filter_map(|part| match part {
+                        OrsContentPart::InputText { text } => Some(text.as_str()),
+                        OrsContentPart::InputImage { .. } | OrsContentPart::InputFile { .. } => None,
+                    })
+                    .collect();
+
+                events.push(OrsEvent::ItemAdded { sequence_number: next_seq(), output_index, item: ResponseItem::message(&item_id) });
+                events.push(OrsEvent::ContentPartAdded {
+                    sequence_number: next_seq(),
+                    item_id: item_id.clone(),
+                    output_index,
+                    content_index: Some(0),
+                    part: serde_json::json!({ "type": "output_text", "text": "" }),
+                });
+                let chars: Vec<char> = text.chars().collect();
+                for chunk in chars.chunks(REPLAY_CHUNK_CHARS) {
+                    events.push(OrsEvent::TextDelta {
+                        sequence_number: next_seq(),
+                        item_id: item_id.clone(),
+                        output_index,
+                        content_index: Some(0),
+                        delta: chunk.iter().collect(),
+                        logprobs: None,
+                    });
+                }
+                events.push(OrsEvent::ContentPartDone {
+                    sequence_number: next_seq(),
+                    item_id: item_id.clone(),
+                    output_index,
+                    content_index: Some(0),
+                    part: serde_json::json!({ "type": "output_text", "text": text }),
+                });
+                let mut item = ResponseItem::message(item_id);
+                item.set_status("completed");
+                events.push(OrsEvent::ItemDone { sequence_number: next_seq(), output_index, item });
+            }
+            OrsInputItem::FunctionCall { id, call_id, name, arguments } => {
+                let arguments = match arguments {
+                    serde_json::Value::String(s) => s.clone(),
+                    other => other.to_string(),
+                };
+                let mut item = ResponseItem::function_call(id, call_id, name);
+                events.push(OrsEvent::ItemAdded { sequence_number: next_seq(), output_index, item: item.clone() });
+                events.push(OrsEvent::FunctionCallArgumentsDelta {
+                    sequence_number: next_seq(),
+                    item_id: id.clone(),
+                    output_index,
+                    delta: arguments.clone(),
+                });
+                if let ResponseItem::FunctionCall { arguments: done_arguments, .. } = &mut item {
+                    *done_arguments = arguments;
+                }
+                item.set_status("completed");
+                events.push(OrsEvent::ItemDone { sequence_number: next_seq(), output_index, item });
+            }
+            OrsInputItem::FunctionCallOutput { .. } | OrsInputItem::ComputerToolResult { .. } => unreachable!("outputs start after the last tool result"),
+        }
+    }
+
+    events
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(role: OrsRole, text: &str) -> OrsInputItem {
+        OrsInputItem::Message {
+            role,
+            content: vec![OrsContentPart::InputText { text: text.to_string() }],
+        }
+    }
+
+    #[test]
+    fn test_replay_only_trailing_outputs() {
+        let items = vec![
+            message(OrsRole::User, "first"),
+            message(OrsRole::Assistant, "old answer"),
+            message(OrsRole::User, "second"),
+            message(OrsRole::Assistant, "Hello, world!"),
+        ];
+        let events = replay_events("conv_1", &items);
+
+        assert!(matches!(&events[0], OrsEvent::Created { id, .. } if id == "conv_1"));
+        let text: String = events
+            .iter()
+            .filter_map(|e| match e {
+                OrsEvent::TextDelta { delta, .. } => Some(delta.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(text, "Hello, world!");
+        assert!(matches!(events.last(), Some(OrsEvent::ItemDone { .. })));
+
+        let seqs: Vec<u32> = events.iter().filter_map(|e| e.sequence_number()).collect();
+        assert_eq!(seqs, (0..events.len() as u32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_replay_without_output_is_empty() {
+        let items = vec![message(OrsRole::User, "pending")];
+        assert!(replay_events("conv_1", &items).is_empty());
+    }
+}
@@ -0,0 +1,114 @@
+use crate::logging;
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    response::Response,
+};
+use futures::future::BoxFuture;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+use tracing::Instrument;
+use uuid::Uuid;
+
+static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// The request's ID, taken from `X-Request-ID` or generated; available to handlers as an
+/// `Extension<RequestId>`.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub String);
+
+/// Tags every request with an ID before routing: stores it in the request extensions, opens
+/// the `request` span all of its logs are recorded under, and echoes it as `X-Request-ID`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RequestIdLayer;
+
+impl<S> Layer<S> for RequestIdLayer {
+    type Service = RequestIdService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequestIdService { inner }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct RequestIdService<S> {
+    inner: S,
+}
+
+impl<S> Service<Request> for RequestIdService<S>
+where
+    S: Service<Request, Response = Response>,
+    S::Future: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = BoxFuture<'static, Result<Response, S::Error>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request) -> Self::Future {
+        let request_id = req
+            .headers()
+            .get(&X_REQUEST_ID)
+            .and_then(|v| v.to_str().ok())
+            .map(str::to_string)
+            .unwrap_or_else(|| Uuid::new_v4().to_string());
+        req.extensions_mut().insert(RequestId(request_id.clone()));
+
+        let span = tracing::info_span!(
+            "request",
+            request_id = %request_id,
+            method = %req.method(),
+            path = %req.uri().path(),
+            log_sample = logging::draw_sample(),
+            conversation_id = tracing::field::Empty,
+            model = tracing::field::Empty,
+            user = tracing::field::Empty,
+            tenant = tracing::field::Empty,
+        );
+        let future = span.in_scope(|| self.inner.call(req));
+
+        Box::pin(
+            async move {
+                let mut res = future.await?;
+                if let Ok(value) = HeaderValue::from_str(&request_id) {
+                    res.headers_mut().insert(X_REQUEST_ID.clone(), value);
+                }
+                Ok(res)
+            }
+            .instrument(span),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Extension, Router};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/", get(|Extension(RequestId(id)): Extension<RequestId>| async move { id }))
+            .layer(RequestIdLayer)
+    }
+
+    #[tokio::test]
+    async fn test_request_id_propagated_from_header() {
+        let req = Request::builder().uri("/").header("x-request-id", "req-123").body(Body::empty()).unwrap();
+        let res = app().oneshot(req).await.unwrap();
+
+        assert_eq!(res.headers()["x-request-id"], "req-123");
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"req-123");
+    }
+
+    #[tokio::test]
+    async fn test_request_id_generated_when_missing() {
+        let res = app().oneshot(Request::builder().uri("/").body(Body::empty()).unwrap()).await.unwrap();
+        let id = res.headers()["x-request-id"].to_str().unwrap();
+        assert!(Uuid::parse_str(id).is_ok());
+    }
+}
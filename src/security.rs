@@ -0,0 +1,68 @@
+use axum::{
+    extract::Request,
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+
+const HSTS: &str = "max-age=31536000; includeSubDomains";
+
+/// Adds browser hardening headers to every response. HSTS is only sent for requests that
+/// arrived over TLS, which for this proxy means via a terminating proxy that sets
+/// `X-Forwarded-Proto: https`.
+pub async fn security_headers(req: Request, next: Next) -> Response {
+    let over_tls = req.uri().scheme_str() == Some("https")
+        || req
+            .headers()
+            .get("x-forwarded-proto")
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|proto| proto.eq_ignore_ascii_case("https"));
+
+    let mut response = next.run(req).await;
+    let headers = response.headers_mut();
+    headers.insert(HeaderName::from_static("x-content-type-options"), HeaderValue::from_static("nosniff"));
+    headers.insert(HeaderName::from_static("x-frame-options"), HeaderValue::from_static("DENY"));
+    headers.insert(HeaderName::from_static("referrer-policy"), HeaderValue::from_static("no-referrer"));
+    if over_tls {
+        headers.insert(HeaderName::from_static("strict-transport-security"), HeaderValue::from_static(HSTS));
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{body::Body, routing::get, Router};
+    use tower::ServiceExt;
+
+    fn app() -> Router {
+        Router::new()
+            .route("/health", get(|| async { "OK" }))
+            .layer(axum::middleware::from_fn(security_headers))
+    }
+
+    #[tokio::test]
+    async fn test_security_headers_present() {
+        let res = app().oneshot(Request::builder().uri("/health").body(Body::empty()).unwrap()).await.unwrap();
+        let headers = res.headers();
+        assert_eq!(headers["x-content-type-options"], "nosniff");
+        assert_eq!(headers["x-frame-options"], "DENY");
+        assert_eq!(headers["referrer-policy"], "no-referrer");
+        assert!(headers.get("strict-transport-security").is_none());
+
+        // Also on responses the router generates itself
+        let res = app().oneshot(Request::builder().uri("/missing").body(Body::empty()).unwrap()).await.unwrap();
+        assert_eq!(res.headers()["x-frame-options"], "DENY");
+    }
+
+    #[tokio::test]
+    async fn test_hsts_only_behind_tls() {
+        let req = Request::builder()
+            .uri("/health")
+            .header("x-forwarded-proto", "https")
+            .body(Body::empty())
+            .unwrap();
+        let res = app().oneshot(req).await.unwrap();
+        assert_eq!(res.headers()["strict-transport-security"], HSTS);
+    }
+}
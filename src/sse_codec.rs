@@ -1,23 +1,83 @@
-use bytes::{Bytes, BytesMut, Buf};
+use bytes::{BytesMut, Buf};
+use std::{fmt, io};
+use tokio_util::codec::Decoder;
 
+/// Default cap on a single buffered line (1 MiB).
+pub const DEFAULT_MAX_LINE_BYTES: usize = 1024 * 1024;
+
+#[derive(Debug)]
+pub enum SseError {
+    /// A complete line wasn't valid UTF-8 and the codec is strict; `offset` is the position
+    /// of the first bad byte in the stream.
+    InvalidUtf8 { offset: usize },
+    /// `length` bytes arrived without a newline, more than the codec will buffer.
+    LineTooLong { length: usize },
+    /// Reading the underlying stream failed (when used as a [`Decoder`]).
+    Io(io::Error),
+}
+
+impl From<io::Error> for SseError {
+    fn from(e: io::Error) -> Self {
+        SseError::Io(e)
+    }
+}
+
+impl fmt::Display for SseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SseError::InvalidUtf8 { offset } => write!(f, "invalid UTF-8 at byte {}", offset),
+            SseError::LineTooLong { length } => write!(f, "{} bytes without a newline", length),
+            SseError::Io(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for SseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SseError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Splits a byte stream into lines: a [`Decoder`] (e.g. for `FramedRead`) yielding one
+/// non-empty line per frame. Bytes are buffered raw until a newline arrives, so multi-byte
+/// characters split across chunks come out whole.
 pub struct SseCodec {
-    buffer: BytesMut,
+    /// Reject lines that aren't valid UTF-8 instead of replacing the bad bytes.
+    strict_utf8: bool,
+    max_line_length: usize,
+    /// Bytes consumed from the stream so far, for error offsets.
+    consumed: usize,
 }
 
 impl SseCodec {
     pub fn new() -> Self {
         Self {
-            buffer: BytesMut::new(),
+            strict_utf8: false,
+            max_line_length: DEFAULT_MAX_LINE_BYTES,
+            consumed: 0,
         }
     }
 
-    pub fn decode(&mut self, chunk: Bytes) -> Vec<String> {
-        self.buffer.extend_from_slice(&chunk);
-        let mut lines = Vec::new();
+    pub fn with_max_line_length(mut self, max: usize) -> Self {
+        self.max_line_length = max;
+        self
+    }
+
+    pub fn with_strict_utf8(mut self, strict: bool) -> Self {
+        self.strict_utf8 = strict;
+        self
+    }
 
-        while let Some(i) = self.buffer.iter().position(|&b| b == b'\n') {
-            let line_bytes = self.buffer.split_to(i);
-            self.buffer.advance(1); // skip newline
+    /// Takes the next non-empty line off the front of `buffer`, or `None` until a newline arrives.
+    fn next_line(&mut self, buffer: &mut BytesMut) -> Result<Option<String>, SseError> {
+        while let Some(i) = buffer.iter().position(|&b| b == b'\n') {
+            let line_bytes = buffer.split_to(i);
+            buffer.advance(1); // skip newline
+            let line_start = self.consumed;
+            self.consumed += i + 1;
             
             // Handle \r if present (CRLF)
             let line_slice = if line_bytes.ends_with(b"\r") {
@@ -26,36 +86,87 @@ impl SseCodec {
                 &line_bytes[..]
             };
 
-            if let Ok(line) = std::str::from_utf8(line_slice) {
-                if !line.is_empty() {
-                    lines.push(line.to_string());
+            let line = match std::str::from_utf8(line_slice) {
+                Ok(line) => line.to_string(),
+                Err(e) => {
+                    if self.strict_utf8 {
+                        return Err(SseError::InvalidUtf8 { offset: line_start + e.valid_up_to() });
+                    }
+                    let line = String::from_utf8_lossy(line_slice).into_owned();
+                    tracing::warn!("Replaced invalid UTF-8 in line: {}", line);
+                    line
                 }
+            };
+            if !line.is_empty() {
+                return Ok(Some(line));
             }
         }
+
+        // Whatever is left has no newline yet; don't let it grow without bound
+        if buffer.len() > self.max_line_length {
+            let length = buffer.len();
+            self.consumed += length;
+            buffer.clear();
+            return Err(SseError::LineTooLong { length });
+        }
         
-        lines
+        Ok(None)
+    }
+}
+
+fn unterminated_line(buffer: &mut BytesMut) -> Option<String> {
+    let rest = buffer.split();
+    let rest = std::str::from_utf8(&rest).ok()?.trim_end_matches('\r');
+    (!rest.is_empty()).then(|| rest.to_string())
+}
+
+impl Decoder for SseCodec {
+    type Item = String;
+    type Error = SseError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<String>, SseError> {
+        self.next_line(src)
+    }
+
+    fn decode_eof(&mut self, src: &mut BytesMut) -> Result<Option<String>, SseError> {
+        match self.next_line(src)? {
+            Some(line) => Ok(Some(line)),
+            None => Ok(unterminated_line(src)),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use bytes::Bytes;
+
+    /// Feeds `chunk` to the codec, returning the lines it completes.
+    fn feed(codec: &mut SseCodec, buffer: &mut BytesMut, chunk: Bytes) -> Result<Vec<String>, SseError> {
+        buffer.extend_from_slice(&chunk);
+        let mut lines = Vec::new();
+        while let Some(line) = Decoder::decode(codec, buffer)? {
+            lines.push(line);
+        }
+        Ok(lines)
+    }
 
     #[test]
     fn test_sse_codec_fragmentation() {
         let mut codec = SseCodec::new();
+        let mut buffer = BytesMut::new();
         
         let chunk1 = Bytes::from("data: {\"foo\":");
-        let lines = codec.decode(chunk1);
+        let lines = feed(&mut codec, &mut buffer, chunk1).unwrap();
         assert!(lines.is_empty());
 
         let chunk2 = Bytes::from(" \"bar\"}\n\ndata: [DO");
-        let lines = codec.decode(chunk2);
+        let lines = feed(&mut codec, &mut buffer, chunk2).unwrap();
         assert_eq!(lines.len(), 1);
         assert_eq!(lines[0], "data: {\"foo\": \"bar\"}");
 
         let chunk3 = Bytes::from("NE]\n");
-        let lines = codec.decode(chunk3);
+        let lines = feed(&mut codec, &mut buffer, chunk3).unwrap();
         assert_eq!(lines.len(), 1);
         assert_eq!(lines[0], "data: [DONE]");
     }
@@ -63,10 +174,81 @@ mod tests {
     #[test]
     fn test_sse_codec_crlf() {
         let mut codec = SseCodec::new();
+        let mut buffer = BytesMut::new();
         let chunk = Bytes::from("data: foo\r\ndata: bar\r\n");
-        let lines = codec.decode(chunk);
+        let lines = feed(&mut codec, &mut buffer, chunk).unwrap();
         assert_eq!(lines.len(), 2);
         assert_eq!(lines[0], "data: foo");
         assert_eq!(lines[1], "data: bar");
     }
+
+    #[test]
+    fn test_sse_codec_eof_returns_unterminated_line() {
+        let (mut codec, mut buffer) = (SseCodec::new(), BytesMut::new());
+        assert_eq!(feed(&mut codec, &mut buffer, Bytes::from("{\"a\":1}\n{\"b\"")).unwrap(), vec!["{\"a\":1}"]);
+        assert!(feed(&mut codec, &mut buffer, Bytes::from(":2}")).unwrap().is_empty());
+        assert_eq!(codec.decode_eof(&mut buffer).unwrap().as_deref(), Some("{\"b\":2}"));
+        assert_eq!(codec.decode_eof(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn test_sse_codec_multibyte_char_split_across_chunks() {
+        let mut codec = SseCodec::new();
+        let mut buffer = BytesMut::new();
+        let line = "data: 你好\n".as_bytes();
+        // Split in the middle of the three-byte '你'
+        let split = "data: ".len() + 1;
+
+        assert!(feed(&mut codec, &mut buffer, Bytes::copy_from_slice(&line[..split])).unwrap().is_empty());
+        let lines = feed(&mut codec, &mut buffer, Bytes::copy_from_slice(&line[split..])).unwrap();
+        assert_eq!(lines, vec!["data: 你好"]);
+    }
+
+    #[test]
+    fn test_sse_codec_invalid_utf8_lossy_or_strict() {
+        let chunk = Bytes::from_static(b"data: \xff\n");
+        let lines = feed(&mut SseCodec::new(), &mut BytesMut::new(), chunk.clone()).unwrap();
+        assert_eq!(lines, vec!["data: \u{FFFD}"]);
+
+        let mut codec = SseCodec::new().with_strict_utf8(true);
+        let mut buffer = BytesMut::new();
+        assert_eq!(feed(&mut codec, &mut buffer, Bytes::from("data: ok\n")).unwrap(), vec!["data: ok"]);
+        assert!(matches!(feed(&mut codec, &mut buffer, chunk), Err(SseError::InvalidUtf8 { offset: 15 })));
+    }
+
+    #[test]
+    fn test_sse_codec_rejects_overlong_line() {
+        let mut codec = SseCodec::new().with_max_line_length(1024 * 1024);
+        let mut buffer = BytesMut::new();
+        let line = Bytes::from(vec![b'a'; 2 * 1024 * 1024]);
+        assert!(matches!(feed(&mut codec, &mut buffer, line), Err(SseError::LineTooLong { length: 2_097_152 })));
+    }
+
+    #[tokio::test]
+    async fn test_framed_read_yields_lines() {
+        use futures::StreamExt;
+        use tokio_util::codec::FramedRead;
+
+        let chunks: Vec<io::Result<Bytes>> = vec![
+            Ok(Bytes::from("data: {\"foo\":")),
+            Ok(Bytes::from(" 1}\r\n\ndata: [DO")),
+            Ok(Bytes::from("NE]")),
+        ];
+        let reader = tokio_util::io::StreamReader::new(futures::stream::iter(chunks));
+        let lines: Vec<String> = FramedRead::new(reader, SseCodec::new()).map(Result::unwrap).collect().await;
+        assert_eq!(lines, vec!["data: {\"foo\": 1}", "data: [DONE]"]);
+    }
+
+    #[tokio::test]
+    async fn test_framed_read_passes_io_errors() {
+        use futures::StreamExt;
+        use tokio_util::codec::FramedRead;
+
+        let chunks: Vec<io::Result<Bytes>> =
+            vec![Ok(Bytes::from("data: 1\n")), Err(io::Error::from(io::ErrorKind::ConnectionReset))];
+        let reader = tokio_util::io::StreamReader::new(futures::stream::iter(chunks));
+        let mut lines = FramedRead::new(reader, SseCodec::new());
+        assert_eq!(lines.next().await.unwrap().unwrap(), "data: 1");
+        assert!(matches!(lines.next().await, Some(Err(SseError::Io(e))) if e.kind() == io::ErrorKind::ConnectionReset));
+    }
 }
@@ -0,0 +1,203 @@
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Span of the `requests_per_minute` window, kept as one-second buckets.
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+const RATE_BUCKET: Duration = Duration::from_secs(1);
+
+/// Process-wide counters reported by `GET /admin/stats`.
+pub struct Stats {
+    started_at: Instant,
+    active_sse_connections: AtomicU64,
+    upstream_requests_total: AtomicU64,
+    upstream_errors_total: AtomicU64,
+    /// Upstream 429s waited out and retried.
+    rate_limit_retries_total: AtomicU64,
+    /// Unix seconds of the most recent `/v1/responses` request; 0 before the first.
+    last_request_at: AtomicI64,
+    total_requests: AtomicU64,
+    /// Responses that ran to completion.
+    successful_requests: AtomicU64,
+    /// Requests rejected or cut short: invalid input, upstream errors, broken streams.
+    failed_requests: AtomicU64,
+    /// Request counts per bucket start, oldest first.
+    recent_requests: Mutex<VecDeque<(Instant, u64)>>,
+}
+
+impl Stats {
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            active_sse_connections: AtomicU64::new(0),
+            upstream_requests_total: AtomicU64::new(0),
+            upstream_errors_total: AtomicU64::new(0),
+            rate_limit_retries_total: AtomicU64::new(0),
+            last_request_at: AtomicI64::new(0),
+            total_requests: AtomicU64::new(0),
+            successful_requests: AtomicU64::new(0),
+            failed_requests: AtomicU64::new(0),
+            recent_requests: Mutex::new(VecDeque::new()),
+        }
+    }
+
+    pub fn record_request(&self, at: i64) {
+        self.last_request_at.store(at, Ordering::Relaxed);
+        self.total_requests.fetch_add(1, Ordering::Relaxed);
+
+        let now = Instant::now();
+        let mut recent = self.recent_requests.lock().unwrap();
+        match recent.back_mut() {
+            Some((started, count)) if now.duration_since(*started) < RATE_BUCKET => *count += 1,
+            _ => recent.push_back((now, 1)),
+        }
+        prune(&mut recent, now);
+    }
+
+    pub fn record_success(&self) {
+        self.successful_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_failure(&self) {
+        self.failed_requests.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Requests seen over the last minute.
+    pub fn requests_per_minute(&self) -> f64 {
+        let mut recent = self.recent_requests.lock().unwrap();
+        prune(&mut recent, Instant::now());
+        recent.iter().map(|(_, count)| count).sum::<u64>() as f64
+    }
+
+    pub fn record_upstream_request(&self) {
+        self.upstream_requests_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_upstream_error(&self) {
+        self.upstream_errors_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_rate_limit_retry(&self) {
+        self.rate_limit_retries_total.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts an open SSE connection until the returned guard is dropped, or returns `None`
+    /// if `max` connections are already open.
+    pub fn try_track_sse_connection(self: &std::sync::Arc<Self>, max: Option<u64>) -> Option<SseConnectionGuard> {
+        self.active_sse_connections
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |active| {
+                max.is_none_or(|max| active < max).then_some(active + 1)
+            })
+            .ok()?;
+        Some(SseConnectionGuard(self.clone()))
+    }
+
+    /// Renders the counters in the Prometheus text format.
+    pub fn prometheus(&self) -> String {
+        format!(
+            "# HELP ors_active_sse_connections Currently open SSE response streams.\n\
+             # TYPE ors_active_sse_connections gauge\n\
+             ors_active_sse_connections {}\n\
+             # HELP ors_upstream_requests_total Requests sent to the upstream.\n\
+             # TYPE ors_upstream_requests_total counter\n\
+             ors_upstream_requests_total {}\n\
+             # HELP ors_upstream_errors_total Upstream requests that failed or returned an error status.\n\
+             # TYPE ors_upstream_errors_total counter\n\
+             ors_upstream_errors_total {}\n\
+             # HELP ors_upstream_rate_limit_retries_total Upstream 429 responses retried after Retry-After.\n\
+             # TYPE ors_upstream_rate_limit_retries_total counter\n\
+             ors_upstream_rate_limit_retries_total {}\n",
+            self.active_sse_connections.load(Ordering::Relaxed),
+            self.upstream_requests_total.load(Ordering::Relaxed),
+            self.upstream_errors_total.load(Ordering::Relaxed),
+            self.rate_limit_retries_total.load(Ordering::Relaxed),
+        )
+    }
+
+    pub fn snapshot(&self) -> serde_json::Value {
+        let requests = self.upstream_requests_total.load(Ordering::Relaxed);
+        let errors = self.upstream_errors_total.load(Ordering::Relaxed);
+        let error_rate = if requests == 0 { 0.0 } else { errors as f64 * 100.0 / requests as f64 };
+        let last_request_at = self.last_request_at.load(Ordering::Relaxed);
+
+        serde_json::json!({
+            "active_sse_connections": self.active_sse_connections.load(Ordering::Relaxed),
+            "upstream_requests_total": requests,
+            "upstream_error_rate_percent": error_rate,
+            "upstream_rate_limit_retries_total": self.rate_limit_retries_total.load(Ordering::Relaxed),
+            "uptime_seconds": self.started_at.elapsed().as_secs(),
+            "last_request_at": (last_request_at > 0).then_some(last_request_at),
+            "total_requests": self.total_requests.load(Ordering::Relaxed),
+            "successful_requests": self.successful_requests.load(Ordering::Relaxed),
+            "failed_requests": self.failed_requests.load(Ordering::Relaxed),
+            "requests_per_minute": self.requests_per_minute(),
+        })
+    }
+}
+
+fn prune(recent: &mut VecDeque<(Instant, u64)>, now: Instant) {
+    while recent.front().is_some_and(|(started, _)| now.duration_since(*started) >= RATE_WINDOW) {
+        recent.pop_front();
+    }
+}
+
+pub struct SseConnectionGuard(std::sync::Arc<Stats>);
+
+impl Drop for SseConnectionGuard {
+    fn drop(&mut self) {
+        self.0.active_sse_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_snapshot_counts() {
+        let stats = Arc::new(Stats::new());
+        stats.record_upstream_request();
+        stats.record_upstream_request();
+        stats.record_upstream_error();
+
+        let guard = stats.try_track_sse_connection(None).unwrap();
+        assert_eq!(stats.snapshot()["active_sse_connections"], 1);
+        drop(guard);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot["active_sse_connections"], 0);
+        assert_eq!(snapshot["upstream_requests_total"], 2);
+        assert_eq!(snapshot["upstream_error_rate_percent"], 50.0);
+        assert!(snapshot["last_request_at"].is_null());
+    }
+
+    #[test]
+    fn test_connection_limit() {
+        let stats = Arc::new(Stats::new());
+        let first = stats.try_track_sse_connection(Some(1)).unwrap();
+        assert!(stats.try_track_sse_connection(Some(1)).is_none());
+        assert!(stats.prometheus().contains("ors_active_sse_connections 1\n"));
+
+        drop(first);
+        assert!(stats.try_track_sse_connection(Some(1)).is_some());
+    }
+
+    #[test]
+    fn test_request_outcomes_and_rate() {
+        let stats = Stats::new();
+        for _ in 0..3 {
+            stats.record_request(1_700_000_000);
+        }
+        stats.record_success();
+        stats.record_failure();
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot["total_requests"], 3);
+        assert_eq!(snapshot["successful_requests"], 1);
+        assert_eq!(snapshot["failed_requests"], 1);
+        assert_eq!(snapshot["requests_per_minute"], 3.0);
+        assert_eq!(snapshot["last_request_at"], 1_700_000_000);
+    }
+}
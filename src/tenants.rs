@@ -0,0 +1,290 @@
+use crate::{
+    admin,
+    db::{now_secs, Db, Tenant, TenantUsage, DEFAULT_TENANT_ID},
+    error_response, AppState,
+};
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Path, Query, State},
+    http::{request::Parts, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Json,
+};
+use dashmap::DashMap;
+use lru::LruCache;
+use std::{
+    collections::{HashMap, VecDeque},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// Tenant ids are 1-64 ASCII letters, digits, `_` or `-`.
+pub fn is_valid_tenant_id(id: &str) -> bool {
+    (1..=64).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// The tenant a request authenticated as, or [`DEFAULT_TENANT_ID`] for requests without a
+/// tenant key. Handlers that only work on stored conversations take this instead of the full
+/// per-tenant [`AppState`]. Only requests that change something count against the tenant's
+/// rate limit.
+pub struct TenantId(pub String);
+
+#[async_trait]
+impl FromRequestParts<AppState> for TenantId {
+    type Rejection = Response;
+
+    async fn from_request_parts(parts: &mut Parts, state: &AppState) -> Result<Self, Self::Rejection> {
+        let tenant = crate::resolve_tenant(state, &parts.headers, !parts.method.is_safe()).await?;
+        Ok(TenantId(tenant.map_or_else(|| DEFAULT_TENANT_ID.to_string(), |t| t.id.clone())))
+    }
+}
+
+/// How long a lookup, including a miss, is trusted before the DB is asked again.
+pub const TENANT_CACHE_TTL: Duration = Duration::from_secs(60);
+const TENANT_CACHE_SIZE: usize = 1000;
+
+/// When a lookup was made and what it found.
+type Entry = (Instant, Option<Arc<Tenant>>);
+
+/// Tenant lookups by API key, so authenticating a request rarely touches the DB. Unknown keys are
+/// cached too; bounded so clients cycling through made-up keys can't grow it without limit.
+pub struct TenantCache {
+    entries: Mutex<LruCache<String, Entry>>,
+    ttl: Duration,
+}
+
+impl TenantCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(NonZeroUsize::new(TENANT_CACHE_SIZE).unwrap())),
+            ttl,
+        }
+    }
+
+    pub async fn lookup(&self, db: &Db, api_key: &str) -> Result<Option<Arc<Tenant>>, sqlx::Error> {
+        if let Some((fetched, tenant)) = self.entries.lock().unwrap().get(api_key) {
+            if fetched.elapsed() < self.ttl {
+                return Ok(tenant.clone());
+            }
+        }
+        let tenant = db.get_tenant(api_key).await?.map(Arc::new);
+        self.entries.lock().unwrap().put(api_key.to_string(), (Instant::now(), tenant.clone()));
+        Ok(tenant)
+    }
+
+    /// Drops the cached lookup so a changed tenant takes effect on its next request.
+    pub fn invalidate(&self, api_key: &str) {
+        self.entries.lock().unwrap().pop(api_key);
+    }
+}
+
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(1);
+/// Tenants without a request for this long are dropped from the rate limiter.
+pub const RATE_LIMIT_IDLE: Duration = Duration::from_secs(60);
+
+/// Sliding one-second windows of request times per tenant, checked against each tenant's
+/// `rate_limit_rps`.
+#[derive(Default)]
+pub struct TenantRateLimiter {
+    windows: DashMap<String, VecDeque<Instant>>,
+}
+
+impl TenantRateLimiter {
+    /// Counts a request from `tenant_id` if fewer than `limit` were made in the last second and
+    /// returns how many remain; otherwise returns how long until the oldest leaves the window.
+    pub fn check(&self, tenant_id: &str, limit: u32) -> Result<u32, Duration> {
+        self.check_at(tenant_id, limit, Instant::now())
+    }
+
+    fn check_at(&self, tenant_id: &str, limit: u32, now: Instant) -> Result<u32, Duration> {
+        let mut window = self.windows.entry(tenant_id.to_string()).or_default();
+        while window.front().is_some_and(|&t| now.duration_since(t) >= RATE_LIMIT_WINDOW) {
+            window.pop_front();
+        }
+        if window.len() >= limit as usize {
+            let oldest = window.front().copied().unwrap_or(now);
+            return Err(RATE_LIMIT_WINDOW.saturating_sub(now.duration_since(oldest)));
+        }
+        window.push_back(now);
+        Ok(limit - window.len() as u32)
+    }
+
+    /// Drops the windows of tenants whose latest request is `idle` or more ago.
+    pub fn purge_idle(&self, idle: Duration) {
+        self.purge_idle_at(idle, Instant::now())
+    }
+
+    fn purge_idle_at(&self, idle: Duration, now: Instant) {
+        self.windows.retain(|_, window| window.back().is_some_and(|&t| now.duration_since(t) < idle));
+    }
+
+    /// Purges idle tenants every [`RATE_LIMIT_IDLE`], keeping the map bounded by recent tenants.
+    pub async fn purge_idle_loop(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(RATE_LIMIT_IDLE);
+        loop {
+            interval.tick().await;
+            self.purge_idle(RATE_LIMIT_IDLE);
+        }
+    }
+}
+
+/// `429` for a tenant over its `rate_limit_rps`.
+pub fn rate_limited(limit: u32, retry_after: Duration) -> Response {
+    let mut res = error_response(
+        StatusCode::TOO_MANY_REQUESTS,
+        "rate_limit_exceeded",
+        format!("Rate limit of {} requests per second exceeded", limit),
+    );
+    let headers = res.headers_mut();
+    headers.insert("x-ratelimit-limit", limit.into());
+    headers.insert("x-ratelimit-remaining", 0.into());
+    // Whole seconds, rounded up so a client waiting that long is let through
+    headers.insert("retry-after", (retry_after.as_millis().div_ceil(1000).max(1) as u64).into());
+    res.into_response()
+}
+
+/// How long a usage report is served before being recomputed.
+pub const USAGE_CACHE_TTL: Duration = Duration::from_secs(60);
+const USAGE_CACHE_SIZE: usize = 1000;
+
+/// `(tenant_id, from, to)`, with `to` left `None` when it defaults to now, so repeated polls for
+/// "usage so far" share an entry.
+type UsageKey = (String, i64, Option<i64>);
+
+/// Recent usage reports, so dashboards polling `GET /v1/tenants/:tenant_id/usage` don't rerun
+/// the aggregation each time.
+pub struct UsageCache {
+    entries: Mutex<LruCache<UsageKey, (Instant, Arc<TenantUsage>)>>,
+    ttl: Duration,
+}
+
+impl UsageCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            entries: Mutex::new(LruCache::new(NonZeroUsize::new(USAGE_CACHE_SIZE).unwrap())),
+            ttl,
+        }
+    }
+
+    pub async fn get(&self, db: &Db, tenant_id: &str, from: i64, to: Option<i64>) -> Result<Arc<TenantUsage>, sqlx::Error> {
+        let key = (tenant_id.to_string(), from, to);
+        if let Some((fetched, usage)) = self.entries.lock().unwrap().get(&key) {
+            if fetched.elapsed() < self.ttl {
+                return Ok(usage.clone());
+            }
+        }
+        let usage = Arc::new(db.get_tenant_usage(tenant_id, from, to.unwrap_or_else(now_secs)).await?);
+        self.entries.lock().unwrap().put(key, (Instant::now(), usage.clone()));
+        Ok(usage)
+    }
+}
+
+/// `GET /v1/tenants/:tenant_id/usage`: token and request totals for billing, overall and per
+/// model. Open to the admin key and to the tenant's own key. `from` and `to` take Unix seconds
+/// or RFC 3339 timestamps and default to the beginning of time and now.
+pub async fn tenant_usage(
+    State(state): State<AppState>,
+    Path(tenant_id): Path<String>,
+    headers: HeaderMap,
+    Query(params): Query<HashMap<String, String>>,
+) -> Response {
+    if !admin::is_authorized(&state, &headers) {
+        match crate::resolve_tenant(&state, &headers, false).await {
+            Ok(Some(tenant)) if tenant.id == tenant_id => {}
+            Ok(_) => return error_response(StatusCode::FORBIDDEN, "forbidden", "Not allowed to read this tenant's usage"),
+            Err(res) => return res,
+        }
+    }
+
+    let (from, to) = match admin::parse_time_range(&state, &params).await {
+        Ok(range) => range,
+        Err(res) => return res,
+    };
+
+    match state.tenant_usage.get(&state.db, &tenant_id, from.unwrap_or(0), to).await {
+        Ok(usage) => Json(&*usage).into_response(),
+        Err(e) => {
+            tracing::error!("Failed to load usage for tenant {}: {}", tenant_id, e);
+            error_response(StatusCode::INTERNAL_SERVER_ERROR, "server_error", "Failed to load usage")
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenant(upstream_key: &str) -> Tenant {
+        Tenant {
+            id: "team-a".to_string(),
+            api_key: "sk-team-a".to_string(),
+            upstream_key: Some(upstream_key.to_string()),
+            upstream_url: None,
+            rate_limit_rps: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_lookup_is_cached_until_invalidated() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let cache = TenantCache::new(TENANT_CACHE_TTL);
+        assert!(cache.lookup(&db, "sk-team-a").await.unwrap().is_none());
+
+        // The miss is cached
+        db.upsert_tenant(&tenant("sk-1")).await.unwrap();
+        assert!(cache.lookup(&db, "sk-team-a").await.unwrap().is_none());
+
+        cache.invalidate("sk-team-a");
+        assert_eq!(*cache.lookup(&db, "sk-team-a").await.unwrap().unwrap(), tenant("sk-1"));
+
+        db.upsert_tenant(&tenant("sk-2")).await.unwrap();
+        assert_eq!(*cache.lookup(&db, "sk-team-a").await.unwrap().unwrap(), tenant("sk-1"));
+    }
+
+    #[tokio::test]
+    async fn test_lookup_expires() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let cache = TenantCache::new(Duration::ZERO);
+        assert!(cache.lookup(&db, "sk-team-a").await.unwrap().is_none());
+
+        db.upsert_tenant(&tenant("sk-1")).await.unwrap();
+        assert!(cache.lookup(&db, "sk-team-a").await.unwrap().is_some());
+    }
+
+    #[test]
+    fn test_rate_limiter_sliding_window() {
+        let limiter = TenantRateLimiter::default();
+        let start = Instant::now();
+        assert_eq!(limiter.check_at("team-a", 2, start), Ok(1));
+        assert_eq!(limiter.check_at("team-a", 2, start + Duration::from_millis(400)), Ok(0));
+        assert_eq!(limiter.check_at("team-a", 2, start + Duration::from_millis(500)), Err(Duration::from_millis(500)));
+        // Other tenants have their own window
+        assert_eq!(limiter.check_at("team-b", 2, start + Duration::from_millis(500)), Ok(1));
+
+        // The first request has left the window, the second hasn't
+        assert_eq!(limiter.check_at("team-a", 2, start + Duration::from_secs(1)), Ok(0));
+        assert!(limiter.check_at("team-a", 2, start + Duration::from_millis(1100)).is_err());
+    }
+
+    #[test]
+    fn test_rate_limiter_purges_idle_tenants() {
+        let limiter = TenantRateLimiter::default();
+        let start = Instant::now();
+        limiter.check_at("team-a", 5, start).unwrap();
+        limiter.check_at("team-b", 5, start + Duration::from_secs(30)).unwrap();
+
+        limiter.purge_idle_at(RATE_LIMIT_IDLE, start + RATE_LIMIT_IDLE);
+        assert!(!limiter.windows.contains_key("team-a"));
+        assert!(limiter.windows.contains_key("team-b"));
+    }
+
+    #[test]
+    fn test_rate_limited_headers() {
+        let res = rate_limited(10, Duration::from_millis(250));
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["x-ratelimit-limit"], "10");
+        assert_eq!(res.headers()["x-ratelimit-remaining"], "0");
+        assert_eq!(res.headers()["retry-after"], "1");
+    }
+}
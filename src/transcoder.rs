@@ -1,245 +1,764 @@
-use crate::types::{LegacyChunk, OrsEvent};
+use crate::types::{
+    LegacyChoice, LegacyChunk, LegacyUsage, LogprobEntry, OrsContentPart, OrsEvent, OrsInputItem, OrsRole, ResponseItem,
+};
+use serde_json::Value;
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 use uuid::Uuid;
 
 pub struct Transcoder {
     response_id: String,
-    current_item_id: Option<String>,
-    current_item_type: Option<String>,
-    current_content_index: Option<u32>,
-    has_emitted_content_start: bool,
+    /// Unix seconds when the transcoder was created, reported in `response.created`.
+    created_at: u64,
+    /// Per-choice item state, keyed by the legacy choice `index` (n > 1 streams several at once).
+    choices: HashMap<usize, ChoiceState>,
     state: TranscoderState,
     sequence_number: u32,
+    /// `output_index` of the next item started. Items are numbered in the order they start,
+    /// across choices, so each indexes its place in the response's `output`.
+    next_output_index: u32,
+    /// The request set `parallel_tool_calls: false`, so tool calls should never overlap.
+    sequential_tool_calls: bool,
+    /// Tool calls that started while another was still in flight despite `sequential_tool_calls`.
+    overlapping_tool_calls: u32,
+    /// Buffer each `output_text` part so its `content_part.done` carries the full text.
+    full_text_parts: bool,
+    /// Estimated prompt tokens, reported if the upstream never sends usage.
+    prompt_estimate: u32,
+    /// Bytes of streamed text (and reasoning) deltas, for the same fallback.
+    accumulated_text_bytes: usize,
+    /// Bytes of streamed function call argument deltas, for the same fallback.
+    accumulated_args_bytes: usize,
+    usage_reported: bool,
 }
 
-enum TranscoderState {
+/// The output item currently being streamed for one choice.
+#[derive(Default)]
+struct ChoiceState {
+    started: bool,
+    /// Echoed, with function call arguments filled in, when the item is done.
+    current_item: Option<ResponseItem>,
+    /// `current_item`'s `output_index`, fixed when it was added.
+    output_index: u32,
+    content_part_states: Vec<ContentPartState>,
+}
+
+/// A content part of the current output item. Parts are opened in order, so a part's
+/// `index` is also its position in `content_part_states`.
+struct ContentPartState {
+    part_type: &'static str, // "output_text", "reasoning_text" or "computer_use"
+    index: u32,
+    started: bool,
+    /// The part's text so far, when `full_text_parts` is set.
+    text: String,
+}
+
+/// Where the response as a whole is, as the upstream chunks move it along:
+///
+/// ```text
+///            first chunk                     text, reasoning
+///   Init ──────────────▶ AwaitingContent ─────────────────────▶ StreamingText
+///    │                         │                                  │      ▲
+///    │                         │ tool call              tool call │      │ text, reasoning
+///    │                         ▼                                  │      │ (a new item)
+///    │               StreamingFunctionArgs ◀──────────────────────┘      │
+///    │                         │                                         │
+///    │                         │ every item done (finish_reason)         │
+///    │                         ▼                                         │
+///    │                 AwaitingCompletion ───────────────────────────────┘
+///    │                         │ done()
+///    └───────────────────────▶ Done
+/// ```
+///
+/// `AwaitingContent` and `StreamingText` may also move straight to `AwaitingCompletion` when
+/// their items finish, and any state to `Done` (an empty or aborted stream). `AwaitingCompletion` may go back to
+/// either streaming state: Anthropic closes an item per content block, so the next block
+/// starts a new item.
+///
+/// The invalid transitions the upstream can cause are text arriving mid tool call and anything
+/// after `Done`.
+/// Both are logged; chunks after `Done` are dropped. With several choices (`n > 1`) their
+/// deltas interleave, so the content states follow whichever came last and text during a
+/// tool call is expected.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TranscoderState {
+    /// Nothing received yet; the first chunk emits `response.created`.
     Init,
-    Streaming,
+    /// `response.created` sent, no content yet (e.g. only a role announcement).
+    AwaitingContent,
+    /// Streaming text, reasoning or computer use actions into a message.
+    StreamingText,
+    /// Streaming a function call's arguments.
+    StreamingFunctionArgs,
+    /// Every item is done; waiting for usage and the end of the upstream stream.
+    AwaitingCompletion,
+    /// `response.done` sent; nothing more belongs to this response.
+    Done,
+}
+
+impl fmt::Display for TranscoderState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            TranscoderState::Init => "init",
+            TranscoderState::AwaitingContent => "awaiting_content",
+            TranscoderState::StreamingText => "streaming_text",
+            TranscoderState::StreamingFunctionArgs => "streaming_function_args",
+            TranscoderState::AwaitingCompletion => "awaiting_completion",
+            TranscoderState::Done => "done",
+        })
+    }
 }
 
 impl Transcoder {
     pub fn new() -> Self {
         Self {
             response_id: format!("resp_{}", Uuid::new_v4().simple()),
-            current_item_id: None,
-            current_item_type: None,
-            current_content_index: None,
-            has_emitted_content_start: false,
+            created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs(),
+            choices: HashMap::new(),
             state: TranscoderState::Init,
             sequence_number: 0,
+            next_output_index: 0,
+            sequential_tool_calls: false,
+            overlapping_tool_calls: 0,
+            full_text_parts: false,
+            prompt_estimate: 0,
+            accumulated_text_bytes: 0,
+            accumulated_args_bytes: 0,
+            usage_reported: false,
         }
     }
 
+    pub fn with_prompt_estimate(mut self, tokens: u32) -> Self {
+        self.prompt_estimate = tokens;
+        self
+    }
+
+    pub fn with_full_text_parts(mut self, full_text: bool) -> Self {
+        self.full_text_parts = full_text;
+        self
+    }
+
+    pub fn with_sequential_tool_calls(mut self, sequential: bool) -> Self {
+        self.sequential_tool_calls = sequential;
+        self
+    }
+
+    /// Starts `item` as the choice's current item and announces it.
+    fn add_item(&mut self, state: &mut ChoiceState, item: ResponseItem, events: &mut Vec<OrsEvent>) {
+        state.output_index = self.next_output_index;
+        self.next_output_index += 1;
+        state.current_item = Some(item.clone());
+
+        let seq = self.next_seq();
+        events.push(OrsEvent::ItemAdded { sequence_number: seq, output_index: Some(state.output_index), item });
+    }
+
+    /// Closes the choice's open item, and its open content part, as `status`.
+    fn close_item(&mut self, state: &mut ChoiceState, status: &str, events: &mut Vec<OrsEvent>) {
+        let Some(mut item) = state.current_item.take() else { return };
+        let output_index = state.output_index;
+        self.close_content_part(state, output_index, item.id(), events);
+        state.content_part_states.clear();
+        item.set_status(status);
+        let seq = self.next_seq();
+        events.push(OrsEvent::ItemDone { sequence_number: seq, output_index: Some(output_index), item });
+    }
+
+    #[cfg(test)]
+    pub fn current_state(&self) -> TranscoderState {
+        self.state
+    }
+
+    /// Moves to `next`, warning if the state machine doesn't allow it. The transition happens
+    /// either way: the events have already been produced and the client is better served
+    /// seeing them than not.
+    fn transition(&mut self, next: TranscoderState) {
+        use TranscoderState::*;
+        let valid = match (self.state, next) {
+            (from, to) if from == to => true,
+            // Nothing follows the end of the response
+            (Done, _) => false,
+            // Anything may end it, even before it started (an empty upstream stream)
+            (_, Done) => true,
+            (Init, AwaitingContent) => true,
+            (Init, _) => false,
+            // Text can't be part of a function call, unless another choice sent it
+            (StreamingFunctionArgs, StreamingText) => self.choices.len() > 1,
+            // A new item may start at any point, after the others finished too
+            (_, StreamingText | StreamingFunctionArgs) => true,
+            (_, AwaitingCompletion) => true,
+            (_, AwaitingContent | Init) => false,
+        };
+        if !valid {
+            tracing::warn!("Unexpected transcoder transition from {} to {}", self.state, next);
+        }
+        self.state = next;
+    }
+
     fn next_seq(&mut self) -> Option<u32> {
         let seq = self.sequence_number;
         self.sequence_number += 1;
         Some(seq)
     }
 
+    /// Returns the content_index to use for a delta of `part_type`, closing the open part and
+    /// starting a new one if the content type changed (e.g. reasoning -> text).
+    fn ensure_content_part(
+        &mut self,
+        choice: &mut ChoiceState,
+        output_index: u32,
+        part_type: &'static str,
+        item_id: &str,
+        events: &mut Vec<OrsEvent>,
+    ) -> u32 {
+        if let Some(part) = choice.content_part_states.last() {
+            if part.started && part.part_type == part_type {
+                return part.index;
+            }
+        }
+
+        self.close_content_part(choice, output_index, item_id, events);
+
+        let index = choice.content_part_states.len() as u32;
+        let seq = self.next_seq();
+        events.push(OrsEvent::ContentPartAdded {
+            sequence_number: seq,
+            item_id: item_id.to_string(),
+            output_index: Some(output_index),
+            content_index: Some(index),
+            part: content_part(part_type, String::new()),
+        });
+        choice.content_part_states.push(ContentPartState { part_type, index, started: true, text: String::new() });
+        index
+    }
+
+    fn close_content_part(
+        &mut self,
+        choice: &mut ChoiceState,
+        output_index: u32,
+        item_id: &str,
+        events: &mut Vec<OrsEvent>,
+    ) {
+        let Some(part) = choice.content_part_states.last_mut().filter(|p| p.started) else {
+            return;
+        };
+        part.started = false;
+        let (part_type, index) = (part.part_type, part.index);
+
+        // The text was already streamed as deltas and is only buffered when asked for, so
+        // the done part usually just echoes its type.
+        let text = std::mem::take(&mut part.text);
+        let seq = self.next_seq();
+        events.push(OrsEvent::ContentPartDone {
+            sequence_number: seq,
+            item_id: item_id.to_string(),
+            output_index: Some(output_index),
+            content_index: Some(index),
+            part: content_part(part_type, text),
+        });
+    }
+
     pub fn process(&mut self, chunk: LegacyChunk) -> Vec<OrsEvent> {
         let mut events = Vec::new();
 
-        // We assume single-choice streaming for now (standard for chat)
-        if let Some(choice) = chunk.choices.first() {
-            // 1. Handle Initialization (First chunk logic)
-            if let TranscoderState::Init = self.state {
-                // Emit response.created
+        if chunk.choices.is_empty() {
+            if let Some(usage) = chunk.usage {
+                events.extend(self.process_usage(usage));
+            }
+            return events;
+        }
+
+        // 1. Handle Initialization (First chunk logic)
+        match self.state {
+            // Init -> AwaitingContent: emit response.created
+            TranscoderState::Init => {
                 let seq = self.next_seq();
                 events.push(OrsEvent::Created {
                     id: self.response_id.clone(),
+                    created_at: self.created_at,
                     sequence_number: seq,
                 });
+                self.transition(TranscoderState::AwaitingContent);
+            }
+            // Done -> anything: the response was already closed
+            TranscoderState::Done => {
+                tracing::warn!("Dropping upstream chunk received after response.done");
+                return events;
+            }
+            // The content states move per delta in process_choice
+            TranscoderState::AwaitingContent
+            | TranscoderState::StreamingText
+            | TranscoderState::StreamingFunctionArgs
+            | TranscoderState::AwaitingCompletion => {}
+        }
+
+        for choice in &chunk.choices {
+            // Take the state out of the map so it can be mutated alongside `self`
+            let mut state = self.choices.remove(&choice.index).unwrap_or_default();
+            self.process_choice(choice, &mut state, &mut events);
+            self.choices.insert(choice.index, state);
+        }
+        // Any -> AwaitingCompletion once every item has its output_item.done
+        if self.choices.values().all(|choice| choice.started && choice.current_item.is_none()) {
+            self.transition(TranscoderState::AwaitingCompletion);
+        }
 
-                let has_tool_calls = choice.delta.tool_calls.as_ref().map(|tc| !tc.is_empty()).unwrap_or(false);
-                let has_content = choice.delta.content.as_ref().map(|s| !s.is_empty()).unwrap_or(false);
-
-                if !has_tool_calls || has_content {
-                    let item_id = format!("msg_{}", Uuid::new_v4().simple());
-                    self.current_item_id = Some(item_id.clone());
-                    self.current_item_type = Some("message".to_string());
-
-                    events.push(OrsEvent::ItemAdded {
-                        sequence_number: seq,
-                        item: serde_json::json!({ 
-                            "id": item_id,
-                            "type": "message", 
-                            "status": "in_progress",
-                            "role": "assistant", 
-                            "content": [] 
-                        }),
-                    });
+        for event in &events {
+            match event {
+                OrsEvent::TextDelta { delta, .. } | OrsEvent::ReasoningDelta { delta, .. } => {
+                    self.accumulated_text_bytes += delta.len();
                 }
+                OrsEvent::FunctionCallArgumentsDelta { delta, .. } => self.accumulated_args_bytes += delta.len(),
+                _ => {}
+            }
+        }
+
+        // Some upstreams attach usage to the last content chunk rather than a separate one
+        if let Some(usage) = chunk.usage {
+            events.extend(self.process_usage(usage));
+        }
+
+        events
+    }
+
+    /// Reports token usage for the response, as sent by upstreams with `include_usage`.
+    pub fn process_usage(&mut self, usage: LegacyUsage) -> Vec<OrsEvent> {
+        self.usage_event(usage, false)
+    }
+
+    fn usage_event(&mut self, usage: LegacyUsage, is_approximate: bool) -> Vec<OrsEvent> {
+        self.usage_reported = true;
+        let seq = self.next_seq();
+        vec![OrsEvent::CompletionUsage {
+            sequence_number: seq,
+            input_tokens: usage.prompt_tokens,
+            output_tokens: usage.completion_tokens,
+            total_tokens: usage.total_tokens,
+            input_tokens_details: usage.prompt_tokens_details,
+            output_tokens_details: usage.completion_tokens_details,
+            is_approximate,
+        }]
+    }
+
+    /// Reports that the upstream is rate limiting and the request will be retried after `wait`.
+    pub fn queued(&mut self, wait: Duration) -> OrsEvent {
+        let seq = self.next_seq();
+        OrsEvent::Queued { sequence_number: seq, retry_after_ms: wait.as_millis() as u64 }
+    }
+
+    /// Reports that the response was aborted; nothing should be processed after this.
+    pub fn error(&mut self, code: &str, message: impl Into<String>) -> OrsEvent {
+        let seq = self.next_seq();
+        OrsEvent::Error { sequence_number: seq, code: code.to_string(), message: message.into() }
+    }
+
+    /// The final event of a completed response, carrying its collected output and usage.
+    pub fn done(&mut self, model: &str, status: &str, output: Vec<Value>, usage: Option<Value>) -> OrsEvent {
+        // Any -> Done
+        self.transition(TranscoderState::Done);
+        let seq = self.next_seq();
+        OrsEvent::ResponseDone {
+            sequence_number: seq,
+            id: self.response_id.clone(),
+            status: status.to_string(),
+            model: model.to_string(),
+            created_at: self.created_at,
+            output,
+            usage,
+        }
+    }
+
+    /// Closes the response once the upstream stream ends. If the upstream never reported
+    /// usage, emits an estimate from the prompt and the bytes of generated output, marked
+    /// `is_approximate`.
+    pub fn finish(&mut self) -> Vec<OrsEvent> {
+        if self.usage_reported || matches!(self.state, TranscoderState::Init) {
+            return Vec::new();
+        }
+        let output_tokens = crate::upstream::estimate_tokens(self.accumulated_text_bytes + self.accumulated_args_bytes);
+        let usage = LegacyUsage {
+            prompt_tokens: self.prompt_estimate,
+            completion_tokens: output_tokens,
+            total_tokens: self.prompt_estimate + output_tokens,
+            prompt_tokens_details: None,
+            completion_tokens_details: None,
+        };
+        self.usage_event(usage, true)
+    }
+
+    /// Closes the choice's current item as completed, so its next chunk starts a new one. For
+    /// upstreams that mark where items end themselves, like Anthropic's content blocks.
+    pub fn end_item(&mut self, choice_index: usize) -> Vec<OrsEvent> {
+        let mut events = Vec::new();
+        let Some(mut state) = self.choices.remove(&choice_index) else { return events };
+        self.close_item(&mut state, "completed", &mut events);
+        state.started = false;
+        self.choices.insert(choice_index, state);
+        events
+    }
+
+    /// Closes every item still open, as `incomplete`, when the response is cut short.
+    pub fn interrupt(&mut self) -> Vec<OrsEvent> {
+        let mut events = Vec::new();
+        let mut open: Vec<(u32, usize)> = self
+            .choices
+            .iter()
+            .filter(|(_, state)| state.current_item.is_some())
+            .map(|(&index, state)| (state.output_index, index))
+            .collect();
+        open.sort_unstable();
+        for (_, index) in open {
+            let mut state = self.choices.remove(&index).unwrap_or_default();
+            self.close_item(&mut state, "incomplete", &mut events);
+            self.choices.insert(index, state);
+        }
+        events
+    }
+
+    fn process_choice(&mut self, choice: &LegacyChoice, state: &mut ChoiceState, events: &mut Vec<OrsEvent>) {
+        if !state.started {
+            state.started = true;
 
-                self.state = TranscoderState::Streaming;
+            let has_tool_calls = choice.delta.tool_calls.as_ref().map(|tc| !tc.is_empty()).unwrap_or(false);
+            let has_content = choice.delta.content.as_ref().map(|s| !s.is_empty()).unwrap_or(false);
+
+            if !has_tool_calls || has_content {
+                let item = ResponseItem::message(format!("msg_{}", Uuid::new_v4().simple()));
+                self.add_item(state, item, events);
             }
+        }
+        let output_index = state.output_index;
 
-            let item_id = self.current_item_id.as_ref().cloned().unwrap_or_default(); // Fallback if no item started (should be handled by tool loop if skipped)
-            
-            // 2. Handle Content Deltas
-            if let Some(content) = &choice.delta.content {
-                if !content.is_empty() {
-                    // If we skipped message creation but got content now (shouldn't happen if logic above is correct for 1st chunk),
-                    // logic works because has_content would be true.
-                    // But if item_id is empty (from unwrap_or_default)?
-                    if !item_id.is_empty() {
-                        // Check if we need to start a content part
-                        if !self.has_emitted_content_start {
-                            let seq = self.next_seq();
-                            let content_idx = self.current_content_index.unwrap_or(0); // Default to 0 for first part
-                            self.current_content_index = Some(content_idx);
-                            
-                            events.push(OrsEvent::ContentPartAdded {
-                                sequence_number: seq,
-                                item_id: item_id.clone(),
-                                output_index: Some(0), // Simple proxy assumes single output
-                                content_index: Some(content_idx),
-                                part: serde_json::json!({ "type": "output_text", "text": "" }),
-                            });
-                            self.has_emitted_content_start = true;
-                        }
+        let item_id = state.current_item.as_ref().map(|item| item.id().to_string()).unwrap_or_default(); // Fallback if no item started (should be handled by tool loop if skipped)
 
-                        let seq = self.next_seq();
-                        events.push(OrsEvent::TextDelta {
-                            sequence_number: seq,
-                            item_id: item_id.clone(),
-                            output_index: Some(0),
-                            content_index: self.current_content_index,
-                            delta: content.clone(),
-                        });
-                     }
+        // 2. Handle Content Deltas
+        // Reasoning arrives under different keys depending on the upstream (vLLM/DeepSeek vs Ollama)
+        let reasoning = choice.delta.reasoning_content.as_deref()
+            .or(choice.delta.reasoning.as_deref())
+            .filter(|s| !s.is_empty());
+
+        // If item_id is empty (from unwrap_or_default), no message item was started to hold content
+        if let Some(reasoning) = reasoning {
+            if !item_id.is_empty() {
+                // -> StreamingText
+                self.transition(TranscoderState::StreamingText);
+                let content_idx = self.ensure_content_part(state, output_index, "reasoning_text", &item_id, events);
+                let seq = self.next_seq();
+                events.push(OrsEvent::ReasoningDelta {
+                    sequence_number: seq,
+                    item_id: item_id.clone(),
+                    output_index: Some(output_index),
+                    content_index: Some(content_idx),
+                    delta: reasoning.to_string(),
+                });
+            }
+        }
+
+        if let Some(content) = &choice.delta.content {
+            if !content.is_empty() && !item_id.is_empty() {
+                // -> StreamingText, which mid tool call puts the text on the call's item
+                self.transition(TranscoderState::StreamingText);
+                let content_idx = self.ensure_content_part(state, output_index, "output_text", &item_id, events);
+                if self.full_text_parts {
+                    if let Some(part) = state.content_part_states.last_mut() {
+                        part.text.push_str(content);
+                    }
                 }
+                let seq = self.next_seq();
+                events.push(OrsEvent::TextDelta {
+                    sequence_number: seq,
+                    item_id: item_id.clone(),
+                    output_index: Some(output_index),
+                    content_index: Some(content_idx),
+                    delta: content.clone(),
+                    logprobs: choice.logprobs.as_ref().or(choice.delta.logprobs.as_ref()).and_then(logprob_entries),
+                });
+            }
+        }
+
+        let action = choice.delta.computer_use.as_ref()
+            .filter(|block| block.get("type").and_then(Value::as_str) == Some("computer_use"))
+            .and_then(|block| block.get("action"));
+        if let Some(action) = action {
+            if !item_id.is_empty() {
+                // -> StreamingText
+                self.transition(TranscoderState::StreamingText);
+                let content_idx = self.ensure_content_part(state, output_index, "computer_use", &item_id, events);
+                let seq = self.next_seq();
+                events.push(OrsEvent::ComputerUseAction {
+                    sequence_number: seq,
+                    item_id: item_id.clone(),
+                    output_index: Some(output_index),
+                    content_index: Some(content_idx),
+                    action: action.clone(),
+                });
             }
+        }
 
-            if let Some(tool_calls) = &choice.delta.tool_calls {
-                for tool_call in tool_calls {
-                    // Check if this tool call starts a new item (has 'id')
-                    // Note: Legacy chunks can contain multiple tool calls or updates to existing ones.
-                    // We assume sequential processing for now. 
-                    // A new 'id' implies a new function call item.
-                    
-                    // Extract relevant fields
-                    let id = tool_call.get("id").and_then(|v| v.as_str());
-                    let function = tool_call.get("function");
-                    let name = function.and_then(|f| f.get("name").and_then(|n| n.as_str()));
-                    let args_delta = function.and_then(|f| f.get("arguments").and_then(|a| a.as_str()));
-                    
-                    if let Some(call_id) = id {
-                        // New Function Call Item!
-                        let new_item_id = format!("fc_{}", Uuid::new_v4().simple());
-                        self.current_item_id = Some(new_item_id.clone());
-                        
-                        let call_name = name.unwrap_or("unknown"); // Name usually comes with ID
-                        
-                        let seq = self.next_seq();
-                        events.push(OrsEvent::ItemAdded {
-                            sequence_number: seq,
-                            item: serde_json::json!({
-                                "id": new_item_id,
-                                "type": "function_call",
-                                "status": "in_progress",
-                                "call_id": call_id,
-                                "name": call_name,
-                                "arguments": "" // Initial state
-                            }),
-                        });
-                        self.current_item_type = Some("function_call".to_string());
+        if let Some(tool_calls) = &choice.delta.tool_calls {
+            for tool_call in tool_calls {
+                // Check if this tool call starts a new item (has 'id')
+                // Note: Legacy chunks can contain multiple tool calls or updates to existing ones.
+                // We assume sequential processing for now. 
+                // A new 'id' implies a new function call item.
+                
+                // Extract relevant fields
+                let id = tool_call.get("id").and_then(|v| v.as_str());
+                let function = tool_call.get("function");
+                let name = function.and_then(|f| f.get("name").and_then(|n| n.as_str()));
+                let args_delta = function.and_then(|f| f.get("arguments").and_then(|a| a.as_str()));
+                
+                if let Some(call_id) = id {
+                    let in_tool_call = matches!(state.current_item, Some(ResponseItem::FunctionCall { .. }));
+                    if self.sequential_tool_calls && in_tool_call {
+                        self.overlapping_tool_calls += 1;
+                        tracing::warn!(
+                            "Upstream started tool call {} while another was in flight despite parallel_tool_calls=false",
+                            call_id
+                        );
                     }
-                    
-                    // If we have an active item and args delta, emit it
-                    // We assume self.current_item_id is pointing to the function call now
-                    if let Some(delta) = args_delta {
-                        if !delta.is_empty() {
-                            let current_id = self.current_item_id.clone();
-                            if let Some(current_id) = current_id {
-                                 let seq = self.next_seq();
-                                 events.push(OrsEvent::FunctionCallArgumentsDelta {
-                                     sequence_number: seq,
-                                     item_id: current_id,
-                                     output_index: Some(0),
-                                     delta: delta.to_string(),
-                                 });
-                            }
+                    // The item in flight, message or earlier call, is complete once the next starts
+                    self.close_item(state, "completed", events);
+                    // New Function Call Item! -> StreamingFunctionArgs
+                    self.transition(TranscoderState::StreamingFunctionArgs);
+                    let call_name = name.unwrap_or("unknown"); // Name usually comes with ID
+                    let item = ResponseItem::function_call(format!("fc_{}", Uuid::new_v4().simple()), call_id, call_name);
+                    self.add_item(state, item, events);
+                }
+                
+                // If we have an active item and args delta, emit it
+                // We assume current_item is the function call now
+                if let Some(delta) = args_delta {
+                    if !delta.is_empty() {
+                        if let Some(item) = state.current_item.as_mut() {
+                             if let ResponseItem::FunctionCall { arguments, .. } = item {
+                                 arguments.push_str(delta);
+                             }
+                             let current_id = item.id().to_string();
+                             let seq = self.next_seq();
+                             events.push(OrsEvent::FunctionCallArgumentsDelta {
+                                 sequence_number: seq,
+                                 item_id: current_id,
+                                 output_index: Some(state.output_index),
+                                 delta: delta.to_string(),
+                             });
                         }
                     }
                 }
             }
+        }
+        
+        // 3. Handle Completion
+        if let Some(finish_reason) = &choice.finish_reason {
+            let status = match finish_reason.as_str() {
+                "stop" => "completed",
+                "length" => "incomplete",
+                "content_filter" => "incomplete", // or failed? Spec says incomplete is exhaustion. Content filter is effectively incomplete/refused.
+                _ => "completed",
+            };
             
-            // 3. Handle Completion
-            if let Some(finish_reason) = &choice.finish_reason {
-                let status = match finish_reason.as_str() {
-                    "stop" => "completed",
-                    "length" => "incomplete",
-                    "content_filter" => "incomplete", // or failed? Spec says incomplete is exhaustion. Content filter is effectively incomplete/refused.
-                    _ => "completed",
-                };
-                
-                // If we were streaming content, close the content part first
-                if self.has_emitted_content_start {
-                     let seq = self.next_seq();
-                     let content_idx = self.current_content_index.unwrap_or(0);
-                     // We don't track accumulated text here easily without buffering. 
-                     // But spec example shows "text": "full text" in ContentPartDone.
-                     // The spec says "The content part is then closed with response.content_part.done".
-                     // Ideally we should send the final part state. 
-                     // IMPORTANT: Since we are valid-proxying, we might not have the full text if we didn't buffer.
-                     // The spec allows the Part in Done event. 
-                     // Verify if Part is required to be fully populated? 
-                     // "part": { "type": "output_text", "text": "..." }
-                     // If we don't have it, we might just emit the type. 
-                     // However, to be safe and simple, let's skip buffering for now and send what we can or empty string?
-                     // Actually, if we are just a proxy, maybe we can omit the `text` field in `done` if unnecessary?
-                     // Spec example uses it. 
-                     // Let's rely on the fact that we sent Deltas.
-                     
-                     events.push(OrsEvent::ContentPartDone {
-                        sequence_number: seq,
-                        item_id: item_id.clone(),
-                        output_index: Some(0),
-                        content_index: Some(content_idx),
-                        part: serde_json::json!({ "type": "output_text", "text": "" }), // Placeholder or nothing
-                     });
-                     
-                     self.has_emitted_content_start = false;
-                     self.current_content_index = None;
-                }
+            // If we were streaming content, close the content part first
+            let output_index = state.output_index;
+            self.close_content_part(state, output_index, &item_id, events);
+            state.content_part_states.clear();
 
-                let seq = self.next_seq();
-                let item_type = self.current_item_type.as_deref().unwrap_or("message");
-                
-                events.push(OrsEvent::ItemDone {
-                    sequence_number: seq,
-                    output_index: Some(0),
-                    item: serde_json::json!({
-                        "id": item_id,
-                        "type": item_type,
-                        "status": status.to_string(),
-                    }),
+            let seq = self.next_seq();
+            let mut item = state.current_item.take().unwrap_or_else(|| ResponseItem::message(item_id));
+            item.set_status(status);
+
+            events.push(OrsEvent::ItemDone {
+                sequence_number: seq,
+                output_index: Some(output_index),
+                item,
+            });
+        }
+    }
+}
+
+/// A content part as announced and closed; computer use parts have no text, only the actions
+/// streamed into them.
+fn content_part(part_type: &str, text: String) -> Value {
+    match part_type {
+        "computer_use" => serde_json::json!({ "type": part_type }),
+        _ => serde_json::json!({ "type": part_type, "text": text }),
+    }
+}
+
+/// Parses an upstream `logprobs` object, `{"content": [...]}`. Some servers send the entries
+/// on the delta rather than the choice; either way, malformed ones are dropped.
+fn logprob_entries(logprobs: &Value) -> Option<Vec<LogprobEntry>> {
+    let entries = logprobs.get("content")?.as_array()?;
+    Some(entries.iter().filter_map(|entry| serde_json::from_value(entry.clone()).ok()).collect())
+}
+
+/// Folds a finished event stream into a single non-streaming response object, with each
+/// message's content parts rebuilt from their deltas.
+///
+/// See [`response_status`] for the status.
+pub fn collect_response(model: &str, events: &[OrsEvent]) -> Value {
+    let mut id = String::new();
+    let mut created_at = 0;
+    let mut parts: HashMap<&str, Vec<Value>> = HashMap::new();
+    let mut output = Vec::new();
+    let mut usage = Value::Null;
+    let mut error = Value::Null;
+
+    for event in events {
+        match event {
+            OrsEvent::Created { id: response_id, created_at: started, .. } => {
+                id = response_id.clone();
+                created_at = *started;
+            }
+            OrsEvent::ContentPartAdded { item_id, part, .. } => parts.entry(item_id).or_default().push(part.clone()),
+            OrsEvent::TextDelta { item_id, content_index, delta, .. }
+            | OrsEvent::ReasoningDelta { item_id, content_index, delta, .. } => {
+                let part = parts
+                    .get_mut(item_id.as_str())
+                    .and_then(|parts| parts.get_mut(content_index.unwrap_or(0) as usize));
+                let Some(part) = part else { continue };
+                if let Some(Value::String(text)) = part.get_mut("text") {
+                    text.push_str(delta);
+                }
+                if let (OrsEvent::TextDelta { logprobs: Some(logprobs), .. }, Value::Object(part)) = (event, part) {
+                    if let Value::Array(all) = part.entry("logprobs").or_insert_with(|| Value::Array(Vec::new())) {
+                        all.extend(logprobs.iter().filter_map(|entry| serde_json::to_value(entry).ok()));
+                    }
+                }
+            }
+            OrsEvent::ComputerUseAction { item_id, content_index, action, .. } => {
+                let part = parts
+                    .get_mut(item_id.as_str())
+                    .and_then(|parts| parts.get_mut(content_index.unwrap_or(0) as usize));
+                if let Some(Value::Object(part)) = part {
+                    if let Value::Array(actions) = part.entry("actions").or_insert_with(|| Value::Array(Vec::new())) {
+                        actions.push(action.clone());
+                    }
+                }
+            }
+            OrsEvent::ItemDone { item, .. } => {
+                let mut item = item.clone();
+                if let ResponseItem::Message { id, content, .. } = &mut item {
+                    *content = parts.remove(id.as_str()).unwrap_or_default();
+                }
+                output.push(item.to_json_value());
+            }
+            OrsEvent::CompletionUsage { input_tokens, output_tokens, total_tokens, input_tokens_details, output_tokens_details, is_approximate, .. } => {
+                usage = serde_json::json!({
+                    "input_tokens": input_tokens,
+                    "output_tokens": output_tokens,
+                    "total_tokens": total_tokens,
+                    "input_tokens_details": input_tokens_details,
+                    "output_tokens_details": output_tokens_details,
                 });
-                
-                self.current_item_id = None;
-                self.current_item_type = None;
+                if *is_approximate {
+                    usage["is_approximate"] = Value::Bool(true);
+                }
             }
+            OrsEvent::Error { code, message, .. } => {
+                error = serde_json::json!({ "code": code, "message": message });
+            }
+            _ => {}
         }
+    }
 
-        events
+    serde_json::json!({
+        "id": id,
+        "object": "response",
+        "created_at": created_at,
+        "status": response_status(events),
+        "model": model,
+        "output": output,
+        "usage": usage,
+        "error": error,
+    })
+}
+
+/// `failed` if the response was cut short, `incomplete` if any item ended early (e.g. hit the
+/// token limit), otherwise `completed`.
+pub fn response_status(events: &[OrsEvent]) -> &'static str {
+    if events.iter().any(|event| matches!(event, OrsEvent::Error { .. })) {
+        "failed"
+    } else if events.iter().any(|event| matches!(event, OrsEvent::ItemDone { item, .. } if item.status() == "incomplete")) {
+        "incomplete"
+    } else {
+        "completed"
     }
 }
 
+/// The response's output as conversation items, in the order they were started: messages with
+/// their full text as assistant messages, and function calls with their arguments.
+pub fn output_items(events: &[OrsEvent]) -> Vec<OrsInputItem> {
+    struct ItemState {
+        item_type: String,
+        content: String,
+        call_id: Option<String>,
+        name: Option<String>,
+    }
+    let mut items: HashMap<String, ItemState> = HashMap::new();
+    let mut order: Vec<String> = Vec::new();
+
+    for event in events {
+        match event {
+            OrsEvent::ItemAdded { item, .. } => {
+                let item = item.to_json_value();
+                let item_id = item.get("id").and_then(|v| v.as_str()).unwrap_or("unknown").to_string();
+                let item_type = item.get("type").and_then(|v| v.as_str()).unwrap_or("unknown").to_string();
+                let call_id = item.get("call_id").and_then(|v| v.as_str()).map(str::to_string);
+                let name = item.get("name").and_then(|v| v.as_str()).map(str::to_string);
+                items.insert(item_id.clone(), ItemState { item_type, content: String::new(), call_id, name });
+                order.push(item_id);
+            }
+            OrsEvent::TextDelta { item_id, delta, .. } | OrsEvent::FunctionCallArgumentsDelta { item_id, delta, .. } => {
+                if let Some(state) = items.get_mut(item_id) {
+                    state.content.push_str(delta);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    order
+        .into_iter()
+        .filter_map(|item_id| {
+            let state = items.remove(&item_id)?;
+            // Function calls must survive as such so later FunctionCallOutputs can be matched
+            // against them
+            Some(match (state.call_id, state.name) {
+                (Some(call_id), Some(name)) if state.item_type == "function_call" => OrsInputItem::FunctionCall {
+                    id: item_id,
+                    call_id,
+                    name,
+                    arguments: serde_json::from_str(&state.content).unwrap_or(Value::String(state.content)),
+                },
+                _ => OrsInputItem::Message {
+                    role: OrsRole::Assistant,
+                    content: vec![OrsContentPart::InputText { text: state.content }],
+                },
+            })
+        })
+        .collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::{LegacyChoice, LegacyDelta};
-    use serde_json::Value;
+    use crate::types::LegacyDelta;
 
     fn make_chunk(content: Option<&str>, finish_reason: Option<&str>) -> LegacyChunk {
         LegacyChunk {
             choices: vec![LegacyChoice {
+                index: 0,
                 delta: LegacyDelta {
                     content: content.map(|s| s.to_string()),
-                    tool_calls: None,
-                    extra: Value::Null,
+                    ..Default::default()
                 },
                 finish_reason: finish_reason.map(|s| s.to_string()),
+                logprobs: None,
             }],
+            usage: None,
         }
     }
 
@@ -259,10 +778,7 @@ mod tests {
             _ => panic!("First event should be Created"),
         }
         match &events[1] {
-            OrsEvent::ItemAdded { item, .. } => {
-                // item is Value
-                assert_eq!(item["type"], "message");
-            },
+            OrsEvent::ItemAdded { item, .. } => assert!(matches!(item, ResponseItem::Message { .. })),
             _ => panic!("Second event should be ItemAdded"),
         }
 
@@ -289,7 +805,7 @@ mod tests {
              _ => panic!("Should be ContentPartDone"),
         }
         match &events[1] {
-            OrsEvent::ItemDone { item, .. } => assert_eq!(item["status"], "completed"),
+            OrsEvent::ItemDone { item, .. } => assert_eq!(item.to_json_value()["status"], "completed"),
             _ => panic!("Should be ItemDone"),
         }
     }
@@ -325,9 +841,7 @@ mod tests {
         }
         match &events1[1] {
             OrsEvent::ItemAdded { item, .. } => {
-                assert_eq!(item["type"], "function_call");
-                assert_eq!(item["call_id"], "call_123");
-                assert_eq!(item["name"], "get_weather");
+                assert_eq!(item, &ResponseItem::function_call(item.id(), "call_123", "get_weather"));
             },
             _ => panic!("Expected ItemAdded"),
         }
@@ -362,10 +876,400 @@ mod tests {
         let chunk3: LegacyChunk = serde_json::from_value(chunk3_json).unwrap();
         let events3 = transcoder.process(chunk3);
         assert_eq!(events3.len(), 1);
-        if let OrsEvent::ItemDone { item, .. } = &events3[0] {
-            assert_eq!(item["status"], "completed");
+        if let OrsEvent::ItemDone { item: ResponseItem::FunctionCall { status, arguments, .. }, .. } = &events3[0] {
+            assert_eq!(status, "completed");
+            assert_eq!(arguments, "{\"loc\"");
         } else {
             panic!("Expected ItemDone");
         }
     }
+
+    #[test]
+    fn test_transcoder_reasoning_then_text_parts() {
+        let mut transcoder = Transcoder::new();
+
+        let chunk1: LegacyChunk = serde_json::from_value(serde_json::json!({
+            "choices": [{ "delta": { "reasoning_content": "Thinking" } }]
+        })).unwrap();
+        let events = transcoder.process(chunk1);
+        assert_eq!(events.len(), 4);
+        match &events[2] {
+            OrsEvent::ContentPartAdded { content_index, part, .. } => {
+                assert_eq!(*content_index, Some(0));
+                assert_eq!(part["type"], "reasoning_text");
+            }
+            _ => panic!("Expected ContentPartAdded"),
+        }
+        assert!(matches!(&events[3], OrsEvent::ReasoningDelta { delta, .. } if delta == "Thinking"));
+
+        // Switching to text closes the reasoning part and opens a new one
+        let events = transcoder.process(make_chunk(Some("Answer"), None));
+        assert_eq!(events.len(), 3);
+        assert!(matches!(&events[0], OrsEvent::ContentPartDone { content_index: Some(0), .. }));
+        match &events[1] {
+            OrsEvent::ContentPartAdded { content_index, part, .. } => {
+                assert_eq!(*content_index, Some(1));
+                assert_eq!(part["type"], "output_text");
+            }
+            _ => panic!("Expected ContentPartAdded"),
+        }
+        assert!(matches!(&events[2], OrsEvent::TextDelta { content_index: Some(1), .. }));
+
+        let events = transcoder.process(make_chunk(None, Some("stop")));
+        assert_eq!(events.len(), 2);
+        assert!(matches!(&events[0], OrsEvent::ContentPartDone { content_index: Some(1), .. }));
+        assert!(matches!(&events[1], OrsEvent::ItemDone { .. }));
+    }
+
+    #[test]
+    fn test_logprobs_ride_on_text_deltas() {
+        let mut transcoder = Transcoder::new();
+        let chunk: LegacyChunk = serde_json::from_value(serde_json::json!({
+            "choices": [{
+                "index": 0,
+                "delta": { "content": "Yes" },
+                "finish_reason": null,
+                "logprobs": { "content": [{
+                    "token": "Yes",
+                    "logprob": -0.01,
+                    "bytes": [89, 101, 115],
+                    "top_logprobs": [{ "token": "No", "logprob": -4.6, "bytes": null }]
+                }] }
+            }]
+        }))
+        .unwrap();
+        let mut events = transcoder.process(chunk);
+        events.extend(transcoder.process(make_chunk(None, Some("stop"))));
+
+        let logprobs = events.iter().find_map(|event| match event {
+            OrsEvent::TextDelta { logprobs, .. } => logprobs.clone(),
+            _ => None,
+        });
+        let logprobs = logprobs.unwrap();
+        assert_eq!(logprobs[0].token, "Yes");
+        assert_eq!(logprobs[0].bytes.as_deref(), Some(&b"Yes"[..]));
+        assert_eq!(logprobs[0].top_logprobs[0].token, "No");
+
+        let response = collect_response("m", &events);
+        assert_eq!(response["output"][0]["content"][0]["logprobs"][0]["logprob"], -0.01);
+    }
+
+    #[test]
+    fn test_state_follows_the_response() {
+        let mut transcoder = Transcoder::new();
+        assert_eq!(transcoder.current_state(), TranscoderState::Init);
+
+        let role_only: LegacyChunk =
+            serde_json::from_value(serde_json::json!({ "choices": [{ "delta": { "role": "assistant" } }] })).unwrap();
+        transcoder.process(role_only);
+        assert_eq!(transcoder.current_state(), TranscoderState::AwaitingContent);
+
+        transcoder.process(make_chunk(Some("Let me check."), None));
+        assert_eq!(transcoder.current_state(), TranscoderState::StreamingText);
+
+        let call: LegacyChunk = serde_json::from_value(serde_json::json!({
+            "choices": [{ "delta": { "tool_calls": [{
+                "index": 0,
+                "id": "call_1",
+                "function": { "name": "get_weather", "arguments": "{}" }
+            }] } }]
+        }))
+        .unwrap();
+        transcoder.process(call);
+        assert_eq!(transcoder.current_state(), TranscoderState::StreamingFunctionArgs);
+
+        // Invalid, but the text still reaches the client
+        let events = transcoder.process(make_chunk(Some("stray"), None));
+        assert!(events.iter().any(|event| matches!(event, OrsEvent::TextDelta { delta, .. } if delta == "stray")));
+        assert_eq!(transcoder.current_state(), TranscoderState::StreamingText);
+
+        transcoder.process(make_chunk(None, Some("tool_calls")));
+        assert_eq!(transcoder.current_state(), TranscoderState::AwaitingCompletion);
+
+        transcoder.done("m", "completed", Vec::new(), None);
+        assert_eq!(transcoder.current_state(), TranscoderState::Done);
+        assert_eq!(TranscoderState::Done.to_string(), "done");
+
+        // Nothing belongs to the response once it's done
+        assert!(transcoder.process(make_chunk(Some("late"), None)).is_empty());
+        assert_eq!(transcoder.current_state(), TranscoderState::Done);
+    }
+
+    #[test]
+    fn test_computer_use_actions() {
+        let mut transcoder = Transcoder::new();
+        // Actions as Anthropic's computer tool describes them
+        let chunks = [
+            serde_json::json!({ "content": "I'll open the browser." }),
+            serde_json::json!({ "computer_use": { "type": "computer_use", "action": { "action": "screenshot" } } }),
+            serde_json::json!({ "computer_use": {
+                "type": "computer_use",
+                "action": { "action": "left_click", "coordinate": [512, 384] }
+            } }),
+        ];
+        let mut events = Vec::new();
+        for delta in chunks {
+            let chunk: LegacyChunk =
+                serde_json::from_value(serde_json::json!({ "choices": [{ "index": 0, "delta": delta }] })).unwrap();
+            events.extend(transcoder.process(chunk));
+        }
+        events.extend(transcoder.process(make_chunk(None, Some("stop"))));
+
+        let added: Vec<&Value> = events
+            .iter()
+            .filter_map(|event| match event {
+                OrsEvent::ContentPartAdded { part, .. } => Some(part),
+                _ => None,
+            })
+            .collect();
+        // One part for both actions
+        assert_eq!(added.len(), 2);
+        assert_eq!(added[1], &serde_json::json!({ "type": "computer_use" }));
+        let actions: Vec<&Value> = events
+            .iter()
+            .filter_map(|event| match event {
+                OrsEvent::ComputerUseAction { content_index: Some(1), action, .. } => Some(action),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(actions.len(), 2);
+        assert_eq!(actions[1]["coordinate"], serde_json::json!([512, 384]));
+        assert_eq!(serde_json::to_value(&events[6]).unwrap()["type"], "response.computer_use.action");
+
+        let response = collect_response("m", &events);
+        let content = &response["output"][0]["content"];
+        assert_eq!(content[0]["text"], "I'll open the browser.");
+        assert_eq!(content[1]["actions"][0], serde_json::json!({ "action": "screenshot" }));
+    }
+
+    #[test]
+    fn test_content_index_increments_per_part() {
+        let mut transcoder = Transcoder::new();
+        let reasoning: LegacyChunk = serde_json::from_value(serde_json::json!({
+            "choices": [{ "delta": { "reasoning_content": "Hmm" } }]
+        }))
+        .unwrap();
+        let mut events = transcoder.process(make_chunk(Some("First"), None));
+        events.extend(transcoder.process(reasoning));
+        events.extend(transcoder.process(make_chunk(Some("Second"), Some("stop"))));
+
+        let indices = |added: bool| -> Vec<Option<u32>> {
+            events
+                .iter()
+                .filter_map(|e| match e {
+                    OrsEvent::ContentPartAdded { content_index, .. } if added => Some(*content_index),
+                    OrsEvent::ContentPartDone { content_index, .. } if !added => Some(*content_index),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(indices(true), vec![Some(0), Some(1), Some(2)]);
+        assert_eq!(indices(false), vec![Some(0), Some(1), Some(2)]);
+
+        let response = collect_response("gpt-4o", &events);
+        let texts: Vec<&str> = response["output"][0]["content"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|part| part["text"].as_str().unwrap())
+            .collect();
+        assert_eq!(texts, vec!["First", "Hmm", "Second"]);
+    }
+
+    #[test]
+    fn test_transcoder_parallel_choices() {
+        let mut transcoder = Transcoder::new();
+
+        let chunk: LegacyChunk = serde_json::from_value(serde_json::json!({
+            "choices": [
+                { "index": 0, "delta": { "content": "Hello" } },
+                { "index": 1, "delta": { "content": "Howdy" } }
+            ]
+        })).unwrap();
+        let events = transcoder.process(chunk);
+
+        // Created, then ItemAdded + ContentPartAdded + TextDelta per choice
+        assert_eq!(events.len(), 7);
+        let deltas: Vec<(Option<u32>, &str, &str)> = events.iter().filter_map(|e| match e {
+            OrsEvent::TextDelta { output_index, item_id, delta, .. } => Some((*output_index, item_id.as_str(), delta.as_str())),
+            _ => None,
+        }).collect();
+        assert_eq!(deltas.len(), 2);
+        assert_eq!((deltas[0].0, deltas[0].2), (Some(0), "Hello"));
+        assert_eq!((deltas[1].0, deltas[1].2), (Some(1), "Howdy"));
+        assert_ne!(deltas[0].1, deltas[1].1);
+
+        let chunk: LegacyChunk = serde_json::from_value(serde_json::json!({
+            "choices": [
+                { "index": 1, "delta": {}, "finish_reason": "stop" },
+                { "index": 0, "delta": {}, "finish_reason": "length" }
+            ]
+        })).unwrap();
+        let events = transcoder.process(chunk);
+        let done: Vec<(Option<u32>, Value)> = events.iter().filter_map(|e| match e {
+            OrsEvent::ItemDone { output_index, item, .. } => Some((*output_index, item.to_json_value()["status"].clone())),
+            _ => None,
+        }).collect();
+        assert_eq!(done, vec![(Some(1), Value::from("completed")), (Some(0), Value::from("incomplete"))]);
+    }
+
+    fn tool_call_chunk(call_id: &str) -> LegacyChunk {
+        serde_json::from_value(serde_json::json!({
+            "choices": [{
+                "delta": {
+                    "tool_calls": [{
+                        "index": 0,
+                        "id": call_id,
+                        "type": "function",
+                        "function": { "name": "get_weather", "arguments": "{}" }
+                    }]
+                }
+            }]
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parallel_tool_calls_allowed_by_default() {
+        let mut transcoder = Transcoder::new();
+        transcoder.process(tool_call_chunk("call_1"));
+        let events = transcoder.process(tool_call_chunk("call_2"));
+
+        assert!(matches!(&events[0], OrsEvent::ItemDone { item: ResponseItem::FunctionCall { call_id, .. }, .. } if call_id == "call_1"));
+        assert!(matches!(&events[1], OrsEvent::ItemAdded { item: ResponseItem::FunctionCall { call_id, .. }, .. } if call_id == "call_2"));
+        assert_eq!(transcoder.overlapping_tool_calls, 0);
+    }
+
+    #[test]
+    fn test_each_item_gets_its_own_output_index() {
+        let mut transcoder = Transcoder::new();
+        let mut events = transcoder.process(tool_call_chunk("call_1"));
+        events.extend(transcoder.process(tool_call_chunk("call_2")));
+        events.extend(transcoder.process(make_chunk(None, Some("tool_calls"))));
+
+        let indices: Vec<(&str, Option<u32>)> = events
+            .iter()
+            .filter_map(|e| match e {
+                OrsEvent::ItemAdded { output_index, .. } => Some(("added", *output_index)),
+                OrsEvent::FunctionCallArgumentsDelta { output_index, .. } => Some(("args", *output_index)),
+                OrsEvent::ItemDone { output_index, .. } => Some(("done", *output_index)),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            indices,
+            vec![
+                ("added", Some(0)),
+                ("args", Some(0)),
+                ("done", Some(0)),
+                ("added", Some(1)),
+                ("args", Some(1)),
+                ("done", Some(1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_sequential_tool_calls_flags_overlap() {
+        let mut transcoder = Transcoder::new().with_sequential_tool_calls(true);
+        transcoder.process(tool_call_chunk("call_1"));
+        assert_eq!(transcoder.overlapping_tool_calls, 0);
+
+        transcoder.process(tool_call_chunk("call_2"));
+        assert_eq!(transcoder.overlapping_tool_calls, 1);
+    }
+
+    #[test]
+    fn test_usage_only_chunk() {
+        let mut transcoder = Transcoder::new();
+        transcoder.process(make_chunk(Some("Hi"), Some("stop")));
+
+        let chunk: LegacyChunk = serde_json::from_str(
+            r#"{"choices":[],"usage":{"prompt_tokens":10,"completion_tokens":25,"total_tokens":35}}"#,
+        )
+        .unwrap();
+        let events = transcoder.process(chunk);
+        assert_eq!(events.len(), 1);
+        match &events[0] {
+            OrsEvent::CompletionUsage { total_tokens, .. } => assert_eq!(*total_tokens, 35),
+            other => panic!("Expected CompletionUsage, got {:?}", other),
+        }
+        assert!(transcoder.finish().is_empty());
+    }
+
+    #[test]
+    fn test_finish_estimates_missing_usage() {
+        let mut transcoder = Transcoder::new().with_prompt_estimate(12);
+        transcoder.process(make_chunk(Some("12345678"), None));
+        transcoder.process(make_chunk(None, Some("stop")));
+
+        match transcoder.finish().as_slice() {
+            [event @ OrsEvent::CompletionUsage { input_tokens, output_tokens, total_tokens, is_approximate, .. }] => {
+                assert_eq!((*input_tokens, *output_tokens, *total_tokens), (12, 2, 14));
+                assert!(*is_approximate);
+                assert_eq!(serde_json::to_value(event).unwrap()["is_approximate"], true);
+            }
+            other => panic!("Expected one CompletionUsage, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_collect_response_rebuilds_text_and_arguments() {
+        let mut transcoder = Transcoder::new();
+        let mut events = transcoder.process(make_chunk(Some("Hel"), None));
+        events.extend(transcoder.process(make_chunk(Some("lo"), Some("length"))));
+        events.extend(transcoder.finish());
+
+        let response = collect_response("gpt-4o", &events);
+        assert_eq!(response["model"], "gpt-4o");
+        assert_eq!(response["status"], "incomplete");
+        assert_eq!(response["output"][0]["content"][0], serde_json::json!({ "type": "output_text", "text": "Hello" }));
+        assert!(response["usage"]["total_tokens"].as_u64().is_some());
+    }
+
+    #[test]
+    fn test_done_follows_the_last_event() {
+        let mut transcoder = Transcoder::new();
+        let events = transcoder.process(make_chunk(Some("Hi"), Some("stop")));
+        let last_seq = events.last().and_then(OrsEvent::sequence_number).unwrap();
+
+        let done = transcoder.done("gpt-4o", "completed", vec![serde_json::json!({ "type": "message" })], None);
+        assert_eq!(done.sequence_number(), Some(last_seq + 1));
+        let json = serde_json::to_value(&done).unwrap();
+        assert_eq!(json["type"], "response.done");
+        assert_eq!(json["id"], transcoder.response_id);
+        assert_eq!(json["status"], "completed");
+        assert_eq!(json["model"], "gpt-4o");
+        assert_eq!(json["created_at"], transcoder.created_at);
+        assert_eq!(json["output"][0]["type"], "message");
+        assert!(json["usage"].is_null());
+    }
+
+    #[test]
+    fn test_full_text_parts() {
+        let done_text = |mut transcoder: Transcoder| {
+            transcoder.process(make_chunk(Some("Hel"), None));
+            let events = transcoder.process(make_chunk(Some("lo"), Some("stop")));
+            events
+                .into_iter()
+                .find_map(|e| match e {
+                    OrsEvent::ContentPartDone { part, .. } => Some(part["text"].clone()),
+                    _ => None,
+                })
+                .unwrap()
+        };
+        assert_eq!(done_text(Transcoder::new()), "");
+        assert_eq!(done_text(Transcoder::new().with_full_text_parts(true)), "Hello");
+    }
+
+    #[test]
+    fn test_created_at_is_fixed_at_construction() {
+        let mut transcoder = Transcoder::new();
+        let created_at = transcoder.created_at;
+        assert!(created_at > 1_600_000_000);
+
+        let events = transcoder.process(make_chunk(Some("Hi"), Some("stop")));
+        assert!(matches!(&events[0], OrsEvent::Created { created_at: at, .. } if *at == created_at));
+        assert_eq!(collect_response("gpt-4o", &events)["created_at"], created_at);
+    }
 }
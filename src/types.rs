@@ -1,21 +1,378 @@
-use serde::{Deserialize, Serialize};
+use crate::validation::Validate;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, HashSet};
 
 // ================================================================================================
 // ORS INBOUND (STRICT)
 // ================================================================================================
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Debug, Clone)]
 pub struct OrsRequest {
     pub model: String,
+    #[serde(deserialize_with = "deserialize_input")]
     pub input: Vec<OrsInputItem>,
     #[serde(default)]
     #[allow(dead_code)]
     pub store: bool,
     pub previous_response_id: Option<String>,
+    /// Stream SSE events rather than return one JSON response; decided by `Accept` when unset.
+    pub stream: Option<bool>,
+    pub metadata: Option<HashMap<String, String>>,
+    /// System prompt for the model; sent ahead of any developer messages in `input`.
+    pub instructions: Option<String>,
+    pub tool_choice: Option<Value>,
+    /// Function tools the model may call.
+    pub tools: Option<Vec<Value>>,
+    /// Set to `false` to have the model call at most one tool at a time.
+    pub parallel_tool_calls: Option<bool>,
+    /// Number of parallel generations to request.
+    pub n: Option<u32>,
+    /// How to trim the conversation when it's too long; defaults to the proxy's configured limits.
+    pub truncation_strategy: Option<TruncationStrategy>,
+    /// Output modalities, `"text"` and/or `"audio"`.
+    pub modalities: Option<Vec<String>>,
+    /// Voice settings; required when `modalities` includes `"audio"`.
+    pub audio: Option<AudioConfig>,
+    /// Optional response fields to opt in to; see [`SUPPORTED_INCLUDES`].
+    pub include: Option<Vec<String>>,
+    /// End-user identifier, passed on to the upstream for abuse monitoring.
+    pub user: Option<String>,
+    /// Answer `202 Accepted` immediately and generate the response in the background.
     #[serde(default)]
-    #[allow(dead_code)]
-    pub stream: bool,
+    pub background: bool,
+    /// Receives a POST with the finished response once it has been saved.
+    pub webhook_url: Option<String>,
+    /// Uploaded files (`POST /v1/files`) to attach to the conversation; they're sent with this
+    /// and every later turn.
+    pub file_ids: Option<Vec<String>>,
+    /// Token ID (as a string) to a bias in `[-100, 100]` added to its logit.
+    pub logit_bias: Option<HashMap<String, f64>>,
+    /// Report the log probability of each output token on `response.output_text.delta` events.
+    pub logprobs: Option<bool>,
+    /// With `logprobs`, also report this many of the most likely alternatives per token.
+    pub top_logprobs: Option<u32>,
+}
+
+/// `input` is either a list of items or a plain string, shorthand for one user text message.
+fn deserialize_input<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<OrsInputItem>, D::Error> {
+    struct InputVisitor;
+
+    impl<'de> de::Visitor<'de> for InputVisitor {
+        type Value = Vec<OrsInputItem>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a string or an array of input items")
+        }
+
+        fn visit_str<E: de::Error>(self, text: &str) -> Result<Self::Value, E> {
+            Ok(vec![OrsInputItem::Message {
+                role: OrsRole::User,
+                content: vec![OrsContentPart::InputText { text: text.to_string() }],
+            }])
+        }
+
+        fn visit_seq<A: de::SeqAccess<'de>>(self, seq: A) -> Result<Self::Value, A::Error> {
+            Deserialize::deserialize(de::value::SeqAccessDeserializer::new(seq))
+        }
+    }
+
+    deserializer.deserialize_any(InputVisitor)
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TruncationStrategy {
+    /// `"auto"` trims the oldest items to fit; `"disabled"` sends the full context as-is.
+    #[serde(rename = "type")]
+    pub type_: String,
+    /// With `"auto"`, keep at most this many non-system items.
+    pub last_messages: Option<u32>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct AudioConfig {
+    pub voice: String,
+    pub format: String,
+}
+
+pub const MAX_METADATA_PAIRS: usize = 16;
+pub const MAX_METADATA_VALUE_CHARS: usize = 512;
+/// OpenAI's limit on `logit_bias` entries.
+pub const MAX_LOGIT_BIAS_ENTRIES: usize = 300;
+pub const MAX_LOGIT_BIAS: f64 = 100.0;
+pub const MAX_TOP_LOGPROBS: u32 = 20;
+/// Longer model names are rejected outright; they end up in logs and error messages.
+pub const MAX_MODEL_NAME_CHARS: usize = 256;
+pub const MAX_USER_CHARS: usize = 256;
+
+/// Conversation IDs are 8-128 ASCII letters, digits, `_` or `-` (generated ones are UUIDs).
+pub fn is_valid_conversation_id(id: &str) -> bool {
+    (8..=128).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// File IDs a request can attach are the upstream's own, `file-` and ASCII letters or digits.
+pub fn is_valid_file_reference(id: &str) -> bool {
+    id.strip_prefix("file-")
+        .is_some_and(|rest| !rest.is_empty() && rest.len() <= 128 && rest.bytes().all(|b| b.is_ascii_alphanumeric()))
+}
+
+const SUPPORTED_MODALITIES: &[&str] = &["text", "audio"];
+
+/// Values accepted in `include`:
+/// - `output_text`: `response.content_part.done` events carry the part's full text rather
+///   than an empty string.
+/// - `message.input_image.image_url`, `reasoning.encrypted_content`, `file_search_call.results`:
+///   accepted for compatibility with ORS clients that send them by default; the proxy never
+///   strips these fields, so they have no effect.
+pub const SUPPORTED_INCLUDES: &[&str] = &[
+    "output_text",
+    "message.input_image.image_url",
+    "reasoning.encrypted_content",
+    "file_search_call.results",
+];
+
+/// A request that is well-formed JSON but semantically invalid; surfaced to the client as a 400.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValidationError {
+    pub param: String,
+    pub message: String,
+    /// Machine-readable reason, for errors clients are expected to handle specifically.
+    pub code: Option<&'static str>,
+}
+
+impl ValidationError {
+    pub fn new(param: impl Into<String>, message: impl Into<String>) -> Self {
+        Self { param: param.into(), message: message.into(), code: None }
+    }
+
+    pub fn with_code(mut self, code: &'static str) -> Self {
+        self.code = Some(code);
+        self
+    }
+}
+
+/// Matches a model name against a `KNOWN_MODELS` entry, where `*` stands for any run of characters.
+pub fn model_matches(pattern: &str, model: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = model.strip_prefix(first) else {
+        return false;
+    };
+    let mut parts: Vec<&str> = parts.collect();
+    let Some(last) = parts.pop() else {
+        // No wildcard at all
+        return rest.is_empty();
+    };
+    for part in parts {
+        match rest.find(part) {
+            Some(i) => rest = &rest[i + part.len()..],
+            None => return false,
+        }
+    }
+    rest.len() >= last.len() && rest.ends_with(last)
+}
+
+impl Validate for OrsRequest {
+    /// Everything that can be checked from the request alone.
+    fn validate(&self) -> Result<(), ValidationError> {
+        if self.model.chars().count() > MAX_MODEL_NAME_CHARS {
+            return Err(ValidationError::new(
+                "model",
+                format!("model may be at most {} characters", MAX_MODEL_NAME_CHARS),
+            ));
+        }
+
+        if self.user.as_ref().is_some_and(|user| user.chars().count() > MAX_USER_CHARS) {
+            return Err(ValidationError::new("user", format!("user may be at most {} characters", MAX_USER_CHARS)));
+        }
+
+        // Empty messages turn into `content: null` upstream, which some providers reject
+        for (i, item) in self.input.iter().enumerate() {
+            let OrsInputItem::Message { content, .. } = item else {
+                continue;
+            };
+            if content.is_empty() {
+                return Err(ValidationError::new(format!("input[{}].content", i), "content array must not be empty"));
+            }
+            for (j, part) in content.iter().enumerate() {
+                if matches!(part, OrsContentPart::InputText { text } if text.trim().is_empty()) {
+                    return Err(ValidationError::new(
+                        format!("input[{}].content[{}].text", i, j),
+                        "text must not be empty",
+                    ));
+                }
+            }
+        }
+
+        if self.tool_choice.as_ref().and_then(Value::as_str) == Some("required") {
+            let answered: HashSet<&str> = self.input.iter().filter_map(OrsInputItem::output_call_id).collect();
+            for item in &self.input {
+                if let OrsInputItem::FunctionCall { call_id, .. } = item {
+                    if !answered.contains(call_id.as_str()) {
+                        return Err(ValidationError::new(
+                            "input",
+                            format!("FunctionCall has no corresponding FunctionCallOutput: {}", call_id),
+                        ));
+                    }
+                }
+            }
+        }
+
+        if self.parallel_tool_calls.is_some() && self.tools.as_ref().is_none_or(|t| t.is_empty()) {
+            return Err(ValidationError::new("parallel_tool_calls", "parallel_tool_calls is only allowed when tools are provided"));
+        }
+
+        if let Some(metadata) = &self.metadata {
+            if metadata.len() > MAX_METADATA_PAIRS {
+                return Err(ValidationError::new(
+                    "metadata",
+                    format!("metadata may contain at most {} key-value pairs", MAX_METADATA_PAIRS),
+                ));
+            }
+            if let Some((key, _)) = metadata.iter().find(|(_, v)| v.chars().count() > MAX_METADATA_VALUE_CHARS) {
+                return Err(ValidationError::new(
+                    format!("metadata.{}", key),
+                    format!("metadata values may be at most {} characters", MAX_METADATA_VALUE_CHARS),
+                ));
+            }
+        }
+
+        if let Some(file_ids) = &self.file_ids {
+            if let Some((i, id)) = file_ids.iter().enumerate().find(|(_, id)| !is_valid_file_reference(id)) {
+                return Err(ValidationError::new(format!("file_ids[{}]", i), format!("Invalid file ID: {}", id)));
+            }
+        }
+
+        if let Some(logit_bias) = &self.logit_bias {
+            if logit_bias.len() > MAX_LOGIT_BIAS_ENTRIES {
+                return Err(ValidationError::new(
+                    "logit_bias",
+                    format!("logit_bias may contain at most {} entries", MAX_LOGIT_BIAS_ENTRIES),
+                ));
+            }
+            if let Some(token) = logit_bias.keys().find(|token| token.parse::<u32>().is_err()) {
+                return Err(ValidationError::new(
+                    format!("logit_bias.{}", token),
+                    "logit_bias keys must be token IDs",
+                ));
+            }
+            if let Some((token, _)) = logit_bias.iter().find(|(_, bias)| !(-MAX_LOGIT_BIAS..=MAX_LOGIT_BIAS).contains(*bias)) {
+                return Err(ValidationError::new(
+                    format!("logit_bias.{}", token),
+                    format!("logit_bias values must be between -{0} and {0}", MAX_LOGIT_BIAS),
+                ));
+            }
+        }
+
+        if let Some(top_logprobs) = self.top_logprobs {
+            if top_logprobs > MAX_TOP_LOGPROBS {
+                return Err(ValidationError::new(
+                    "top_logprobs",
+                    format!("top_logprobs may be at most {}", MAX_TOP_LOGPROBS),
+                ));
+            }
+            if self.logprobs != Some(true) {
+                return Err(ValidationError::new("top_logprobs", "top_logprobs requires logprobs to be true"));
+            }
+        }
+
+        if let Some(strategy) = &self.truncation_strategy {
+            if strategy.type_ != "auto" && strategy.type_ != "disabled" {
+                return Err(ValidationError::new(
+                    "truncation_strategy.type",
+                    format!("Unsupported truncation strategy: {} (expected auto or disabled)", strategy.type_),
+                ));
+            }
+        }
+
+        if let Some(include) = &self.include {
+            if let Some((i, unknown)) = include.iter().enumerate().find(|(_, v)| !SUPPORTED_INCLUDES.contains(&v.as_str())) {
+                return Err(ValidationError::new(
+                    format!("include[{}]", i),
+                    format!("Unsupported include value: {} (expected one of: {})", unknown, SUPPORTED_INCLUDES.join(", ")),
+                ));
+            }
+        }
+
+        if let Some(modalities) = &self.modalities {
+            if let Some(unknown) = modalities.iter().find(|m| !SUPPORTED_MODALITIES.contains(&m.as_str())) {
+                return Err(ValidationError::new(
+                    "modalities",
+                    format!("Unsupported modality: {} (expected one of: text, audio)", unknown),
+                ));
+            }
+            if self.wants_audio() && self.audio.is_none() {
+                return Err(ValidationError::new("audio", "audio is required when modalities includes \"audio\""));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl OrsRequest {
+    /// Checks the request against `history`, the context loaded for `previous_response_id`,
+    /// and against `known_models` unless that is empty. The rest is [`Validate::validate`].
+    pub fn validate_context(&self, history: &[OrsInputItem], known_models: &[String]) -> Result<(), ValidationError> {
+        if !known_models.is_empty() && !known_models.iter().any(|p| model_matches(p, &self.model)) {
+            return Err(ValidationError::new(
+                "model",
+                format!("Unknown model: '{}' Available models: [{}]", self.model, known_models.join(", ")),
+            )
+            .with_code("model_not_found"));
+        }
+
+        let mut known_calls: HashSet<&str> = history
+            .iter()
+            .filter_map(|item| match item {
+                OrsInputItem::FunctionCall { call_id, .. } => Some(call_id.as_str()),
+                _ => None,
+            })
+            .collect();
+
+        for item in &self.input {
+            match item {
+                OrsInputItem::FunctionCall { call_id, .. } => {
+                    known_calls.insert(call_id);
+                }
+                _ => {
+                    if let Some(call_id) = item.output_call_id().filter(|call_id| !known_calls.contains(call_id)) {
+                        return Err(ValidationError::new(
+                            "input",
+                            format!("FunctionCallOutput references unknown call_id: {}", call_id),
+                        ));
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// An explicit `stream` wins; otherwise clients asking for `text/event-stream` get SSE.
+    pub fn wants_stream(&self, accept: Option<&str>) -> bool {
+        self.stream
+            .unwrap_or_else(|| accept.is_some_and(|accept| accept.contains("text/event-stream")))
+    }
+
+    pub fn wants_audio(&self) -> bool {
+        self.modalities.as_ref().is_some_and(|m| m.iter().any(|m| m == "audio"))
+    }
+
+    /// Metadata to store with a new conversation: the request's, plus `user` under the indexed
+    /// `user_id` key so conversations can be listed per user.
+    pub fn conversation_metadata(&self) -> Option<HashMap<String, String>> {
+        let Some(user) = &self.user else {
+            return self.metadata.clone();
+        };
+        let mut metadata = self.metadata.clone().unwrap_or_default();
+        metadata.entry("user_id".to_string()).or_insert_with(|| user.clone());
+        Some(metadata)
+    }
+
+    pub fn includes(&self, field: &str) -> bool {
+        self.include.as_ref().is_some_and(|include| include.iter().any(|f| f == field))
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
@@ -34,10 +391,89 @@ pub enum OrsInputItem {
     FunctionCallOutput {
         id: String,
         call_id: String,
-        output: String, // Value?
+        output: FunctionCallOutputContent,
+        /// Name of the function that produced this output; some upstreams require it on tool messages.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+    },
+    /// What a computer use tool saw after carrying out a call: screenshots and UI state.
+    #[serde(rename = "computer_tool_result")]
+    ComputerToolResult {
+        id: String,
+        call_id: String,
+        output: Vec<ComputerOutput>,
     },
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type")]
+pub enum ComputerOutput {
+    /// The screen, as a data URL or an https URL.
+    #[serde(rename = "computer_screenshot", alias = "screenshot")]
+    ScreenshotOutput { image_url: String },
+    #[serde(rename = "text", alias = "input_text")]
+    TextOutput { text: String },
+}
+
+impl From<ComputerOutput> for FunctionCallOutputPart {
+    fn from(output: ComputerOutput) -> Self {
+        match output {
+            ComputerOutput::ScreenshotOutput { image_url } => FunctionCallOutputPart::ImageUrl { image_url, detail: None },
+            ComputerOutput::TextOutput { text } => FunctionCallOutputPart::InputText { text },
+        }
+    }
+}
+
+impl OrsInputItem {
+    /// The call this item answers, if it's a tool's output.
+    pub fn output_call_id(&self) -> Option<&str> {
+        match self {
+            OrsInputItem::FunctionCallOutput { call_id, .. } | OrsInputItem::ComputerToolResult { call_id, .. } => {
+                Some(call_id)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A tool result: either plain text (the common case) or a list of parts for tools that
+/// return images, e.g. `[{"type": "image_url", "image_url": "https://..."}]`.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum FunctionCallOutputContent {
+    Text(String),
+    Parts(Vec<FunctionCallOutputPart>),
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FunctionCallOutputPart {
+    #[serde(alias = "text")]
+    InputText { text: String },
+    ImageUrl {
+        image_url: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        detail: Option<String>,
+    },
+}
+
+impl FunctionCallOutputContent {
+    /// Text rendering of the output, with images reduced to a placeholder.
+    pub fn to_text(&self) -> String {
+        match self {
+            Self::Text(text) => text.clone(),
+            Self::Parts(parts) => parts
+                .iter()
+                .map(|part| match part {
+                    FunctionCallOutputPart::InputText { text } => text.as_str(),
+                    FunctionCallOutputPart::ImageUrl { .. } => "[image]",
+                })
+                .collect::<Vec<_>>()
+                .join(" "),
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
 #[serde(rename_all = "snake_case")]
 pub enum OrsRole {
@@ -48,9 +484,12 @@ pub enum OrsRole {
 
 #[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
 #[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)] // Named after their wire types
 pub enum OrsContentPart {
     InputText { text: String },
     InputImage { image_url: Value },
+    /// A file uploaded through `POST /v1/files`.
+    InputFile { file_id: String },
 }
 
 // ================================================================================================
@@ -62,6 +501,33 @@ pub struct LegacyChatRequest {
     pub model: String,
     pub messages: Vec<LegacyMessage>,
     pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub n: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<Value>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub parallel_tool_calls: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub modalities: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioConfig>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", serialize_with = "serialize_logit_bias")]
+    pub logit_bias: Option<HashMap<String, f64>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub top_logprobs: Option<u32>,
+}
+
+/// Writes `logit_bias` keyed by the token IDs as numbers, so `"050256"` goes out as `"50256"`,
+/// in token order. Keys that aren't token IDs were rejected by validation and are dropped.
+fn serialize_logit_bias<S: Serializer>(logit_bias: &Option<HashMap<String, f64>>, serializer: S) -> Result<S::Ok, S::Error> {
+    let tokens: Option<BTreeMap<u32, f64>> = logit_bias.as_ref().map(|bias| {
+        bias.iter().filter_map(|(token, bias)| Some((token.parse().ok()?, *bias))).collect()
+    });
+    tokens.serialize(serializer)
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -73,38 +539,164 @@ pub struct LegacyMessage {
     pub tool_calls: Option<Vec<Value>>, // Upstream tool format (OpenAI compatible)
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_call_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct LegacyChunk {
+    /// Empty on the trailing usage chunk sent with `stream_options.include_usage`.
+    #[serde(default)]
     pub choices: Vec<LegacyChoice>,
+    #[serde(default)]
+    pub usage: Option<LegacyUsage>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct LegacyUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+    /// e.g. `{"cached_tokens": 1024}`
+    #[serde(default)]
+    pub prompt_tokens_details: Option<Value>,
+    /// e.g. `{"reasoning_tokens": 256}`
+    #[serde(default)]
+    pub completion_tokens_details: Option<Value>,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct LegacyChoice {
+    #[serde(default)]
+    pub index: usize,
     pub delta: LegacyDelta,
     pub finish_reason: Option<String>,
+    /// `{"content": [...]}` with the delta's tokens, when the request asked for `logprobs`.
+    #[serde(default)]
+    pub logprobs: Option<Value>,
 }
 
-#[derive(Deserialize, Debug)]
+/// The log probability of one output token.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct LogprobEntry {
+    pub token: String,
+    pub logprob: f64,
+    /// The token's UTF-8 bytes, for tokens that aren't valid UTF-8 on their own.
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
+    /// The most likely tokens at this position, `top_logprobs` of them.
+    #[serde(default)]
+    pub top_logprobs: Vec<TopLogprob>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct TopLogprob {
+    pub token: String,
+    pub logprob: f64,
+    #[serde(default)]
+    pub bytes: Option<Vec<u8>>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
 pub struct LegacyDelta {
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub content: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub tool_calls: Option<Vec<Value>>,
-    #[serde(flatten)]
-    #[allow(dead_code)]
-    pub extra: Value,
+    /// Reasoning text as sent by vLLM and DeepSeek.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning_content: Option<String>,
+    /// Reasoning text as sent by Ollama.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reasoning: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub refusal: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub logprobs: Option<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<Value>,
+    /// A computer use block, `{"type": "computer_use", "action": {...}}`, from models that
+    /// stream UI actions as content.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub computer_use: Option<Value>,
 }
 
 // ================================================================================================
 // ORS OUTBOUND EVENTS
 // ================================================================================================
 
+/// An output item as carried by `response.output_item.added` / `.done`.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ResponseItem {
+    Message {
+        id: String,
+        status: String,
+        role: String,
+        content: Vec<Value>,
+    },
+    FunctionCall {
+        id: String,
+        status: String,
+        call_id: String,
+        name: String,
+        arguments: String,
+    },
+}
+
+impl ResponseItem {
+    /// A new, empty assistant message.
+    pub fn message(id: impl Into<String>) -> Self {
+        Self::Message {
+            id: id.into(),
+            status: "in_progress".to_string(),
+            role: "assistant".to_string(),
+            content: Vec::new(),
+        }
+    }
+
+    /// A new function call whose arguments are yet to stream in.
+    pub fn function_call(id: impl Into<String>, call_id: impl Into<String>, name: impl Into<String>) -> Self {
+        Self::FunctionCall {
+            id: id.into(),
+            status: "in_progress".to_string(),
+            call_id: call_id.into(),
+            name: name.into(),
+            arguments: String::new(),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        match self {
+            Self::Message { id, .. } | Self::FunctionCall { id, .. } => id,
+        }
+    }
+
+    pub fn status(&self) -> &str {
+        match self {
+            Self::Message { status, .. } | Self::FunctionCall { status, .. } => status,
+        }
+    }
+
+    pub fn set_status(&mut self, new_status: &str) {
+        match self {
+            Self::Message { status, .. } | Self::FunctionCall { status, .. } => *status = new_status.to_string(),
+        }
+    }
+
+    pub fn to_json_value(&self) -> Value {
+        serde_json::to_value(self).unwrap_or(Value::Null)
+    }
+}
+
 #[derive(Serialize, Debug, Clone)]
 #[serde(tag = "type")]
 pub enum OrsEvent {
     #[serde(rename = "response.created")]
     Created { 
         id: String,
+        /// Unix seconds when the response started.
+        created_at: u64,
         #[serde(skip_serializing_if = "Option::is_none")]
         sequence_number: Option<u32>,
     },
@@ -113,7 +705,9 @@ pub enum OrsEvent {
     ItemAdded {
         #[serde(skip_serializing_if = "Option::is_none")]
         sequence_number: Option<u32>,
-        item: Value, // Must contain id, type, status
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output_index: Option<u32>,
+        item: ResponseItem,
     },
 
     #[serde(rename = "response.content_part.added")]
@@ -138,6 +732,21 @@ pub enum OrsEvent {
         #[serde(skip_serializing_if = "Option::is_none")]
         content_index: Option<u32>,
         delta: String,
+        /// The delta's tokens, when the request asked for `logprobs`.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        logprobs: Option<Vec<LogprobEntry>>,
+    },
+
+    #[serde(rename = "response.reasoning_text.delta")]
+    ReasoningDelta {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sequence_number: Option<u32>,
+        item_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output_index: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content_index: Option<u32>,
+        delta: String,
     },
 
     #[serde(rename = "response.function_call_arguments.delta")]
@@ -150,6 +759,20 @@ pub enum OrsEvent {
         delta: String,
     },
 
+    /// A UI action from a computer use model, as its upstream described it (e.g. a click at a
+    /// coordinate). The actions are streamed in a `computer_use` content part.
+    #[serde(rename = "response.computer_use.action")]
+    ComputerUseAction {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sequence_number: Option<u32>,
+        item_id: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output_index: Option<u32>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        content_index: Option<u32>,
+        action: Value,
+    },
+
     #[serde(rename = "response.content_part.done")]
     ContentPartDone {
         #[serde(skip_serializing_if = "Option::is_none")]
@@ -168,6 +791,376 @@ pub enum OrsEvent {
         sequence_number: Option<u32>,
         #[serde(skip_serializing_if = "Option::is_none")]
         output_index: Option<u32>,
-        item: Value, // Echo the full item or at least id, type, status
+        item: ResponseItem,
+    },
+
+    #[serde(rename = "response.completed")]
+    CompletionUsage {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sequence_number: Option<u32>,
+        input_tokens: u32,
+        output_tokens: u32,
+        total_tokens: u32,
+        /// Cached/uncached breakdown, passed through from the upstream.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        input_tokens_details: Option<Value>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        output_tokens_details: Option<Value>,
+        /// The counts were estimated by the proxy because the upstream sent none.
+        #[serde(skip_serializing_if = "std::ops::Not::not")]
+        is_approximate: bool,
+    },
+
+    /// The upstream is rate limiting; the response starts once its retry goes through.
+    #[serde(rename = "response.queued")]
+    Queued {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sequence_number: Option<u32>,
+        /// How long the proxy waits before retrying.
+        retry_after_ms: u64,
+    },
+
+    /// The response was cut short; nothing follows but, after a timeout, `response.done`.
+    #[serde(rename = "response.error")]
+    Error {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sequence_number: Option<u32>,
+        code: String,
+        message: String,
+    },
+
+    /// The whole response finished, unlike `response.output_item.done` which ends one item.
+    /// Carries what the non-streaming response object would: every output item, the usage and
+    /// the final status. Always the last event of a stream that wasn't cut short.
+    #[serde(rename = "response.done")]
+    ResponseDone {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        sequence_number: Option<u32>,
+        id: String,
+        /// `completed`, `incomplete`, or `failed` after a timeout.
+        status: String,
+        model: String,
+        /// Unix seconds, as in `response.created`.
+        created_at: u64,
+        output: Vec<Value>,
+        usage: Option<Value>,
     },
 }
+
+impl OrsEvent {
+    pub fn sequence_number(&self) -> Option<u32> {
+        match self {
+            OrsEvent::Created { sequence_number, .. }
+            | OrsEvent::ItemAdded { sequence_number, .. }
+            | OrsEvent::ContentPartAdded { sequence_number, .. }
+            | OrsEvent::TextDelta { sequence_number, .. }
+            | OrsEvent::ReasoningDelta { sequence_number, .. }
+            | OrsEvent::FunctionCallArgumentsDelta { sequence_number, .. }
+            | OrsEvent::ComputerUseAction { sequence_number, .. }
+            | OrsEvent::ContentPartDone { sequence_number, .. }
+            | OrsEvent::ItemDone { sequence_number, .. }
+            | OrsEvent::CompletionUsage { sequence_number, .. }
+            | OrsEvent::Queued { sequence_number, .. }
+            | OrsEvent::Error { sequence_number, .. }
+            | OrsEvent::ResponseDone { sequence_number, .. } => *sequence_number,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(input: Value) -> OrsRequest {
+        serde_json::from_value(serde_json::json!({ "model": "m", "input": input })).unwrap()
+    }
+
+    fn call(call_id: &str) -> OrsInputItem {
+        OrsInputItem::FunctionCall {
+            id: format!("fc_{}", call_id),
+            call_id: call_id.to_string(),
+            name: "get_weather".to_string(),
+            arguments: serde_json::json!({}),
+        }
+    }
+
+    #[test]
+    fn test_string_input_is_a_user_message() {
+        let shorthand = request(Value::String("hello".to_string()));
+        let full = request(serde_json::json!([
+            { "type": "message", "role": "user", "content": [{ "type": "input_text", "text": "hello" }] }
+        ]));
+        assert_eq!(shorthand.input, full.input);
+
+        let err = serde_json::from_value::<OrsRequest>(serde_json::json!({ "model": "m", "input": 42 })).unwrap_err();
+        assert!(err.to_string().contains("a string or an array of input items"));
+    }
+
+    #[test]
+    fn test_validate_rejects_orphan_function_call_output() {
+        let req = request(serde_json::json!([
+            { "type": "function_call_output", "id": "o1", "call_id": "call_1", "output": "Sunny" }
+        ]));
+        let err = req.validate_context(&[], &[]).unwrap_err();
+        assert_eq!(err.param, "input");
+        assert!(err.message.contains("call_1"));
+
+        // The matching call may come from the stored context
+        assert!(req.validate_context(&[call("call_1")], &[]).is_ok());
+    }
+
+    #[test]
+    fn test_computer_tool_result_screenshot() {
+        let item: OrsInputItem = serde_json::from_value(serde_json::json!({
+            "type": "computer_tool_result",
+            "id": "ctr_1",
+            "call_id": "call_1",
+            "output": [{ "type": "computer_screenshot", "image_url": "data:image/png;base64,iVBORw0KGgo=" }]
+        }))
+        .unwrap();
+        let OrsInputItem::ComputerToolResult { call_id, output, .. } = &item else {
+            panic!("Expected ComputerToolResult");
+        };
+        assert_eq!(call_id, "call_1");
+        assert_eq!(
+            output[0],
+            ComputerOutput::ScreenshotOutput { image_url: "data:image/png;base64,iVBORw0KGgo=".to_string() }
+        );
+        assert_eq!(serde_json::to_value(&item).unwrap()["output"][0]["type"], "computer_screenshot");
+    }
+
+    #[test]
+    fn test_computer_tool_result_text() {
+        let item: OrsInputItem = serde_json::from_value(serde_json::json!({
+            "type": "computer_tool_result",
+            "id": "ctr_1",
+            "call_id": "call_1",
+            "output": [{ "type": "text", "text": "Clicked at (10, 20)" }]
+        }))
+        .unwrap();
+        let OrsInputItem::ComputerToolResult { output, .. } = &item else {
+            panic!("Expected ComputerToolResult");
+        };
+        assert_eq!(output[0], ComputerOutput::TextOutput { text: "Clicked at (10, 20)".to_string() });
+
+        // Like a function call output, it needs its call
+        let req = request(serde_json::json!([serde_json::to_value(&item).unwrap()]));
+        assert!(req.validate_context(&[], &[]).is_err());
+        assert!(req.validate_context(&[call("call_1")], &[]).is_ok());
+    }
+
+    #[test]
+    fn test_validate_requires_output_when_tool_choice_required() {
+        let mut req = request(serde_json::json!([
+            { "type": "function_call", "id": "f1", "call_id": "call_1", "name": "get_weather", "arguments": {} }
+        ]));
+        assert!(req.validate().is_ok());
+
+        req.tool_choice = Some(Value::String("required".to_string()));
+        assert!(req.validate().is_err());
+    }
+
+    #[test]
+    fn test_conversation_id_format() {
+        assert!(is_valid_conversation_id(&uuid::Uuid::new_v4().to_string()));
+        assert!(is_valid_conversation_id("resp_0123abcd"));
+        assert!(is_valid_conversation_id(&"a".repeat(128)));
+
+        assert!(!is_valid_conversation_id("short"));
+        assert!(!is_valid_conversation_id(&"a".repeat(129)));
+        assert!(!is_valid_conversation_id("conv_1'; DROP TABLE items;--"));
+        assert!(!is_valid_conversation_id("conv_123\0abcd"));
+        assert!(!is_valid_conversation_id("conv_ü_12345"));
+    }
+
+    #[test]
+    fn test_validate_user_length() {
+        let mut req = request(serde_json::json!([]));
+        req.user = Some("u".repeat(MAX_USER_CHARS));
+        assert!(req.validate().is_ok());
+
+        req.user = Some("u".repeat(MAX_USER_CHARS + 1));
+        assert_eq!(req.validate().unwrap_err().param, "user");
+    }
+
+    #[test]
+    fn test_validate_include() {
+        let mut req = request(serde_json::json!([]));
+        req.include = Some(vec!["output_text".to_string(), "message.input_image.image_url".to_string()]);
+        assert!(req.validate().is_ok());
+        assert!(req.includes("output_text"));
+
+        req.include = Some(vec!["output_text".to_string(), "everything".to_string()]);
+        let err = req.validate().unwrap_err();
+        assert_eq!(err.param, "include[1]");
+        assert!(err.message.contains("everything"));
+    }
+
+    #[test]
+    fn test_validate_modalities() {
+        let mut req = request(serde_json::json!([]));
+        req.modalities = Some(vec!["text".to_string(), "video".to_string()]);
+        assert_eq!(req.validate().unwrap_err().param, "modalities");
+
+        req.modalities = Some(vec!["text".to_string(), "audio".to_string()]);
+        assert_eq!(req.validate().unwrap_err().param, "audio");
+
+        req.audio = Some(AudioConfig { voice: "alloy".to_string(), format: "wav".to_string() });
+        assert!(req.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_parallel_tool_calls_requires_tools() {
+        let mut req = request(serde_json::json!([]));
+        req.parallel_tool_calls = Some(false);
+        assert_eq!(req.validate().unwrap_err().param, "parallel_tool_calls");
+
+        req.tools = Some(vec![serde_json::json!({ "type": "function", "name": "get_weather" })]);
+        assert!(req.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_metadata_limits() {
+        let mut req = request(serde_json::json!([]));
+        req.metadata = Some((0..=MAX_METADATA_PAIRS).map(|i| (format!("k{}", i), "v".to_string())).collect());
+        assert_eq!(req.validate().unwrap_err().param, "metadata");
+
+        req.metadata = Some([("note".to_string(), "x".repeat(MAX_METADATA_VALUE_CHARS + 1))].into_iter().collect());
+        assert_eq!(req.validate().unwrap_err().param, "metadata.note");
+
+        req.metadata = Some([("user_id".to_string(), "u_123".to_string())].into_iter().collect());
+        assert!(req.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_file_ids() {
+        let mut req = request(serde_json::json!([]));
+        req.file_ids = Some(vec!["file-abc123".to_string(), "file-".to_string()]);
+        assert_eq!(req.validate().unwrap_err().param, "file_ids[1]");
+
+        req.file_ids = Some(vec!["file_abc".to_string()]);
+        assert!(req.validate().is_err());
+
+        req.file_ids = Some(vec!["file-abc123".to_string()]);
+        assert!(req.validate().is_ok());
+    }
+
+    #[test]
+    fn test_validate_logit_bias() {
+        let mut req = request(serde_json::json!([]));
+        req.logit_bias = Some((0..=MAX_LOGIT_BIAS_ENTRIES as u32).map(|token| (token.to_string(), 1.0)).collect());
+        assert_eq!(req.validate().unwrap_err().param, "logit_bias");
+
+        req.logit_bias = Some([("50256".to_string(), -100.5)].into_iter().collect());
+        assert_eq!(req.validate().unwrap_err().param, "logit_bias.50256");
+
+        req.logit_bias = Some([("yes".to_string(), 5.0)].into_iter().collect());
+        assert_eq!(req.validate().unwrap_err().param, "logit_bias.yes");
+
+        req.logit_bias = Some([("50256".to_string(), -100.0), ("9891".to_string(), 100.0)].into_iter().collect());
+        assert!(req.validate().is_ok());
+    }
+
+    #[test]
+    fn test_logit_bias_round_trip() {
+        let req: OrsRequest = serde_json::from_value(serde_json::json!({
+            "model": "m",
+            "input": "Yes or no?",
+            "logit_bias": { "9891": 5, "050256": -100 }
+        }))
+        .unwrap();
+        let legacy = LegacyChatRequest {
+            model: req.model.clone(),
+            messages: Vec::new(),
+            stream: true,
+            n: None,
+            tools: None,
+            parallel_tool_calls: None,
+            modalities: None,
+            audio: None,
+            user: None,
+            logit_bias: req.logit_bias.clone(),
+            logprobs: None,
+            top_logprobs: None,
+        };
+        let json = serde_json::to_value(&legacy).unwrap();
+        assert_eq!(json["logit_bias"], serde_json::json!({ "9891": 5.0, "50256": -100.0 }));
+
+        let legacy = LegacyChatRequest { logit_bias: None, ..legacy };
+        assert!(serde_json::to_value(&legacy).unwrap().get("logit_bias").is_none());
+    }
+
+    #[test]
+    fn test_validate_known_models() {
+        let known = vec!["gpt-4*".to_string(), "llama-3-70b".to_string()];
+        let mut req = request(serde_json::json!([]));
+
+        req.model = "gpt-4o-mini".to_string();
+        assert!(req.validate_context(&[], &known).is_ok());
+        req.model = "llama-3-70b".to_string();
+        assert!(req.validate_context(&[], &known).is_ok());
+
+        req.model = "llama-3-70b-instruct".to_string();
+        let err = req.validate_context(&[], &known).unwrap_err();
+        assert_eq!(err.code, Some("model_not_found"));
+        assert!(err.message.contains("'llama-3-70b-instruct'"));
+        assert!(err.message.contains("[gpt-4*, llama-3-70b]"));
+
+        // Without a list, any model goes through
+        assert!(req.validate_context(&[], &[]).is_ok());
+    }
+
+    #[test]
+    fn test_validate_model_name_length() {
+        let mut req = request(serde_json::json!([]));
+        req.model = "m".repeat(MAX_MODEL_NAME_CHARS + 1);
+        let err = req.validate().unwrap_err();
+        assert_eq!((err.param.as_str(), err.code), ("model", None));
+    }
+
+    #[test]
+    fn test_model_matches_patterns() {
+        assert!(model_matches("*", "anything"));
+        assert!(model_matches("gpt-*-mini", "gpt-4o-mini"));
+        assert!(!model_matches("gpt-*-mini", "gpt-4o"));
+        assert!(model_matches("*-instruct", "llama-instruct"));
+        assert!(!model_matches("a*a", "a"));
+        assert!(!model_matches("gpt-4", "gpt-4o"));
+    }
+
+    #[test]
+    fn test_validate_rejects_empty_message_content() {
+        let req = request(serde_json::json!([
+            { "type": "message", "role": "user", "content": [{ "type": "input_text", "text": "Hi" }] },
+            { "type": "message", "role": "user", "content": [] }
+        ]));
+        let err = req.validate().unwrap_err();
+        assert_eq!(err.param, "input[1].content");
+        assert_eq!(err.message, "content array must not be empty");
+    }
+
+    #[test]
+    fn test_validate_rejects_whitespace_only_text() {
+        let req = request(serde_json::json!([
+            { "type": "message", "role": "user", "content": [
+                { "type": "input_text", "text": "Look:" },
+                { "type": "input_text", "text": " \n\t" }
+            ] }
+        ]));
+        assert_eq!(req.validate().unwrap_err().param, "input[0].content[1].text");
+    }
+
+    #[test]
+    fn test_wants_stream_from_accept_header() {
+        let mut req = request(serde_json::json!([]));
+        assert!(req.wants_stream(Some("text/event-stream")));
+        assert!(req.wants_stream(Some("application/json, text/event-stream;q=0.9")));
+        assert!(!req.wants_stream(Some("application/json")));
+        assert!(!req.wants_stream(None));
+
+        req.stream = Some(true);
+        assert!(req.wants_stream(Some("application/json")));
+        req.stream = Some(false);
+        assert!(!req.wants_stream(Some("text/event-stream")));
+    }
+}
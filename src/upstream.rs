@@ -1,7 +1,207 @@
-use crate::types::{LegacyMessage, OrsContentPart, OrsInputItem, OrsRole};
+use crate::types::{
+    FunctionCallOutputContent, FunctionCallOutputPart, LegacyMessage, OrsContentPart, OrsInputItem, OrsRole,
+};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::time::{Duration, SystemTime};
+use tracing::warn;
 
-pub fn transform_ors_to_legacy(input: Vec<OrsInputItem>) -> Vec<LegacyMessage> {
+pub mod anthropic;
+pub mod ollama;
+
+/// Text outputs stay a plain string; outputs with images become a content array.
+fn legacy_tool_content(output: FunctionCallOutputContent) -> serde_json::Value {
+    match output {
+        FunctionCallOutputContent::Text(text) => serde_json::Value::String(text),
+        FunctionCallOutputContent::Parts(parts) => serde_json::Value::Array(
+            parts
+                .into_iter()
+                .map(|part| match part {
+                    FunctionCallOutputPart::InputText { text } => serde_json::json!({
+                        "type": "text",
+                        "text": text
+                    }),
+                    FunctionCallOutputPart::ImageUrl { image_url, detail } => {
+                        let mut url = serde_json::json!({ "url": image_url });
+                        if let Some(detail) = detail {
+                            url["detail"] = serde_json::Value::String(detail);
+                        }
+                        serde_json::json!({ "type": "image_url", "image_url": url })
+                    }
+                })
+                .collect(),
+        ),
+    }
+}
+
+/// Picks the API key whose model prefix is the longest match for `model`.
+pub fn resolve_auth_key<'a>(keys: &'a HashMap<String, String>, model: &str) -> Option<&'a str> {
+    keys.iter()
+        .filter(|(prefix, _)| model.starts_with(prefix.as_str()))
+        .max_by_key(|(prefix, _)| prefix.len())
+        .map(|(_, key)| key.as_str())
+}
+
+/// Returns true if the upstream dropped the connection (typically a stale pooled connection),
+/// as opposed to timing out or answering with garbage.
+pub fn is_connection_reset(err: &reqwest::Error) -> bool {
+    let mut source = std::error::Error::source(err);
+    while let Some(e) = source {
+        if let Some(io) = e.downcast_ref::<std::io::Error>() {
+            if matches!(
+                io.kind(),
+                ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted | ErrorKind::BrokenPipe | ErrorKind::UnexpectedEof
+            ) {
+                return true;
+            }
+        }
+        if let Some(h) = e.downcast_ref::<hyper::Error>() {
+            if h.is_incomplete_message() || h.is_closed() {
+                return true;
+            }
+        }
+        source = e.source();
+    }
+    false
+}
+
+/// Sends the request, retrying once if the connection was reset and `retry_on_reset` is set.
+pub async fn send_with_retry(
+    req: reqwest::RequestBuilder,
+    retry_on_reset: bool,
+) -> Result<reqwest::Response, reqwest::Error> {
+    let retry = if retry_on_reset { req.try_clone() } else { None };
+
+    match req.send().await {
+        Err(e) if is_connection_reset(&e) => {
+            warn!("Upstream connection reset: {}", e);
+            match retry {
+                Some(req) => {
+                    warn!("Retrying upstream request once");
+                    req.send().await
+                }
+                None => Err(e),
+            }
+        }
+        other => other,
+    }
+}
+
+/// Retries after an upstream 429 before its error is passed on to the client.
+pub const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+
+/// Wait before retrying a 429 that doesn't say how long to back off.
+const DEFAULT_RETRY_AFTER: Duration = Duration::from_secs(1);
+
+/// If the upstream answered 429, how long to wait before retrying: its `Retry-After`, in either
+/// delay-seconds or HTTP-date form, capped at `max`.
+pub fn rate_limit_delay(res: &reqwest::Response, max: Duration) -> Option<Duration> {
+    if res.status() != reqwest::StatusCode::TOO_MANY_REQUESTS {
+        return None;
+    }
+    let wait = res
+        .headers()
+        .get(reqwest::header::RETRY_AFTER)
+        .and_then(|v| v.to_str().ok())
+        .and_then(parse_retry_after)
+        .unwrap_or(DEFAULT_RETRY_AFTER);
+    Some(wait.min(max))
+}
+
+fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let at = httpdate::parse_http_date(value).ok()?;
+    // A date already in the past means retry now
+    Some(at.duration_since(SystemTime::now()).unwrap_or(Duration::ZERO))
+}
+
+/// Converts ORS function tools (`{"type": "function", "name", "parameters", ...}`) to the nested
+/// chat completions shape. Tools already in that shape, or of other types, pass through untouched.
+pub fn legacy_tools(tools: &[serde_json::Value]) -> Vec<serde_json::Value> {
+    tools
+        .iter()
+        .map(|tool| {
+            let is_flat_function = tool.get("type").and_then(|t| t.as_str()) == Some("function")
+                && tool.get("function").is_none();
+            if !is_flat_function {
+                return tool.clone();
+            }
+            let mut function = tool.clone();
+            if let Some(obj) = function.as_object_mut() {
+                obj.remove("type");
+            }
+            serde_json::json!({ "type": "function", "function": function })
+        })
+        .collect()
+}
+
+/// Rough token count of `chars` characters, for usage reporting when the upstream doesn't send
+/// one: about four characters per token for English text with BPE tokenizers.
+pub fn estimate_tokens(chars: usize) -> u32 {
+    chars.div_ceil(4) as u32
+}
+
+pub const DRY_RUN_TEXT: &str = "Dry-run response from ors-proxy.";
+
+/// Fabricates a streaming chat completion, as an upstream would send it, for `NO_UPSTREAM` mode.
+pub fn dry_run_response(model: &str) -> reqwest::Response {
+    let chunks = [
+        serde_json::json!({
+            "object": "chat.completion.chunk",
+            "model": model,
+            "choices": [{ "index": 0, "delta": { "role": "assistant", "content": DRY_RUN_TEXT }, "finish_reason": null }]
+        }),
+        serde_json::json!({
+            "object": "chat.completion.chunk",
+            "model": model,
+            "choices": [{ "index": 0, "delta": {}, "finish_reason": "stop" }]
+        }),
+    ];
+    let mut body: String = chunks.iter().map(|chunk| format!("data: {}\n\n", chunk)).collect();
+    body.push_str("data: [DONE]\n\n");
+
+    let res = axum::http::Response::builder()
+        .header("content-type", "text/event-stream")
+        .body(body)
+        .expect("static response parts are valid");
+    reqwest::Response::from(res)
+}
+
+/// Puts the files ahead of the first user message's content, or in a message of their own if the
+/// (trimmed) context has no user message left.
+pub fn attach_files(input: &mut Vec<OrsInputItem>, file_ids: &[String]) {
+    if file_ids.is_empty() {
+        return;
+    }
+    let parts = file_ids.iter().map(|file_id| OrsContentPart::InputFile { file_id: file_id.clone() });
+    let first_user = input.iter_mut().find_map(|item| match item {
+        OrsInputItem::Message { role: OrsRole::User, content } => Some(content),
+        _ => None,
+    });
+    match first_user {
+        Some(content) => {
+            content.splice(0..0, parts);
+        }
+        None => input.push(OrsInputItem::Message { role: OrsRole::User, content: parts.collect() }),
+    }
+}
+
+pub fn transform_ors_to_legacy(instructions: Option<&str>, input: Vec<OrsInputItem>) -> Vec<LegacyMessage> {
     let mut messages = Vec::new();
+    if let Some(instructions) = instructions {
+        messages.push(LegacyMessage {
+            role: "system".to_string(),
+            content: Some(serde_json::Value::String(instructions.to_string())),
+            tool_calls: None,
+            tool_call_id: None,
+            name: None,
+        });
+    }
+    // Outputs stored before they carried a name can borrow it from their call
+    let mut call_names: HashMap<String, String> = HashMap::new();
 
     for item in input {
         match item {
@@ -13,7 +213,7 @@ pub fn transform_ors_to_legacy(input: Vec<OrsInputItem>) -> Vec<LegacyMessage> {
                 };
 
                 let mut content_parts: Vec<serde_json::Value> = Vec::new();
-                let mut has_image = false;
+                let mut has_media = false;
 
                 for part in content {
                     match part {
@@ -26,7 +226,7 @@ pub fn transform_ors_to_legacy(input: Vec<OrsInputItem>) -> Vec<LegacyMessage> {
                              }
                         },
                         OrsContentPart::InputImage { image_url } => {
-                            has_image = true;
+                            has_media = true;
                             // ORS image_url is already a Value (object or string) matching OpenAI format mostly.
                             // If it's just a string URI, we might need to wrap it.
                             // But types.rs says image_url: Value.
@@ -38,10 +238,17 @@ pub fn transform_ors_to_legacy(input: Vec<OrsInputItem>) -> Vec<LegacyMessage> {
                                 "image_url": image_url
                             }));
                         }
+                        OrsContentPart::InputFile { file_id } => {
+                            has_media = true;
+                            content_parts.push(serde_json::json!({
+                                "type": "file",
+                                "file": { "file_id": file_id }
+                            }));
+                        }
                     }
                 }
                 
-                let legacy_content = if has_image {
+                let legacy_content = if has_media {
                     Some(serde_json::Value::Array(content_parts))
                 } else {
                     // Optimized: simple string if text only (and if only one part? Or strict join?)
@@ -64,9 +271,11 @@ pub fn transform_ors_to_legacy(input: Vec<OrsInputItem>) -> Vec<LegacyMessage> {
                     content: legacy_content,
                     tool_calls: None,
                     tool_call_id: None,
+                    name: None,
                 });
             }
             OrsInputItem::FunctionCall { id: _, call_id, name, arguments } => {
+                call_names.insert(call_id.clone(), name.clone());
                 // ORS FunctionCall maps to a Legacy assistant message with tool_calls
                 messages.push(LegacyMessage {
                     role: "assistant".to_string(),
@@ -84,15 +293,30 @@ pub fn transform_ors_to_legacy(input: Vec<OrsInputItem>) -> Vec<LegacyMessage> {
                         }
                     })]),
                     tool_call_id: None,
+                    name: Some(name),
                 });
             }
-            OrsInputItem::FunctionCallOutput { id: _, call_id, output } => {
+            OrsInputItem::FunctionCallOutput { id: _, call_id, output, name } => {
+                let name = name.or_else(|| call_names.get(&call_id).cloned());
                 // ORS FunctionCallOutput maps to a Legacy tool role message
                 messages.push(LegacyMessage {
                     role: "tool".to_string(),
-                    content: Some(serde_json::Value::String(output)),
+                    content: Some(legacy_tool_content(output)),
+                    tool_calls: None,
+                    tool_call_id: Some(call_id),
+                    name,
+                });
+            }
+            OrsInputItem::ComputerToolResult { id: _, call_id, output } => {
+                // Goes up like any other tool output, the screenshot as an image part
+                let parts = output.into_iter().map(FunctionCallOutputPart::from).collect();
+                let name = call_names.get(&call_id).cloned();
+                messages.push(LegacyMessage {
+                    role: "tool".to_string(),
+                    content: Some(legacy_tool_content(FunctionCallOutputContent::Parts(parts))),
                     tool_calls: None,
                     tool_call_id: Some(call_id),
+                    name,
                 });
             }
         }
@@ -103,7 +327,145 @@ pub fn transform_ors_to_legacy(input: Vec<OrsInputItem>) -> Vec<LegacyMessage> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::types::{OrsContentPart, OrsInputItem, OrsRole};
+    use crate::types::ComputerOutput;
+    use futures::StreamExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+
+    #[tokio::test]
+    async fn test_dry_run_response_transcodes() {
+        let res = dry_run_response("llama3");
+        assert!(res.status().is_success());
+        let body = res.text().await.unwrap();
+
+        let mut transcoder = crate::transcoder::Transcoder::new();
+        let mut text = String::new();
+        for json_str in body.lines().filter_map(|l| l.strip_prefix("data: ")) {
+            if json_str == "[DONE]" {
+                continue;
+            }
+            let chunk: crate::types::LegacyChunk = serde_json::from_str(json_str).unwrap();
+            for event in transcoder.process(chunk) {
+                if let crate::types::OrsEvent::TextDelta { delta, .. } = event {
+                    text.push_str(&delta);
+                }
+            }
+        }
+        assert_eq!(text, DRY_RUN_TEXT);
+        assert!(body.contains("\"model\":\"llama3\""));
+    }
+
+    #[test]
+    fn test_resolve_auth_key_longest_prefix() {
+        let keys: HashMap<String, String> = [
+            ("gpt-4".to_string(), "sk-general".to_string()),
+            ("gpt-4o".to_string(), "sk-omni".to_string()),
+            ("llama".to_string(), "r8-llama".to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(resolve_auth_key(&keys, "gpt-4-turbo"), Some("sk-general"));
+        assert_eq!(resolve_auth_key(&keys, "gpt-4o-mini"), Some("sk-omni"));
+        assert_eq!(resolve_auth_key(&keys, "llama3"), Some("r8-llama"));
+        assert_eq!(resolve_auth_key(&keys, "mistral"), None);
+    }
+
+    fn rate_limited(retry_after: Option<&str>) -> reqwest::Response {
+        let mut res = axum::http::Response::builder().status(429);
+        if let Some(value) = retry_after {
+            res = res.header("retry-after", value);
+        }
+        reqwest::Response::from(res.body(String::new()).unwrap())
+    }
+
+    #[test]
+    fn test_rate_limit_delay() {
+        let max = Duration::from_secs(30);
+        assert_eq!(rate_limit_delay(&rate_limited(Some("5")), max), Some(Duration::from_secs(5)));
+        assert_eq!(rate_limit_delay(&rate_limited(Some("120")), max), Some(max));
+        assert_eq!(rate_limit_delay(&rate_limited(None), max), Some(DEFAULT_RETRY_AFTER));
+        assert_eq!(rate_limit_delay(&rate_limited(Some("soon")), max), Some(DEFAULT_RETRY_AFTER));
+
+        let in_ten = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(10));
+        let wait = rate_limit_delay(&rate_limited(Some(&in_ten)), max).unwrap();
+        assert!(wait > Duration::from_secs(8) && wait <= Duration::from_secs(10));
+        let past = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(10));
+        assert_eq!(rate_limit_delay(&rate_limited(Some(&past)), max), Some(Duration::ZERO));
+
+        assert_eq!(rate_limit_delay(&dry_run_response("llama3"), max), None);
+    }
+
+    /// Accepts connections, hanging up on the first `resets` of them before answering.
+    async fn flaky_server(resets: usize) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut accepted = 0;
+            loop {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                let mut buf = [0u8; 4096];
+                let _ = sock.read(&mut buf).await;
+                accepted += 1;
+                if accepted <= resets {
+                    drop(sock);
+                    continue;
+                }
+                let body = "data: [DONE]\n\n";
+                let resp = format!(
+                    "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\ncontent-length: {}\r\n\r\n{}",
+                    body.len(),
+                    body
+                );
+                let _ = sock.write_all(resp.as_bytes()).await;
+            }
+        });
+        format!("http://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_send_retries_once_on_reset() {
+        let url = flaky_server(1).await;
+        let client = reqwest::Client::new();
+
+        let res = send_with_retry(client.post(&url).body("{}"), true).await.unwrap();
+        assert!(res.status().is_success());
+    }
+
+    #[tokio::test]
+    async fn test_send_without_retry_surfaces_reset() {
+        let url = flaky_server(1).await;
+        let client = reqwest::Client::new();
+
+        let err = send_with_retry(client.post(&url).body("{}"), false).await.unwrap_err();
+        assert!(is_connection_reset(&err));
+    }
+
+    #[tokio::test]
+    async fn test_reset_mid_stream_is_detected() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut sock, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 4096];
+            let _ = sock.read(&mut buf).await;
+            // Promise more body than we deliver, then hang up
+            let _ = sock
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\ncontent-length: 1000\r\n\r\ndata: {}\n\n")
+                .await;
+        });
+
+        let res = reqwest::Client::new().get(format!("http://{}", addr)).send().await.unwrap();
+        let mut stream = res.bytes_stream();
+        let mut saw_reset = false;
+        while let Some(chunk) = stream.next().await {
+            if let Err(e) = chunk {
+                saw_reset = is_connection_reset(&e);
+                break;
+            }
+        }
+        assert!(saw_reset);
+    }
 
     #[test]
     fn test_transform_simple_message() {
@@ -114,7 +476,7 @@ mod tests {
             }],
         }];
 
-        let legacy = transform_ors_to_legacy(input);
+        let legacy = transform_ors_to_legacy(None, input);
         assert_eq!(legacy.len(), 1);
         assert_eq!(legacy[0].role, "user");
         assert_eq!(legacy[0].content, Some(serde_json::Value::String("Hello world".to_string())));
@@ -129,7 +491,7 @@ mod tests {
             }],
         }];
 
-        let legacy = transform_ors_to_legacy(input);
+        let legacy = transform_ors_to_legacy(None, input);
         assert_eq!(legacy.len(), 1);
         assert_eq!(legacy[0].role, "system");
     }
@@ -144,10 +506,28 @@ mod tests {
             ],
         }];
 
-        let legacy = transform_ors_to_legacy(input);
+        let legacy = transform_ors_to_legacy(None, input);
         assert_eq!(legacy[0].content, Some(serde_json::Value::String("Part 1 Part 2".to_string())));
     }
 
+    #[test]
+    fn test_attach_files_to_first_user_message() {
+        let mut input = vec![
+            OrsInputItem::Message { role: OrsRole::Developer, content: vec![OrsContentPart::InputText { text: "Be brief".to_string() }] },
+            OrsInputItem::Message { role: OrsRole::User, content: vec![OrsContentPart::InputText { text: "Summarize".to_string() }] },
+        ];
+        attach_files(&mut input, &["file-abc".to_string()]);
+
+        let legacy = transform_ors_to_legacy(None, input);
+        let content = legacy[1].content.as_ref().unwrap().as_array().unwrap();
+        assert_eq!(content[0], serde_json::json!({ "type": "file", "file": { "file_id": "file-abc" } }));
+        assert_eq!(content[1]["text"], "Summarize");
+
+        let mut input = Vec::new();
+        attach_files(&mut input, &["file-abc".to_string()]);
+        assert!(matches!(&input[..], [OrsInputItem::Message { role: OrsRole::User, .. }]));
+    }
+
     #[test]
     fn test_transform_image_multimodal() {
         let input = vec![OrsInputItem::Message {
@@ -158,7 +538,7 @@ mod tests {
             ],
         }];
 
-        let legacy = transform_ors_to_legacy(input);
+        let legacy = transform_ors_to_legacy(None, input);
         let content = legacy[0].content.as_ref().unwrap();
         assert!(content.is_array());
         let array = content.as_array().unwrap();
@@ -180,11 +560,12 @@ mod tests {
             OrsInputItem::FunctionCallOutput {
                 id: "item_2".to_string(),
                 call_id: "call_abc".to_string(),
-                output: "Sunny".to_string(),
+                output: FunctionCallOutputContent::Text("Sunny".to_string()),
+                name: None,
             }
         ];
 
-        let legacy = transform_ors_to_legacy(input);
+        let legacy = transform_ors_to_legacy(None, input);
         assert_eq!(legacy.len(), 2);
         
         // Tool Call (Assistant)
@@ -197,5 +578,100 @@ mod tests {
         assert_eq!(legacy[1].role, "tool");
         assert_eq!(legacy[1].tool_call_id.as_deref(), Some("call_abc"));
         assert_eq!(legacy[1].content.as_ref().unwrap().as_str(), Some("Sunny"));
+        // Name is carried over from the matching call
+        assert_eq!(legacy[1].name.as_deref(), Some("get_weather"));
+        assert_eq!(legacy[0].name.as_deref(), Some("get_weather"));
+    }
+
+    #[test]
+    fn test_transform_image_tool_output() {
+        let input = vec![OrsInputItem::FunctionCallOutput {
+            id: "item_2".to_string(),
+            call_id: "call_chart".to_string(),
+            output: FunctionCallOutputContent::Parts(vec![
+                FunctionCallOutputPart::InputText { text: "Rendered".to_string() },
+                FunctionCallOutputPart::ImageUrl {
+                    image_url: "http://chart.png".to_string(),
+                    detail: Some("low".to_string()),
+                },
+            ]),
+            name: None,
+        }];
+
+        let legacy = transform_ors_to_legacy(None, input);
+        let content = legacy[0].content.as_ref().unwrap().as_array().unwrap();
+        assert_eq!(content[0]["type"], "text");
+        assert_eq!(content[1]["type"], "image_url");
+        assert_eq!(content[1]["image_url"]["url"], "http://chart.png");
+        assert_eq!(content[1]["image_url"]["detail"], "low");
+    }
+
+    #[test]
+    fn test_transform_computer_tool_result() {
+        let input = vec![OrsInputItem::ComputerToolResult {
+            id: "ctr_1".to_string(),
+            call_id: "call_screen".to_string(),
+            output: vec![
+                ComputerOutput::ScreenshotOutput { image_url: "data:image/png;base64,iVBORw0KGgo=".to_string() },
+                ComputerOutput::TextOutput { text: "Desktop".to_string() },
+            ],
+        }];
+
+        let legacy = transform_ors_to_legacy(None, input);
+        assert_eq!(legacy[0].role, "tool");
+        assert_eq!(legacy[0].tool_call_id.as_deref(), Some("call_screen"));
+        let content = legacy[0].content.as_ref().unwrap().as_array().unwrap();
+        assert_eq!(content[0]["image_url"]["url"], "data:image/png;base64,iVBORw0KGgo=");
+        assert_eq!(content[1]["text"], "Desktop");
+    }
+
+    #[test]
+    fn test_transform_tool_output_name_serialized() {
+        let input = vec![OrsInputItem::FunctionCallOutput {
+            id: "item_2".to_string(),
+            call_id: "call_abc".to_string(),
+            output: FunctionCallOutputContent::Text("Sunny".to_string()),
+            name: Some("get_weather".to_string()),
+        }];
+
+        let legacy = transform_ors_to_legacy(None, input);
+        let json = serde_json::to_value(&legacy[0]).unwrap();
+        assert_eq!(json["role"], "tool");
+        assert_eq!(json["name"], "get_weather");
+    }
+
+    #[test]
+    fn test_instructions_precede_developer_messages() {
+        let input = vec![
+            OrsInputItem::Message {
+                role: OrsRole::Developer,
+                content: vec![OrsContentPart::InputText { text: "Be terse".to_string() }],
+            },
+            OrsInputItem::Message {
+                role: OrsRole::User,
+                content: vec![OrsContentPart::InputText { text: "Hi".to_string() }],
+            },
+        ];
+        let legacy = transform_ors_to_legacy(Some("You are a pirate"), input);
+        assert_eq!(legacy.len(), 3);
+        assert_eq!(legacy[0].role, "system");
+        assert_eq!(legacy[0].content, Some(serde_json::json!("You are a pirate")));
+        assert_eq!(legacy[1].role, "system");
+        assert_eq!(legacy[1].content, Some(serde_json::json!("Be terse")));
+        assert_eq!(legacy[2].role, "user");
+    }
+
+    #[test]
+    fn test_legacy_tools_nests_flat_functions() {
+        let tools = vec![
+            serde_json::json!({ "type": "function", "name": "get_weather", "parameters": { "type": "object" } }),
+            serde_json::json!({ "type": "function", "function": { "name": "already_nested" } }),
+        ];
+        let legacy = legacy_tools(&tools);
+        assert_eq!(
+            legacy[0],
+            serde_json::json!({ "type": "function", "function": { "name": "get_weather", "parameters": { "type": "object" } } })
+        );
+        assert_eq!(legacy[1], tools[1]);
     }
 }
@@ -0,0 +1,500 @@
+//! Anthropic Messages API (`/v1/messages`).
+//!
+//! Stream events are translated into chat completion chunks and fed through the regular
+//! [`Transcoder`], so item and sequence bookkeeping is shared with the OpenAI path. Each SSE
+//! `data:` payload repeats its event name in `type`, so the `event:` lines aren't needed.
+
+use crate::{
+    adapter::{AdapterError, RequestConfig, StreamOptions, StreamTranscoder, UpstreamAdapter},
+    transcoder::Transcoder,
+    types::{
+        ComputerOutput, FunctionCallOutputContent, FunctionCallOutputPart, LegacyChoice, LegacyChunk, LegacyDelta, LegacyUsage,
+        OrsContentPart, OrsEvent, OrsInputItem, OrsRole,
+    },
+};
+use reqwest::RequestBuilder;
+use serde_json::{json, Value};
+use std::time::Duration;
+
+const API_VERSION: &str = "2023-06-01";
+
+/// Opts into the files API (`/v1/files`).
+const FILES_BETA: &str = "files-api-2025-04-14";
+
+/// Anthropic requires `max_tokens`, and ORS requests don't carry one.
+const MAX_TOKENS: u32 = 4096;
+
+pub struct AnthropicAdapter;
+
+impl UpstreamAdapter for AnthropicAdapter {
+    fn build_request(&self, input: Vec<OrsInputItem>, config: &RequestConfig) -> Result<RequestBuilder, AdapterError> {
+        let request = config.request;
+        if request.n.is_some_and(|n| n > 1) {
+            return Err(AdapterError::Unsupported("n > 1".to_string()));
+        }
+        if request.logit_bias.is_some() {
+            return Err(AdapterError::Unsupported("logit_bias".to_string()));
+        }
+        if request.logprobs == Some(true) {
+            return Err(AdapterError::Unsupported("logprobs".to_string()));
+        }
+        if request.modalities.as_ref().is_some_and(|m| m.iter().any(|m| m == "audio")) {
+            return Err(AdapterError::Unsupported("audio output".to_string()));
+        }
+
+        let (system, messages) = messages(request.instructions.as_deref(), input);
+        let mut body = json!({
+            "model": request.model,
+            "max_tokens": MAX_TOKENS,
+            "messages": messages,
+            "stream": true,
+        });
+        if let Some(system) = system {
+            body["system"] = Value::String(system);
+        }
+        if let Some(user) = &request.user {
+            body["metadata"] = json!({ "user_id": user });
+        }
+        let tools: Vec<Value> = request.tools.as_deref().unwrap_or_default().iter().filter_map(tool).collect();
+        if !tools.is_empty() {
+            body["tools"] = Value::Array(tools);
+            if request.parallel_tool_calls == Some(false) {
+                body["tool_choice"] = json!({ "type": "auto", "disable_parallel_tool_use": true });
+            }
+        }
+
+        let builder = config.client.post(config.url).header("x-request-id", config.request_id).json(&body);
+        Ok(self.authorize(builder, config.api_key))
+    }
+
+    fn authorize(&self, builder: RequestBuilder, api_key: Option<&str>) -> RequestBuilder {
+        // The files API is still in beta; other endpoints ignore the flag
+        let builder = builder.header("anthropic-version", API_VERSION).header("anthropic-beta", FILES_BETA);
+        match api_key {
+            Some(key) => builder.header("x-api-key", key),
+            None => builder,
+        }
+    }
+
+    fn stream(&self, options: StreamOptions) -> Box<dyn StreamTranscoder> {
+        Box::new(AnthropicStream {
+            transcoder: options.transcoder(),
+            block_open: false,
+            input_tokens: 0,
+            cached_tokens: None,
+        })
+    }
+}
+
+/// Splits ORS input into Anthropic's top-level `system` prompt and its messages. Developer
+/// messages join the system prompt, and consecutive items of the same role are merged since
+/// Anthropic requires user and assistant turns to alternate.
+fn messages(instructions: Option<&str>, input: Vec<OrsInputItem>) -> (Option<String>, Vec<Value>) {
+    let mut system: Vec<String> = instructions.map(str::to_string).into_iter().collect();
+    let mut messages: Vec<(&'static str, Vec<Value>)> = Vec::new();
+
+    for item in input {
+        let (role, blocks) = match item {
+            OrsInputItem::Message { role: OrsRole::Developer, content } => {
+                system.extend(content.into_iter().filter_map(|part| match part {
+                    OrsContentPart::InputText { text } => Some(text),
+                    OrsContentPart::InputImage { .. } | OrsContentPart::InputFile { .. } => None,
+                }));
+                continue;
+            }
+            OrsInputItem::Message { role, content } => {
+                let role = if role == OrsRole::Assistant { "assistant" } else { "user" };
+                let blocks = content
+                    .into_iter()
+                    .filter_map(|part| match part {
+                        OrsContentPart::InputText { text } if text.is_empty() => None,
+                        OrsContentPart::InputText { text } => Some(json!({ "type": "text", "text": text })),
+                        OrsContentPart::InputImage { image_url } => {
+                            let url = image_url.get("url").unwrap_or(&image_url).as_str()?;
+                            Some(image_block(url))
+                        }
+                        OrsContentPart::InputFile { .. } => None,
+                    })
+                    .collect();
+                (role, blocks)
+            }
+            OrsInputItem::FunctionCall { call_id, name, arguments, .. } => {
+                // Arguments are stored as the raw JSON string the model produced
+                let input = match arguments {
+                    Value::String(s) => serde_json::from_str(&s).unwrap_or_else(|_| json!({})),
+                    other => other,
+                };
+                ("assistant", vec![json!({ "type": "tool_use", "id": call_id, "name": name, "input": input })])
+            }
+            OrsInputItem::FunctionCallOutput { call_id, output, .. } => {
+                let content = match output {
+                    FunctionCallOutputContent::Text(text) => Value::String(text),
+                    FunctionCallOutputContent::Parts(parts) => parts
+                        .into_iter()
+                        .map(|part| match part {
+                            FunctionCallOutputPart::InputText { text } => json!({ "type": "text", "text": text }),
+                            FunctionCallOutputPart::ImageUrl { image_url, .. } => image_block(&image_url),
+                        })
+                        .collect(),
+                };
+                ("user", vec![json!({ "type": "tool_result", "tool_use_id": call_id, "content": content })])
+            }
+            OrsInputItem::ComputerToolResult { call_id, output, .. } => {
+                let content: Vec<Value> = output
+                    .into_iter()
+                    .map(|output| match output {
+                        ComputerOutput::ScreenshotOutput { image_url } => image_block(&image_url),
+                        ComputerOutput::TextOutput { text } => json!({ "type": "text", "text": text }),
+                    })
+                    .collect();
+                ("user", vec![json!({ "type": "tool_result", "tool_use_id": call_id, "content": content })])
+            }
+        };
+
+        if blocks.is_empty() {
+            continue;
+        }
+        match messages.last_mut() {
+            Some((last_role, last_blocks)) if *last_role == role => last_blocks.extend(blocks),
+            _ => messages.push((role, blocks)),
+        }
+    }
+
+    let system = (!system.is_empty()).then(|| system.join("\n\n"));
+    let messages = messages
+        .into_iter()
+        .map(|(role, content)| json!({ "role": role, "content": content }))
+        .collect();
+    (system, messages)
+}
+
+/// Data URLs are sent inline as base64; anything else as a URL source.
+fn image_block(url: &str) -> Value {
+    let source = match url.strip_prefix("data:").and_then(|rest| rest.split_once(";base64,")) {
+        Some((media_type, data)) => json!({ "type": "base64", "media_type": media_type, "data": data }),
+        None => json!({ "type": "url", "url": url }),
+    };
+    json!({ "type": "image", "source": source })
+}
+
+/// Converts an ORS function tool, flat or chat-completions nested, to Anthropic's shape.
+/// Other tool types have no Anthropic equivalent and are dropped.
+fn tool(tool: &Value) -> Option<Value> {
+    if tool.get("type").and_then(Value::as_str) != Some("function") {
+        return None;
+    }
+    let function = tool.get("function").unwrap_or(tool);
+    let mut converted = json!({
+        "name": function.get("name")?,
+        "input_schema": function.get("parameters").cloned().unwrap_or_else(|| json!({ "type": "object" })),
+    });
+    if let Some(description) = function.get("description") {
+        converted["description"] = description.clone();
+    }
+    Some(converted)
+}
+
+/// One response's stream state.
+struct AnthropicStream {
+    transcoder: Transcoder,
+    /// A content block has started and its item hasn't been closed yet.
+    block_open: bool,
+    /// From `message_start`; Anthropic reports output tokens separately in `message_delta`.
+    input_tokens: u32,
+    cached_tokens: Option<u64>,
+}
+
+impl AnthropicStream {
+    fn chunk(&mut self, delta: LegacyDelta, finish_reason: Option<&str>, usage: Option<LegacyUsage>) -> Vec<OrsEvent> {
+        self.transcoder.process(LegacyChunk {
+            choices: vec![LegacyChoice { index: 0, delta, finish_reason: finish_reason.map(str::to_string), logprobs: None }],
+            usage,
+        })
+    }
+
+    /// Closes the previous block's item before a new block starts, so each block becomes its
+    /// own item.
+    fn close_block(&mut self) -> Vec<OrsEvent> {
+        if !std::mem::take(&mut self.block_open) {
+            return Vec::new();
+        }
+        self.transcoder.end_item(0)
+    }
+}
+
+impl StreamTranscoder for AnthropicStream {
+    fn transcode_chunk(&mut self, raw: &str) -> Result<Vec<OrsEvent>, AdapterError> {
+        let event: Value = serde_json::from_str(raw).map_err(|_| AdapterError::InvalidChunk(raw.to_string()))?;
+        let str_field = |value: &Value, key: &str| value.get(key).and_then(Value::as_str).map(str::to_string);
+
+        let events = match event.get("type").and_then(Value::as_str).unwrap_or_default() {
+            "message_start" => {
+                let usage = &event["message"]["usage"];
+                let tokens = |key: &str| usage.get(key).and_then(Value::as_u64).unwrap_or(0);
+                // Cache reads and writes are billed as input but reported apart from input_tokens
+                self.input_tokens =
+                    (tokens("input_tokens") + tokens("cache_creation_input_tokens") + tokens("cache_read_input_tokens"))
+                        as u32;
+                self.cached_tokens = usage.get("cache_read_input_tokens").and_then(Value::as_u64);
+                Vec::new()
+            }
+            "content_block_start" => {
+                let block = &event["content_block"];
+                let index = event.get("index").and_then(Value::as_u64).unwrap_or(0);
+                let mut events = self.close_block();
+                self.block_open = true;
+                let delta = match block.get("type").and_then(Value::as_str) {
+                    Some("tool_use") => LegacyDelta {
+                        tool_calls: Some(vec![json!({
+                            "index": index,
+                            "id": str_field(block, "id"),
+                            "type": "function",
+                            "function": { "name": str_field(block, "name"), "arguments": "" },
+                        })]),
+                        ..Default::default()
+                    },
+                    _ => LegacyDelta { content: Some(String::new()), ..Default::default() },
+                };
+                events.extend(self.chunk(delta, None, None));
+                events
+            }
+            "content_block_delta" => {
+                let delta = &event["delta"];
+                let index = event.get("index").and_then(Value::as_u64).unwrap_or(0);
+                let delta = match delta.get("type").and_then(Value::as_str) {
+                    Some("text_delta") => LegacyDelta { content: str_field(delta, "text"), ..Default::default() },
+                    Some("input_json_delta") => LegacyDelta {
+                        tool_calls: Some(vec![json!({
+                            "index": index,
+                            "function": { "arguments": str_field(delta, "partial_json") },
+                        })]),
+                        ..Default::default()
+                    },
+                    Some("thinking_delta") => {
+                        LegacyDelta { reasoning_content: str_field(delta, "thinking"), ..Default::default() }
+                    }
+                    // e.g. signature_delta, which only matters when replaying thinking blocks
+                    _ => return Ok(Vec::new()),
+                };
+                self.chunk(delta, None, None)
+            }
+            "message_delta" => {
+                let finish_reason = match event["delta"].get("stop_reason").and_then(Value::as_str) {
+                    Some("max_tokens") => "length",
+                    Some("refusal") => "content_filter",
+                    _ => "stop",
+                };
+                let output_tokens = event["usage"].get("output_tokens").and_then(Value::as_u64).unwrap_or(0) as u32;
+                let usage = LegacyUsage {
+                    prompt_tokens: self.input_tokens,
+                    completion_tokens: output_tokens,
+                    total_tokens: self.input_tokens + output_tokens,
+                    prompt_tokens_details: self.cached_tokens.map(|cached| json!({ "cached_tokens": cached })),
+                    completion_tokens_details: None,
+                };
+                self.block_open = false;
+                self.chunk(LegacyDelta::default(), Some(finish_reason), Some(usage))
+            }
+            "error" => {
+                let error = &event["error"];
+                let code = str_field(error, "type").unwrap_or_else(|| "upstream_error".to_string());
+                let message = str_field(error, "message").unwrap_or_default();
+                vec![self.transcoder.error(&code, message)]
+            }
+            // message_stop, content_block_stop, ping, and any event types added later
+            _ => Vec::new(),
+        };
+        Ok(events)
+    }
+
+    fn finish(&mut self) -> Vec<OrsEvent> {
+        self.transcoder.finish()
+    }
+
+    fn interrupt(&mut self) -> Vec<OrsEvent> {
+        self.block_open = false;
+        self.transcoder.interrupt()
+    }
+
+    fn queued(&mut self, wait: Duration) -> OrsEvent {
+        self.transcoder.queued(wait)
+    }
+
+    fn error(&mut self, code: &str, message: String) -> OrsEvent {
+        self.transcoder.error(code, message)
+    }
+
+    fn done(&mut self, model: &str, status: &str, output: Vec<Value>, usage: Option<Value>) -> OrsEvent {
+        self.transcoder.done(model, status, output, usage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OrsRequest;
+    use reqwest::Client;
+
+    fn request(value: Value) -> OrsRequest {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn test_request_format() {
+        let client = Client::new();
+        let request = request(json!({
+            "model": "claude-sonnet-4-5",
+            "instructions": "Be terse",
+            "input": [],
+            "tools": [{ "type": "function", "name": "get_weather", "parameters": { "type": "object" } }],
+        }));
+        let input: Vec<OrsInputItem> = serde_json::from_value(json!([
+            { "type": "message", "role": "developer", "content": [{ "type": "input_text", "text": "Use metric" }] },
+            { "type": "message", "role": "user", "content": [{ "type": "input_text", "text": "Weather?" }] },
+            { "type": "function_call", "id": "fc_1", "call_id": "toolu_1", "name": "get_weather", "arguments": "{\"city\":\"Oslo\"}" },
+            { "type": "function_call_output", "id": "fco_1", "call_id": "toolu_1", "output": "4C" },
+            { "type": "message", "role": "user", "content": [{ "type": "input_text", "text": "Thanks" }] },
+        ]))
+        .unwrap();
+        let config = RequestConfig {
+            client: &client,
+            url: "https://api.anthropic.test/v1/messages",
+            api_key: Some("sk-ant"),
+            request_id: "req-1",
+            request: &request,
+        };
+        let req = AnthropicAdapter.build_request(input, &config).unwrap().build().unwrap();
+        assert_eq!(req.headers()["x-api-key"], "sk-ant");
+        assert_eq!(req.headers()["anthropic-version"], API_VERSION);
+        assert!(req.headers().get("authorization").is_none());
+
+        let body: Value = serde_json::from_slice(req.body().unwrap().as_bytes().unwrap()).unwrap();
+        assert_eq!(body["system"], "Be terse\n\nUse metric");
+        assert_eq!(body["tools"][0]["name"], "get_weather");
+        assert_eq!(body["tools"][0]["input_schema"], json!({ "type": "object" }));
+
+        let messages = body["messages"].as_array().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[1]["content"][0]["input"], json!({ "city": "Oslo" }));
+        // The tool result and the next user message share a turn
+        assert_eq!(messages[2]["role"], "user");
+        assert_eq!(messages[2]["content"][0]["type"], "tool_result");
+        assert_eq!(messages[2]["content"][1]["text"], "Thanks");
+    }
+
+    #[test]
+    fn test_rejects_multiple_choices() {
+        let client = Client::new();
+        let request = request(json!({ "model": "claude", "input": [], "n": 2 }));
+        let config =
+            RequestConfig { client: &client, url: "http://x", api_key: None, request_id: "r", request: &request };
+        assert!(matches!(AnthropicAdapter.build_request(Vec::new(), &config), Err(AdapterError::Unsupported(_))));
+    }
+
+    #[test]
+    fn test_stream_events() {
+        let mut stream = AnthropicAdapter.stream(StreamOptions::default());
+        let mut events = Vec::new();
+        for raw in [
+            r#"{"type":"message_start","message":{"usage":{"input_tokens":10,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Checking"}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"get_weather","input":{}}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{\"city\":"}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"\"Oslo\"}"}}"#,
+            r#"{"type":"content_block_stop","index":1}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"tool_use"},"usage":{"output_tokens":7}}"#,
+            r#"{"type":"message_stop"}"#,
+        ] {
+            events.extend(stream.transcode_chunk(raw).unwrap());
+        }
+        events.extend(stream.finish());
+
+        let text: String = events
+            .iter()
+            .filter_map(|e| match e {
+                OrsEvent::TextDelta { delta, .. } => Some(delta.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(text, "Checking");
+
+        let arguments: String = events
+            .iter()
+            .filter_map(|e| match e {
+                OrsEvent::FunctionCallArgumentsDelta { delta, .. } => Some(delta.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(arguments, r#"{"city":"Oslo"}"#);
+
+        let done = events.iter().filter(|e| matches!(e, OrsEvent::ItemDone { .. })).count();
+        assert_eq!(done, 2);
+        assert!(matches!(
+            events.last(),
+            Some(OrsEvent::CompletionUsage { input_tokens: 10, output_tokens: 7, is_approximate: false, .. })
+        ));
+    }
+
+    #[test]
+    fn test_each_block_gets_its_own_item() {
+        let mut stream = AnthropicAdapter.stream(StreamOptions::default());
+        let mut events = Vec::new();
+        for raw in [
+            r#"{"type":"message_start","message":{"usage":{"input_tokens":10,"output_tokens":1}}}"#,
+            r#"{"type":"content_block_start","index":0,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":0,"delta":{"type":"text_delta","text":"Let me check."}}"#,
+            r#"{"type":"content_block_stop","index":0}"#,
+            r#"{"type":"content_block_start","index":1,"content_block":{"type":"tool_use","id":"toolu_1","name":"get_weather","input":{}}}"#,
+            r#"{"type":"content_block_delta","index":1,"delta":{"type":"input_json_delta","partial_json":"{}"}}"#,
+            r#"{"type":"content_block_stop","index":1}"#,
+            r#"{"type":"content_block_start","index":2,"content_block":{"type":"text","text":""}}"#,
+            r#"{"type":"content_block_delta","index":2,"delta":{"type":"text_delta","text":"Checked."}}"#,
+            r#"{"type":"content_block_stop","index":2}"#,
+            r#"{"type":"message_delta","delta":{"stop_reason":"end_turn"},"usage":{"output_tokens":9}}"#,
+        ] {
+            events.extend(stream.transcode_chunk(raw).unwrap());
+        }
+
+        let items: Vec<(&str, Option<u32>, &str)> = events
+            .iter()
+            .filter_map(|e| match e {
+                OrsEvent::ItemAdded { output_index, item, .. } => Some(("added", *output_index, item.id())),
+                OrsEvent::ItemDone { output_index, item, .. } => Some(("done", *output_index, item.id())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            items.iter().map(|(kind, index, _)| (*kind, *index)).collect::<Vec<_>>(),
+            vec![
+                ("added", Some(0)),
+                ("done", Some(0)),
+                ("added", Some(1)),
+                ("done", Some(1)),
+                ("added", Some(2)),
+                ("done", Some(2)),
+            ]
+        );
+        assert!(items[0].2 != items[4].2);
+
+        // The text after the tool call is on the last item, not dropped
+        let text_items: Vec<(&str, &str)> = events
+            .iter()
+            .filter_map(|e| match e {
+                OrsEvent::TextDelta { item_id, delta, .. } => Some((item_id.as_str(), delta.as_str())),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(text_items, vec![(items[0].2, "Let me check."), (items[4].2, "Checked.")]);
+    }
+
+    #[test]
+    fn test_stream_error_event() {
+        let mut stream = AnthropicAdapter.stream(StreamOptions::default());
+        let events = stream
+            .transcode_chunk(r#"{"type":"error","error":{"type":"overloaded_error","message":"Overloaded"}}"#)
+            .unwrap();
+        assert!(matches!(&events[..], [OrsEvent::Error { code, .. }] if code == "overloaded_error"));
+    }
+}
@@ -0,0 +1,298 @@
+//! Ollama's native chat API (`/api/chat`), for deployments that don't expose `/v1`.
+//!
+//! The response streams as newline-delimited JSON rather than SSE, one message delta per line,
+//! ending with a line that has `"done": true` and the token counts.
+
+use crate::{
+    adapter::{AdapterError, RequestConfig, StreamOptions, StreamTranscoder, UpstreamAdapter},
+    transcoder::Transcoder,
+    types::{
+        ComputerOutput, LegacyChoice, LegacyChunk, LegacyDelta, LegacyUsage, OrsContentPart, OrsEvent, OrsInputItem, OrsRole,
+    },
+    upstream,
+};
+use reqwest::RequestBuilder;
+use serde_json::{json, Value};
+use std::time::Duration;
+use uuid::Uuid;
+
+pub struct OllamaAdapter;
+
+impl UpstreamAdapter for OllamaAdapter {
+    fn build_request(&self, input: Vec<OrsInputItem>, config: &RequestConfig) -> Result<RequestBuilder, AdapterError> {
+        let request = config.request;
+        if request.n.is_some_and(|n| n > 1) {
+            return Err(AdapterError::Unsupported("n > 1".to_string()));
+        }
+        if request.logit_bias.is_some() {
+            return Err(AdapterError::Unsupported("logit_bias".to_string()));
+        }
+        if request.logprobs == Some(true) {
+            return Err(AdapterError::Unsupported("logprobs".to_string()));
+        }
+
+        let mut body = json!({
+            "model": request.model,
+            "messages": messages(request.instructions.as_deref(), input)?,
+            "stream": true,
+        });
+        if let Some(tools) = request.tools.as_deref().filter(|tools| !tools.is_empty()) {
+            body["tools"] = Value::Array(upstream::legacy_tools(tools));
+        }
+
+        let builder = config.client.post(config.url).header("x-request-id", config.request_id).json(&body);
+        // Ollama itself ignores auth, but it's often deployed behind a proxy that doesn't
+        Ok(self.authorize(builder, config.api_key))
+    }
+
+    fn stream_content_type(&self) -> &'static str {
+        "application/x-ndjson"
+    }
+
+    fn stream(&self, options: StreamOptions) -> Box<dyn StreamTranscoder> {
+        Box::new(OllamaStream {
+            transcoder: options.transcoder(),
+        })
+    }
+}
+
+fn messages(instructions: Option<&str>, input: Vec<OrsInputItem>) -> Result<Vec<Value>, AdapterError> {
+    let mut messages: Vec<Value> =
+        instructions.map(|text| json!({ "role": "system", "content": text })).into_iter().collect();
+
+    for item in input {
+        let message = match item {
+            OrsInputItem::Message { role, content } => {
+                let role = match role {
+                    OrsRole::User => "user",
+                    OrsRole::Assistant => "assistant",
+                    OrsRole::Developer => "system",
+                };
+                let mut text = String::new();
+                let mut images = Vec::new();
+                for part in content {
+                    match part {
+                        OrsContentPart::InputText { text: part } => text.push_str(&part),
+                        OrsContentPart::InputImage { image_url } => {
+                            let url = image_url.get("url").unwrap_or(&image_url).as_str().unwrap_or_default();
+                            images.push(image_data(url)?);
+                        }
+                        OrsContentPart::InputFile { .. } => {}
+                    }
+                }
+                let mut message = json!({ "role": role, "content": text });
+                if !images.is_empty() {
+                    message["images"] = json!(images);
+                }
+                message
+            }
+            OrsInputItem::FunctionCall { name, arguments, .. } => {
+                // Ollama takes arguments as an object, not the model's raw JSON string
+                let arguments = match arguments {
+                    Value::String(s) => serde_json::from_str(&s).unwrap_or_else(|_| json!({})),
+                    other => other,
+                };
+                json!({
+                    "role": "assistant",
+                    "content": "",
+                    "tool_calls": [{ "function": { "name": name, "arguments": arguments } }],
+                })
+            }
+            OrsInputItem::FunctionCallOutput { output, name, .. } => {
+                let mut message = json!({ "role": "tool", "content": output.to_text() });
+                if let Some(name) = name {
+                    message["tool_name"] = Value::String(name);
+                }
+                message
+            }
+            OrsInputItem::ComputerToolResult { output, .. } => {
+                let mut text = Vec::new();
+                let mut images = Vec::new();
+                for output in output {
+                    match output {
+                        ComputerOutput::ScreenshotOutput { image_url } => images.push(image_data(&image_url)?),
+                        ComputerOutput::TextOutput { text: t } => text.push(t),
+                    }
+                }
+                let mut message = json!({ "role": "tool", "content": text.join("\n") });
+                if !images.is_empty() {
+                    message["images"] = json!(images);
+                }
+                message
+            }
+        };
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// Ollama only accepts inline base64 images.
+fn image_data(url: &str) -> Result<String, AdapterError> {
+    url.strip_prefix("data:")
+        .and_then(|rest| rest.split_once(";base64,"))
+        .map(|(_, data)| data.to_string())
+        .ok_or_else(|| AdapterError::Unsupported("image URLs other than base64 data URLs".to_string()))
+}
+
+/// One response's stream state.
+struct OllamaStream {
+    transcoder: Transcoder,
+}
+
+impl StreamTranscoder for OllamaStream {
+    fn payload<'a>(&self, line: &'a str) -> Option<&'a str> {
+        Some(line).filter(|line| !line.is_empty())
+    }
+
+    fn transcode_chunk(&mut self, raw: &str) -> Result<Vec<OrsEvent>, AdapterError> {
+        let chunk: Value = serde_json::from_str(raw).map_err(|_| AdapterError::InvalidChunk(raw.to_string()))?;
+        if let Some(message) = chunk.get("error").and_then(Value::as_str) {
+            return Ok(vec![self.transcoder.error("upstream_error", message.to_string())]);
+        }
+
+        let message = &chunk["message"];
+        let text = |key: &str| message.get(key).and_then(Value::as_str).map(str::to_string);
+        // Ollama sends each tool call whole, without an ID
+        let tool_calls = message.get("tool_calls").and_then(Value::as_array).map(|calls| {
+            calls
+                .iter()
+                .enumerate()
+                .map(|(index, call)| {
+                    json!({
+                        "index": index,
+                        "id": format!("call_{}", Uuid::new_v4().simple()),
+                        "type": "function",
+                        "function": {
+                            "name": call["function"]["name"],
+                            "arguments": call["function"]["arguments"].to_string(),
+                        },
+                    })
+                })
+                .collect()
+        });
+        let delta = LegacyDelta { content: text("content"), reasoning: text("thinking"), tool_calls, ..Default::default() };
+
+        let done = chunk.get("done").and_then(Value::as_bool).unwrap_or(false);
+        let finish_reason = done.then(|| match chunk.get("done_reason").and_then(Value::as_str) {
+            Some("length") => "length".to_string(),
+            _ => "stop".to_string(),
+        });
+        let usage = done.then(|| {
+            let count = |key: &str| chunk.get(key).and_then(Value::as_u64).unwrap_or(0) as u32;
+            LegacyUsage {
+                prompt_tokens: count("prompt_eval_count"),
+                completion_tokens: count("eval_count"),
+                total_tokens: count("prompt_eval_count") + count("eval_count"),
+                prompt_tokens_details: None,
+                completion_tokens_details: None,
+            }
+        });
+
+        Ok(self.transcoder.process(LegacyChunk {
+            choices: vec![LegacyChoice { index: 0, delta, finish_reason, logprobs: None }],
+            usage,
+        }))
+    }
+
+    fn finish(&mut self) -> Vec<OrsEvent> {
+        self.transcoder.finish()
+    }
+
+    fn interrupt(&mut self) -> Vec<OrsEvent> {
+        self.transcoder.interrupt()
+    }
+
+    fn queued(&mut self, wait: Duration) -> OrsEvent {
+        self.transcoder.queued(wait)
+    }
+
+    fn error(&mut self, code: &str, message: String) -> OrsEvent {
+        self.transcoder.error(code, message)
+    }
+
+    fn done(&mut self, model: &str, status: &str, output: Vec<Value>, usage: Option<Value>) -> OrsEvent {
+        self.transcoder.done(model, status, output, usage)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::OrsRequest;
+    use reqwest::Client;
+
+    #[test]
+    fn test_request_format() {
+        let client = Client::new();
+        let request: OrsRequest =
+            serde_json::from_value(json!({ "model": "llama3.2", "instructions": "Be terse", "input": [] })).unwrap();
+        let input: Vec<OrsInputItem> = serde_json::from_value(json!([
+            { "type": "message", "role": "user", "content": [
+                { "type": "input_text", "text": "What's this?" },
+                { "type": "input_image", "image_url": "data:image/png;base64,iVBORw0KGgo=" },
+            ] },
+            { "type": "function_call", "id": "fc_1", "call_id": "call_1", "name": "lookup", "arguments": "{\"q\":\"png\"}" },
+            { "type": "function_call_output", "id": "fco_1", "call_id": "call_1", "output": "an image", "name": "lookup" },
+        ]))
+        .unwrap();
+        let config = RequestConfig {
+            client: &client,
+            url: "http://localhost:11434/api/chat",
+            api_key: None,
+            request_id: "req-1",
+            request: &request,
+        };
+        let req = OllamaAdapter.build_request(input, &config).unwrap().build().unwrap();
+        let body: Value = serde_json::from_slice(req.body().unwrap().as_bytes().unwrap()).unwrap();
+
+        assert_eq!(body["messages"][0], json!({ "role": "system", "content": "Be terse" }));
+        assert_eq!(body["messages"][1]["images"], json!(["iVBORw0KGgo="]));
+        assert_eq!(body["messages"][2]["tool_calls"][0]["function"]["arguments"], json!({ "q": "png" }));
+        assert_eq!(body["messages"][3], json!({ "role": "tool", "content": "an image", "tool_name": "lookup" }));
+    }
+
+    #[test]
+    fn test_stream_lines() {
+        let mut stream = OllamaAdapter.stream(StreamOptions::default());
+        assert_eq!(stream.payload(""), None);
+
+        let mut events = Vec::new();
+        for line in [
+            r#"{"model":"llama3.2","message":{"role":"assistant","content":"Hel"},"done":false}"#,
+            r#"{"model":"llama3.2","message":{"role":"assistant","content":"lo"},"done":false}"#,
+            r#"{"model":"llama3.2","message":{"role":"assistant","content":""},"done":true,"done_reason":"stop","prompt_eval_count":26,"eval_count":2}"#,
+        ] {
+            let raw = stream.payload(line).unwrap();
+            events.extend(stream.transcode_chunk(raw).unwrap());
+        }
+        events.extend(stream.finish());
+
+        let text: String = events
+            .iter()
+            .filter_map(|e| match e {
+                OrsEvent::TextDelta { delta, .. } => Some(delta.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(text, "Hello");
+        assert!(events.iter().any(|e| matches!(e, OrsEvent::ItemDone { .. })));
+        assert!(matches!(
+            events.last(),
+            Some(OrsEvent::CompletionUsage { input_tokens: 26, output_tokens: 2, is_approximate: false, .. })
+        ));
+    }
+
+    #[test]
+    fn test_stream_tool_call() {
+        let mut stream = OllamaAdapter.stream(StreamOptions::default());
+        let events = stream
+            .transcode_chunk(
+                r#"{"message":{"role":"assistant","content":"","tool_calls":[{"function":{"name":"lookup","arguments":{"q":"png"}}}]},"done":false}"#,
+            )
+            .unwrap();
+        assert!(events.iter().any(|e| matches!(
+            e,
+            OrsEvent::FunctionCallArgumentsDelta { delta, .. } if delta == r#"{"q":"png"}"#
+        )));
+    }
+}
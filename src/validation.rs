@@ -0,0 +1,219 @@
+use crate::{db, ndjson::OrsRequestBody, types::ValidationError, AppState};
+use axum::{
+    body::{Body, Bytes},
+    extract::{FromRequest, Request},
+    response::{IntoResponse, Response},
+};
+use futures::future::BoxFuture;
+use std::{
+    convert::Infallible,
+    marker::PhantomData,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+};
+use tower::{Layer, Service};
+
+/// Checks that need nothing but the request itself: no state, no stored context.
+pub trait Validate {
+    fn validate(&self) -> Result<(), ValidationError>;
+}
+
+impl Validate for OrsRequestBody {
+    fn validate(&self) -> Result<(), ValidationError> {
+        self.0.validate()
+    }
+}
+
+/// A body [`ValidationLayer`] already parsed and validated, left in the request extensions for
+/// the handler's extractor to take rather than parse it again.
+pub struct Validated<T>(Arc<Mutex<Option<T>>>);
+
+impl<T> Validated<T> {
+    fn new(body: T) -> Self {
+        Self(Arc::new(Mutex::new(Some(body))))
+    }
+
+    pub fn take(&self) -> Option<T> {
+        self.0.lock().unwrap().take()
+    }
+}
+
+impl<T> Clone for Validated<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+/// Rejects invalid bodies before the handler runs, so it never touches the upstream or the
+/// conversation for them. The body is extracted as `T` and validated; an unparseable body gets
+/// `T`'s rejection and an invalid one a 400, either counted as a failed request (and audited
+/// through the entry [`crate::audit::open`] put in the extensions, dropped with them). A valid one is handed on as a [`Validated`] extension.
+pub struct ValidationLayer<T> {
+    state: AppState,
+    _body: PhantomData<fn() -> T>,
+}
+
+impl<T> ValidationLayer<T> {
+    pub fn new(state: AppState) -> Self {
+        Self { state, _body: PhantomData }
+    }
+}
+
+impl<T> Clone for ValidationLayer<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.state.clone())
+    }
+}
+
+impl<S, T> Layer<S> for ValidationLayer<T> {
+    type Service = ValidationService<S, T>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        ValidationService { inner, state: self.state.clone(), _body: PhantomData }
+    }
+}
+
+pub struct ValidationService<S, T> {
+    inner: S,
+    state: AppState,
+    _body: PhantomData<fn() -> T>,
+}
+
+impl<S: Clone, T> Clone for ValidationService<S, T> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone(), state: self.state.clone(), _body: PhantomData }
+    }
+}
+
+/// Counts a request rejected before reaching the handler, which would otherwise have.
+fn reject(state: &AppState, response: Response) -> Response {
+    state.stats.record_request(db::now_secs());
+    state.stats.record_failure();
+    response
+}
+
+impl<S, T> Service<Request> for ValidationService<S, T>
+where
+    S: Service<Request, Response = Response, Error = Infallible> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    T: FromRequest<()> + Validate + Send + 'static,
+    T::Rejection: IntoResponse,
+{
+    type Response = Response;
+    type Error = Infallible;
+    type Future = BoxFuture<'static, Result<Response, Infallible>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // The clone that was polled ready handles this request
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let state = self.state.clone();
+        Box::pin(async move {
+            let (mut parts, body) = req.into_parts();
+            // Buffered within the route's body limit, so the handler still gets the raw bytes
+            let bytes = match Bytes::from_request(Request::from_parts(parts.clone(), body), &()).await {
+                Ok(bytes) => bytes,
+                Err(rejection) => return Ok(reject(&state, rejection.into_response())),
+            };
+            let copy = Request::from_parts(parts.clone(), Body::from(bytes.clone()));
+            match T::from_request(copy, &()).await {
+                Ok(body) => {
+                    if let Err(e) = body.validate() {
+                        tracing::debug!("Rejecting invalid request: {}", e.message);
+                        return Ok(reject(&state, e.into_response()));
+                    }
+                    parts.extensions.insert(Validated::new(body));
+                }
+                Err(rejection) => return Ok(reject(&state, rejection.into_response())),
+            }
+            inner.call(Request::from_parts(parts, Body::from(bytes))).await
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        http::{header::CONTENT_TYPE, StatusCode},
+        routing::post,
+        Router,
+    };
+    use crate::{request_id::RequestId, tests::test_state};
+    use tower::ServiceExt;
+
+    fn app(state: AppState) -> Router {
+        Router::new()
+            .route("/", post(|OrsRequestBody(req): OrsRequestBody| async move { req.model }))
+            .layer(ValidationLayer::<OrsRequestBody>::new(state.clone()))
+            .layer(axum::middleware::from_fn_with_state(state, crate::audit::open))
+    }
+
+    /// Audit log entries, once the writes of dropped handles have landed.
+    async fn audit_log(state: &AppState, expected: usize) -> Vec<db::AuditRecord> {
+        for _ in 0..100 {
+            let log = state.db.get_audit_log(0, i64::MAX, 10).await.unwrap();
+            if log.len() >= expected {
+                return log;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        Vec::new()
+    }
+
+    fn post_json(body: serde_json::Value) -> Request {
+        Request::post("/").header(CONTENT_TYPE, "application/json").body(Body::from(body.to_string())).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_valid_body_reaches_the_handler() {
+        let state = test_state().await;
+        let req = post_json(serde_json::json!({ "model": "llama3", "input": "Hi" }));
+        let res = app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"llama3");
+        assert_eq!(state.stats.snapshot()["failed_requests"], 0);
+    }
+
+    #[tokio::test]
+    async fn test_handler_gets_the_parsed_body() {
+        // A handler reading only the raw body would see nothing: the layer's parse is handed on
+        let app = Router::new()
+            .route(
+                "/",
+                post(|mut req: Request| async move {
+                    let validated = req.extensions_mut().remove::<Validated<OrsRequestBody>>().unwrap();
+                    validated.take().unwrap().0.model
+                }),
+            )
+            .layer(ValidationLayer::<OrsRequestBody>::new(test_state().await));
+        let res = app.oneshot(post_json(serde_json::json!({ "model": "llama3", "input": "Hi" }))).await.unwrap();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(&body[..], b"llama3");
+    }
+
+    #[tokio::test]
+    async fn test_invalid_body_is_rejected() {
+        let state = test_state().await;
+        let mut req = post_json(serde_json::json!({ "model": "llama3", "input": "Hi", "top_logprobs": 5 }));
+        req.extensions_mut().insert(RequestId("req_invalid".to_string()));
+        let res = app(state.clone()).oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["error"]["param"], "top_logprobs");
+
+        let res = app(state.clone()).oneshot(post_json(serde_json::json!({ "input": "Hi" }))).await.unwrap();
+        assert!(res.status().is_client_error());
+
+        assert_eq!(state.stats.snapshot()["failed_requests"], 2);
+        let log = audit_log(&state, 2).await;
+        assert_eq!(log.len(), 2);
+        assert!(log.iter().any(|record| record.entry.request_id == "req_invalid"));
+    }
+}
@@ -0,0 +1,373 @@
+use crate::{
+    db::{Db, WebhookDelivery},
+    types::ValidationError,
+};
+use hmac::{Hmac, Mac};
+use reqwest::header::CONTENT_TYPE;
+use sha2::Sha256;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+/// Retries after the first POST fails.
+const MAX_RETRIES: u32 = 3;
+/// Wait before the first retry; doubled for each one after.
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const TIMEOUT: Duration = Duration::from_secs(30);
+const EVENT_HEADER: &str = "x-ors-webhook-event";
+const SIGNATURE_HEADER: &str = "x-ors-signature";
+const TIMESTAMP_HEADER: &str = "x-ors-timestamp";
+/// Where `WEBHOOK_SECRET=auto` saves its key unless `WEBHOOK_SECRET_FILE` says otherwise.
+const DEFAULT_SECRET_FILE: &str = "webhook_secret.key";
+
+/// Whether a webhook may be delivered to `ip`. Loopback, private, link-local and unspecified
+/// addresses would let a request make the proxy call into its own network.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast())
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let unique_local = ip.segments()[0] & 0xfe00 == 0xfc00;
+                let link_local = ip.segments()[0] & 0xffc0 == 0xfe80;
+                !(ip.is_loopback() || ip.is_unspecified() || unique_local || link_local)
+            }
+        },
+    }
+}
+
+/// The address a webhook to `url` goes to: the first its host resolves to. Every address it
+/// resolves to must be public unless `allow_private`.
+async fn resolve(url: &reqwest::Url, allow_private: bool) -> Result<SocketAddr, String> {
+    let host = url.host_str().ok_or("webhook_url has no host")?;
+    let port = url.port_or_known_default().ok_or("webhook_url has no port")?;
+    // IPv6 literals come bracketed
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    let addrs: Vec<SocketAddr> = match tokio::net::lookup_host((host, port)).await {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => return Err(format!("webhook_url host {} doesn't resolve: {}", host, e)),
+    };
+    if let Some(addr) = addrs.iter().find(|addr| !allow_private && !is_public(addr.ip())) {
+        return Err(format!("webhook_url host {} resolves to non-public address {}", host, addr.ip()));
+    }
+    addrs.first().copied().ok_or_else(|| format!("webhook_url host {} doesn't resolve", host))
+}
+
+/// A client for one delivery to `url`, pinned to the address [`resolve`] checked, so a DNS
+/// answer that changes after the check can't send it elsewhere. Redirects aren't followed for
+/// the same reason. Webhooks get their own client anyway: a slow receiver shouldn't tie up
+/// upstream connections, and deliveries need a much shorter timeout than streamed completions.
+async fn client(url: &reqwest::Url, allow_private: bool) -> Result<reqwest::Client, String> {
+    let addr = resolve(url, allow_private).await?;
+    let mut builder = reqwest::Client::builder().timeout(TIMEOUT).redirect(reqwest::redirect::Policy::none());
+    if let Some(domain) = url.domain() {
+        builder = builder.resolve(domain, addr);
+    }
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// The key webhook bodies are signed with, from `WEBHOOK_SECRET`; `auto` generates a random one
+/// for this process and saves it to `WEBHOOK_SECRET_FILE` for receivers to pick up. `None`
+/// leaves webhooks unsigned.
+pub fn signing_key() -> Option<&'static [u8]> {
+    static KEY: OnceLock<Option<Vec<u8>>> = OnceLock::new();
+    KEY.get_or_init(|| match std::env::var("WEBHOOK_SECRET").ok().filter(|s| !s.is_empty()) {
+        Some(secret) if secret == "auto" => {
+            let key = format!("{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple());
+            let path = std::env::var("WEBHOOK_SECRET_FILE").unwrap_or_else(|_| DEFAULT_SECRET_FILE.to_string());
+            // Never logged: log output tends to end up somewhere more readable than a 0600 file
+            if let Err(e) = write_secret(Path::new(&path), &key) {
+                panic!("Failed to save the generated webhook signing key to {}: {}", path, e);
+            }
+            tracing::warn!("WEBHOOK_SECRET=auto: saved a generated signing key to {} (changes on restart)", path);
+            Some(key.into_bytes())
+        }
+        secret => secret.map(String::into_bytes),
+    })
+    .as_deref()
+}
+
+/// Writes `secret` to `path`, readable by this user only.
+fn write_secret(path: &Path, secret: &str) -> std::io::Result<()> {
+    use std::io::Write;
+
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+        options.mode(0o600);
+        let file = options.open(path)?;
+        // The mode only applies to files created here, not to one left by an earlier run
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+        (&file).write_all(secret.as_bytes())
+    }
+    #[cfg(not(unix))]
+    options.open(path)?.write_all(secret.as_bytes())
+}
+
+/// Hex-encoded `HMAC-SHA256(key, "{timestamp}.{body}")`. Covering the timestamp stops a captured
+/// delivery from being replayed later with a fresh one.
+fn sign(key: &[u8], timestamp: i64, body: &[u8]) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(format!("{}.", timestamp).as_bytes());
+    mac.update(body);
+    hex::encode(mac.finalize().into_bytes())
+}
+
+/// Webhooks must be `https` unless `allow_http` (`ALLOW_HTTP_WEBHOOKS=true`), and their host must
+/// resolve to public addresses only unless `allow_private` (`ALLOW_PRIVATE_WEBHOOKS=true`).
+pub async fn validate_url(url: &str, allow_http: bool, allow_private: bool) -> Result<(), ValidationError> {
+    let parsed = reqwest::Url::parse(url).map_err(|_| ValidationError::new("webhook_url", "webhook_url is not a valid URL"))?;
+    match parsed.scheme() {
+        "https" => {}
+        "http" if allow_http => {}
+        _ => return Err(ValidationError::new("webhook_url", "webhook_url must use https")),
+    }
+    resolve(&parsed, allow_private).await.map(|_| ()).map_err(|e| ValidationError::new("webhook_url", e))
+}
+
+/// POSTs the finished response to `url` as a `response.completed` event, retrying with
+/// exponential backoff, and records the outcome in `webhook_deliveries`. The host is resolved
+/// and checked again first, as it may point somewhere else by now than when the request was
+/// validated.
+///
+/// With a [`signing_key`], each attempt carries `X-ORS-Timestamp` (Unix seconds) and
+/// `X-ORS-Signature: sha256=<hex HMAC-SHA256 of "{timestamp}.{body}">`. Receivers should reject
+/// requests whose timestamp is 300 or more seconds off their clock, then check the signature.
+pub async fn deliver(db: &Db, conversation_id: &str, url: &str, response: &serde_json::Value, allow_private: bool) {
+    deliver_with_backoff(db, conversation_id, url, response, signing_key(), allow_private, INITIAL_BACKOFF).await
+}
+
+async fn deliver_with_backoff(
+    db: &Db,
+    conversation_id: &str,
+    url: &str,
+    response: &serde_json::Value,
+    key: Option<&[u8]>,
+    allow_private: bool,
+    initial_backoff: Duration,
+) {
+    let client = match reqwest::Url::parse(url) {
+        Ok(parsed) => client(&parsed, allow_private).await,
+        Err(e) => Err(e.to_string()),
+    };
+    let client = match client {
+        Ok(client) => client,
+        Err(e) => {
+            tracing::error!("Not delivering webhook for {}: {}", conversation_id, e);
+            let delivery = WebhookDelivery { url, delivered: false, attempts: 0, status_code: None, error: Some(&e) };
+            if let Err(e) = db.record_webhook_delivery(conversation_id, &delivery).await {
+                tracing::error!("Failed to record webhook delivery: {}", e);
+            }
+            return;
+        }
+    };
+    let body = serde_json::to_vec(response).expect("JSON values always serialize");
+    let mut attempts = 0;
+    let mut backoff = initial_backoff;
+    let (status_code, error) = loop {
+        attempts += 1;
+        let mut req = client
+            .post(url)
+            .header(CONTENT_TYPE, "application/json")
+            .header(EVENT_HEADER, "response.completed")
+            .body(body.clone());
+        if let Some(key) = key {
+            let timestamp = crate::db::now_secs();
+            let signature = format!("sha256={}", sign(key, timestamp, &body));
+            req = req.header(SIGNATURE_HEADER, signature).header(TIMESTAMP_HEADER, timestamp);
+        }
+        let (status_code, error) = match req.send().await {
+            Ok(res) if res.status().is_success() => break (Some(res.status().as_u16()), None),
+            Ok(res) => (Some(res.status().as_u16()), format!("receiver returned {}", res.status())),
+            Err(e) => (None, e.to_string()),
+        };
+        if attempts > MAX_RETRIES {
+            break (status_code, Some(error));
+        }
+        tracing::warn!("Webhook delivery for {} failed ({}), retrying in {:?}", conversation_id, error, backoff);
+        tokio::time::sleep(backoff).await;
+        backoff *= 2;
+    };
+
+    match &error {
+        None => tracing::info!("Delivered webhook for {} after {} attempt(s)", conversation_id, attempts),
+        Some(e) => tracing::error!("Giving up on webhook for {} after {} attempts: {}", conversation_id, attempts, e),
+    }
+    let delivery = WebhookDelivery { url, delivered: error.is_none(), attempts, status_code, error: error.as_deref() };
+    if let Err(e) = db.record_webhook_delivery(conversation_id, &delivery).await {
+        tracing::error!("Failed to record webhook delivery: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc;
+
+    /// What a receiver does with a delivery: reject stale timestamps, then check the HMAC of
+    /// `"{timestamp}.{body}"` in constant time.
+    fn verify_webhook_signature(secret: &[u8], body: &[u8], timestamp: i64, signature: &str) -> bool {
+        if (crate::db::now_secs() - timestamp).abs() >= 300 {
+            return false;
+        }
+        let Some(Ok(expected)) = signature.strip_prefix("sha256=").map(hex::decode) else {
+            return false;
+        };
+        let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+        mac.update(format!("{}.", timestamp).as_bytes());
+        mac.update(body);
+        mac.verify_slice(&expected).is_ok()
+    }
+
+    fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+        request.lines().find_map(|line| {
+            let (key, value) = line.split_once(':')?;
+            key.eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+
+    #[tokio::test]
+    async fn test_validate_url() {
+        assert!(validate_url("https://93.184.215.14/ors", false, false).await.is_ok());
+        assert!(validate_url("http://93.184.215.14/ors", true, false).await.is_ok());
+
+        let err = validate_url("http://hooks.example.com/ors", false, false).await.unwrap_err();
+        assert_eq!(err.param, "webhook_url");
+        assert!(validate_url("ftp://hooks.example.com", true, false).await.is_err());
+        assert!(validate_url("not a url", true, false).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_validate_url_rejects_internal_addresses() {
+        for url in [
+            "https://127.0.0.1/ors",
+            "https://localhost:8443/ors",
+            "https://10.0.0.5/ors",
+            "https://192.168.1.1/ors",
+            "https://169.254.169.254/latest/meta-data",
+            "https://0.0.0.0/ors",
+            "https://[::1]/ors",
+            "https://[fd00::1]/ors",
+            "https://[fe80::1]/ors",
+            "https://[::ffff:127.0.0.1]/ors",
+        ] {
+            let err = validate_url(url, false, false).await.unwrap_err();
+            assert!(err.message.contains("non-public"), "{}: {}", url, err.message);
+            assert!(validate_url(url, false, true).await.is_ok(), "{}", url);
+        }
+    }
+
+    /// Answers each request with the next of `statuses`, forwarding the raw request text.
+    async fn receiver(statuses: Vec<u16>) -> (String, mpsc::UnboundedReceiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for status in statuses {
+                let (mut sock, _) = listener.accept().await.unwrap();
+                // The JSON body ends the request
+                let mut request = Vec::new();
+                let mut buf = [0u8; 8192];
+                while !request.ends_with(b"}") {
+                    let n = sock.read(&mut buf).await.unwrap();
+                    if n == 0 {
+                        break;
+                    }
+                    request.extend_from_slice(&buf[..n]);
+                }
+                tx.send(String::from_utf8_lossy(&request).into_owned()).unwrap();
+                let resp = format!("HTTP/1.1 {} X\r\ncontent-length: 0\r\nconnection: close\r\n\r\n", status);
+                let _ = sock.write_all(resp.as_bytes()).await;
+            }
+        });
+        (format!("http://{}/hook", addr), rx)
+    }
+
+    async fn deliveries(db: &Db) -> Vec<(i64, i64, Option<i64>)> {
+        sqlx::query_as("SELECT delivered, attempts, status_code FROM webhook_deliveries WHERE conversation_id = 'conv_w'")
+            .fetch_all(db.pool())
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_deliver_retries_until_accepted() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let (url, mut requests) = receiver(vec![500, 200]).await;
+        let response = serde_json::json!({ "id": "conv_w", "status": "completed" });
+
+        deliver_with_backoff(&db, "conv_w", &url, &response, None, true, Duration::from_millis(1)).await;
+
+        let first = requests.recv().await.unwrap();
+        assert_eq!(header(&first, EVENT_HEADER), Some("response.completed"));
+        assert_eq!(header(&first, SIGNATURE_HEADER), None);
+        assert!(first.contains(r#""id":"conv_w""#));
+        assert!(requests.recv().await.is_some());
+        assert_eq!(deliveries(&db).await, vec![(1, 2, Some(200))]);
+    }
+
+    #[tokio::test]
+    async fn test_deliver_gives_up_after_retries() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let (url, _requests) = receiver(vec![503; MAX_RETRIES as usize + 1]).await;
+
+        deliver_with_backoff(&db, "conv_w", &url, &serde_json::json!({}), None, true, Duration::from_millis(1)).await;
+
+        assert_eq!(deliveries(&db).await, vec![(0, MAX_RETRIES as i64 + 1, Some(503))]);
+    }
+
+    #[tokio::test]
+    async fn test_signed_delivery_verifies() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let (url, mut requests) = receiver(vec![200]).await;
+        let response = serde_json::json!({ "id": "conv_w", "status": "completed" });
+
+        deliver_with_backoff(&db, "conv_w", &url, &response, Some(b"s3cret"), true, Duration::from_millis(1)).await;
+
+        let request = requests.recv().await.unwrap();
+        let body = request.split_once("\r\n\r\n").unwrap().1.as_bytes();
+        let timestamp: i64 = header(&request, TIMESTAMP_HEADER).unwrap().parse().unwrap();
+        let signature = header(&request, SIGNATURE_HEADER).unwrap();
+        assert!(verify_webhook_signature(b"s3cret", body, timestamp, signature));
+        assert!(!verify_webhook_signature(b"wrong", body, timestamp, signature));
+        assert!(!verify_webhook_signature(b"s3cret", b"{}", timestamp, signature));
+        assert!(!verify_webhook_signature(b"s3cret", body, timestamp - 300, signature));
+        // The signature doesn't carry over to another timestamp
+        assert!(!verify_webhook_signature(b"s3cret", body, timestamp - 1, signature));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_secret_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let path = std::env::temp_dir().join(format!("ors-webhook-{}.key", uuid::Uuid::new_v4().simple()));
+        // A file left readable by an earlier run is tightened too
+        std::fs::write(&path, "an older, longer key").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+
+        write_secret(&path, "s3cret").unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "s3cret");
+        assert_eq!(std::fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_deliver_checks_the_address_again() {
+        let db = Db::new("sqlite::memory:").await.unwrap();
+        let (url, mut requests) = receiver(vec![200]).await;
+
+        // Accepted when the request came in, but a loopback address by the time it's delivered
+        deliver_with_backoff(&db, "conv_w", &url, &serde_json::json!({}), None, false, Duration::from_millis(1)).await;
+
+        assert_eq!(deliveries(&db).await, vec![(0, 0, None)]);
+        assert!(requests.try_recv().is_err());
+    }
+}
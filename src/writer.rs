@@ -0,0 +1,248 @@
+use crate::{
+    db::{Db, UsageEvent},
+    transcoder::{collect_response, response_status},
+    types::{OrsEvent, OrsInputItem},
+    webhook,
+};
+use dashmap::DashMap;
+use std::{collections::HashMap, sync::Arc};
+use tokio::sync::{
+    mpsc::{self, error::TrySendError},
+    watch,
+};
+use tokio::task::JoinHandle;
+
+/// The parts of a request that are persisted once its response has finished streaming.
+pub struct Interaction {
+    pub conversation_id: String,
+    /// Tenant the conversation belongs to.
+    pub tenant_id: String,
+    pub model: String,
+    /// Time to the upstream's response headers; `None` when no upstream was involved.
+    pub upstream_latency_ms: Option<u64>,
+    pub input: Vec<OrsInputItem>,
+    pub instructions: Option<String>,
+    pub metadata: Option<HashMap<String, String>>,
+    /// Notified with the finished response once it's saved.
+    pub webhook_url: Option<String>,
+    /// Whether `webhook_url` may resolve to an internal address (`ALLOW_PRIVATE_WEBHOOKS`).
+    pub allow_private_webhook: bool,
+    /// Files the request attached, linked to the conversation once it's saved.
+    pub file_ids: Vec<String>,
+}
+
+pub struct SaveRequest {
+    pub interaction: Interaction,
+    pub events: Vec<OrsEvent>,
+}
+
+/// Write-behind persistence: finished responses are queued and saved by a background worker,
+/// so a stream can end without waiting on the DB. A follow-up turn calls [`DbWriter::flush`]
+/// before loading the conversation, so it sees the previous turn even if it's still queued.
+#[derive(Clone)]
+pub struct DbWriter {
+    db: Arc<Db>,
+    tx: mpsc::Sender<(SaveRequest, PendingWrite)>,
+    capacity: usize,
+    pending: Arc<PendingWrites>,
+}
+
+/// Writes submitted but not yet saved, by conversation.
+#[derive(Default)]
+struct PendingWrites(DashMap<String, watch::Sender<usize>>);
+
+impl PendingWrites {
+    fn add(self: &Arc<Self>, conversation_id: &str) -> PendingWrite {
+        self.0.entry(conversation_id.to_string()).or_insert_with(|| watch::Sender::new(0)).send_modify(|n| *n += 1);
+        PendingWrite { pending: self.clone(), conversation_id: conversation_id.to_string() }
+    }
+}
+
+/// One pending write, done when dropped.
+struct PendingWrite {
+    pending: Arc<PendingWrites>,
+    conversation_id: String,
+}
+
+impl Drop for PendingWrite {
+    fn drop(&mut self) {
+        if let Some(count) = self.pending.0.get(&self.conversation_id) {
+            count.send_modify(|n| *n -= 1);
+        }
+        self.pending.0.remove_if(&self.conversation_id, |_, count| *count.borrow() == 0);
+    }
+}
+
+impl DbWriter {
+    /// Starts the worker. It runs until every `DbWriter` clone is dropped and the queue is drained.
+    pub fn spawn(db: Arc<Db>, capacity: usize) -> (Self, JoinHandle<()>) {
+        let capacity = capacity.max(1);
+        let (tx, mut rx) = mpsc::channel::<(SaveRequest, PendingWrite)>(capacity);
+        let worker_db = db.clone();
+        let worker = tokio::spawn(async move {
+            while let Some((req, _pending)) = rx.recv().await {
+                persist(&worker_db, req).await;
+            }
+        });
+        (Self { db, tx, capacity, pending: Arc::default() }, worker)
+    }
+
+    /// Queues `req`, or writes it inline if the queue is full or the worker has stopped.
+    pub async fn submit(&self, req: SaveRequest) {
+        let pending = self.pending.add(&req.interaction.conversation_id);
+        match self.tx.try_send((req, pending)) {
+            Ok(()) => {}
+            Err(TrySendError::Full((req, _pending))) => {
+                tracing::warn!("DB write queue full ({} pending), writing inline", self.capacity);
+                persist(&self.db, req).await;
+            }
+            Err(TrySendError::Closed((req, _pending))) => persist(&self.db, req).await,
+        }
+    }
+
+    /// Waits until the writes submitted so far for the conversation are saved.
+    pub async fn flush(&self, conversation_id: &str) {
+        let Some(mut count) = self.pending.0.get(conversation_id).map(|count| count.subscribe()) else { return };
+        // An error means the last write finished and its counter went away
+        let _ = count.wait_for(|n| *n == 0).await;
+    }
+
+    pub fn queue_depth(&self) -> usize {
+        self.capacity - self.tx.capacity()
+    }
+}
+
+async fn persist(db: &Arc<Db>, req: SaveRequest) {
+    let SaveRequest { interaction, events } = req;
+    let conversation_id = &interaction.conversation_id;
+
+    let saved = db
+        .save_interaction(conversation_id, &interaction.tenant_id, interaction.metadata.as_ref(), interaction.input, events.clone())
+        .await;
+    match saved {
+        Ok(()) => {}
+        Err(sqlx::Error::RowNotFound) => {
+            tracing::error!("Not saving {}: it belongs to another tenant", conversation_id);
+            return;
+        }
+        Err(e) => tracing::error!("Failed to save interaction: {}", e),
+    }
+    for file_id in &interaction.file_ids {
+        if let Err(e) = db.attach_file(conversation_id, file_id).await {
+            tracing::error!("Failed to link file {} to conversation {}: {}", file_id, conversation_id, e);
+        }
+    }
+    if let Some(instructions) = &interaction.instructions {
+        if let Err(e) = db.save_instructions(conversation_id, instructions).await {
+            tracing::error!("Failed to save instructions: {}", e);
+        }
+    }
+    if let Err(e) = db.save_events(conversation_id, &events).await {
+        tracing::error!("Failed to save events: {}", e);
+    }
+    if let Err(e) = db.set_response_status(conversation_id, response_status(&events)).await {
+        tracing::error!("Failed to save response status: {}", e);
+    }
+    let usage = events.iter().find_map(|event| match event {
+        OrsEvent::CompletionUsage { input_tokens, output_tokens, total_tokens, .. } => {
+            Some((*input_tokens, *output_tokens, *total_tokens))
+        }
+        _ => None,
+    });
+    if let Some((input_tokens, output_tokens, total_tokens)) = usage {
+        if let Err(e) = db.add_usage(conversation_id, input_tokens, output_tokens, total_tokens).await {
+            tracing::error!("Failed to save usage: {}", e);
+        }
+    }
+    let usage_event = UsageEvent {
+        tenant_id: &interaction.tenant_id,
+        model: &interaction.model,
+        usage: usage.unwrap_or_default(),
+        upstream_latency_ms: interaction.upstream_latency_ms,
+    };
+    if let Err(e) = db.record_usage_event(conversation_id, &usage_event).await {
+        tracing::error!("Failed to record usage event: {}", e);
+    }
+    if let Some(url) = interaction.webhook_url {
+        let response = collect_response(&interaction.model, &events);
+        let (db, conversation_id) = (db.clone(), conversation_id.clone());
+        let allow_private = interaction.allow_private_webhook;
+        // Retries can take a while; don't hold up the writes queued behind this one
+        tokio::spawn(async move { webhook::deliver(&db, &conversation_id, &url, &response, allow_private).await });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::DEFAULT_TENANT_ID;
+    use crate::types::{OrsContentPart, OrsRole};
+
+    fn request(conversation_id: &str) -> SaveRequest {
+        SaveRequest {
+            interaction: Interaction {
+                conversation_id: conversation_id.to_string(),
+                tenant_id: DEFAULT_TENANT_ID.to_string(),
+                model: "m".to_string(),
+                upstream_latency_ms: Some(5),
+                input: vec![OrsInputItem::Message {
+                    role: OrsRole::User,
+                    content: vec![OrsContentPart::InputText { text: "Hi".to_string() }],
+                }],
+                instructions: None,
+                metadata: None,
+                webhook_url: None,
+                allow_private_webhook: false,
+                file_ids: vec![],
+            },
+            events: vec![],
+        }
+    }
+
+    #[tokio::test]
+    async fn test_worker_drains_queue_on_shutdown() {
+        let db = Arc::new(Db::new("sqlite::memory:").await.unwrap());
+        let (writer, worker) = DbWriter::spawn(db.clone(), 1);
+
+        for i in 0..3 {
+            writer.submit(request(&format!("c{}", i))).await;
+        }
+        drop(writer);
+        worker.await.unwrap();
+
+        for i in 0..3 {
+            assert_eq!(db.load_context(&format!("c{}", i), DEFAULT_TENANT_ID).await.unwrap().len(), 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flush_waits_for_queued_writes() {
+        let db = Arc::new(Db::new("sqlite::memory:").await.unwrap());
+        let (writer, _worker) = DbWriter::spawn(db.clone(), 10);
+
+        writer.submit(request("c_flush")).await;
+        writer.submit(request("c_flush")).await;
+        writer.flush("c_flush").await;
+        assert_eq!(db.load_context("c_flush", DEFAULT_TENANT_ID).await.unwrap().len(), 2);
+        assert!(writer.pending.0.is_empty());
+
+        // Nothing pending: returns right away
+        writer.flush("c_other").await;
+    }
+
+    #[tokio::test]
+    async fn test_files_linked_once_saved() {
+        let db = Arc::new(Db::new("sqlite::memory:").await.unwrap());
+        let mut req = request("c_files");
+        req.interaction.file_ids = vec!["file-abc".to_string()];
+        persist(&db, req).await;
+        assert_eq!(db.conversation_files("c_files").await.unwrap(), vec!["file-abc"]);
+
+        // Not for a conversation that turned out to be someone else's
+        let mut req = request("c_files");
+        req.interaction.tenant_id = "team-b".to_string();
+        req.interaction.file_ids = vec!["file-def".to_string()];
+        persist(&db, req).await;
+        assert_eq!(db.conversation_files("c_files").await.unwrap(), vec!["file-abc"]);
+    }
+}
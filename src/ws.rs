@@ -0,0 +1,458 @@
+use crate::{
+    authenticate_tenant, db, error_response, prepare,
+    request_id::RequestId,
+    start_upstream,
+    transcoder::output_items,
+    validation::Validate,
+    types::{FunctionCallOutputContent, OrsEvent, OrsInputItem, OrsRequest, ResponseItem},
+    AppState,
+};
+use axum::{
+    extract::{
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        State,
+    },
+    http::{HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+    Extension,
+};
+use futures::{
+    stream::{SplitSink, SplitStream},
+    SinkExt, StreamExt,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use std::collections::HashMap;
+use tokio::sync::mpsc;
+use tracing::Instrument;
+use uuid::Uuid;
+
+/// Frames a client may send once its request is running.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+enum ClientFrame {
+    ToolOutput { call_id: String, output: String },
+}
+
+/// What the reader task passes on to the session.
+#[derive(Debug)]
+enum Inbound {
+    Request(Box<OrsRequest>),
+    ToolOutput { call_id: String, output: String },
+    /// A frame that couldn't be used; reported back without ending the session.
+    Invalid(String),
+}
+
+type Sender = SplitSink<WebSocket, Message>;
+
+/// `GET /v1/responses/stream`: a WebSocket session for one request and its tool round trips.
+///
+/// The client's first text frame is an `OrsRequest`; the server answers with its `OrsEvent`s as
+/// JSON text frames. Tool results are sent back as
+/// `{"type": "tool_output", "call_id": "...", "output": "..."}`, as soon as the call has been
+/// announced. Once a response has finished and every call in it has an output, the upstream is
+/// called again with the conversation so far plus those outputs. The session closes after a
+/// response without tool calls. Problems are reported as
+/// `{"type": "error", "error": {"type": "...", "message": "..."}}` frames.
+pub async fn stream_responses(
+    State(state): State<AppState>,
+    Extension(RequestId(request_id)): Extension<RequestId>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+) -> Response {
+    let state = match authenticate_tenant(state, &headers, true).await {
+        Ok(state) => state,
+        Err(res) => return res,
+    };
+    // Counted with SSE streams; both hold an upstream connection open for their lifetime
+    let Some(connection) = state.stats.try_track_sse_connection(state.max_connections) else {
+        tracing::warn!("Rejecting WebSocket: {} streams already open", state.max_connections.unwrap_or_default());
+        return error_response(StatusCode::SERVICE_UNAVAILABLE, "service_unavailable", "Too many active connections");
+    };
+    let span = tracing::Span::current();
+    ws.on_upgrade(move |socket| {
+        async move {
+            run_session(state, request_id, socket).await;
+            drop(connection);
+        }
+        .instrument(span)
+    })
+}
+
+async fn run_session(state: AppState, request_id: String, socket: WebSocket) {
+    let (mut sender, receiver) = socket.split();
+    let (tx, mut rx) = mpsc::channel(16);
+    let reader = tokio::spawn(read_frames(receiver, tx));
+
+    let mut payload = match rx.recv().await {
+        Some(Inbound::Request(payload)) => *payload,
+        Some(Inbound::Invalid(message)) => {
+            let _ = sender.send(error_frame("invalid_request_error", message)).await;
+            return close(sender, reader).await;
+        }
+        _ => return close(sender, reader).await,
+    };
+    state.stats.record_request(db::now_secs());
+    if payload.background {
+        let _ = sender.send(error_frame("invalid_request_error", "background isn't supported over WebSocket")).await;
+        return close(sender, reader).await;
+    }
+    let (conversation_id, mut history) = match prepare(&state, &payload).await {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            let _ = sender.send(error_frame_from(e.into_response()).await).await;
+            return close(sender, reader).await;
+        }
+    };
+
+    let mut client_open = true;
+    loop {
+        let mut events = match start_upstream(&state, &request_id, payload.clone(), conversation_id.clone(), history.clone()).await {
+            Ok((events, _)) => events,
+            Err(e) => {
+                let _ = sender.send(error_frame_from(e.into_response()).await).await;
+                break;
+            }
+        };
+
+        // Tool outputs arrive while the response is still streaming, so both are read at once.
+        // After the client leaves, the rest is still drained so the response gets saved.
+        let mut collected = Vec::new();
+        let mut outputs: HashMap<String, String> = HashMap::new();
+        let mut failed = false;
+        loop {
+            tokio::select! {
+                event = events.next() => match event {
+                    Some(Ok(event)) => {
+                        if client_open && sender.send(event_frame(&event)).await.is_err() {
+                            client_open = false;
+                        }
+                        collected.push(event);
+                    }
+                    Some(Err(e)) => {
+                        tracing::error!("Upstream stream failed: {}", e);
+                        state.stats.record_failure();
+                        let _ = sender.send(error_frame("upstream_error", format!("Upstream error: {}", e))).await;
+                        failed = true;
+                        break;
+                    }
+                    None => break,
+                },
+                inbound = rx.recv(), if client_open => match inbound {
+                    Some(inbound) => accept(&mut sender, inbound, &collected, &mut outputs).await,
+                    None => client_open = false,
+                },
+            }
+        }
+        if failed || !client_open {
+            break;
+        }
+        state.stats.record_success();
+
+        let calls = function_calls(&collected);
+        if calls.is_empty() {
+            break;
+        }
+        while calls.iter().any(|(call_id, _)| !outputs.contains_key(call_id)) {
+            match rx.recv().await {
+                Some(inbound) => accept(&mut sender, inbound, &collected, &mut outputs).await,
+                None => {
+                    tracing::debug!("Client left before answering every tool call");
+                    return close(sender, reader).await;
+                }
+            }
+        }
+
+        history.append(&mut payload.input);
+        history.extend(output_items(&collected));
+        payload.input = calls
+            .into_iter()
+            .map(|(call_id, name)| OrsInputItem::FunctionCallOutput {
+                id: format!("fco_{}", Uuid::new_v4().simple()),
+                output: FunctionCallOutputContent::Text(outputs.remove(&call_id).unwrap_or_default()),
+                call_id,
+                name: Some(name),
+            })
+            .collect();
+    }
+
+    close(sender, reader).await
+}
+
+async fn close(mut sender: Sender, reader: tokio::task::JoinHandle<()>) {
+    let _ = sender.send(Message::Close(None)).await;
+    reader.abort();
+}
+
+/// Parses frames until the client closes, passing them on in order.
+async fn read_frames(mut receiver: SplitStream<WebSocket>, tx: mpsc::Sender<Inbound>) {
+    let mut started = false;
+    while let Some(Ok(message)) = receiver.next().await {
+        let inbound = match message {
+            Message::Text(text) => parse_frame(&text, started),
+            Message::Binary(_) => Inbound::Invalid("binary frames are not supported".to_string()),
+            Message::Close(_) => break,
+            Message::Ping(_) | Message::Pong(_) => continue,
+        };
+        started |= matches!(inbound, Inbound::Request(_));
+        if tx.send(inbound).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// The first frame is the request; every one after it a [`ClientFrame`].
+fn parse_frame(text: &str, started: bool) -> Inbound {
+    if !started {
+        return match serde_json::from_str::<OrsRequest>(text) {
+            Ok(request) => match request.validate() {
+                Ok(()) => Inbound::Request(Box::new(request)),
+                Err(e) => Inbound::Invalid(format!("invalid request: {}", e.message)),
+            },
+            Err(e) => Inbound::Invalid(format!("invalid request: {}", e)),
+        };
+    }
+    match serde_json::from_str(text) {
+        Ok(ClientFrame::ToolOutput { call_id, output }) => Inbound::ToolOutput { call_id, output },
+        Err(e) => Inbound::Invalid(format!("invalid frame: {}", e)),
+    }
+}
+
+/// Records a tool output against the calls announced so far, or tells the client what was
+/// wrong with its frame.
+async fn accept(sender: &mut Sender, inbound: Inbound, events: &[OrsEvent], outputs: &mut HashMap<String, String>) {
+    let error = match inbound {
+        Inbound::ToolOutput { call_id, .. } if !function_calls(events).iter().any(|(id, _)| *id == call_id) => {
+            format!("tool_output references unknown call_id: {}", call_id)
+        }
+        Inbound::ToolOutput { call_id, .. } if outputs.contains_key(&call_id) => {
+            format!("call_id {} already has an output", call_id)
+        }
+        Inbound::ToolOutput { call_id, output } => {
+            outputs.insert(call_id, output);
+            return;
+        }
+        Inbound::Request(_) => "a session takes a single request; send tool_output frames".to_string(),
+        Inbound::Invalid(message) => message,
+    };
+    let _ = sender.send(error_frame("invalid_request_error", error)).await;
+}
+
+/// `(call_id, name)` of every function call started in the response.
+fn function_calls(events: &[OrsEvent]) -> Vec<(String, String)> {
+    events
+        .iter()
+        .filter_map(|event| match event {
+            OrsEvent::ItemAdded { item: ResponseItem::FunctionCall { call_id, name, .. }, .. } => {
+                Some((call_id.clone(), name.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+fn event_frame(event: &OrsEvent) -> Message {
+    Message::Text(serde_json::to_string(event).expect("events always serialize"))
+}
+
+fn error_frame(error_type: &str, message: impl std::fmt::Display) -> Message {
+    let frame = serde_json::json!({ "type": "error", "error": { "type": error_type, "message": message.to_string() } });
+    Message::Text(frame.to_string())
+}
+
+/// Turns an error response meant for HTTP clients into an error frame carrying the same details.
+async fn error_frame_from(res: Response) -> Message {
+    let status = res.status();
+    let body = axum::body::to_bytes(res.into_body(), 64 * 1024).await.unwrap_or_default();
+    let error = serde_json::from_slice::<Value>(&body)
+        .ok()
+        .and_then(|mut body| body.get_mut("error").map(Value::take))
+        .unwrap_or_else(|| serde_json::json!({ "type": "server_error", "message": String::from_utf8_lossy(&body) }));
+    let frame = serde_json::json!({ "type": "error", "status": status.as_u16(), "error": error });
+    Message::Text(frame.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{request_id::RequestIdLayer, tests::test_state};
+    use axum::{routing::{get, post}, Router};
+    use std::sync::{Arc, Mutex};
+    use tokio_tungstenite::tungstenite;
+
+    fn text(message: Message) -> Value {
+        match message {
+            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+            other => panic!("expected a text frame, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_parse_frames() {
+        let request = parse_frame(r#"{"model": "llama3", "input": "Hi"}"#, false);
+        assert!(matches!(request, Inbound::Request(request) if request.model == "llama3"));
+        assert!(matches!(parse_frame(r#"{"input": "Hi"}"#, false), Inbound::Invalid(_)));
+        assert!(matches!(parse_frame(r#"{"model": "llama3", "input": " "}"#, false), Inbound::Invalid(_)));
+
+        let output = parse_frame(r#"{"type": "tool_output", "call_id": "call_1", "output": "42"}"#, true);
+        assert!(matches!(output, Inbound::ToolOutput { call_id, output } if call_id == "call_1" && output == "42"));
+        for frame in [
+            r#"{"type": "tool_output", "call_id": "call_1"}"#,
+            r#"{"type": "tool_output", "call_id": "call_1", "output": "42", "extra": 1}"#,
+            r#"{"type": "cancel"}"#,
+            r#"{"model": "llama3", "input": "Hi"}"#,
+            "not json",
+        ] {
+            assert!(matches!(parse_frame(frame, true), Inbound::Invalid(_)), "{}", frame);
+        }
+    }
+
+    #[test]
+    fn test_function_calls() {
+        let events = vec![
+            OrsEvent::ItemAdded { sequence_number: Some(0), output_index: Some(0), item: ResponseItem::message("msg_1") },
+            OrsEvent::ItemAdded {
+                sequence_number: Some(1),
+                output_index: Some(1),
+                item: ResponseItem::function_call("fc_1", "call_1", "get_weather"),
+            },
+        ];
+        assert_eq!(function_calls(&events), vec![("call_1".to_string(), "get_weather".to_string())]);
+    }
+
+    #[tokio::test]
+    async fn test_error_frame_from_response() {
+        let res = error_response(StatusCode::BAD_GATEWAY, "upstream_error", "Upstream is down");
+        let frame = text(error_frame_from(res).await);
+        assert_eq!(frame["type"], "error");
+        assert_eq!(frame["status"], 502);
+        assert_eq!(frame["error"]["type"], "upstream_error");
+        assert_eq!(frame["error"]["message"], "Upstream is down");
+
+        let res = (StatusCode::INTERNAL_SERVER_ERROR, "Failed to load context").into_response();
+        let frame = text(error_frame_from(res).await);
+        assert_eq!(frame["error"], serde_json::json!({ "type": "server_error", "message": "Failed to load context" }));
+    }
+
+    /// Serves `app` on a local port, returning its address.
+    async fn serve(app: Router) -> std::net::SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    /// A chat completions upstream that calls `get_weather` on the first request and answers
+    /// in text on the next, recording the requests it gets.
+    async fn upstream(requests: Arc<Mutex<Vec<Value>>>) -> String {
+        let app = Router::new().route(
+            "/v1/chat/completions",
+            post(move |axum::Json(body): axum::Json<Value>| {
+                let requests = requests.clone();
+                async move {
+                    let mut requests = requests.lock().unwrap();
+                    requests.push(body);
+                    let chunks = if requests.len() == 1 {
+                        vec![
+                            serde_json::json!({ "choices": [{
+                                "index": 0,
+                                "delta": { "tool_calls": [{
+                                    "index": 0,
+                                    "id": "call_1",
+                                    "type": "function",
+                                    "function": { "name": "get_weather", "arguments": "{\"city\":\"SF\"}" },
+                                }] },
+                                "finish_reason": null,
+                            }] }),
+                            serde_json::json!({ "choices": [{ "index": 0, "delta": {}, "finish_reason": "tool_calls" }] }),
+                        ]
+                    } else {
+                        vec![
+                            serde_json::json!({ "choices": [{ "index": 0, "delta": { "content": "Sunny" }, "finish_reason": null }] }),
+                            serde_json::json!({ "choices": [{ "index": 0, "delta": {}, "finish_reason": "stop" }] }),
+                        ]
+                    };
+                    let body: String = chunks.iter().map(|chunk| format!("data: {}\n\n", chunk)).collect();
+                    ([("content-type", "text/event-stream")], body + "data: [DONE]\n\n")
+                }
+            }),
+        );
+        format!("http://{}/v1/chat/completions", serve(app).await)
+    }
+
+    /// Runs a session sending `request` against [`upstream`], answering the tool call once the
+    /// first response has been saved. Returns the event types received and the upstream requests.
+    async fn round_trip(request: Value) -> (Vec<String>, Vec<Value>) {
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let state = test_state().await;
+        state.upstream_url.store(Arc::new(upstream(requests.clone()).await));
+        let db = state.db.clone();
+        let app = Router::new().route("/v1/responses/stream", get(stream_responses)).layer(RequestIdLayer).with_state(state);
+        let addr = serve(app).await;
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/v1/responses/stream", addr)).await.unwrap();
+        socket.send(tungstenite::Message::Text(request.to_string())).await.unwrap();
+
+        let mut types = Vec::new();
+        while let Some(message) = socket.next().await {
+            let frame: Value = match message.unwrap() {
+                tungstenite::Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                tungstenite::Message::Close(_) => break,
+                other => panic!("unexpected frame {:?}", other),
+            };
+            let event_type = frame["type"].as_str().unwrap().to_string();
+            if event_type == "response.output_item.added" && frame["item"]["type"] == "function_call" {
+                assert_eq!(frame["item"]["call_id"], "call_1");
+            }
+            if event_type == "response.done" && !types.contains(&event_type) {
+                // The first turn is saved by the write-behind queue
+                for _ in 0..100 {
+                    let saved: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM items").fetch_one(db.pool()).await.unwrap();
+                    if saved > 0 {
+                        break;
+                    }
+                    tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+                }
+                let output = serde_json::json!({ "type": "tool_output", "call_id": "call_1", "output": "72F" });
+                socket.send(tungstenite::Message::Text(output.to_string())).await.unwrap();
+            }
+            types.push(event_type);
+        }
+
+        let requests = requests.lock().unwrap().clone();
+        (types, requests)
+    }
+
+    #[tokio::test]
+    async fn test_session_runs_a_tool_round_trip() {
+        let (types, requests) = round_trip(serde_json::json!({ "model": "m", "input": "Weather in SF?" })).await;
+
+        // Two responses, the first ending in the tool call and the second answering with its output
+        assert!(!types.contains(&"error".to_string()), "{:?}", types);
+        assert_eq!(types.iter().filter(|t| *t == "response.done").count(), 2, "{:?}", types);
+        let first_done = types.iter().position(|t| t == "response.done").unwrap();
+        assert!(types[first_done..].contains(&"response.output_text.delta".to_string()));
+
+        assert_eq!(requests.len(), 2);
+        let messages = requests[1]["messages"].as_array().unwrap();
+        let tool = messages.iter().find(|message| message["role"] == "tool").unwrap();
+        assert_eq!(tool["tool_call_id"], "call_1");
+        assert_eq!(tool["content"], "72F");
+    }
+
+    #[tokio::test]
+    async fn test_tool_round_trip_keeps_the_input_alongside_instructions() {
+        let request = serde_json::json!({ "model": "m", "input": "Weather in SF?", "instructions": "Be terse" });
+        let (types, requests) = round_trip(request).await;
+        assert_eq!(types.iter().filter(|t| *t == "response.done").count(), 2, "{:?}", types);
+
+        // The instructions saved with the first turn don't push the question out of the second
+        assert_eq!(requests.len(), 2);
+        let messages = requests[1]["messages"].as_array().unwrap();
+        let user: Vec<_> = messages.iter().filter(|message| message["role"] == "user").collect();
+        assert_eq!(user.len(), 1, "{:?}", messages);
+        assert!(user[0]["content"].to_string().contains("Weather in SF?"), "{:?}", messages);
+        let instructions = messages.iter().filter(|message| message.to_string().contains("Be terse")).count();
+        assert_eq!(instructions, 1, "{:?}", messages);
+    }
+}
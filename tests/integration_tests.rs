@@ -0,0 +1,136 @@
+//! End-to-end tests: the proxy binary in front of a real Ollama running in Docker.
+//!
+//! Only built with `--features integration`. The first run pulls the Ollama image and the model,
+//! which takes a few minutes.
+#![cfg(feature = "integration")]
+
+use std::{
+    net::{Ipv4Addr, TcpListener},
+    path::Path,
+    time::Duration,
+};
+use testcontainers::{
+    core::{IntoContainerPort, WaitFor},
+    runners::AsyncRunner,
+    ContainerAsync, GenericImage, ImageExt,
+};
+use tokio::process::{Child, Command};
+
+const OLLAMA_PORT: u16 = 11434;
+const MODEL: &str = "llama3.2:1b";
+
+/// Starts Ollama and pulls `MODEL` into it. Returns the container (stopped when dropped) and
+/// Ollama's base URL.
+async fn start_ollama() -> (ContainerAsync<GenericImage>, String) {
+    let container = GenericImage::new("ollama/ollama", "latest")
+        .with_exposed_port(OLLAMA_PORT.tcp())
+        .with_wait_for(WaitFor::message_on_stderr("Listening on"))
+        .with_startup_timeout(Duration::from_secs(300))
+        .start()
+        .await
+        .expect("Failed to start Ollama; is Docker running?");
+    let port = container.get_host_port_ipv4(OLLAMA_PORT).await.unwrap();
+    let base_url = format!("http://127.0.0.1:{}", port);
+
+    let res = reqwest::Client::new()
+        .post(format!("{}/api/pull", base_url))
+        .json(&serde_json::json!({ "model": MODEL, "stream": false }))
+        .timeout(Duration::from_secs(900))
+        .send()
+        .await
+        .expect("Failed to pull model");
+    assert!(res.status().is_success(), "Pulling {} failed: {}", MODEL, res.text().await.unwrap_or_default());
+    (container, base_url)
+}
+
+/// A port nothing is listening on right now.
+fn free_port() -> u16 {
+    TcpListener::bind((Ipv4Addr::LOCALHOST, 0)).unwrap().local_addr().unwrap().port()
+}
+
+/// Runs the proxy on `port` against `upstream_url`, storing into the SQLite file at `db_path`.
+/// Killed when the returned child is dropped.
+async fn start_proxy(port: u16, upstream_url: &str, db_path: &Path) -> Child {
+    let child = Command::new(env!("CARGO_BIN_EXE_rust-ors-proxy"))
+        .env("LISTEN_ADDR", format!("127.0.0.1:{}", port))
+        .env("UPSTREAM_URL", upstream_url)
+        .env("DATABASE_URL", format!("sqlite://{}?mode=rwc", db_path.display()))
+        .env("RUST_LOG", "warn")
+        .kill_on_drop(true)
+        .spawn()
+        .expect("Failed to start the proxy");
+
+    let health = format!("http://127.0.0.1:{}/health", port);
+    for _ in 0..100 {
+        if reqwest::get(&health).await.is_ok_and(|res| res.status().is_success()) {
+            return child;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    panic!("Proxy didn't come up on port {}", port);
+}
+
+#[tokio::test]
+async fn test_streams_a_response_from_ollama() {
+    let (_ollama, ollama_url) = start_ollama().await;
+    let port = free_port();
+    let db_path = std::env::temp_dir().join(format!("ors-integration-{}.db", uuid::Uuid::new_v4().simple()));
+    let _proxy = start_proxy(port, &format!("{}/v1/chat/completions", ollama_url), &db_path).await;
+
+    let res = reqwest::Client::new()
+        .post(format!("http://127.0.0.1:{}/v1/responses", port))
+        .header("Accept", "text/event-stream")
+        .json(&serde_json::json!({
+            "model": MODEL,
+            "stream": true,
+            "input": [{
+                "type": "message",
+                "role": "user",
+                "content": [{ "type": "input_text", "text": "Say the word 'hello' only." }]
+            }]
+        }))
+        .timeout(Duration::from_secs(300))
+        .send()
+        .await
+        .unwrap();
+    assert!(res.status().is_success(), "Proxy answered {}", res.status());
+    let body = res.text().await.unwrap();
+
+    let events: Vec<&str> = body.lines().filter_map(|line| line.strip_prefix("event: ")).collect();
+    assert_eq!(events.first(), Some(&"response.created"), "{}", body);
+    assert!(events.contains(&"response.output_text.delta"), "{}", body);
+    assert_eq!(events.last(), Some(&"response.done"), "{}", body);
+
+    // Event ids are `{conversation_id}:{seq}`
+    let conversation_id = body
+        .lines()
+        .find_map(|line| line.strip_prefix("id: "))
+        .and_then(|id| id.rsplit_once(':'))
+        .map(|(conversation_id, _)| conversation_id.to_string())
+        .expect("Events carry no id");
+
+    // The interaction is written by a background worker, so give it a moment
+    let pool = sqlx::SqlitePool::connect(&format!("sqlite://{}", db_path.display())).await.unwrap();
+    let mut items = 0;
+    for _ in 0..50 {
+        items = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM items WHERE conversation_id = ?")
+            .bind(&conversation_id)
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        if items >= 2 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+    assert!(items >= 2, "Expected the input and the answer stored for {}, found {} items", conversation_id, items);
+    let conversations: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM conversations WHERE id = ?")
+        .bind(&conversation_id)
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+    assert_eq!(conversations, 1);
+
+    pool.close().await;
+    let _ = std::fs::remove_file(&db_path);
+}